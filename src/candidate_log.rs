@@ -0,0 +1,172 @@
+//! Log of the liquidation candidates of every scan cycle, as newline-delimited JSON, for
+//! offline analysis of the opportunities seen and the actions taken on them
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crossbeam::channel::{Sender, TrySendError};
+use log::{error, warn};
+
+/// Cycles queued for writing, cycles queued beyond it are dropped
+const CANDIDATE_LOG_QUEUE_SIZE: usize = 64;
+
+/// What was done with a candidate during its scan cycle
+#[derive(Debug, Clone, Copy, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CandidateAction {
+    /// Beyond `max_liquidation_candidates`, or the cycle stopped before reaching it
+    NotAttempted,
+    Liquidated,
+    /// Attempted, but skipped before sending, e.g. because it recovered or wasn't profitable
+    Skipped,
+    Failed,
+}
+
+#[derive(Debug, serde::Serialize)]
+pub struct CandidateLogEntry {
+    /// Position in the cycle's candidates, ranked by profit
+    pub rank: usize,
+    pub account: String,
+    pub liquidatable_amount: f64,
+    pub profit: f64,
+    pub action: CandidateAction,
+}
+
+#[derive(Debug, serde::Serialize)]
+struct CandidateLogCycle {
+    timestamp_ms: u128,
+    candidates: Vec<CandidateLogEntry>,
+}
+
+/// Writes the candidate log from a background thread, the scan path only queues cycles
+///
+/// Once the log grows past `max_bytes` it is rotated, keeping a single previous log with a
+/// `.1` suffix.
+pub struct CandidateLog {
+    cycle_tx: Sender<CandidateLogCycle>,
+}
+
+impl CandidateLog {
+    pub fn new(path: String, max_bytes: u64) -> io::Result<Self> {
+        let (cycle_tx, cycle_rx) =
+            crossbeam::channel::bounded::<CandidateLogCycle>(CANDIDATE_LOG_QUEUE_SIZE);
+
+        let mut file = Self::open(&path)?;
+
+        thread::Builder::new()
+            .name("evaCandidateLog".to_string())
+            .spawn(move || {
+                for cycle in cycle_rx {
+                    if let Err(e) = Self::write_cycle(&path, &mut file, max_bytes, &cycle) {
+                        error!("Failed to write candidate log {}: {:?}", path, e);
+                    }
+                }
+            })?;
+
+        Ok(Self { cycle_tx })
+    }
+
+    /// Queue the candidates of a scan cycle for writing, without waiting for the write
+    pub fn log_cycle(&self, candidates: Vec<CandidateLogEntry>) {
+        let cycle = CandidateLogCycle {
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_millis())
+                .unwrap_or_default(),
+            candidates,
+        };
+
+        match self.cycle_tx.try_send(cycle) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => warn!("Candidate log writer is behind, cycle dropped"),
+            Err(TrySendError::Disconnected(_)) => error!("Candidate log writer stopped"),
+        }
+    }
+
+    fn open(path: &str) -> io::Result<File> {
+        OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    fn write_cycle(
+        path: &str,
+        file: &mut File,
+        max_bytes: u64,
+        cycle: &CandidateLogCycle,
+    ) -> io::Result<()> {
+        let mut line = serde_json::to_vec(cycle)?;
+        line.push(b'\n');
+
+        let len = file.metadata()?.len();
+
+        if len > 0 && len + line.len() as u64 > max_bytes {
+            fs::rename(path, format!("{}.1", path))?;
+            *file = Self::open(path)?;
+        }
+
+        file.write_all(&line)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cycle(account: &str) -> CandidateLogCycle {
+        CandidateLogCycle {
+            timestamp_ms: 1_700_000_000_000,
+            candidates: vec![CandidateLogEntry {
+                rank: 0,
+                account: account.to_string(),
+                liquidatable_amount: 1_000_000.0,
+                profit: 2.5,
+                action: CandidateAction::Liquidated,
+            }],
+        }
+    }
+
+    #[test]
+    fn cycles_are_logged_as_ndjson_and_rotated_past_the_max_size() {
+        let dir = std::env::temp_dir().join(format!(
+            "eva_candidate_log_{}_{}",
+            std::process::id(),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("candidates.ndjson").to_string_lossy().to_string();
+
+        let line_len = serde_json::to_vec(&cycle("account1")).unwrap().len() as u64 + 1;
+        let mut file = CandidateLog::open(&path).unwrap();
+
+        // Two lines fit, the third one rotates the log
+        for account in ["account1", "account2", "account3"] {
+            CandidateLog::write_cycle(&path, &mut file, 2 * line_len, &cycle(account)).unwrap();
+        }
+
+        let rotated = fs::read_to_string(format!("{}.1", path)).unwrap();
+        let current = fs::read_to_string(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        let accounts = |log: &str| {
+            log.lines()
+                .map(|line| {
+                    let cycle: serde_json::Value = serde_json::from_str(line).unwrap();
+                    assert_eq!(cycle["candidates"][0]["action"], "liquidated");
+                    cycle["candidates"][0]["account"]
+                        .as_str()
+                        .unwrap()
+                        .to_string()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(accounts(&rotated), vec!["account1", "account2"]);
+        assert_eq!(accounts(&current), vec!["account3"]);
+    }
+}
@@ -1,14 +1,19 @@
 use crate::{processor::EvaLiquidator, state_engine::engine::StateEngineConfig};
 use env_logger::Builder;
-use log::{info, warn};
+use log::{error, info, warn};
 use solana_sdk::pubkey::Pubkey;
 use state_engine::engine::StateEngineService;
 use std::error::Error;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use structopt::StructOpt;
 
+mod candidate_log;
 mod marginfi_account;
 mod marginfi_ixs;
+mod metrics;
 mod processor;
+mod rpc_pool;
 mod sender;
 mod state_engine;
 mod token_account_manager;
@@ -65,6 +70,14 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     info!("config: {:#?}", config);
 
+    for warning in config.liquidator_config.diagnose_noop_config() {
+        warn!("Config will prevent liquidations: {}", warning);
+    }
+
+    if let Some(bind_address) = &config.liquidator_config.metrics_bind_address {
+        metrics::start_exporter(bind_address)?;
+    }
+
     // Assemble stateful engine service
     info!("starting eva");
 
@@ -79,16 +92,32 @@ fn main() -> Result<(), Box<dyn Error>> {
             .unwrap();
     });
 
+    let shutdown = Arc::new(AtomicBool::new(false));
+
+    let shutdown_clone = shutdown.clone();
+
+    tokio_rt.spawn(async move {
+        match tokio::signal::ctrl_c().await {
+            Ok(()) => {
+                warn!("Received Ctrl-C, shutting down");
+                shutdown_clone.store(true, Ordering::Relaxed);
+            }
+            Err(e) => error!("Failed to listen for Ctrl-C: {:?}", e),
+        }
+    });
+
     let state_eng_clone = state_engine.clone();
+    let shutdown_clone = shutdown.clone();
 
     let state_eng_handle = tokio_rt.spawn(async move {
-        state_eng_clone.start().await.unwrap();
+        state_eng_clone.start(shutdown_clone).await.unwrap();
     });
 
-    let handle = EvaLiquidator::start(
+    let (handle, _liquidation_events) = EvaLiquidator::start(
         state_engine.clone(),
         update_rx,
         config.liquidator_config.clone(),
+        shutdown,
     )?;
 
     let state_eng_clone = state_engine.clone();
@@ -2,18 +2,25 @@ use std::sync::{Arc, RwLock};
 
 use log::{error, info};
 use marginfi::state::marginfi_group::BankVaultType;
-use solana_client::rpc_client::RpcClient;
 use solana_sdk::{
-    compute_budget::ComputeBudgetInstruction, pubkey::Pubkey, signature::Keypair, signer::Signer,
+    compute_budget::ComputeBudgetInstruction,
+    pubkey::Pubkey,
+    signature::{Keypair, Signature},
+    signer::Signer,
     transaction::Transaction,
 };
 
 use crate::{
     marginfi_ixs::*,
-    sender::{aggressive_send_tx, SenderCfg},
+    rpc_pool::RpcPool,
+    sender::{aggressive_send_tx, send_bundle, JitoCfg, SendTxError, SenderCfg},
     state_engine::{engine::StateEngineService, marginfi_account::MarginfiAccountWrapper},
 };
 
+/// Default compute unit limit requested for liquidation transactions, comfortably covering a
+/// marginfi liquidate instruction
+pub const LIQUIDATE_COMPUTE_UNIT_LIMIT: u32 = 400_000;
+
 #[derive(thiserror::Error, Debug)]
 pub enum MarginfiAccountError {
     #[error("Failed to perform action: {0}")]
@@ -22,20 +29,41 @@ pub enum MarginfiAccountError {
     RWError,
     #[error("Client error: {0}")]
     RpcClientError(#[from] solana_client::client_error::ClientError),
+    #[error("Transaction {0} not confirmed in time")]
+    ConfirmationTimeout(Signature),
+}
+
+impl MarginfiAccountError {
+    /// `ActionFailed(action)` for a failed send, keeping confirmation timeouts apart since the
+    /// transaction may still land
+    fn from_send_error(err: SendTxError, action: &'static str) -> Self {
+        match err {
+            SendTxError::ConfirmationTimeout(signature) => {
+                MarginfiAccountError::ConfirmationTimeout(signature)
+            }
+            _ => MarginfiAccountError::ActionFailed(action),
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct TxConfig {
     pub compute_unit_price_micro_lamports: Option<u64>,
+    /// Compute unit limit requested by liquidation transactions
+    pub compute_unit_limit: u32,
+    /// Build and sign transactions without sending them
+    pub dry_run: bool,
+    pub sender_cfg: SenderCfg,
+    /// Submit liquidations as Jito bundles instead of through the RPC
+    pub jito: Option<JitoCfg>,
 }
 
 pub struct MarginfiAccount {
     pub account_wrapper: Arc<RwLock<MarginfiAccountWrapper>>,
     state_engine: Arc<StateEngineService>,
     signer_keypair: Arc<Keypair>,
-    rpc_client: Arc<RpcClient>,
+    rpc_pool: Arc<RpcPool>,
     program_id: Pubkey,
-    token_program: Pubkey,
     group: Pubkey,
 }
 
@@ -44,23 +72,28 @@ impl MarginfiAccount {
         account_wrapper: Arc<RwLock<MarginfiAccountWrapper>>,
         state_engine: Arc<StateEngineService>,
         signer_keypair: Arc<Keypair>,
-        rpc_client: Arc<RpcClient>,
+        rpc_pool: Arc<RpcPool>,
     ) -> Self {
         let program_id = marginfi::id();
-        let token_program = spl_token::id();
         let group = account_wrapper.read().unwrap().account.group;
 
         Self {
             account_wrapper,
             state_engine,
             signer_keypair,
-            rpc_client,
+            rpc_pool,
             program_id,
-            token_program,
             group,
         }
     }
 
+    /// Token program owning `mint`, which token transfers of the mint's bank go through
+    fn get_token_program(&self, mint: &Pubkey) -> Pubkey {
+        self.state_engine
+            .token_account_manager
+            .get_token_program_for_mint(mint)
+    }
+
     pub fn deposit(
         &self,
         bank_pk: Pubkey,
@@ -93,11 +126,11 @@ impl MarginfiAccount {
             bank_pk,
             token_account,
             bank.bank.liquidity_vault,
-            self.token_program,
+            self.get_token_program(&bank.bank.mint),
             amount,
         );
 
-        let recent_blockhash = self.rpc_client.get_latest_blockhash()?;
+        let recent_blockhash = self.rpc_pool.get_latest_blockhash()?;
 
         let mut ixs = vec![deposit_ix];
 
@@ -116,11 +149,10 @@ impl MarginfiAccount {
 
         drop(bank);
 
-        let sig =
-            aggressive_send_tx(self.rpc_client.clone(), &tx, SenderCfg::DEFAULT).map_err(|e| {
-                info!("Failed to deposit: {:?}", e);
-                MarginfiAccountError::ActionFailed("Failed to deposit")
-            })?;
+        let sig = self.send_tx(&tx, &send_cfg).map_err(|e| {
+            info!("Failed to deposit: {:?}", e);
+            MarginfiAccountError::from_send_error(e, "Failed to deposit")
+        })?;
 
         info!("Deposit successful, tx signature: {:?}", sig);
 
@@ -162,12 +194,12 @@ impl MarginfiAccount {
             bank_pk,
             token_account,
             bank.bank.liquidity_vault,
-            self.token_program,
+            self.get_token_program(&bank.bank.mint),
             amount,
             repay_all,
         );
 
-        let recent_blockhash = self.rpc_client.get_latest_blockhash()?;
+        let recent_blockhash = self.rpc_pool.get_latest_blockhash()?;
 
         let compute_budget_price_ix = ComputeBudgetInstruction::set_compute_unit_price(10_000);
 
@@ -188,8 +220,9 @@ impl MarginfiAccount {
 
         drop(bank);
 
-        let sig = aggressive_send_tx(self.rpc_client.clone(), &tx, SenderCfg::DEFAULT)
-            .map_err(|_e| MarginfiAccountError::ActionFailed("Failed to repay"))?;
+        let sig = self
+            .send_tx(&tx, &send_cfg)
+            .map_err(|e| MarginfiAccountError::from_send_error(e, "Failed to repay"))?;
 
         info!("Repay successful, tx signature: {:?}", sig);
 
@@ -249,7 +282,7 @@ impl MarginfiAccount {
             )
             .0,
             bank.bank.liquidity_vault,
-            self.token_program,
+            self.get_token_program(&bank.bank.mint),
             observation_accounts,
             amount,
             withdraw_all,
@@ -262,7 +295,7 @@ impl MarginfiAccount {
             ixs.push(compute_budget_price_ix);
         }
 
-        let recent_blockhash = self.rpc_client.get_latest_blockhash()?;
+        let recent_blockhash = self.rpc_pool.get_latest_blockhash()?;
         let compute_budget_price_ix = ComputeBudgetInstruction::set_compute_unit_price(10_000);
 
         let tx = Transaction::new_signed_with_payer(
@@ -274,17 +307,44 @@ impl MarginfiAccount {
 
         drop(bank);
 
-        let sig =
-            aggressive_send_tx(self.rpc_client.clone(), &tx, SenderCfg::DEFAULT).map_err(|e| {
-                error!("Failed to withdraw: {:?}", e);
-                MarginfiAccountError::ActionFailed("Failed to withdraw")
-            })?;
+        let sig = self.send_tx(&tx, &send_cfg).map_err(|e| {
+            error!("Failed to withdraw: {:?}", e);
+            MarginfiAccountError::from_send_error(e, "Failed to withdraw")
+        })?;
 
         info!("Repay successful, tx signature: {:?}", sig);
 
         Ok(())
     }
 
+    /// Send a transaction, or only log it in a dry run
+    fn send_tx(&self, tx: &Transaction, send_cfg: &TxConfig) -> Result<Signature, SendTxError> {
+        if send_cfg.dry_run {
+            let signature = tx.signatures[0];
+            info!(
+                "Dry run, not sending transaction {} with instructions: {:?}",
+                signature, tx.message.instructions
+            );
+            return Ok(signature);
+        }
+
+        aggressive_send_tx(self.rpc_pool.clone(), tx, send_cfg.sender_cfg)
+    }
+
+    /// Send a transaction as a Jito bundle, or only log it in a dry run
+    fn send_bundle_tx(
+        &self,
+        tx: Transaction,
+        jito_cfg: &JitoCfg,
+        send_cfg: &TxConfig,
+    ) -> Result<Signature, SendTxError> {
+        if send_cfg.dry_run {
+            return self.send_tx(&tx, send_cfg);
+        }
+
+        send_bundle(self.rpc_pool.clone(), &[tx], jito_cfg, send_cfg.sender_cfg)
+    }
+
     pub fn liquidate(
         &self,
         liquidate_account: Arc<RwLock<MarginfiAccountWrapper>>,
@@ -292,7 +352,7 @@ impl MarginfiAccount {
         liab_bank_pk: Pubkey,
         asset_amount: u64,
         send_cfg: TxConfig,
-    ) -> Result<(), MarginfiAccountError> {
+    ) -> Result<Signature, MarginfiAccountError> {
         let asset_bank_ref = self.state_engine.get_bank(&asset_bank_pk).unwrap();
         let asset_bank = asset_bank_ref
             .read()
@@ -324,7 +384,8 @@ impl MarginfiAccount {
         let bank_liquidity_vault = liab_bank.bank.liquidity_vault;
         let bank_insurance_vault = liab_bank.bank.insurance_vault;
 
-        let token_program = self.token_program;
+        // Vault transfers of a liquidation are all in the liability token
+        let token_program = self.get_token_program(&liab_bank.bank.mint);
 
         let liquidator_observation_accounts = self
             .account_wrapper
@@ -359,9 +420,10 @@ impl MarginfiAccount {
         drop(asset_bank);
         drop(liab_bank);
 
-        let compute_budget_limit_ix = ComputeBudgetInstruction::set_compute_unit_limit(400_000);
+        let compute_budget_limit_ix =
+            ComputeBudgetInstruction::set_compute_unit_limit(send_cfg.compute_unit_limit);
 
-        let mut ixs = vec![liquidate_ix, compute_budget_limit_ix];
+        let mut ixs = vec![compute_budget_limit_ix];
 
         if let Some(price) = send_cfg.compute_unit_price_micro_lamports {
             let compute_budget_price_ix = ComputeBudgetInstruction::set_compute_unit_price(price);
@@ -369,21 +431,31 @@ impl MarginfiAccount {
             ixs.push(compute_budget_price_ix);
         }
 
+        ixs.push(liquidate_ix);
+
+        if let Some(jito_cfg) = &send_cfg.jito {
+            ixs.push(jito_cfg.make_tip_ix(&signer_pk));
+        }
+
         let tx = Transaction::new_signed_with_payer(
             &ixs,
             Some(&signer_pk),
             &[self.signer_keypair.as_ref()],
-            self.rpc_client.get_latest_blockhash()?,
+            self.rpc_pool.get_latest_blockhash()?,
         );
 
-        let sig =
-            aggressive_send_tx(self.rpc_client.clone(), &tx, SenderCfg::DEFAULT).map_err(|e| {
-                error!("Failed to liquidate: {:?}", e);
-                MarginfiAccountError::ActionFailed("Failed to liquidate")
-            })?;
+        let res = match &send_cfg.jito {
+            Some(jito_cfg) => self.send_bundle_tx(tx, jito_cfg, &send_cfg),
+            None => self.send_tx(&tx, &send_cfg),
+        };
+
+        let sig = res.map_err(|e| {
+            error!("Failed to liquidate: {:?}", e);
+            MarginfiAccountError::from_send_error(e, "Failed to liquidate")
+        })?;
 
         info!("Liquidation successful, tx signature: {:?}", sig);
 
-        Ok(())
+        Ok(sig)
     }
 }
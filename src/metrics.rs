@@ -0,0 +1,177 @@
+//! Prometheus metrics of the state engine and the liquidator, served over HTTP at `/metrics`
+//! when `metrics_bind_address` is set
+//!
+//! Metrics are always collected, serving them is the only thing the exporter adds.
+
+use std::{
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    sync::OnceLock,
+    thread,
+    time::Duration,
+};
+
+use log::{error, info, warn};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts,
+    Registry, TextEncoder,
+};
+
+static METRICS: OnceLock<Metrics> = OnceLock::new();
+
+/// Time a metrics connection gets to send its request and read the response, requests are
+/// served one at a time so a stalled client would otherwise block the exporter
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct Metrics {
+    registry: Registry,
+    /// Accounts evaluated in the last scan cycle
+    pub accounts_scanned: IntGauge,
+    pub scan_duration_seconds: Histogram,
+    pub liquidations_attempted: IntCounter,
+    /// Attempted liquidations by outcome: succeeded, skipped or failed
+    pub liquidations: IntCounterVec,
+    /// Swaps by source and destination mint and outcome
+    pub swaps: IntCounterVec,
+    /// Swap duration, including retries, by source mint
+    pub swap_duration_seconds: HistogramVec,
+    /// Geyser account updates by kind: marginfi, oracle, token or sol
+    pub geyser_updates: IntCounterVec,
+    /// RPC requests by endpoint host and outcome, errors only counting transient failures
+    pub rpc_requests: IntCounterVec,
+}
+
+/// Metrics shared by the whole process, registered on first use
+pub fn metrics() -> &'static Metrics {
+    METRICS.get_or_init(|| Metrics::new().expect("Failed to register metrics"))
+}
+
+impl Metrics {
+    fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("eva01".to_string()), None)?;
+
+        let accounts_scanned = IntGauge::new(
+            "accounts_scanned",
+            "Accounts evaluated in the last scan cycle",
+        )?;
+        let scan_duration_seconds = Histogram::with_opts(HistogramOpts::new(
+            "scan_duration_seconds",
+            "Duration of the liquidation candidate search of a scan cycle",
+        ))?;
+        let liquidations_attempted =
+            IntCounter::new("liquidations_attempted_total", "Liquidations attempted")?;
+        let liquidations = IntCounterVec::new(
+            Opts::new("liquidations_total", "Attempted liquidations by outcome"),
+            &["outcome"],
+        )?;
+        let swaps = IntCounterVec::new(
+            Opts::new("swaps_total", "Swaps by mint and outcome"),
+            &["src_mint", "dst_mint", "outcome"],
+        )?;
+        let swap_duration_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "swap_duration_seconds",
+                "Duration of swaps, retries included",
+            )
+            .buckets(vec![0.5, 1.0, 2.5, 5.0, 10.0, 20.0, 45.0, 90.0]),
+            &["src_mint"],
+        )?;
+        let geyser_updates = IntCounterVec::new(
+            Opts::new("geyser_updates_total", "Geyser account updates by kind"),
+            &["kind"],
+        )?;
+        let rpc_requests = IntCounterVec::new(
+            Opts::new("rpc_requests_total", "RPC requests by endpoint and outcome"),
+            &["endpoint", "outcome"],
+        )?;
+
+        registry.register(Box::new(accounts_scanned.clone()))?;
+        registry.register(Box::new(scan_duration_seconds.clone()))?;
+        registry.register(Box::new(liquidations_attempted.clone()))?;
+        registry.register(Box::new(liquidations.clone()))?;
+        registry.register(Box::new(swaps.clone()))?;
+        registry.register(Box::new(swap_duration_seconds.clone()))?;
+        registry.register(Box::new(geyser_updates.clone()))?;
+        registry.register(Box::new(rpc_requests.clone()))?;
+
+        Ok(Self {
+            registry,
+            accounts_scanned,
+            scan_duration_seconds,
+            liquidations_attempted,
+            liquidations,
+            swaps,
+            swap_duration_seconds,
+            geyser_updates,
+            rpc_requests,
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+
+        if let Err(e) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            error!("Failed to encode metrics: {:?}", e);
+        }
+
+        buffer
+    }
+}
+
+/// Serve the metrics at `http://<bind_address>/metrics` from a background thread
+pub fn start_exporter(bind_address: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(bind_address)?;
+
+    info!("Serving metrics at http://{}/metrics", bind_address);
+
+    thread::Builder::new()
+        .name("evaMetrics".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = handle_request(stream) {
+                            warn!("Failed to serve metrics request: {:?}", e);
+                        }
+                    }
+                    Err(e) => warn!("Failed to accept metrics connection: {:?}", e),
+                }
+            }
+        })?;
+
+    Ok(())
+}
+
+fn handle_request(mut stream: TcpStream) -> io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let mut request_line = String::new();
+    BufReader::new(&stream).read_line(&mut request_line)?;
+
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+
+    let (status, content_type, body) = if path == "/metrics" {
+        (
+            "200 OK",
+            TextEncoder::new().format_type().to_string(),
+            metrics().encode(),
+        )
+    } else {
+        (
+            "404 Not Found",
+            "text/plain".to_string(),
+            b"Not Found".to_vec(),
+        )
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(&body)?;
+    stream.flush()
+}
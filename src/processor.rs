@@ -1,16 +1,21 @@
 use std::{
     cmp::min,
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     error::Error,
-    sync::{Arc, RwLock, RwLockReadGuard},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc, Mutex, RwLock, RwLockReadGuard,
+    },
     thread::{self, JoinHandle},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use crossbeam::channel::Receiver;
+use crossbeam::channel::{Receiver, TryRecvError};
+use dashmap::DashMap;
 use fixed::types::I80F48;
 use fixed_macro::types::I80F48;
 use jupiter_swap_api_client::{
-    quote::QuoteRequest,
+    quote::{QuoteRequest, SwapMode},
     swap::SwapRequest,
     transaction_config::{ComputeUnitPriceMicroLamports, TransactionConfig},
     JupiterSwapApiClient,
@@ -20,31 +25,56 @@ use marginfi::{
     constants::EXP_10_I80F48,
     state::{
         marginfi_account::{BalanceSide, RequirementType},
+        marginfi_group::Bank,
         price::{OraclePriceType, PriceAdapter, PriceBias},
     },
 };
+use rayon::{iter::ParallelIterator, slice::ParallelSlice};
 use sha2::{Digest, Sha256};
 use solana_sdk::{
+    hash::Hash,
+    instruction::InstructionError,
+    message::VersionedMessage,
+    native_token::LAMPORTS_PER_SOL,
     pubkey,
     pubkey::Pubkey,
-    signature::{read_keypair_file, Keypair},
-    signer::{SeedDerivable, Signer},
-    transaction::VersionedTransaction,
+    signature::{read_keypair_file, Keypair, Signature},
+    signer::{SeedDerivable, Signer, SignerError},
+    system_instruction,
+    transaction::{Transaction, TransactionError, VersionedTransaction},
 };
+use tokio::sync::broadcast;
 
 use crate::{
-    marginfi_account::{MarginfiAccountError, TxConfig},
-    sender::{aggressive_send_tx, SenderCfg},
+    candidate_log::{CandidateAction, CandidateLog, CandidateLogEntry},
+    marginfi_account::{MarginfiAccountError, TxConfig, LIQUIDATE_COMPUTE_UNIT_LIMIT},
+    metrics::metrics,
+    sender::{aggressive_send_tx, aggressive_send_tx_async, JitoCfg, SendTxError, SenderCfg},
     state_engine::{
-        engine::StateEngineService,
-        marginfi_account::{MarginfiAccountWrapper, MarginfiAccountWrapperError},
+        engine::{BankWrapper, StateEngineService},
+        marginfi_account::{
+            LiquidationOpportunity, MarginfiAccountWrapper, MarginfiAccountWrapperError,
+        },
     },
     utils::{
-        calc_weighted_assets, calc_weighted_liabs, fixed_from_float, from_pubkey_string,
-        from_vec_str_to_pubkey,
+        calc_weighted_assets, calc_weighted_liabs, fixed_from_float, fixed_to_native_amount,
+        from_option_vec_pubkey_string, from_pubkey_string, from_pubkey_string_map,
+        from_vec_str_to_pubkey, set_message_compute_unit_limit,
     },
 };
 
+/// Share of the liquidated asset value kept by the liquidator
+const LIQUIDATOR_FEE: f64 = 0.025;
+
+/// Jupiter program error code for a swap that exceeded its slippage tolerance
+const JUPITER_SLIPPAGE_TOLERANCE_EXCEEDED: u32 = 6001;
+
+/// How long a slippage failure on a mint pair keeps pre-widening the slippage of later swaps
+const SLIPPAGE_FAILURE_TTL: Duration = Duration::from_secs(600);
+
+/// Liquidation events buffered per subscriber, slower subscribers miss the oldest ones
+const LIQUIDATION_EVENT_CAPACITY: usize = 256;
+
 #[derive(thiserror::Error, Debug)]
 pub enum ProcessorError {
     #[error("Failed to read account")]
@@ -61,6 +91,48 @@ pub enum ProcessorError {
     ReqwsetError(#[from] reqwest::Error),
     #[error("AnyhowError: {0}")]
     AnyhowError(#[from] anyhow::Error),
+    #[error("Swap slippage tolerance exceeded")]
+    SlippageExceeded,
+    #[error("No route delivers the exact swap output within the input budget")]
+    ExactOutUnavailable,
+    #[error("State engine update channel disconnected")]
+    UpdateChannelDisconnected,
+    #[error("Confirmed transaction didn't change balances as expected")]
+    UnexpectedBalanceDelta,
+    #[error("Arithmetic overflow or division by zero computing {0}")]
+    MathError(&'static str),
+    #[error("Transaction {0} not confirmed in time")]
+    ConfirmationTimeout(Signature),
+    #[error("Failed to start liquidator, configured mints have no bank in the group: {0:?}")]
+    UnresolvedMints(Vec<Pubkey>),
+}
+
+impl ProcessorError {
+    /// Whether a sent transaction wasn't confirmed in time, it may still land so the state it
+    /// changes is unknown
+    pub fn is_confirmation_timeout(&self) -> bool {
+        match self {
+            ProcessorError::ConfirmationTimeout(_)
+            | ProcessorError::MarginfiAccountError(MarginfiAccountError::ConfirmationTimeout(_)) => {
+                true
+            }
+            ProcessorError::AnyhowError(e) => matches!(
+                e.downcast_ref::<MarginfiAccountError>(),
+                Some(MarginfiAccountError::ConfirmationTimeout(_))
+            ),
+            _ => false,
+        }
+    }
+}
+
+/// Sending error of a transaction, keeping confirmation timeouts apart from other failures
+fn send_error(err: SendTxError, message: &'static str) -> ProcessorError {
+    match err {
+        SendTxError::ConfirmationTimeout(signature) => {
+            ProcessorError::ConfirmationTimeout(signature)
+        }
+        _ => ProcessorError::Error(message),
+    }
 }
 
 #[derive(Debug, Clone, serde::Deserialize)]
@@ -73,11 +145,66 @@ pub struct EvaLiquidatorCfg {
         deserialize_with = "fixed_from_float"
     )]
     pub token_account_dust_threshold: I80F48,
+    /// Per-mint dust thresholds of token account balances, keyed by mint, in USD or in
+    /// native units
+    ///
+    /// A mint's override takes precedence over `token_account_dust_threshold`, which applies
+    /// to all other mints.
+    ///
+    /// Example:
+    /// [token_account_dust_thresholds]
+    /// "So11111111111111111111111111111111111111112" = { usd = 0.5 }
+    /// "DezXAZ8z7PnrnRJjz3wXBoRgixCa6xjnB7YaB1pPB263" = { native = 1000000 }
+    ///
+    /// Default: {}
+    #[serde(
+        default = "EvaLiquidatorCfg::default_token_account_dust_thresholds",
+        deserialize_with = "from_pubkey_string_map"
+    )]
+    pub token_account_dust_thresholds: HashMap<Pubkey, DustThreshold>,
+    /// Price bias used to value token account balances against the dust thresholds
+    ///
+    /// `neutral` values balances at the mid price, `conservative` at the low end of the
+    /// oracle's confidence interval, so that balances only above the threshold at the
+    /// optimistic mid price are left alone.
+    ///
+    /// Default: neutral
+    #[serde(default = "EvaLiquidatorCfg::default_dust_check_price_bias")]
+    pub dust_check_price_bias: EquityPriceBias,
+    /// Handling of the fractional native units left behind by deposits and withdrawals
+    ///
+    /// Balances are converted to instruction amounts by rounding down to whole native units,
+    /// see [`fixed_to_native_amount`]. `sweep` closes a deposit with `withdraw_all` when the
+    /// rounded amount covers all of its whole units, so no sub-unit remainder is left behind.
+    /// `keep` leaves the remainder in the account and ignores deposits below one native unit
+    /// when deciding whether the liquidator needs to be rebalanced.
+    ///
+    /// Default: sweep
+    #[serde(default = "EvaLiquidatorCfg::default_native_residual_handling")]
+    pub native_residual_handling: NativeResidualHandling,
+    /// SOL the signer keeps, anything above is wrapped and sold for the swap mint when
+    /// rebalancing
+    ///
+    /// Needs a SOL bank, whose token account the SOL is wrapped into.
+    ///
+    /// Default: 1
     #[serde(
         default = "EvaLiquidatorCfg::default_max_sol_balance",
         deserialize_with = "fixed_from_float"
     )]
     pub max_sol_balance: I80F48,
+    /// SOL the signer needs to pay fees with, liquidations are paused while it holds less
+    ///
+    /// Default: 0.01
+    #[serde(
+        default = "EvaLiquidatorCfg::default_min_sol_balance",
+        deserialize_with = "fixed_from_float"
+    )]
+    pub min_sol_balance: I80F48,
+    /// Mints kept as deposits when rebalancing, other deposits are sold to `swap_mint`
+    ///
+    /// Every mint, like `swap_mint`, must have a bank in the group, the liquidator doesn't
+    /// start otherwise. Duplicates are ignored.
     #[serde(
         default = "EvaLiquidatorCfg::default_preferred_mints",
         deserialize_with = "from_vec_str_to_pubkey"
@@ -93,10 +220,114 @@ pub struct EvaLiquidatorCfg {
     pub jup_swap_api_url: String,
     #[serde(default = "EvaLiquidatorCfg::default_slippage_bps")]
     pub slippage_bps: u16,
+    /// Slippage of swaps out of specific mints, keyed by the source mint, in place of
+    /// `slippage_bps`
+    ///
+    /// Example:
+    /// [liquidator_config.slippage_bps_overrides]
+    /// "EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v" = 10
+    ///
+    /// Default: {}
+    #[serde(
+        default = "EvaLiquidatorCfg::default_slippage_bps_overrides",
+        deserialize_with = "from_pubkey_string_map"
+    )]
+    pub slippage_bps_overrides: HashMap<Pubkey, u16>,
+    /// Maximum slippage a swap is retried with after failing on slippage
+    ///
+    /// Default: 1000
+    #[serde(default = "EvaLiquidatorCfg::default_max_slippage_bps")]
+    pub max_slippage_bps: u16,
+    /// Slippage added on each retry of a swap that failed on slippage
+    ///
+    /// Default: 100
+    #[serde(default = "EvaLiquidatorCfg::default_slippage_escalation_step")]
+    pub slippage_escalation_step: u16,
+    /// Maximum number of attempts of a swap failing on slippage, including the first one,
+    /// before giving up even if `max_slippage_bps` wasn't reached
+    ///
+    /// Default: 5
+    #[serde(default = "EvaLiquidatorCfg::default_max_swap_attempts")]
+    pub max_swap_attempts: u32,
+    /// Size swaps from Jupiter's execution price, derived from a quote, instead of oracle prices
+    ///
+    /// Only affects how much is swapped during rebalancing. Health and liquidatability are
+    /// always computed from oracle prices, as they must match the on-chain program.
+    ///
+    /// Default: false
+    #[serde(default = "EvaLiquidatorCfg::default_use_jupiter_prices_for_sizing")]
+    pub use_jupiter_prices_for_sizing: bool,
+    /// Buy liabilities with ExactOut swaps, acquiring exactly the liability to repay instead
+    /// of spending an estimated input amount and leaving dust behind
+    ///
+    /// Falls back to an ExactIn swap when no route delivers the exact output within the swap
+    /// mint available for it.
+    ///
+    /// Default: true
+    #[serde(default = "EvaLiquidatorCfg::default_use_exact_out_liability_swaps")]
+    pub use_exact_out_liability_swaps: bool,
+    /// Extra swap mint, in bps of the estimated input, withdrawn to buy a liability with an
+    /// ExactOut swap, covering the input the route may need over the oracle estimate
+    ///
+    /// Default: 100
+    #[serde(default = "EvaLiquidatorCfg::default_exact_out_swap_buffer_bps")]
+    pub exact_out_swap_buffer_bps: u16,
+    /// Maximum USD a single swap may lose to price impact and slippage, swaps quoted with a
+    /// worse minimum output are aborted regardless of `slippage_bps`
+    pub max_slippage_usd: Option<f64>,
+    /// Maximum share of the input value, in bps, a swap quote may lose at its quoted output,
+    /// valued at oracle prices
+    ///
+    /// Guards against thin routes or a misbehaving Jupiter endpoint turning a large balance
+    /// into next to nothing. Quotes losing more are aborted before anything is sent.
+    ///
+    /// Default: 1000
+    #[serde(default = "EvaLiquidatorCfg::default_max_swap_value_loss_bps")]
+    pub max_swap_value_loss_bps: u16,
     #[serde(default = "EvaLiquidatorCfg::default_compute_unit_price_micro_lamports")]
     pub compute_unit_price_micro_lamports: Option<u64>,
+    /// Compute unit limit requested by liquidation transactions, also used to price their
+    /// priority fee
+    ///
+    /// Default: 400000
+    #[serde(default = "EvaLiquidatorCfg::default_compute_unit_limit")]
+    pub compute_unit_limit: u32,
+    /// Compute unit limit replacing the one Jupiter requests in swap transactions
+    ///
+    /// Swaps routed through several AMMs can need well over what a liquidation does, so
+    /// Jupiter's own limit is kept when unset.
+    ///
+    /// Example:
+    /// 600000
+    pub swap_compute_unit_limit: Option<u32>,
+    /// Percentile of the recent prioritization fees, fetched from the RPC for the accounts a
+    /// transaction writes to, used as the compute unit price of swaps and liquidations
+    ///
+    /// The estimate is clamped to `min_compute_unit_price_micro_lamports` and
+    /// `max_compute_unit_price_micro_lamports`, `compute_unit_price_micro_lamports` is used
+    /// when the fees can't be fetched. Disabled when unset.
+    ///
+    /// Example:
+    /// 75 pays more than three quarters of the recent transactions
+    pub dynamic_priority_fee_percentile: Option<u8>,
+    /// Compute unit price of critical swaps, buying the liability taken on in a liquidation
+    ///
+    /// Until the liability is bought back the liquidator carries it, so landing these swaps
+    /// quickly is worth a higher priority fee.
+    ///
+    /// Default: the dynamic or static compute unit price
+    pub critical_swap_compute_unit_price_micro_lamports: Option<u64>,
+    /// Compute unit price of rebalance swaps, offloading seized collateral and leftover tokens
+    ///
+    /// These swaps are cleanup and can usually wait, so a lower priority fee is enough.
+    ///
+    /// Default: the dynamic or static compute unit price
+    pub rebalance_swap_compute_unit_price_micro_lamports: Option<u64>,
     /// Minimum profit on a liquidation to be considered, denominated in USD
     ///
+    /// Checked on the gross profit when scanning, and again on the profit net of the priority
+    /// fee and the slippage of selling the seized collateral before a liquidation is sent.
+    ///
     /// Example:
     /// 0.01 is $0.01
     ///
@@ -105,6 +336,625 @@ pub struct EvaLiquidatorCfg {
     pub min_profit: f64,
     /// Maximum liquidation value in USD
     pub max_liquidation_value: Option<f64>,
+    /// Minimum liquidation value in USD, smaller liquidations are skipped
+    ///
+    /// Catches liquidations capped to dust by the liquidator's capacity, which would spend a
+    /// transaction on a trivial amount.
+    ///
+    /// Default: 1
+    #[serde(default = "EvaLiquidatorCfg::default_min_liquidation_value")]
+    pub min_liquidation_value: f64,
+    /// Minimum total deposit value in USD of a scanned account, smaller accounts are skipped
+    /// before their health is computed, 0 scans every account
+    ///
+    /// Most accounts of large groups are dust that can never be profitably liquidated,
+    /// skipping them keeps scan cycles short during volatility.
+    ///
+    /// Default: 0
+    #[serde(default = "EvaLiquidatorCfg::default_min_account_value_usd")]
+    pub min_account_value_usd: f64,
+    /// Fraction of a liquidation's expected profit spent on its priority fee, so that more
+    /// profitable liquidations get more aggressive fees
+    ///
+    /// Takes precedence over `compute_unit_price_micro_lamports` for liquidations, the
+    /// resulting compute unit price is clamped to `min_compute_unit_price_micro_lamports` and
+    /// `max_compute_unit_price_micro_lamports`. Disabled when unset.
+    ///
+    /// Example:
+    /// 0.1 spends 10% of the expected profit on the priority fee
+    pub priority_fee_profit_fraction: Option<f64>,
+    /// Minimum compute unit price of profit based and dynamic priority fees
+    ///
+    /// Default: 0
+    #[serde(default = "EvaLiquidatorCfg::default_min_compute_unit_price_micro_lamports")]
+    pub min_compute_unit_price_micro_lamports: u64,
+    /// Maximum compute unit price of profit based and dynamic priority fees
+    ///
+    /// Default: 1000000
+    #[serde(default = "EvaLiquidatorCfg::default_max_compute_unit_price_micro_lamports")]
+    pub max_compute_unit_price_micro_lamports: u64,
+    /// Skip liquidations whose priority fee exceeds this fraction of the expected profit
+    ///
+    /// Example:
+    /// 0.5 skips liquidations where the priority fee would eat more than half of the profit
+    pub max_priority_fee_fraction_of_profit: Option<f64>,
+    /// Address the Prometheus metrics are served at, under `/metrics`
+    ///
+    /// Example: "0.0.0.0:9090"
+    pub metrics_bind_address: Option<String>,
+    /// Maximum total value in USD liquidated in a single scan cycle, across all candidates
+    ///
+    /// Setting it opts into liquidating several candidates per cycle, until the cap is
+    /// reached. Unset, a cycle stops after its first sent liquidation.
+    pub max_cycle_liquidation_value_usd: Option<f64>,
+    /// Number of the most profitable liquidatable accounts logged and attempted in a scan
+    /// cycle, in order of profit
+    ///
+    /// Default: 10
+    #[serde(default = "EvaLiquidatorCfg::default_max_liquidation_candidates")]
+    pub max_liquidation_candidates: usize,
+    /// Seconds an account isn't liquidated again after a liquidation transaction was sent for
+    /// it, unless an update of the account arrives in the meantime
+    ///
+    /// Keeps a slowly confirming liquidation from being repeated off the pre-liquidation state,
+    /// while an account still unhealthy after the update can be liquidated again right away.
+    ///
+    /// Default: 10
+    #[serde(default = "EvaLiquidatorCfg::default_liquidation_cooldown_secs")]
+    pub liquidation_cooldown_secs: u64,
+    /// Restrict the liquidation scan to these accounts, instead of every account of the group
+    ///
+    /// Health is only computed for the watched accounts, trading coverage for focus and a
+    /// much cheaper scan, e.g. to run several bots each targeting known large positions.
+    /// Watched accounts still have to be loaded by the state engine, which keeps streaming the
+    /// whole group unless `account_whitelist` limits it as well.
+    ///
+    /// Example:
+    /// watch_only_accounts = ["3T1kGHp7CrdeW9Qj1t8NMc2Ks233RyvzVhoaUPWoBEFK"]
+    #[serde(
+        default = "EvaLiquidatorCfg::default_watch_only_accounts",
+        deserialize_with = "from_option_vec_pubkey_string"
+    )]
+    pub watch_only_accounts: Option<Vec<Pubkey>>,
+    /// Path of a newline-delimited JSON log of every scan cycle's candidates, ranked by
+    /// profit, along with the action taken on each of them
+    ///
+    /// Writes happen on a background thread, cycles are dropped rather than slowing the scan
+    /// down when the writer falls behind.
+    ///
+    /// Example: "candidates.ndjson"
+    pub candidate_log_path: Option<String>,
+    /// Size in bytes past which the candidate log is rotated, keeping one previous log
+    ///
+    /// Default: 104857600
+    #[serde(default = "EvaLiquidatorCfg::default_candidate_log_max_bytes")]
+    pub candidate_log_max_bytes: u64,
+    /// Re-fetch the liquidator's own account before each liquidation that follows another in
+    /// the same cycle, so its capacity accounts for what prior liquidations consumed
+    ///
+    /// Without it, capacity is computed from the streamed account state, which may not reflect
+    /// the previous liquidation yet, over-estimating the capacity.
+    ///
+    /// Default: true
+    #[serde(default = "EvaLiquidatorCfg::default_refresh_liquidator_account_between_liquidations")]
+    pub refresh_liquidator_account_between_liquidations: bool,
+    /// Minimum free collateral in USD the liquidator needs for accounts to be scanned for
+    /// liquidations, the scan is skipped below it
+    ///
+    /// Rebalancing still runs while scans are skipped, it's what frees the collateral back up
+    /// by selling non-preferred deposits and repaying liabilities.
+    ///
+    /// Example:
+    /// 10.0 skips scans while the liquidator has less than $10 of free collateral
+    pub min_free_collateral_to_scan: Option<f64>,
+    /// Interval in seconds at which the liquidator's capacity, its free collateral and max
+    /// borrow per bank, is recomputed and cached for liquidation sizing and the
+    /// `min_free_collateral_to_scan` check, disabled when unset
+    ///
+    /// A cached capacity is at most this old, an older one is recomputed on demand instead.
+    /// The cache is dropped after each liquidation and rebalance, which change the capacity.
+    pub capacity_refresh_interval_secs: Option<u64>,
+    /// Log the transactions the liquidator would send, fully built and signed, instead of
+    /// sending them
+    ///
+    /// Scanning and sizing run as usual, so targeting and sizing can be checked against live
+    /// state without risking funds. Since nothing is sent, the liquidator's positions never
+    /// change and a rebalance is attempted at most once per iteration.
+    ///
+    /// Default: false
+    #[serde(default = "EvaLiquidatorCfg::default_dry_run")]
+    pub dry_run: bool,
+    /// Send swap transactions from the runtime's blocking pool instead of the processor task
+    ///
+    /// Swaps are sent from async code, sending them inline holds the runtime's worker thread
+    /// for the whole send and confirmation loop. Marginfi transactions (liquidations, deposits,
+    /// withdrawals and repayments) are sent from synchronous code on the processor thread and
+    /// always use the blocking client directly.
+    ///
+    /// Default: true
+    #[serde(default = "EvaLiquidatorCfg::default_send_swaps_on_blocking_pool")]
+    pub send_swaps_on_blocking_pool: bool,
+    /// Simulate liquidations, swaps and other transactions before sending them, and skip the
+    /// send when the simulation fails, disabling it skips the preflight
+    ///
+    /// A liquidation that would fail on-chain, because prices moved or a competitor got there
+    /// first, is then caught before any priority fee is paid, at the cost of a simulation
+    /// round trip before every send.
+    ///
+    /// Default: true
+    #[serde(default = "EvaLiquidatorCfg::default_simulate_before_send")]
+    pub simulate_before_send: bool,
+    /// Number of times liquidation, swap and other transactions are sent before waiting for
+    /// their confirmation
+    ///
+    /// Default: 12
+    #[serde(default = "EvaLiquidatorCfg::default_send_retry_count")]
+    pub send_retry_count: u64,
+    /// Interval in milliseconds between two sends of the same transaction
+    ///
+    /// Default: 0
+    #[serde(default = "EvaLiquidatorCfg::default_send_resend_interval_ms")]
+    pub send_resend_interval_ms: u64,
+    /// Wait for sent transactions to be confirmed before moving on to the steps depending on
+    /// them, such as depositing the proceeds of a swap
+    ///
+    /// Without it steps run against balances that may not reflect the previous step yet, they
+    /// are only caught up with through the stream.
+    ///
+    /// Default: true
+    #[serde(default = "EvaLiquidatorCfg::default_wait_for_confirmation")]
+    pub wait_for_confirmation: bool,
+    /// Maximum time in seconds waited for the confirmation of a sent transaction
+    ///
+    /// A transaction not confirmed in time may still land, the liquidator's state is reloaded
+    /// before anything depending on it runs.
+    ///
+    /// Default: 45
+    #[serde(default = "EvaLiquidatorCfg::default_confirmation_timeout_secs")]
+    pub confirmation_timeout_secs: u64,
+    /// Submit liquidations as Jito bundles, tipping the block engine, instead of sending
+    /// them through the RPC
+    ///
+    /// Example:
+    /// [liquidator_config.jito]
+    /// block_engine_url = "https://mainnet.block-engine.jito.wtf"
+    /// tip_lamports = 10000
+    /// tip_account = "96gYZGLnJYVFmbjzopPSU6QiEV5fGqZNyN9nmNhvrZU5"
+    pub jito: Option<JitoCfg>,
+    /// Verify liquidations and liability purchasing swaps by the balance change they cause,
+    /// on top of their confirmation
+    ///
+    /// Balances are re-fetched before and after the operation, a change falling short of the
+    /// expected one by more than `balance_delta_tolerance_bps` fails the operation.
+    ///
+    /// Default: false
+    #[serde(default = "EvaLiquidatorCfg::default_verify_balance_deltas")]
+    pub verify_balance_deltas: bool,
+    /// Shortfall in bps of a balance change tolerated by `verify_balance_deltas`, covering
+    /// interest accrued in between
+    ///
+    /// Default: 100
+    #[serde(default = "EvaLiquidatorCfg::default_balance_delta_tolerance_bps")]
+    pub balance_delta_tolerance_bps: u16,
+    /// Verify that the liquidator can cover the liability side of a liquidation before
+    /// submitting it, sizing the liquidation down to what its liability bank balance and
+    /// borrow capacity can cover, or skipping it when nothing can be covered
+    ///
+    /// Default: true
+    #[serde(default = "EvaLiquidatorCfg::default_check_liability_coverage")]
+    pub check_liability_coverage: bool,
+    /// Fraction of the computed asset amount actually seized in a liquidation
+    ///
+    /// Scales the seized amount down, never up. The computed amount is the most the liquidatee
+    /// can give up before becoming healthy, sized from the streamed oracle prices. Prices
+    /// moving between the computation and the transaction landing can make that amount exceed
+    /// what the program allows, failing the liquidation. Seizing less also reduces the
+    /// liability taken on, so the swaps of the following rebalance are smaller. The cost is
+    /// the liquidation fee on the amount left unseized, set it to 1.0 to seize the full amount.
+    /// Values above 1.0 always exceed the program's limit and are reported at startup.
+    ///
+    /// Default: 0.98
+    #[serde(
+        default = "EvaLiquidatorCfg::default_asset_slippage_buffer",
+        deserialize_with = "fixed_from_float"
+    )]
+    pub asset_slippage_buffer: I80F48,
+    /// Fraction of the liquidator's liability capacity used to size a liquidation
+    ///
+    /// Leaves room for slippage on the swap buying back the liability taken on during
+    /// rebalancing. Lower it for liabilities with thin liquidity.
+    ///
+    /// Default: 1.0
+    #[serde(
+        default = "EvaLiquidatorCfg::default_liability_slippage_buffer",
+        deserialize_with = "fixed_from_float"
+    )]
+    pub liability_slippage_buffer: I80F48,
+    /// Recompute the liquidatee's maintenance health from the latest streamed state right
+    /// before submitting a liquidation, and skip it if the account has recovered
+    ///
+    /// Default: true
+    #[serde(default = "EvaLiquidatorCfg::default_recheck_before_submit")]
+    pub recheck_before_submit: bool,
+    /// Verify at startup that the swap mint token account exists and belongs to the signer,
+    /// creating it if it doesn't exist, before the liquidator starts swapping
+    ///
+    /// Default: true
+    #[serde(default = "EvaLiquidatorCfg::default_ensure_swap_mint_token_account")]
+    pub ensure_swap_mint_token_account: bool,
+    /// Cluster the liquidator runs on, `mainnet_beta`, `devnet` or `testnet`, used to link
+    /// submitted transactions to the explorer
+    ///
+    /// Default: mainnet_beta
+    #[serde(default = "EvaLiquidatorCfg::default_explorer_cluster")]
+    pub explorer_cluster: ExplorerCluster,
+    /// Number of worker threads of the processor runtime
+    ///
+    /// The runtime is always multi-threaded, the blocking RPC client moves its calls off the
+    /// worker with `block_in_place`, which a current-thread runtime doesn't support.
+    ///
+    /// Default: number of CPU cores
+    pub runtime_worker_threads: Option<usize>,
+    /// Name of the threads of the processor runtime
+    ///
+    /// Default: "evaLiquidatorRuntime"
+    #[serde(default = "EvaLiquidatorCfg::default_runtime_thread_name")]
+    pub runtime_thread_name: String,
+    /// Number of accounts per batch when computing account health in parallel
+    ///
+    /// Each batch is processed sequentially on one thread. Smaller batches balance the load
+    /// better across cores but add scheduling overhead, larger ones leave cores idle when the
+    /// number of batches gets close to the number of cores. Aim for several batches per core,
+    /// e.g. the number of tracked accounts divided by 4 times the core count.
+    ///
+    /// Default: 256
+    #[serde(default = "EvaLiquidatorCfg::default_health_computation_batch_size")]
+    pub health_computation_batch_size: usize,
+    /// Time in seconds prices loaded at startup are used before the stream refreshes them
+    ///
+    /// Once it elapsed, accounts with a balance in a bank whose oracle was never refreshed by
+    /// the stream are skipped, as the age of the loaded price is unknown.
+    ///
+    /// Default: disabled
+    pub oracle_initial_grace_secs: Option<u64>,
+    /// Interval in milliseconds within which every oracle is expected to update, disabled
+    /// when unset
+    ///
+    /// An oracle without an update within it is considered silent, e.g. during an oracle
+    /// operator outage: it's reported and accounts with a balance in one of its banks are
+    /// skipped until it updates again. Unlike price staleness, this relies on when updates
+    /// were received rather than on the timestamp the price carries.
+    pub expected_oracle_update_interval_ms: Option<u64>,
+    /// Per-oracle override of `expected_oracle_update_interval_ms`, keyed by oracle address
+    ///
+    /// Default: {}
+    #[serde(
+        default = "EvaLiquidatorCfg::default_expected_oracle_update_intervals_ms",
+        deserialize_with = "from_pubkey_string_map"
+    )]
+    pub expected_oracle_update_intervals_ms: HashMap<Pubkey, u64>,
+    /// Reuse the health computed for an account in a previous scan as long as neither the
+    /// account nor the banks and oracles of its balances were updated since
+    ///
+    /// Default: true
+    #[serde(default = "EvaLiquidatorCfg::default_cache_account_health")]
+    pub cache_account_health: bool,
+    /// Evaluate a scan cycle against a snapshot of the banks referenced by the scanned
+    /// accounts, taken at the start of the cycle
+    ///
+    /// Without it, banks and oracles updated while a scan is running are picked up midway,
+    /// so accounts of the same cycle, or even the health and the liquidation amounts of a
+    /// single account, can be computed from different prices. With it, every account of
+    /// the cycle is scanned, and liquidation amounts are sized, from the same copy of each
+    /// bank and its price. `recheck_before_submit` still checks the live state.
+    ///
+    /// Default: false
+    #[serde(default = "EvaLiquidatorCfg::default_snapshot_banks_for_scan")]
+    pub snapshot_banks_for_scan: bool,
+    /// Log accounts entering and leaving the liquidation candidate set between scans, with
+    /// the likely reason an account stopped being liquidatable
+    ///
+    /// Default: true
+    #[serde(default = "EvaLiquidatorCfg::default_log_candidate_transitions")]
+    pub log_candidate_transitions: bool,
+    /// Interval in seconds at which utilization and interest rates of all banks are logged,
+    /// disabled when unset
+    pub bank_rates_report_interval_secs: Option<u64>,
+    /// Log a structured record of the full rationale behind each executed liquidation, see
+    /// [`LiquidationAudit`]
+    ///
+    /// Default: false
+    #[serde(default = "EvaLiquidatorCfg::default_verbose_liquidation_audit")]
+    pub verbose_liquidation_audit: bool,
+    /// Order of the rebalance steps, see [`RebalanceOrder`] for the risk profile of each
+    ///
+    /// Default: sell_first
+    #[serde(default = "EvaLiquidatorCfg::default_rebalance_order")]
+    pub rebalance_order: RebalanceOrder,
+    /// Maintenance liability ratio of the liquidator above which the `adaptive` rebalance
+    /// order repays liabilities first
+    ///
+    /// Default: 0.8
+    #[serde(default = "EvaLiquidatorCfg::default_rebalance_repay_first_liability_ratio")]
+    pub rebalance_repay_first_liability_ratio: f64,
+    /// Number of times the failed steps of a rebalance are retried, on their own, before the
+    /// whole rebalance is considered failed
+    ///
+    /// A failed step doesn't abort the rebalance, the remaining steps still run unless they
+    /// depend on the failed one, see [`RebalanceStep::dependencies`].
+    ///
+    /// Default: 2
+    #[serde(default = "EvaLiquidatorCfg::default_rebalance_step_retries")]
+    pub rebalance_step_retries: u32,
+    /// Mints that proceeds can be routed through on their way to `swap_mint`, e.g. SOL
+    ///
+    /// Jupiter already routes through intermediate tokens on its own, so a direct swap is
+    /// tried first. Only when its price impact exceeds `max_direct_swap_price_impact_bps` are
+    /// two-hop routes through these mints quoted, and the best one is used over the direct
+    /// swap if it yields more `swap_mint`. Mints without a bank are ignored.
+    ///
+    /// Example:
+    /// intermediate_swap_mints = ["So11111111111111111111111111111111111111112"]
+    ///
+    /// Default: []
+    #[serde(
+        default = "EvaLiquidatorCfg::default_intermediate_swap_mints",
+        deserialize_with = "from_vec_str_to_pubkey"
+    )]
+    pub intermediate_swap_mints: Vec<Pubkey>,
+    /// Mints tried in order when selling to `swap_mint` fails, e.g. because Jupiter has no
+    /// route from an exotic collateral to it
+    ///
+    /// Fallback tokens are left in the token account, rebalancing sells them to `swap_mint`
+    /// on a later cycle like any other token. Mints without a bank are ignored.
+    ///
+    /// Example:
+    /// fallback_swap_mints = ["So11111111111111111111111111111111111111112"]
+    ///
+    /// Default: []
+    #[serde(
+        default = "EvaLiquidatorCfg::default_fallback_swap_mints",
+        deserialize_with = "from_vec_str_to_pubkey"
+    )]
+    pub fallback_swap_mints: Vec<Pubkey>,
+    /// Banks the liquidator never liquidates against or swaps out of, e.g. a deprecated bank
+    /// or one with a broken oracle
+    ///
+    /// Liquidations pick the best pair among the remaining banks of an account, accounts
+    /// left without one are skipped.
+    ///
+    /// Default: []
+    #[serde(
+        default = "EvaLiquidatorCfg::default_excluded_banks",
+        deserialize_with = "from_vec_str_to_pubkey"
+    )]
+    pub excluded_banks: Vec<Pubkey>,
+    /// Mints whose banks are all treated as `excluded_banks`, e.g. assets the liquidator
+    /// can't custody
+    ///
+    /// Default: []
+    #[serde(
+        default = "EvaLiquidatorCfg::default_excluded_mints",
+        deserialize_with = "from_vec_str_to_pubkey"
+    )]
+    pub excluded_mints: Vec<Pubkey>,
+    /// Oracle based price impact, in bps, above which a direct swap to `swap_mint` is
+    /// compared against routes through `intermediate_swap_mints`
+    ///
+    /// Default: 100
+    #[serde(default = "EvaLiquidatorCfg::default_max_direct_swap_price_impact_bps")]
+    pub max_direct_swap_price_impact_bps: u16,
+    /// Tolerance in bps between the amount a swap is requested for and the input amount of
+    /// Jupiter's quote
+    ///
+    /// A quote consuming more than the requested amount beyond the tolerance is rejected, so a
+    /// swap never spends more than intended. A quote consuming less is accepted, the residual
+    /// stays in the token account and is swept by the next rebalance.
+    ///
+    /// Default: 10
+    #[serde(default = "EvaLiquidatorCfg::default_swap_in_amount_tolerance_bps")]
+    pub swap_in_amount_tolerance_bps: u16,
+    /// Consecutive failed Jupiter API calls after which the swap subsystem is marked degraded
+    ///
+    /// While degraded, liquidations whose liability the liquidator doesn't already hold, and
+    /// would have to buy on Jupiter to repay, are paused. Liquidations of liabilities held as
+    /// tokens or deposits continue. The first successful Jupiter call, e.g. from a rebalance,
+    /// lifts the pause. Unset disables the circuit breaker.
+    ///
+    /// Example: 5
+    pub jupiter_failure_threshold: Option<u32>,
+}
+
+/// Amount side of a swap
+#[derive(Debug, Clone, Copy)]
+enum SwapAmount {
+    /// Spend exactly this amount of the source token
+    ExactIn(u64),
+    /// Receive exactly `out_amount` of the destination token, spending at most
+    /// `max_in_amount` of the source token
+    ExactOut { out_amount: u64, max_in_amount: u64 },
+}
+
+/// How urgently a swap needs to land, selecting its priority fee
+#[derive(Debug, Clone, Copy)]
+pub enum SwapUrgency {
+    /// Swaps on the liquidation path, buying the liability taken on
+    Critical,
+    /// Cleanup swaps, offloading collateral and leftover tokens
+    Rebalance,
+}
+
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ExplorerCluster {
+    MainnetBeta,
+    Devnet,
+    Testnet,
+}
+
+impl ExplorerCluster {
+    pub fn tx_url(&self, signature: &Signature) -> String {
+        match self {
+            ExplorerCluster::MainnetBeta => {
+                format!("https://explorer.solana.com/tx/{}", signature)
+            }
+            ExplorerCluster::Devnet => {
+                format!(
+                    "https://explorer.solana.com/tx/{}?cluster=devnet",
+                    signature
+                )
+            }
+            ExplorerCluster::Testnet => {
+                format!(
+                    "https://explorer.solana.com/tx/{}?cluster=testnet",
+                    signature
+                )
+            }
+        }
+    }
+}
+
+/// Price bias of equity valuations used for gating decisions
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EquityPriceBias {
+    /// Mid price
+    Neutral,
+    /// Low end of the oracle's confidence interval
+    Conservative,
+}
+
+impl EquityPriceBias {
+    pub fn to_price_bias(self) -> Option<PriceBias> {
+        match self {
+            EquityPriceBias::Neutral => None,
+            EquityPriceBias::Conservative => Some(PriceBias::Low),
+        }
+    }
+}
+
+/// Handling of sub-unit remainders of converting balances to native amounts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NativeResidualHandling {
+    /// Leave the remainder in the account and ignore it when rebalancing
+    Keep,
+    /// Close the balance when all of its whole units are moved
+    Sweep,
+}
+
+/// Balance below which a token account is left as dust instead of being swept
+#[derive(Debug, Clone, Copy, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DustThreshold {
+    /// USD value of the balance
+    Usd(f64),
+    /// Balance in native units of the mint
+    Native(u64),
+}
+
+/// Order in which the liquidator is rebalanced
+///
+/// Each order trades off the intermediate states the liquidator goes through: holding
+/// unswapped tokens, or having sold collateral without having repaid its liabilities yet.
+/// Every order first wraps SOL above `max_sol_balance`, which is then sold along with the
+/// other tokens in token accounts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RebalanceOrder {
+    /// Sell non-preferred deposits, repay liabilities, sell tokens in token accounts, then
+    /// deposit preferred tokens
+    ///
+    /// Converts collateral to the swap mint before anything else, so the liquidator is
+    /// exposed to the price of non-preferred collateral for the shortest time. Liabilities
+    /// stay open until the sell is done, which is risky close to the health limit.
+    SellFirst,
+    /// Repay liabilities, sell non-preferred deposits, sell tokens in token accounts, then
+    /// deposit preferred tokens
+    ///
+    /// Closes liabilities first, lowering the liquidator's risk of becoming liquidatable
+    /// itself, at the cost of holding non-preferred collateral for longer.
+    RepayFirst,
+    /// Repay liabilities, deposit preferred tokens, sell non-preferred deposits, sell
+    /// tokens in token accounts, then deposit preferred tokens again
+    ///
+    /// Like `repay_first`, but puts leftover swap mint tokens back to work as collateral right
+    /// after repaying instead of holding them idle while selling.
+    Interleaved,
+    /// `repay_first` when the liquidator's maintenance liability ratio is above
+    /// `rebalance_repay_first_liability_ratio`, `sell_first` otherwise
+    Adaptive,
+}
+
+/// Single step of a liquidator rebalance
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebalanceStep {
+    WrapExcessSol,
+    SellNonPreferredDeposits,
+    RepayLiabilities,
+    HandleTokensInTokenAccounts,
+    DepositPreferredTokens,
+}
+
+impl RebalanceStep {
+    /// Steps that must not have failed earlier in the rebalance for this step to run
+    ///
+    /// Depositing preferred tokens is deferred when a sell failed, since the sell produces the
+    /// tokens to deposit. Other steps are safe to run after any failure, leftovers of a failed
+    /// sell are picked up by selling tokens in token accounts.
+    pub fn dependencies(&self) -> &'static [RebalanceStep] {
+        match self {
+            RebalanceStep::DepositPreferredTokens => &[
+                RebalanceStep::SellNonPreferredDeposits,
+                RebalanceStep::HandleTokensInTokenAccounts,
+            ],
+            _ => &[],
+        }
+    }
+
+    /// First of the step's dependencies among `failed_steps`, the step is deferred if any
+    pub fn failed_dependency(&self, failed_steps: &[RebalanceStep]) -> Option<RebalanceStep> {
+        self.dependencies()
+            .iter()
+            .find(|dependency| failed_steps.contains(dependency))
+            .copied()
+    }
+}
+
+impl RebalanceOrder {
+    /// Steps of the order, `near_health_limit` selects the order `adaptive` resolves to
+    pub fn steps(&self, near_health_limit: bool) -> &'static [RebalanceStep] {
+        use RebalanceStep::*;
+
+        match self {
+            RebalanceOrder::SellFirst => &[
+                WrapExcessSol,
+                SellNonPreferredDeposits,
+                RepayLiabilities,
+                HandleTokensInTokenAccounts,
+                DepositPreferredTokens,
+            ],
+            RebalanceOrder::RepayFirst => &[
+                WrapExcessSol,
+                RepayLiabilities,
+                SellNonPreferredDeposits,
+                HandleTokensInTokenAccounts,
+                DepositPreferredTokens,
+            ],
+            RebalanceOrder::Interleaved => &[
+                WrapExcessSol,
+                RepayLiabilities,
+                DepositPreferredTokens,
+                SellNonPreferredDeposits,
+                HandleTokensInTokenAccounts,
+                DepositPreferredTokens,
+            ],
+            RebalanceOrder::Adaptive if near_health_limit => {
+                RebalanceOrder::RepayFirst.steps(near_health_limit)
+            }
+            RebalanceOrder::Adaptive => RebalanceOrder::SellFirst.steps(near_health_limit),
+        }
+    }
 }
 
 impl EvaLiquidatorCfg {
@@ -112,10 +962,66 @@ impl EvaLiquidatorCfg {
         I80F48!(0.01)
     }
 
+    pub fn default_token_account_dust_thresholds() -> HashMap<Pubkey, DustThreshold> {
+        HashMap::new()
+    }
+
+    pub fn default_dust_check_price_bias() -> EquityPriceBias {
+        EquityPriceBias::Neutral
+    }
+
+    pub fn default_max_liquidation_candidates() -> usize {
+        10
+    }
+
+    pub fn default_liquidation_cooldown_secs() -> u64 {
+        10
+    }
+
+    pub fn default_watch_only_accounts() -> Option<Vec<Pubkey>> {
+        None
+    }
+
+    pub fn default_candidate_log_max_bytes() -> u64 {
+        100 * 1024 * 1024
+    }
+
+    pub fn default_send_retry_count() -> u64 {
+        SenderCfg::default_spam_times()
+    }
+
+    pub fn default_send_resend_interval_ms() -> u64 {
+        0
+    }
+
+    pub fn default_wait_for_confirmation() -> bool {
+        SenderCfg::default_wait_for_confirmation()
+    }
+
+    pub fn default_confirmation_timeout_secs() -> u64 {
+        SenderCfg::default_confirmation_timeout().as_secs()
+    }
+
+    pub fn default_simulate_before_send() -> bool {
+        true
+    }
+
+    pub fn default_send_swaps_on_blocking_pool() -> bool {
+        true
+    }
+
+    pub fn default_native_residual_handling() -> NativeResidualHandling {
+        NativeResidualHandling::Sweep
+    }
+
     pub fn default_max_sol_balance() -> I80F48 {
         I80F48!(1)
     }
 
+    pub fn default_min_sol_balance() -> I80F48 {
+        I80F48!(0.01)
+    }
+
     pub fn default_preferred_mints() -> Vec<Pubkey> {
         vec![pubkey!("EPjFWdd5AufqSSqeM2qN1xzybapC8G4wEGGkZwyTDt1v")]
     }
@@ -132,39 +1038,544 @@ impl EvaLiquidatorCfg {
         250
     }
 
-    pub fn default_compute_unit_price_micro_lamports() -> Option<u64> {
-        Some(10_000)
+    pub fn default_use_jupiter_prices_for_sizing() -> bool {
+        false
     }
 
-    pub fn default_min_profit() -> f64 {
-        0.1
+    pub fn default_use_exact_out_liability_swaps() -> bool {
+        true
     }
 
-    pub fn get_tx_config(&self) -> TxConfig {
-        TxConfig {
-            compute_unit_price_micro_lamports: self.compute_unit_price_micro_lamports,
-        }
+    pub fn default_exact_out_swap_buffer_bps() -> u16 {
+        100
     }
-}
 
-pub struct EvaLiquidator {
-    // liquidator_account: Arc<RwLock<MarginfiAccountWrapper>>,
-    liquidator_account: crate::marginfi_account::MarginfiAccount,
-    state_engine: Arc<StateEngineService>,
-    update_rx: Receiver<()>,
-    signer_keypair: Arc<Keypair>,
+    pub fn default_slippage_bps_overrides() -> HashMap<Pubkey, u16> {
+        HashMap::new()
+    }
+
+    pub fn default_max_slippage_bps() -> u16 {
+        1000
+    }
+
+    pub fn default_slippage_escalation_step() -> u16 {
+        100
+    }
+
+    pub fn default_max_swap_value_loss_bps() -> u16 {
+        1000
+    }
+
+    pub fn default_max_swap_attempts() -> u32 {
+        5
+    }
+
+    pub fn default_compute_unit_price_micro_lamports() -> Option<u64> {
+        Some(10_000)
+    }
+
+    pub fn default_compute_unit_limit() -> u32 {
+        LIQUIDATE_COMPUTE_UNIT_LIMIT
+    }
+
+    pub fn default_min_compute_unit_price_micro_lamports() -> u64 {
+        0
+    }
+
+    pub fn default_max_compute_unit_price_micro_lamports() -> u64 {
+        1_000_000
+    }
+
+    pub fn default_min_profit() -> f64 {
+        0.1
+    }
+
+    pub fn default_min_liquidation_value() -> f64 {
+        1.0
+    }
+
+    pub fn default_min_account_value_usd() -> f64 {
+        0.0
+    }
+
+    pub fn default_check_liability_coverage() -> bool {
+        true
+    }
+
+    pub fn default_asset_slippage_buffer() -> I80F48 {
+        I80F48!(0.98)
+    }
+
+    pub fn default_liability_slippage_buffer() -> I80F48 {
+        I80F48!(1)
+    }
+
+    pub fn default_recheck_before_submit() -> bool {
+        true
+    }
+
+    /// Inspects the effective config for combinations that prevent any liquidation from ever
+    /// being executed, returning a description of each problem along with a suggested fix
+    pub fn diagnose_noop_config(&self) -> Vec<String> {
+        let mut warnings = vec![];
+
+        if !self.min_profit.is_finite() {
+            warnings.push(format!(
+                "min_profit is {}, no liquidation can ever pass the profit filter; set it to a finite USD amount",
+                self.min_profit
+            ));
+        }
+
+        if let Some(max_liquidation_value) = self.max_liquidation_value {
+            if max_liquidation_value <= 0.0 {
+                warnings.push(format!(
+                    "max_liquidation_value is {}, every liquidation is capped to nothing; raise it or remove it",
+                    max_liquidation_value
+                ));
+            } else if self.min_liquidation_value > max_liquidation_value {
+                warnings.push(format!(
+                    "min_liquidation_value ${} exceeds max_liquidation_value ${}, every liquidation is skipped; lower min_liquidation_value or raise max_liquidation_value",
+                    self.min_liquidation_value, max_liquidation_value
+                ));
+            } else {
+                let max_profit = max_liquidation_value * LIQUIDATOR_FEE;
+                if self.min_profit > max_profit {
+                    warnings.push(format!(
+                        "min_profit ${} exceeds the maximum profit of ${:.4} attainable with max_liquidation_value ${}; lower min_profit or raise max_liquidation_value",
+                        self.min_profit, max_profit, max_liquidation_value
+                    ));
+                }
+            }
+        }
+
+        if self.asset_slippage_buffer <= I80F48::ZERO {
+            warnings.push(format!(
+                "asset_slippage_buffer is {}, every liquidation seizes nothing; set it between 0 and 1",
+                self.asset_slippage_buffer
+            ));
+        } else if self.asset_slippage_buffer > I80F48::ONE {
+            warnings.push(format!(
+                "asset_slippage_buffer is {}, every liquidation seizes more than the liquidatee can give up and fails; set it between 0 and 1",
+                self.asset_slippage_buffer
+            ));
+        }
+
+        if self.liability_slippage_buffer <= I80F48::ZERO {
+            warnings.push(format!(
+                "liability_slippage_buffer is {}, the liquidator can't cover any liability; set it between 0 and 1",
+                self.liability_slippage_buffer
+            ));
+        }
+
+        if self.preferred_mints.is_empty() {
+            warnings.push(
+                "preferred_mints is empty, every deposit is sold during rebalancing so the liquidator never leaves the rebalance loop; add at least the swap mint"
+                    .to_string(),
+            );
+        } else if !self.preferred_mints.contains(&self.swap_mint) {
+            warnings.push(format!(
+                "swap_mint {} is not in preferred_mints, swapped proceeds are deposited and then sold again so the liquidator never leaves the rebalance loop; add it to preferred_mints",
+                self.swap_mint
+            ));
+        }
+
+        if self.max_liquidation_candidates == 0 {
+            warnings.push(
+                "max_liquidation_candidates is 0, no candidate is ever attempted; set it to at least 1"
+                    .to_string(),
+            );
+        }
+
+        if self.dry_run {
+            warnings.push(
+                "dry_run is enabled, transactions are logged instead of sent; set dry_run = false to liquidate"
+                    .to_string(),
+            );
+        }
+
+        warnings
+    }
+
+    pub fn default_health_computation_batch_size() -> usize {
+        256
+    }
+
+    pub fn default_expected_oracle_update_intervals_ms() -> HashMap<Pubkey, u64> {
+        HashMap::new()
+    }
+
+    /// Interval within which `oracle` is expected to update, `None` when not monitored
+    pub fn get_expected_oracle_update_interval(&self, oracle: &Pubkey) -> Option<Duration> {
+        self.expected_oracle_update_intervals_ms
+            .get(oracle)
+            .copied()
+            .or(self.expected_oracle_update_interval_ms)
+            .map(Duration::from_millis)
+    }
+
+    pub fn default_cache_account_health() -> bool {
+        true
+    }
+
+    pub fn default_snapshot_banks_for_scan() -> bool {
+        false
+    }
+
+    pub fn default_log_candidate_transitions() -> bool {
+        true
+    }
+
+    pub fn default_verbose_liquidation_audit() -> bool {
+        false
+    }
+
+    pub fn default_rebalance_order() -> RebalanceOrder {
+        RebalanceOrder::SellFirst
+    }
+
+    pub fn default_rebalance_repay_first_liability_ratio() -> f64 {
+        0.8
+    }
+
+    pub fn default_dry_run() -> bool {
+        false
+    }
+
+    pub fn default_verify_balance_deltas() -> bool {
+        false
+    }
+
+    pub fn default_balance_delta_tolerance_bps() -> u16 {
+        100
+    }
+
+    pub fn default_refresh_liquidator_account_between_liquidations() -> bool {
+        true
+    }
+
+    pub fn default_rebalance_step_retries() -> u32 {
+        2
+    }
+
+    pub fn default_intermediate_swap_mints() -> Vec<Pubkey> {
+        vec![]
+    }
+
+    pub fn default_fallback_swap_mints() -> Vec<Pubkey> {
+        vec![]
+    }
+
+    pub fn default_excluded_banks() -> Vec<Pubkey> {
+        vec![]
+    }
+
+    pub fn default_excluded_mints() -> Vec<Pubkey> {
+        vec![]
+    }
+
+    pub fn default_max_direct_swap_price_impact_bps() -> u16 {
+        100
+    }
+
+    pub fn default_swap_in_amount_tolerance_bps() -> u16 {
+        10
+    }
+
+    pub fn default_ensure_swap_mint_token_account() -> bool {
+        true
+    }
+
+    pub fn default_explorer_cluster() -> ExplorerCluster {
+        ExplorerCluster::MainnetBeta
+    }
+
+    pub fn default_runtime_thread_name() -> String {
+        "evaLiquidatorRuntime".to_string()
+    }
+
+    pub fn build_runtime(&self) -> std::io::Result<tokio::runtime::Runtime> {
+        build_multi_thread_runtime(self.runtime_worker_threads, &self.runtime_thread_name)
+    }
+
+    /// Whether a token account balance of `amount` native units of `mint`, worth `value`
+    /// USD, is dust
+    pub fn is_token_account_dust(&self, mint: &Pubkey, amount: I80F48, value: I80F48) -> bool {
+        match self.token_account_dust_thresholds.get(mint) {
+            Some(DustThreshold::Usd(threshold)) => value < I80F48::from_num(*threshold),
+            Some(DustThreshold::Native(threshold)) => amount < I80F48::from_num(*threshold),
+            None => value < self.token_account_dust_threshold,
+        }
+    }
+
+    /// Slippage of swaps out of `src_mint`, its entry in `slippage_bps_overrides` if it has
+    /// one, otherwise `slippage_bps`
+    pub fn get_slippage_bps(&self, src_mint: &Pubkey) -> u16 {
+        self.slippage_bps_overrides
+            .get(src_mint)
+            .copied()
+            .unwrap_or(self.slippage_bps)
+    }
+
+    /// Compute unit price configured for swaps of the given urgency, if any
+    pub fn get_swap_compute_unit_price(&self, urgency: SwapUrgency) -> Option<u64> {
+        match urgency {
+            SwapUrgency::Critical => self.critical_swap_compute_unit_price_micro_lamports,
+            SwapUrgency::Rebalance => self.rebalance_swap_compute_unit_price_micro_lamports,
+        }
+    }
+
+    pub fn clamp_compute_unit_price(&self, compute_unit_price: u64) -> u64 {
+        compute_unit_price.clamp(
+            self.min_compute_unit_price_micro_lamports,
+            self.max_compute_unit_price_micro_lamports
+                .max(self.min_compute_unit_price_micro_lamports),
+        )
+    }
+
+    pub fn get_tx_config(&self) -> TxConfig {
+        TxConfig {
+            compute_unit_price_micro_lamports: self.compute_unit_price_micro_lamports,
+            compute_unit_limit: self.compute_unit_limit,
+            dry_run: self.dry_run,
+            sender_cfg: self.get_sender_cfg(),
+            jito: self.jito.clone(),
+        }
+    }
+
+    pub fn get_sender_cfg(&self) -> SenderCfg {
+        SenderCfg {
+            spam_times: self.send_retry_count,
+            resend_interval: Duration::from_millis(self.send_resend_interval_ms),
+            skip_preflight: !self.simulate_before_send,
+            wait_for_confirmation: self.wait_for_confirmation,
+            confirmation_timeout: Duration::from_secs(self.confirmation_timeout_secs),
+            ..SenderCfg::DEFAULT
+        }
+    }
+}
+
+/// Everything that went into an executed liquidation, enough to reconstruct it after the fact
+///
+/// Values are in USD, amounts in native units of the respective mint.
+#[derive(Debug, serde::Serialize)]
+pub struct LiquidationAudit {
+    pub liquidatee: String,
+    pub signature: String,
+    /// Maintenance health of the liquidatee before the liquidation
+    pub assets_value_maint: f64,
+    pub liabs_value_maint: f64,
+    pub asset_bank: LiquidationAuditBank,
+    pub liab_bank: LiquidationAuditBank,
+    /// Asset amount that brings the liquidatee back to health
+    pub max_liquidatable_asset_amount: f64,
+    /// Liability amount the liquidator can cover, after `liability_slippage_buffer`
+    pub max_liab_coverage_amount: f64,
+    /// USD value the liquidator can take on, after `max_liquidation_value` and the cycle cap
+    pub liquidator_capacity_value: f64,
+    pub liquidator_capacity_asset_amount: f64,
+    pub asset_slippage_buffer: f64,
+    pub liability_slippage_buffer: f64,
+    /// Asset amount in the submitted liquidation
+    pub submitted_asset_amount: u64,
+    pub liquidated_value: f64,
+    pub expected_profit: f64,
+    pub compute_unit_price_micro_lamports: Option<u64>,
+}
+
+/// Liquidation transaction published to the subscribers returned by [`EvaLiquidator::start`]
+///
+/// Amounts are in native units of the asset mint, profits in USD, timestamps in milliseconds
+/// since the unix epoch.
+#[derive(Debug, Clone)]
+pub enum LiquidationEvent {
+    Sent {
+        account: Pubkey,
+        asset_bank: Pubkey,
+        liab_bank: Pubkey,
+        asset_amount: u64,
+        estimated_profit: I80F48,
+        signature: Signature,
+        timestamp: u128,
+    },
+    /// The liquidation transaction couldn't be built or sent
+    Failed {
+        account: Pubkey,
+        asset_bank: Pubkey,
+        liab_bank: Pubkey,
+        asset_amount: u64,
+        estimated_profit: I80F48,
+        error: String,
+        timestamp: u128,
+    },
+}
+
+/// Bank side of a [`LiquidationAudit`]
+#[derive(Debug, serde::Serialize)]
+pub struct LiquidationAuditBank {
+    pub bank: String,
+    pub mint: String,
+    /// Maintenance value of the liquidatee's balance in the bank, the bank is chosen for
+    /// holding the highest valued balance of its side
+    pub balance_value_maint: f64,
+    pub real_time_price: Option<f64>,
+    /// Time weighted price, biased the way the liquidated side is valued
+    pub time_weighted_price: Option<f64>,
+}
+
+impl LiquidationAuditBank {
+    fn new(bank: &BankWrapper, side: BalanceSide, balance_value_maint: I80F48) -> Self {
+        let price_bias = match side {
+            BalanceSide::Assets => PriceBias::Low,
+            BalanceSide::Liabilities => PriceBias::High,
+        };
+        let price_adapter = &bank.oracle_adapter.price_adapter;
+
+        Self {
+            bank: bank.address.to_string(),
+            mint: bank.bank.mint.to_string(),
+            balance_value_maint: balance_value_maint.to_num(),
+            real_time_price: price_adapter
+                .get_price_of_type(OraclePriceType::RealTime, None)
+                .ok()
+                .map(|price| price.to_num()),
+            time_weighted_price: price_adapter
+                .get_price_of_type(OraclePriceType::TimeWeighted, Some(price_bias))
+                .ok()
+                .map(|price| price.to_num()),
+        }
+    }
+}
+
+/// Runs a task on trigger unless it's already running, coalescing the triggers arriving while
+/// it runs into a single follow-up run
+///
+/// At most one run is in flight and one is queued at any time.
+#[derive(Default)]
+struct CoalescingTrigger {
+    in_flight: AtomicBool,
+    pending: AtomicBool,
+}
+
+impl CoalescingTrigger {
+    /// Run `task`, or queue a follow-up run when it's already in flight and return right away
+    ///
+    /// The in-flight caller runs the queued follow-ups itself, stopping at the first error.
+    async fn run<E, Fut>(&self, mut task: impl FnMut() -> Fut) -> Result<(), E>
+    where
+        Fut: std::future::Future<Output = Result<(), E>>,
+    {
+        if self.in_flight.swap(true, Ordering::AcqRel) {
+            debug!("Task already in flight, queueing a follow-up");
+            self.pending.store(true, Ordering::Release);
+            return Ok(());
+        }
+
+        let result = loop {
+            self.pending.store(false, Ordering::Release);
+
+            if let Err(e) = task().await {
+                break Err(e);
+            }
+
+            if !self.pending.load(Ordering::Acquire) {
+                break Ok(());
+            }
+
+            debug!("Running queued follow-up");
+        };
+
+        self.in_flight.store(false, Ordering::Release);
+
+        result
+    }
+}
+
+/// Counts consecutive failures of calls to an external service, opening once `threshold` of them
+/// failed in a row and closing again on the first success
+#[derive(Default)]
+struct CircuitBreaker {
+    consecutive_failures: AtomicU32,
+    open: AtomicBool,
+}
+
+impl CircuitBreaker {
+    /// Record the outcome of a call, returns the new state when the breaker opened or closed
+    fn record(&self, success: bool, threshold: u32) -> Option<bool> {
+        if success {
+            self.consecutive_failures.store(0, Ordering::Relaxed);
+
+            return self.open.swap(false, Ordering::Relaxed).then_some(false);
+        }
+
+        let failures = self.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+
+        (failures >= threshold && !self.open.swap(true, Ordering::Relaxed)).then_some(true)
+    }
+
+    fn is_open(&self) -> bool {
+        self.open.load(Ordering::Relaxed)
+    }
+}
+
+pub struct EvaLiquidator {
+    // liquidator_account: Arc<RwLock<MarginfiAccountWrapper>>,
+    liquidator_account: crate::marginfi_account::MarginfiAccount,
+    state_engine: Arc<StateEngineService>,
+    update_rx: Receiver<()>,
+    signer_keypair: Arc<Keypair>,
     config: EvaLiquidatorCfg,
     preferred_mints: HashSet<Pubkey>,
     swap_mint_bank_pk: Pubkey,
+    recent_slippage_failures: DashMap<(Pubkey, Pubkey), (u16, Instant)>,
+    /// When a liquidation transaction was last sent for an account, along with the account
+    /// version it was computed from
+    recently_liquidated: DashMap<Pubkey, (Instant, u64)>,
+    rebalance_trigger: CoalescingTrigger,
+    previous_candidates: Mutex<HashSet<Pubkey>>,
+    silent_oracles: Mutex<HashSet<Pubkey>>,
+    capacity_cache: Mutex<Option<LiquidatorCapacity>>,
+    jupiter_circuit_breaker: CircuitBreaker,
+    candidate_log: Option<CandidateLog>,
+    shutdown: Arc<AtomicBool>,
+    liquidation_event_tx: broadcast::Sender<LiquidationEvent>,
+}
+
+/// Snapshot of the liquidator's capacity, see `capacity_refresh_interval_secs`
+struct LiquidatorCapacity {
+    free_collateral: I80F48,
+    max_borrows: HashMap<Pubkey, I80F48>,
+    computed_at: Instant,
+}
+
+impl LiquidatorCapacity {
+    /// Whether the capacity was computed less than `interval` ago
+    fn is_fresh(&self, interval: Duration) -> bool {
+        self.computed_at.elapsed() < interval
+    }
 }
 
 impl EvaLiquidator {
+    /// Start the processor on its own thread, running until `shutdown` is set
+    ///
+    /// Returns a subscription to the processor's [`LiquidationEvent`]s along with the thread
+    /// handle, further subscriptions are made with `resubscribe`.
+    #[allow(clippy::type_complexity)]
     pub fn start(
         state_engine: Arc<StateEngineService>,
         update_rx: Receiver<()>,
         cfg: EvaLiquidatorCfg,
-    ) -> Result<JoinHandle<Result<(), ProcessorError>>, ProcessorError> {
-        thread::Builder::new()
+        shutdown: Arc<AtomicBool>,
+    ) -> Result<
+        (
+            JoinHandle<Result<(), ProcessorError>>,
+            broadcast::Receiver<LiquidationEvent>,
+        ),
+        ProcessorError,
+    > {
+        let (liquidation_event_tx, liquidation_event_rx) =
+            broadcast::channel(LIQUIDATION_EVENT_CAPACITY);
+
+        let handle = thread::Builder::new()
             .name("evaLiquidatorProcessor".to_string())
             .spawn(move || -> Result<(), ProcessorError> {
                 info!("Starting liquidator processor");
@@ -193,7 +1604,7 @@ impl EvaLiquidator {
                     ProcessorError::SetupFailed
                 })?);
 
-                state_engine
+                let created_token_accounts = state_engine
                     .token_account_manager
                     .create_token_accounts(keypair.clone())
                     .map_err(|e| {
@@ -201,16 +1612,112 @@ impl EvaLiquidator {
                         ProcessorError::SetupFailed
                     })?;
 
-                let preferred_mints = cfg.preferred_mints.iter().cloned().collect();
+                if !created_token_accounts.is_empty() {
+                    info!("Created {} token accounts", created_token_accounts.len());
+                    state_engine.track_token_accounts(created_token_accounts);
+                }
+
+                if cfg.ensure_swap_mint_token_account {
+                    state_engine
+                        .token_account_manager
+                        .ensure_token_account(cfg.swap_mint, keypair.clone())
+                        .map_err(|e| {
+                            error!("Failed to ensure swap mint token account: {:?}", e);
+                            ProcessorError::SetupFailed
+                        })?;
+                }
 
-                let swap_mint_bank_pk = state_engine
-                    .get_bank_for_mint(&cfg.swap_mint)
-                    .ok_or(ProcessorError::Error("Failed to get bank for swap mint"))?
-                    .read()
-                    .unwrap()
-                    .address;
+                let swap_mint_bank = state_engine.get_bank_for_mint(&cfg.swap_mint).or_else(|| {
+                    warn!(
+                        "Bank of swap mint {} not loaded, loading it directly",
+                        cfg.swap_mint
+                    );
+                    state_engine
+                        .load_bank_for_mint(&cfg.swap_mint)
+                        .map_err(|e| error!("Failed to load the swap mint bank: {}", e))
+                        .ok()
+                });
+
+                let mut cfg = cfg;
+
+                let mut seen_preferred_mints = HashSet::new();
+
+                cfg.preferred_mints.retain(|mint| {
+                    let first = seen_preferred_mints.insert(*mint);
+
+                    if !first {
+                        warn!("Preferred mint {} is listed more than once", mint);
+                    }
+
+                    first
+                });
+
+                let mut unresolved_mints = cfg
+                    .preferred_mints
+                    .iter()
+                    .filter(|mint| {
+                        **mint != cfg.swap_mint && state_engine.get_bank_for_mint(mint).is_none()
+                    })
+                    .copied()
+                    .collect::<Vec<_>>();
+
+                let swap_mint_bank = match swap_mint_bank {
+                    Some(swap_mint_bank) if unresolved_mints.is_empty() => swap_mint_bank,
+                    swap_mint_bank => {
+                        if swap_mint_bank.is_none() {
+                            unresolved_mints.insert(0, cfg.swap_mint);
+                        }
+
+                        error!(
+                            "Configured mints have no bank in group {}: {:?}",
+                            state_engine.get_marginfi_group_address(),
+                            unresolved_mints
+                        );
+                        return Err(ProcessorError::UnresolvedMints(unresolved_mints));
+                    }
+                };
+
+                let swap_mint_bank_pk = swap_mint_bank.read().unwrap().address;
+
+                let swap_mint_banks = state_engine.get_banks_for_mint(&cfg.swap_mint);
+
+                if swap_mint_banks.len() > 1 {
+                    warn!(
+                        "{} banks share the swap mint {}, using {}",
+                        swap_mint_banks.len(),
+                        cfg.swap_mint,
+                        swap_mint_bank_pk
+                    );
+                }
+
+                cfg.fallback_swap_mints.retain(|mint| {
+                    let has_bank = state_engine.get_bank_for_mint(mint).is_some();
+
+                    if !has_bank {
+                        warn!("Fallback swap mint {} has no bank, ignoring it", mint);
+                    }
+
+                    has_bank
+                });
+
+                let preferred_mints = seen_preferred_mints;
+
+                let rpc_pool = state_engine.rpc_pool.clone();
+
+                let candidate_log = cfg
+                    .candidate_log_path
+                    .clone()
+                    .map(|path| CandidateLog::new(path, cfg.candidate_log_max_bytes))
+                    .transpose()
+                    .map_err(|e| {
+                        error!("Failed to open candidate log: {:?}", e);
+                        ProcessorError::SetupFailed
+                    })?;
 
-                let rpc_client = state_engine.rpc_client.clone();
+                let runtime = cfg.build_runtime().map_err(|e| {
+                    error!("Failed to build processor runtime: {:?}", e);
+                    ProcessorError::SetupFailed
+                })?;
 
                 let processor = EvaLiquidator {
                     state_engine: state_engine.clone(),
@@ -219,18 +1726,25 @@ impl EvaLiquidator {
                         liquidator_account,
                         state_engine.clone(),
                         keypair.clone(),
-                        rpc_client,
+                        rpc_pool,
                     ),
                     signer_keypair: keypair,
                     config: cfg,
                     preferred_mints,
                     swap_mint_bank_pk,
+                    recent_slippage_failures: DashMap::new(),
+                    recently_liquidated: DashMap::new(),
+                    rebalance_trigger: CoalescingTrigger::default(),
+                    previous_candidates: Mutex::new(HashSet::new()),
+                    silent_oracles: Mutex::new(HashSet::new()),
+                    capacity_cache: Mutex::new(None),
+                    jupiter_circuit_breaker: CircuitBreaker::default(),
+                    candidate_log,
+                    shutdown,
+                    liquidation_event_tx,
                 };
 
-                if let Err(e) = tokio::runtime::Runtime::new()
-                    .unwrap()
-                    .block_on(processor.run_outer())
-                {
+                if let Err(e) = runtime.block_on(processor.run_outer()) {
                     error!("Error running processor: {:?}", e);
                 }
 
@@ -238,15 +1752,32 @@ impl EvaLiquidator {
 
                 Ok(())
             })
-            .map_err(|_| ProcessorError::SetupFailed)
+            .map_err(|_| ProcessorError::SetupFailed)?;
+
+        Ok((handle, liquidation_event_rx))
+    }
+
+    /// Publish a liquidation event, it's dropped when nobody is subscribed
+    fn publish_liquidation_event(&self, event: LiquidationEvent) {
+        if self.liquidation_event_tx.send(event).is_err() {
+            trace!("No liquidation event subscribers");
+        }
     }
 
     async fn run_outer(&self) -> Result<(), ProcessorError> {
         loop {
             match self.run().await {
+                Ok(_) if self.shutdown.load(Ordering::Relaxed) => {
+                    info!("Processor shut down");
+                    return Ok(());
+                }
                 Ok(_) => {
                     warn!("Processor exited, restarting...");
                 }
+                Err(ProcessorError::UpdateChannelDisconnected) => {
+                    error!("State engine update channel disconnected, shutting down processor");
+                    return Err(ProcessorError::UpdateChannelDisconnected);
+                }
                 Err(e) => {
                     error!("Error running processor: {:?}, restarting...", e);
                 }
@@ -255,62 +1786,389 @@ impl EvaLiquidator {
     }
 
     async fn run(&self) -> Result<(), ProcessorError> {
+        let mut last_bank_rates_report: Option<Instant> = None;
+
         loop {
-            while self.needs_to_be_rebalanced() {
-                self.rebalance_with_recovery().await?;
+            if self.shutdown.load(Ordering::Relaxed) {
+                break;
             }
 
-            if let Err(e) = self.evaluate_all_accounts() {
-                error!("Error processing accounts: {:?}", e);
+            self.drain_update_signals()?;
+
+            while self.needs_to_be_rebalanced() {
+                self.trigger_rebalance().await?;
+
+                if self.config.dry_run {
+                    break;
+                }
             }
 
-            tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-        }
+            self.refresh_capacity_cache_if_due();
 
-        Ok(())
-    }
+            if self.has_free_collateral_to_scan() && self.has_sol_fee_reserve() {
+                if let Err(e) = self.evaluate_all_accounts() {
+                    error!("Error processing accounts: {:?}", e);
+                }
+            }
 
-    async fn rebalance_with_recovery(&self) -> Result<(), ProcessorError> {
-        let mut retries = 0;
-        while self.rebalance_accounts().await.is_err() {
-            retries += 1;
+            self.monitor_oracle_updates();
 
-            if retries > 5 {
-                error!("Failed to rebalance accounts after 5 retries, exiting...");
-                self.state_engine
-                    .load_initial_state(self.config.liquidator_account)
-                    .await?;
-                return Err(ProcessorError::Error("Failed to rebalance accounts"));
+            if let Some(interval) = self.config.bank_rates_report_interval_secs {
+                let report_due = last_bank_rates_report
+                    .map_or(true, |last| last.elapsed() >= Duration::from_secs(interval));
+
+                if report_due {
+                    self.report_bank_rates();
+                    last_bank_rates_report = Some(Instant::now());
+                }
             }
 
-            error!("Error rebalancing accounts, retrying...");
             tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
         }
 
-        debug!("Rebalanced accounts");
-
         Ok(())
     }
 
-    async fn rebalance_accounts(&self) -> Result<(), ProcessorError> {
-        self.sell_non_preferred_deposits().await?;
-        self.replay_liabilities().await?;
-        self.handle_tokens_in_token_accounts().await?;
-        self.deposit_preferred_tokens().await?;
-
-        Ok(())
+    /// Whether the liquidator has the `min_free_collateral_to_scan` it needs for scanning
+    /// accounts to be worth it
+    fn has_free_collateral_to_scan(&self) -> bool {
+        has_min_free_collateral(self.config.min_free_collateral_to_scan, || {
+            match self.get_cached_capacity(|capacity| Some(capacity.free_collateral)) {
+                Some(free_collateral) => Ok(free_collateral),
+                None => self.get_free_collateral(),
+            }
+        })
     }
 
-    /// Check if a user needs to be rebalanced
+    /// Drain pending update signals from the state engine
     ///
-    /// - User has tokens in token accounts
-    /// - User has non-stable deposits
-    /// - User has any liabilities
-    fn needs_to_be_rebalanced(&self) -> bool {
+    /// The channel only disconnects once the state engine is gone, after which no state
+    /// updates will ever arrive again, so it's treated as fatal.
+    fn drain_update_signals(&self) -> Result<(), ProcessorError> {
+        loop {
+            match self.update_rx.try_recv() {
+                Ok(_) => continue,
+                Err(TryRecvError::Empty) => return Ok(()),
+                Err(TryRecvError::Disconnected) => {
+                    return Err(ProcessorError::UpdateChannelDisconnected)
+                }
+            }
+        }
+    }
+
+    /// Recompute the cached capacity once it's `capacity_refresh_interval_secs` old
+    fn refresh_capacity_cache_if_due(&self) {
+        let interval = match self.config.capacity_refresh_interval_secs {
+            Some(interval) => Duration::from_secs(interval),
+            None => return,
+        };
+
+        let due = match self.capacity_cache.lock() {
+            Ok(cache) => cache
+                .as_ref()
+                .map_or(true, |capacity| !capacity.is_fresh(interval)),
+            Err(_) => return,
+        };
+
+        if !due {
+            return;
+        }
+
+        let start = Instant::now();
+
+        let capacity = self.get_free_collateral().map(|free_collateral| {
+            let max_borrows = self
+                .state_engine
+                .banks
+                .iter()
+                .filter_map(|bank| {
+                    self.compute_max_borrow_for_bank(bank.key())
+                        .map(|max_borrow| (*bank.key(), max_borrow))
+                        .map_err(|e| {
+                            debug!("Failed to compute max borrow for {}: {:?}", bank.key(), e)
+                        })
+                        .ok()
+                })
+                .collect::<HashMap<_, _>>();
+
+            LiquidatorCapacity {
+                free_collateral,
+                max_borrows,
+                computed_at: Instant::now(),
+            }
+        });
+
+        match capacity {
+            Ok(capacity) => {
+                debug!(
+                    "Refreshed liquidator capacity in {:?}: ${} free collateral",
+                    start.elapsed(),
+                    capacity.free_collateral
+                );
+
+                if let Ok(mut cache) = self.capacity_cache.lock() {
+                    *cache = Some(capacity);
+                }
+            }
+            Err(e) => warn!("Failed to refresh liquidator capacity: {:?}", e),
+        }
+    }
+
+    /// Drop the cached capacity after the liquidator's positions changed
+    fn invalidate_capacity_cache(&self) {
+        if let Ok(mut cache) = self.capacity_cache.lock() {
+            *cache = None;
+        }
+    }
+
+    /// Cached capacity value selected by `select`, `None` when there is no capacity cached
+    /// within `capacity_refresh_interval_secs`
+    fn get_cached_capacity<T>(
+        &self,
+        select: impl FnOnce(&LiquidatorCapacity) -> Option<T>,
+    ) -> Option<T> {
+        let interval = Duration::from_secs(self.config.capacity_refresh_interval_secs?);
+
+        self.capacity_cache
+            .lock()
+            .ok()?
+            .as_ref()
+            .filter(|capacity| capacity.is_fresh(interval))
+            .and_then(select)
+    }
+
+    /// Report oracles going silent or recovering, see `expected_oracle_update_interval_ms`
+    fn monitor_oracle_updates(&self) {
+        let mut update_ages = HashMap::new();
+
+        for bank in self.state_engine.banks.iter() {
+            if let Ok(bank) = bank.read() {
+                update_ages.insert(
+                    bank.oracle_adapter.address,
+                    bank.oracle_adapter.update_age(),
+                );
+            }
+        }
+
+        let mut silent_oracles = match self.silent_oracles.lock() {
+            Ok(silent_oracles) => silent_oracles,
+            Err(_) => return,
+        };
+
+        for (oracle, update_age) in update_ages {
+            let expected_interval = match self.config.get_expected_oracle_update_interval(&oracle) {
+                Some(expected_interval) => expected_interval,
+                None => continue,
+            };
+
+            trace!("Oracle {} last updated {:?} ago", oracle, update_age);
+
+            if update_age > expected_interval {
+                if silent_oracles.insert(oracle) {
+                    warn!(
+                        "Oracle {} went silent, no update for {:?} (expected every {:?})",
+                        oracle, update_age, expected_interval
+                    );
+                }
+            } else if silent_oracles.remove(&oracle) {
+                info!("Oracle {} is updating again", oracle);
+            }
+        }
+    }
+
+    /// Log utilization and interest rates of all banks, highest borrowing rate first
+    fn report_bank_rates(&self) {
+        let mut bank_rates = self
+            .state_engine
+            .banks
+            .iter()
+            .filter_map(|bank_ref| {
+                let bank = bank_ref.read().ok()?;
+
+                let rates = bank
+                    .calc_utilization()
+                    .and_then(|utilization| Ok((utilization, bank.calc_interest_rates()?)));
+
+                match rates {
+                    Ok((utilization, (lending_apr, borrowing_apr))) => Some((
+                        bank.address,
+                        bank.bank.mint,
+                        utilization,
+                        lending_apr,
+                        borrowing_apr,
+                    )),
+                    Err(e) => {
+                        warn!("Failed to compute rates for bank {}: {:?}", bank.address, e);
+                        None
+                    }
+                }
+            })
+            .collect::<Vec<_>>();
+
+        bank_rates.sort_by(|a, b| b.4.cmp(&a.4));
+
+        info!("Bank rates:");
+        for (bank_pk, mint, utilization, lending_apr, borrowing_apr) in bank_rates {
+            info!(
+                "Bank {} (mint: {}): utilization {:.2}%, lending APR {:.2}%, borrowing APR {:.2}%",
+                bank_pk,
+                mint,
+                utilization * I80F48!(100),
+                lending_apr * I80F48!(100),
+                borrowing_apr * I80F48!(100)
+            );
+        }
+    }
+
+    /// Run a rebalance unless one is already in flight
+    ///
+    /// Triggers arriving while a rebalance is in flight don't start a concurrent one, they are
+    /// coalesced into a single follow-up rebalance that the in-flight caller runs once it's
+    /// done. At most one rebalance is in flight and one is queued at any time.
+    async fn trigger_rebalance(&self) -> Result<(), ProcessorError> {
+        self.rebalance_trigger
+            .run(|| async {
+                let rebalance_result = self.rebalance_with_recovery().await;
+                self.invalidate_capacity_cache();
+                rebalance_result
+            })
+            .await
+    }
+
+    async fn rebalance_with_recovery(&self) -> Result<(), ProcessorError> {
+        let mut retries = 0;
+        while self.rebalance_accounts().await.is_err() {
+            retries += 1;
+
+            if retries > 5 {
+                error!("Failed to rebalance accounts after 5 retries, exiting...");
+                self.state_engine
+                    .load_initial_state(self.config.liquidator_account)
+                    .await?;
+                return Err(ProcessorError::Error("Failed to rebalance accounts"));
+            }
+
+            error!("Error rebalancing accounts, retrying...");
+            tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+        }
+
+        debug!("Rebalanced accounts");
+
+        Ok(())
+    }
+
+    async fn rebalance_accounts(&self) -> Result<(), ProcessorError> {
+        let near_health_limit = self.config.rebalance_order == RebalanceOrder::Adaptive
+            && self.get_liquidator_account()?.maintenance_liability_ratio()
+                > I80F48::from_num(self.config.rebalance_repay_first_liability_ratio);
+
+        let steps = self.config.rebalance_order.steps(near_health_limit);
+
+        debug!("Rebalancing with steps {:?}", steps);
+
+        let mut failed_steps = self.run_rebalance_steps(steps).await;
+
+        for retry in 1..=self.config.rebalance_step_retries {
+            if failed_steps.is_empty() {
+                break;
+            }
+
+            warn!(
+                "Retrying failed rebalance steps {:?} ({}/{})",
+                failed_steps, retry, self.config.rebalance_step_retries
+            );
+            tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+
+            failed_steps = self.run_rebalance_steps(&failed_steps).await;
+        }
+
+        if !failed_steps.is_empty() {
+            error!("Rebalance steps {:?} failed", failed_steps);
+            return Err(ProcessorError::Error("Failed to run rebalance steps"));
+        }
+
+        Ok(())
+    }
+
+    /// Run rebalance steps in order, continuing past failed steps, and return the steps that
+    /// failed or were deferred because a step they depend on failed
+    async fn run_rebalance_steps(&self, steps: &[RebalanceStep]) -> Vec<RebalanceStep> {
+        let mut failed_steps: Vec<RebalanceStep> = vec![];
+
+        for step in steps {
+            if let Some(dependency) = step.failed_dependency(&failed_steps) {
+                warn!(
+                    "Deferring rebalance step {:?}, {:?} failed",
+                    step, dependency
+                );
+            } else {
+                let result = match step {
+                    RebalanceStep::WrapExcessSol => self.wrap_excess_sol(),
+                    RebalanceStep::SellNonPreferredDeposits => {
+                        self.sell_non_preferred_deposits().await
+                    }
+                    RebalanceStep::RepayLiabilities => self.replay_liabilities().await,
+                    RebalanceStep::HandleTokensInTokenAccounts => {
+                        self.handle_tokens_in_token_accounts().await
+                    }
+                    RebalanceStep::DepositPreferredTokens => self.deposit_preferred_tokens().await,
+                };
+
+                match result {
+                    Ok(()) => continue,
+                    Err(e) if e.is_confirmation_timeout() => {
+                        warn!(
+                            "Rebalance step {:?} sent a transaction that wasn't confirmed in time, reloading state before going on: {:?}",
+                            step, e
+                        );
+                        self.reload_state_after_confirmation_timeout().await;
+                    }
+                    Err(e) => error!("Rebalance step {:?} failed: {:?}", step, e),
+                }
+            }
+
+            if !failed_steps.contains(step) {
+                failed_steps.push(*step);
+            }
+        }
+
+        failed_steps
+    }
+
+    /// Reload the liquidator's state a transaction not confirmed in time may have changed, so
+    /// that the following steps are evaluated against the chain rather than assumed outcomes
+    async fn reload_state_after_confirmation_timeout(&self) {
+        if let Err(e) = self
+            .state_engine
+            .load_liquidator_account(self.config.liquidator_account)
+        {
+            error!("Failed to reload the liquidator account: {:?}", e);
+        }
+
+        if let Err(e) = self.state_engine.load_sol_accounts() {
+            error!("Failed to reload the SOL balance: {:?}", e);
+        }
+
+        if let Err(e) = self
+            .state_engine
+            .refresh_token_account(&self.swap_mint_bank_pk)
+            .await
+        {
+            error!("Failed to reload the swap mint token account: {:?}", e);
+        }
+    }
+
+    /// Check if a user needs to be rebalanced
+    ///
+    /// - User has tokens in token accounts
+    /// - User has non-stable deposits
+    /// - User has any liabilities
+    fn needs_to_be_rebalanced(&self) -> bool {
         debug!("Checking if liquidator needs to be rebalanced");
         let rebalance_needed = self.has_tokens_in_token_accounts()
             || self.has_non_preferred_deposits()
-            || self.has_liabilties();
+            || self.has_liabilties()
+            || self.has_excess_sol();
 
         if rebalance_needed {
             info!("Liquidator needs to be rebalanced");
@@ -321,6 +2179,131 @@ impl EvaLiquidator {
         rebalance_needed
     }
 
+    /// Signer's SOL balance, `None` until it's loaded
+    fn get_sol_balance(&self) -> Option<I80F48> {
+        self.state_engine
+            .sol_accounts
+            .get(&self.signer_keypair.pubkey())
+            .map(|account| I80F48::from_num(account.lamports) / I80F48::from_num(LAMPORTS_PER_SOL))
+    }
+
+    fn has_excess_sol(&self) -> bool {
+        let has_excess_sol = self
+            .get_sol_balance()
+            .map_or(false, |balance| balance > self.config.max_sol_balance)
+            && self
+                .state_engine
+                .get_bank_for_mint(&spl_token::native_mint::id())
+                .is_some();
+
+        if has_excess_sol {
+            info!("Liquidator has SOL above max_sol_balance");
+        }
+
+        has_excess_sol
+    }
+
+    /// Whether the signer holds the `min_sol_balance` it needs to pay fees
+    fn has_sol_fee_reserve(&self) -> bool {
+        match self.get_sol_balance() {
+            Some(balance) if balance < self.config.min_sol_balance => {
+                warn!(
+                    "SOL balance {} below min_sol_balance {}, pausing liquidations",
+                    balance, self.config.min_sol_balance
+                );
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Wrap the signer's SOL above `max_sol_balance` into its wrapped SOL token account, from
+    /// where it's sold for the swap mint along with the other tokens in token accounts
+    fn wrap_excess_sol(&self) -> Result<(), ProcessorError> {
+        let excess = match self.get_sol_balance() {
+            Some(balance) => balance - self.config.max_sol_balance,
+            None => return Ok(()),
+        };
+
+        if !excess.is_positive() {
+            return Ok(());
+        }
+
+        if self
+            .state_engine
+            .get_bank_for_mint(&spl_token::native_mint::id())
+            .is_none()
+        {
+            warn!("No SOL bank to sell {} excess SOL through", excess);
+            return Ok(());
+        }
+
+        let lamports: u64 = (excess * I80F48::from_num(LAMPORTS_PER_SOL)).to_num();
+        let signer_pk = self.signer_keypair.pubkey();
+        let wsol_account = spl_associated_token_account::get_associated_token_address(
+            &signer_pk,
+            &spl_token::native_mint::id(),
+        );
+
+        let ixs = vec![
+            spl_associated_token_account::instruction::create_associated_token_account_idempotent(
+                &signer_pk,
+                &signer_pk,
+                &spl_token::native_mint::id(),
+                &spl_token::ID,
+            ),
+            system_instruction::transfer(&signer_pk, &wsol_account, lamports),
+            spl_token::instruction::sync_native(&spl_token::ID, &wsol_account)
+                .map_err(|_| ProcessorError::Error("Failed to build sync native instruction"))?,
+        ];
+
+        let recent_blockhash = self
+            .state_engine
+            .rpc_pool
+            .get_latest_blockhash()
+            .map_err(|e| {
+                error!("Failed to get latest blockhash: {:?}", e);
+                ProcessorError::Error("Failed to get latest blockhash")
+            })?;
+
+        let tx = Transaction::new_signed_with_payer(
+            &ixs,
+            Some(&signer_pk),
+            &[self.signer_keypair.as_ref()],
+            recent_blockhash,
+        );
+
+        if self.config.dry_run {
+            info!(
+                "Dry run, not wrapping {} excess SOL with transaction {}",
+                excess, tx.signatures[0]
+            );
+            return Ok(());
+        }
+
+        info!("Wrapping {} excess SOL", excess);
+
+        let sig = aggressive_send_tx(
+            self.state_engine.rpc_pool.clone(),
+            &tx,
+            self.config.get_sender_cfg(),
+        )
+        .map_err(|e| {
+            error!("Failed to wrap excess SOL: {:?}", e);
+            send_error(e, "Failed to wrap excess SOL")
+        })?;
+
+        info!("Wrapped excess SOL: {}", sig);
+
+        // The rebalance loop checks the SOL balance again right away, it must not see the
+        // wrapped excess before the streamed update lands
+        if let Err(e) = self.state_engine.load_sol_accounts() {
+            error!("Failed to reload the SOL balance: {:?}", e);
+        }
+
+        Ok(())
+    }
+
     fn has_tokens_in_token_accounts(&self) -> bool {
         debug!("Checking if liquidator has tokens in token accounts");
         let has_tokens_in_tas = self.state_engine.token_accounts.iter().any(|account| {
@@ -328,9 +2311,13 @@ impl EvaLiquidator {
                 .read()
                 .map_err(|_| ProcessorError::FailedToReadAccount)
                 .map(|account| {
-                    let value = account.get_value().unwrap();
+                    let value = account
+                        .get_value_with_bias(self.config.dust_check_price_bias.to_price_bias())
+                        .unwrap();
                     debug!("Token account {} value: {:?}", account.mint, value);
-                    value > self.config.token_account_dust_threshold
+                    !self
+                        .config
+                        .is_token_account_dust(&account.mint, account.get_amount(), value)
                 })
                 .unwrap_or(false)
         });
@@ -352,6 +2339,7 @@ impl EvaLiquidator {
             .iter()
             .map(|e| *e.key())
             .filter(|bank_pk| self.swap_mint_bank_pk != *bank_pk)
+            .filter(|bank_pk| !self.is_bank_excluded(bank_pk))
             .collect::<Vec<_>>();
 
         for bank_pk in bank_addresses {
@@ -365,10 +2353,12 @@ impl EvaLiquidator {
         let balance = self.get_token_balance_for_bank(&self.swap_mint_bank_pk)?;
 
         if let Some(balance) = balance {
-            if !balance.is_zero() {
+            let (amount, _) = fixed_to_native_amount(balance);
+
+            if amount > 0 {
                 self.liquidator_account.deposit(
                     self.swap_mint_bank_pk,
-                    balance.to_num(),
+                    amount,
                     self.config.get_tx_config(),
                 )?;
             }
@@ -391,22 +2381,27 @@ impl EvaLiquidator {
 
         trace!("Found token balance of {} for bank {}", amount, bank_pk);
 
-        let value = self.get_value(
-            amount,
-            bank_pk,
-            RequirementType::Equity,
-            BalanceSide::Assets,
-        )?;
+        let value = self
+            .state_engine
+            .get_bank(bank_pk)
+            .ok_or(ProcessorError::Error("Failed to get bank"))?
+            .read()
+            .map_err(|_| ProcessorError::Error("Failed to get bank"))?
+            .calc_biased_value(amount, self.config.dust_check_price_bias.to_price_bias())?;
 
         trace!("Token balance value: ${}", value);
 
-        if value < self.config.token_account_dust_threshold {
+        if self
+            .config
+            .is_token_account_dust(&self.get_mint_for_bank(bank_pk)?, amount, value)
+        {
             trace!("Token balance value is below dust threshold");
             return Ok(());
         }
 
-        self.swap(amount.to_num(), bank_pk, &self.swap_mint_bank_pk)
-            .await?;
+        let (amount, _) = fixed_to_native_amount(amount);
+
+        self.sell_to_swap_mint(amount, bank_pk).await?;
 
         Ok(())
     }
@@ -420,21 +2415,21 @@ impl EvaLiquidator {
             return Ok(());
         }
 
-        let balance = balance.unwrap();
+        let (amount, _) = fixed_to_native_amount(balance.unwrap());
 
-        if balance.is_zero() {
+        if amount == 0 {
             debug!("No token balance found for bank {}", self.swap_mint_bank_pk);
             return Ok(());
         }
 
         debug!(
             "Found token balance of {} for bank {}",
-            balance, self.swap_mint_bank_pk
+            amount, self.swap_mint_bank_pk
         );
 
         self.liquidator_account.deposit(
             self.swap_mint_bank_pk,
-            balance.to_num(),
+            amount,
             self.config.get_tx_config(),
         )?;
 
@@ -477,9 +2472,8 @@ impl EvaLiquidator {
     ) -> Result<Option<I80F48>, ProcessorError> {
         let mint = self
             .state_engine
-            .banks
-            .get(bank_pk)
-            .and_then(|bank| bank.read().ok().map(|bank| bank.bank.mint));
+            .get_bank_mint(bank_pk)
+            .map(|(mint, _)| mint);
 
         if mint.is_none() {
             warn!("No mint found for bank {}", bank_pk);
@@ -564,7 +2558,7 @@ impl EvaLiquidator {
 
         debug!("Liability to purchase: {}", liab_to_purchase);
 
-        if !liab_to_purchase.is_zero() {
+        if liab_to_purchase.is_positive() {
             let liab_usd_value = self.get_value(
                 liab_to_purchase,
                 &bank_pk,
@@ -574,9 +2568,30 @@ impl EvaLiquidator {
 
             debug!("Liability value: ${}", liab_usd_value);
 
-            let required_swap_token =
+            let mut required_swap_token =
                 self.get_amount(liab_usd_value, &self.swap_mint_bank_pk, None)?;
 
+            if self.config.use_jupiter_prices_for_sizing {
+                required_swap_token = self
+                    .get_jupiter_sizing_amount(
+                        &self.swap_mint_bank_pk,
+                        &bank_pk,
+                        required_swap_token,
+                        liab_to_purchase,
+                    )
+                    .await?;
+            }
+
+            let exact_out = self.config.use_exact_out_liability_swaps;
+
+            // The exact input of an ExactOut swap is only known from its quote, withdraw enough
+            // for the route to need somewhat more than estimated
+            if exact_out {
+                required_swap_token += required_swap_token
+                    * I80F48::from_num(self.config.exact_out_swap_buffer_bps)
+                    / I80F48::from_num(10_000);
+            }
+
             debug!(
                 "Required swap token amount: {} for ${}",
                 required_swap_token, liab_usd_value
@@ -614,41 +2629,109 @@ impl EvaLiquidator {
 
                 let withdraw_amount = min(max_withdraw_amount, token_balance_to_withdraw);
 
-                self.liquidator_account.withdraw(
-                    &self.swap_mint_bank_pk,
-                    withdraw_amount.to_num(),
-                    Some(withdraw_all),
-                    self.config.get_tx_config(),
-                )?;
+                if withdraw_amount < token_balance_to_withdraw {
+                    warn!(
+                        "Not enough free collateral to withdraw {} of {}, repaying {} partially",
+                        token_balance_to_withdraw, self.swap_mint_bank_pk, bank_pk
+                    );
+                }
 
-                withdraw_amount
+                if withdraw_amount.is_positive() {
+                    let (amount, _) = fixed_to_native_amount(withdraw_amount);
+
+                    self.liquidator_account.withdraw(
+                        &self.swap_mint_bank_pk,
+                        amount,
+                        Some(withdraw_all),
+                        self.config.get_tx_config(),
+                    )?;
+                }
+
+                withdraw_amount.max(I80F48::ZERO)
             } else {
                 I80F48::ZERO
             };
 
-            let amount_to_swap = min(liab_balance + withdrawn_amount, required_swap_token);
+            // Swap what's available in swap mint tokens, up to what the liability requires
+            let amount_to_swap = min(swap_token_balance + withdrawn_amount, required_swap_token);
 
             if amount_to_swap.is_positive() {
-                self.swap(amount_to_swap.to_num(), &self.swap_mint_bank_pk, &bank_pk)
-                    .await?;
+                let verify_balance_delta = self.should_verify_balance_deltas();
+
+                let pre_swap_balance = if verify_balance_delta {
+                    self.state_engine.refresh_token_account(&bank_pk).await?;
+                    self.get_token_balance_for_bank(&bank_pk)?
+                        .unwrap_or_default()
+                } else {
+                    I80F48::ZERO
+                };
+
+                let exact_out_result = if exact_out {
+                    self.swap_exact_out(
+                        liab_to_purchase.ceil().to_num(),
+                        amount_to_swap.to_num(),
+                        &self.swap_mint_bank_pk,
+                        &bank_pk,
+                        SwapUrgency::Critical,
+                    )
+                    .await
+                } else {
+                    Err(ProcessorError::ExactOutUnavailable)
+                };
+
+                let min_out_amount = match exact_out_result {
+                    Err(ProcessorError::ExactOutUnavailable) => {
+                        if exact_out {
+                            warn!(
+                                "No ExactOut route buys {} for {} within {}, swapping the whole budget ExactIn",
+                                liab_to_purchase, bank_pk, amount_to_swap
+                            );
+                        }
+
+                        self.swap(
+                            amount_to_swap.to_num(),
+                            &self.swap_mint_bank_pk,
+                            &bank_pk,
+                            SwapUrgency::Critical,
+                        )
+                        .await?
+                    }
+                    result => result?,
+                };
 
                 self.state_engine.refresh_token_account(&bank_pk).await?;
-            }
 
-            let token_balance = self
-                .get_token_balance_for_bank(&bank_pk)?
-                .unwrap_or_default();
+                if verify_balance_delta {
+                    self.verify_balance_delta(
+                        &bank_pk,
+                        pre_swap_balance,
+                        self.get_token_balance_for_bank(&bank_pk)?
+                            .unwrap_or_default(),
+                        I80F48::from_num(min_out_amount),
+                    )?;
+                }
+            }
+        }
 
-            let repay_all = token_balance >= liab_balance;
+        let token_balance = self
+            .get_token_balance_for_bank(&bank_pk)?
+            .unwrap_or_default();
 
-            self.liquidator_account.repay(
-                bank_pk,
-                token_balance.to_num(),
-                Some(repay_all),
-                self.config.get_tx_config(),
-            )?;
+        if token_balance.is_zero() {
+            warn!("No {} tokens to repay the liability with", bank_pk);
+            return Ok(());
         }
 
+        let repay_all = token_balance >= liab_balance;
+        let (amount, _) = fixed_to_native_amount(token_balance);
+
+        self.liquidator_account.repay(
+            bank_pk,
+            amount,
+            Some(repay_all),
+            self.config.get_tx_config(),
+        )?;
+
         Ok(())
     }
 
@@ -693,7 +2776,25 @@ impl EvaLiquidator {
 
         let (withdraw_amount, withdraw_all) = self.get_max_withdraw_for_bank(bank_pk)?;
 
-        let amount = withdraw_amount.to_num::<u64>();
+        let (amount, _) = fixed_to_native_amount(withdraw_amount);
+        let (whole_balance, residual) = fixed_to_native_amount(balance);
+
+        let withdraw_all = match self.config.native_residual_handling {
+            NativeResidualHandling::Sweep => withdraw_all || amount >= whole_balance,
+            NativeResidualHandling::Keep => withdraw_all,
+        };
+
+        if !withdraw_all && amount >= whole_balance && residual.is_positive() {
+            debug!(
+                "Keeping residual of {} native units in bank {}",
+                residual, bank_pk
+            );
+        }
+
+        if amount == 0 && !withdraw_all {
+            debug!("Nothing to withdraw from bank {}", bank_pk);
+            return Ok(());
+        }
 
         self.liquidator_account.withdraw(
             bank_pk,
@@ -702,7 +2803,9 @@ impl EvaLiquidator {
             self.config.get_tx_config(),
         )?;
 
-        self.swap(amount, bank_pk, &self.swap_mint_bank_pk).await?;
+        if amount > 0 {
+            self.sell_to_swap_mint(amount, bank_pk).await?;
+        }
 
         Ok(())
     }
@@ -775,16 +2878,31 @@ impl EvaLiquidator {
             .iter()
             .filter(|balance| balance.active)
             .any(|balance| {
-                let mint = self
+                let (mint, amount) = self
                     .state_engine
                     .banks
                     .get(&balance.bank_pk)
-                    .and_then(|bank| bank.read().ok().map(|bank| bank.bank.mint))
+                    .and_then(|bank| {
+                        bank.read().ok().map(|bank| {
+                            (
+                                bank.bank.mint,
+                                bank.bank
+                                    .get_asset_amount(balance.asset_shares.into())
+                                    .unwrap_or_default(),
+                            )
+                        })
+                    })
                     .unwrap();
 
+                // Sub-unit remainders can't be withdrawn, they are kept rather than swept
+                let is_kept_residual = self.config.native_residual_handling
+                    == NativeResidualHandling::Keep
+                    && amount < I80F48::ONE;
+
                 let has_non_preferred_deposit =
                     matches!(balance.get_side(), Some(BalanceSide::Assets))
-                        && !self.preferred_mints.contains(&mint);
+                        && !self.preferred_mints.contains(&mint)
+                        && !is_kept_residual;
 
                 debug!("Found non-preferred {} deposits", mint);
 
@@ -800,100 +2918,479 @@ impl EvaLiquidator {
         has_non_preferred_deposits
     }
 
-    fn evaluate_all_accounts(&self) -> Result<bool, ProcessorError> {
-        let start = std::time::Instant::now();
+    /// Liquidation opportunities of the accounts that can currently be liquidated, sorted by
+    /// estimated profit descending
+    ///
+    /// Only scans the streamed state, nothing is liquidated.
+    pub fn find_liquidatable_accounts(&self) -> Vec<LiquidationOpportunity> {
+        self.find_liquidatable_accounts_with_banks(None)
+    }
 
-        let mut accounts = self
-            .state_engine
-            .marginfi_accounts
+    /// Snapshot of the banks referenced by the streamed accounts when
+    /// `snapshot_banks_for_scan` is enabled
+    fn get_scan_bank_snapshot(&self) -> Option<Arc<DashMap<Pubkey, Arc<RwLock<BankWrapper>>>>> {
+        if !self.config.snapshot_banks_for_scan {
+            return None;
+        }
+
+        let bank_pks = self
+            .get_scan_accounts()
             .iter()
-            .filter_map(|account| {
-                let account = account.value();
+            .flat_map(|account| account.read().unwrap().get_active_banks())
+            .collect::<HashSet<_>>();
+
+        Some(self.state_engine.snapshot_banks(bank_pks))
+    }
+
+    /// Accounts a scan cycle evaluates, the watched ones when `watch_only_accounts` is set,
+    /// otherwise every streamed account
+    fn get_scan_accounts(&self) -> Vec<Arc<RwLock<MarginfiAccountWrapper>>> {
+        select_scan_accounts(
+            &self.state_engine.marginfi_accounts,
+            self.config.watch_only_accounts.as_deref(),
+        )
+    }
+
+    /// Same as [`Self::find_liquidatable_accounts`], evaluating accounts against
+    /// `bank_snapshot` instead of the live banks when one is given
+    pub fn find_liquidatable_accounts_with_banks(
+        &self,
+        bank_snapshot: Option<&Arc<DashMap<Pubkey, Arc<RwLock<BankWrapper>>>>>,
+    ) -> Vec<LiquidationOpportunity> {
+        let all_accounts = self.get_scan_accounts();
+
+        metrics().accounts_scanned.set(all_accounts.len() as i64);
+
+        let group = self.state_engine.get_marginfi_group_address();
+
+        let mut accounts = par_filter_map_in_batches(
+            &all_accounts,
+            self.config.health_computation_batch_size,
+            |account| {
+                let snapshot_account;
+                let account = match bank_snapshot {
+                    Some(banks) => {
+                        snapshot_account = Arc::new(RwLock::new(
+                            account.read().unwrap().with_banks(banks.clone()),
+                        ));
+                        &snapshot_account
+                    }
+                    None => account,
+                };
 
                 if !account.read().unwrap().has_liabs() {
                     return None;
                 }
 
-                let (max_liquidation_amount, profit) = account
+                // Only accounts of the liquidator's group, holding only banks of that
+                // group, can be liquidated by it
+                {
+                    let account = account.read().unwrap();
+
+                    if account.account.group != group {
+                        trace!(
+                            "Skipping account {} of group {}",
+                            account.address,
+                            account.account.group
+                        );
+                        return None;
+                    }
+
+                    let cross_group_banks = account.get_cross_group_banks(&group);
+
+                    if !cross_group_banks.is_empty() {
+                        debug!(
+                            "Skipping account {} referencing banks outside of the group: {:?}",
+                            account.address, cross_group_banks
+                        );
+                        return None;
+                    }
+                }
+
+                if self.config.min_account_value_usd > 0.0 {
+                    let account = account.read().unwrap();
+
+                    // Accounts whose deposits can't be priced are left to the checks below
+                    if let Ok(value) = account.get_total_deposits_value() {
+                        if value < I80F48::from_num(self.config.min_account_value_usd) {
+                            trace!(
+                                "Skipping account {} with deposits worth ${}",
+                                account.address,
+                                value
+                            );
+                            return None;
+                        }
+                    }
+                }
+
+                if account.read().unwrap().has_silent_oracles(|oracle| {
+                    self.config.get_expected_oracle_update_interval(oracle)
+                }) {
+                    trace!(
+                        "Skipping account {} with silent oracles",
+                        account.read().unwrap().address
+                    );
+                    return None;
+                }
+
+                if account
                     .read()
                     .unwrap()
-                    .compute_max_liquidatable_asset_amount()
-                    .ok()?;
+                    .has_stale_oracles(self.state_engine.get_default_oracle_max_age_secs())
+                {
+                    trace!(
+                        "Skipping account {} with stale oracles",
+                        account.read().unwrap().address
+                    );
+                    return None;
+                }
+
+                if let Some(grace) = self.config.oracle_initial_grace_secs {
+                    if !account
+                        .read()
+                        .unwrap()
+                        .has_warm_oracles(Duration::from_secs(grace))
+                    {
+                        trace!(
+                            "Skipping account {} with oracles not refreshed since load",
+                            account.read().unwrap().address
+                        );
+                        return None;
+                    }
+                }
+
+                // Cheap pre-filter, healthy accounts skip the liquidation pair search. Same
+                // boundary as compute_max_liquidatable_asset_amount_with_banks: an account
+                // exactly at assets == liabs isn't liquidatable on-chain either.
+                {
+                    let (assets, liabs) = account
+                        .read()
+                        .unwrap()
+                        .calc_health(RequirementType::Maintenance);
+
+                    if assets >= liabs {
+                        return None;
+                    }
+                }
+
+                let opportunity = {
+                    let account = account.read().unwrap();
+
+                    let is_excluded = |bank_pk: &Pubkey| self.is_bank_excluded(bank_pk);
 
-                if max_liquidation_amount.is_zero() || profit < self.config.min_profit {
+                    let opportunity = if self.config.cache_account_health {
+                        account.compute_max_liquidatable_asset_amount_cached(is_excluded)
+                    } else {
+                        account.compute_max_liquidatable_asset_amount(is_excluded)
+                    };
+
+                    opportunity.ok()??
+                };
+
+                if opportunity.max_liquidatable_asset_amount.is_zero()
+                    || opportunity.estimated_profit < self.config.min_profit
+                {
                     return None;
                 }
 
-                Some((account.clone(), (max_liquidation_amount, profit)))
-            })
-            .collect::<Vec<_>>();
+                Some(opportunity)
+            },
+        );
+
+        accounts.sort_by(|a, b| b.estimated_profit.cmp(&a.estimated_profit));
+
+        accounts
+    }
+
+    fn evaluate_all_accounts(&self) -> Result<bool, ProcessorError> {
+        let start = std::time::Instant::now();
+
+        let bank_snapshot = self.get_scan_bank_snapshot();
+
+        let accounts = self.find_liquidatable_accounts_with_banks(bank_snapshot.as_ref());
 
-        accounts.sort_by(|(_, (_, profit_a)), (_, (_, profit_b))| profit_a.cmp(profit_b));
+        if self.config.log_candidate_transitions {
+            self.log_candidate_transitions(
+                accounts
+                    .iter()
+                    .map(|opportunity| opportunity.account)
+                    .collect(),
+            );
+        }
 
         accounts
             .iter()
-            .rev()
-            .take(10)
-            .for_each(|(account, (lv, profit))| {
+            .take(self.config.max_liquidation_candidates)
+            .for_each(|opportunity| {
                 info!(
-                    "Account {} liquidatable amount: {}, profit: {}",
-                    account.read().unwrap().address,
-                    lv,
-                    profit
+                    "Account {} liquidatable amount: {} of {} (${}), profit: {}",
+                    opportunity.account,
+                    opportunity.max_liquidatable_asset_amount,
+                    opportunity.asset_bank,
+                    opportunity.max_liquidatable_value,
+                    opportunity.estimated_profit
                 );
             });
 
-        let unhealty_top_10 = accounts.iter().rev().take(10).collect::<Vec<_>>();
+        // Watched accounts may be listed more than once, each is attempted once per cycle
+        let mut seen = HashSet::new();
+
+        let candidates = accounts
+            .iter()
+            .take(self.config.max_liquidation_candidates)
+            .enumerate()
+            .filter(|(_, opportunity)| seen.insert(opportunity.account))
+            .filter_map(|(rank, opportunity)| {
+                self.state_engine
+                    .marginfi_accounts
+                    .get(&opportunity.account)
+                    .map(|account| (rank, account.value().clone()))
+            })
+            .collect::<Vec<_>>();
 
         let end = start.elapsed();
 
+        metrics().scan_duration_seconds.observe(end.as_secs_f64());
+
         debug!(
             "Processed accounts {} in {:?}",
             self.state_engine.marginfi_accounts.len(),
             end
         );
 
-        let first = unhealty_top_10.first();
+        let mut actions = vec![CandidateAction::NotAttempted; accounts.len()];
+
+        let liquidated_any = run_liquidation_cycle(
+            candidates,
+            self.config.max_cycle_liquidation_value_usd,
+            |(rank, account), remaining_cycle_value, liquidated_any| {
+                if liquidated_any && self.config.refresh_liquidator_account_between_liquidations {
+                    if let Err(e) = self
+                        .state_engine
+                        .load_liquidator_account(self.config.liquidator_account)
+                    {
+                        error!(
+                            "Failed to refresh the liquidator account, halting further liquidations: {:?}",
+                            e
+                        );
+                        return AttemptOutcome::Halt;
+                    }
+                }
 
-        if let Some((account, _)) = first {
-            info!("Liquidating account {}", account.read().unwrap().address);
-            self.liquidate_account(account.clone())?;
+                if self.is_in_liquidation_cooldown(&account.read().unwrap()) {
+                    debug!(
+                        "Skipping account {} liquidated less than {}s ago without an update since",
+                        account.read().unwrap().address,
+                        self.config.liquidation_cooldown_secs
+                    );
+                    actions[rank] = CandidateAction::Skipped;
+                    return AttemptOutcome::NotLiquidated;
+                }
 
-            return Ok(true);
-        } else {
+                let unpriceable_banks = {
+                    let account = account.read().unwrap();
+
+                    match &bank_snapshot {
+                        Some(banks) => account.with_banks(banks.clone()).get_unpriceable_banks(),
+                        None => account.get_unpriceable_banks(),
+                    }
+                };
+
+                if !unpriceable_banks.is_empty() {
+                    warn!(
+                        "Skipping account {} with banks that can't be priced: {:?}",
+                        account.read().unwrap().address,
+                        unpriceable_banks
+                    );
+                    actions[rank] = CandidateAction::Skipped;
+                    return AttemptOutcome::NotLiquidated;
+                }
+
+                info!("Liquidating account {}", account.read().unwrap().address);
+
+                metrics().liquidations_attempted.inc();
+
+                match self.liquidate_account(
+                    account.clone(),
+                    remaining_cycle_value,
+                    bank_snapshot.as_ref(),
+                ) {
+                    Ok(Some(liquidated_value)) => {
+                        actions[rank] = CandidateAction::Liquidated;
+                        metrics()
+                            .liquidations
+                            .with_label_values(&["succeeded"])
+                            .inc();
+                        self.invalidate_capacity_cache();
+
+                        AttemptOutcome::Liquidated(liquidated_value)
+                    }
+                    Ok(None) => {
+                        actions[rank] = CandidateAction::Skipped;
+                        metrics().liquidations.with_label_values(&["skipped"]).inc();
+
+                        AttemptOutcome::NotLiquidated
+                    }
+                    Err(e) => {
+                        actions[rank] = CandidateAction::Failed;
+                        metrics().liquidations.with_label_values(&["failed"]).inc();
+                        error!(
+                            "Failed to liquidate account {}: {:?}",
+                            account.read().unwrap().address,
+                            e
+                        );
+
+                        AttemptOutcome::NotLiquidated
+                    }
+                }
+            },
+        );
+
+        if !liquidated_any {
             debug!("No accounts to liquidate");
         }
 
-        Ok(false)
+        if let Some(candidate_log) = &self.candidate_log {
+            candidate_log.log_cycle(
+                accounts
+                    .iter()
+                    .zip(actions)
+                    .enumerate()
+                    .map(|(rank, (opportunity, action))| CandidateLogEntry {
+                        rank,
+                        account: opportunity.account.to_string(),
+                        liquidatable_amount: opportunity.max_liquidatable_asset_amount.to_num(),
+                        profit: opportunity.estimated_profit.to_num(),
+                        action,
+                    })
+                    .collect(),
+            );
+        }
+
+        Ok(liquidated_any)
+    }
+
+    /// Log accounts that became liquidatable or stopped being liquidatable since the previous
+    /// scan, with the likely reason for the latter
+    fn log_candidate_transitions(&self, current_candidates: HashSet<Pubkey>) {
+        let mut previous_candidates = match self.previous_candidates.lock() {
+            Ok(previous_candidates) => previous_candidates,
+            Err(_) => {
+                warn!("Failed to lock previous candidates");
+                return;
+            }
+        };
+
+        let (newly_liquidatable, recovered) =
+            candidate_transitions(&previous_candidates, &current_candidates);
+
+        for address in newly_liquidatable.iter() {
+            debug!("Account {} became liquidatable", address);
+        }
+
+        for address in recovered.iter() {
+            let account = self.state_engine.marginfi_accounts.get(address);
+            let reason = recovery_reason(
+                account
+                    .as_ref()
+                    .map(|account| account.read().unwrap())
+                    .as_deref(),
+            );
+
+            info!("Account {} is no longer liquidatable: {}", address, reason);
+        }
+
+        if !newly_liquidatable.is_empty() || !recovered.is_empty() {
+            info!(
+                "Candidates: {} newly liquidatable, {} recovered, {} total",
+                newly_liquidatable.len(),
+                recovered.len(),
+                current_candidates.len()
+            );
+        }
+
+        *previous_candidates = current_candidates;
     }
 
+    /// Liquidate an account, returning the USD value of the assets liquidated, or `None` if
+    /// the liquidation was skipped
+    ///
+    /// `max_value` caps the USD value liquidated on top of `max_liquidation_value`.
     fn liquidate_account(
         &self,
         liquidate_account: Arc<RwLock<MarginfiAccountWrapper>>,
-    ) -> Result<(), ProcessorError> {
-        let (asset_bank_pk, liab_bank_pk, max_asset_liquidation_amount) = {
-            let account = liquidate_account
-                .read()
-                .map_err(|_| ProcessorError::FailedToReadAccount)?;
+        max_value: Option<I80F48>,
+        bank_snapshot: Option<&Arc<DashMap<Pubkey, Arc<RwLock<BankWrapper>>>>>,
+    ) -> Result<Option<I80F48>, ProcessorError> {
+        let banks = bank_snapshot
+            .cloned()
+            .unwrap_or_else(|| self.state_engine.banks.clone());
+
+        // The account evaluated against the same banks as the rest of the computation
+        let account_view = liquidate_account
+            .read()
+            .map_err(|_| ProcessorError::FailedToReadAccount)?
+            .with_banks(banks.clone());
 
-            let (assets_bank, liab_bank) = account.find_liquidaiton_bank_canididates()?;
+        let (asset_bank_pk, liab_bank_pk, max_asset_liquidation_amount) = {
+            let account = &account_view;
+
+            let (assets_bank, liab_bank) = match account
+                .find_liquidaiton_bank_canididates(|bank_pk| self.is_bank_excluded(bank_pk))?
+            {
+                Some(candidates) => candidates,
+                None => {
+                    info!(
+                        "No viable liquidation pair outside of the excluded banks for account {}, skipping",
+                        account.address
+                    );
+                    return Ok(None);
+                }
+            };
 
-            let (max_liquidation_amount, _) = account
+            let max_liquidation_amount = account
                 .compute_max_liquidatable_asset_amount_with_banks(
-                    self.state_engine.banks.clone(),
+                    banks.clone(),
                     &assets_bank,
                     &liab_bank,
-                )?;
+                )?
+                .map_or(I80F48::ZERO, |opportunity| {
+                    opportunity.max_liquidatable_asset_amount
+                });
 
             (assets_bank, liab_bank, max_liquidation_amount)
         };
 
-        // Max amount of liability the liquidator can cover
-        let max_liab_coverage_amount = self.get_max_borrow_for_bank(&liab_bank_pk)?;
+        if self.jupiter_circuit_breaker.is_open() && !self.holds_liability_mint(&liab_bank_pk)? {
+            info!(
+                "Jupiter is degraded and repaying {} requires a swap, skipping liquidation",
+                liab_bank_pk
+            );
+            return Ok(None);
+        }
 
-        let liab_bank_ref = self
-            .state_engine
-            .banks
+        // Pre-liquidation health and balance values of the chosen banks, for the audit record
+        let pre_liquidation_state = if self.config.verbose_liquidation_audit {
+            let account = &account_view;
+
+            Some((
+                account.calc_health(RequirementType::Maintenance),
+                balance_value_in_bank(
+                    account.get_deposits_values(RequirementType::Maintenance)?,
+                    &asset_bank_pk,
+                ),
+                balance_value_in_bank(
+                    account.get_liabilities_value(RequirementType::Maintenance)?,
+                    &liab_bank_pk,
+                ),
+            ))
+        } else {
+            None
+        };
+
+        let max_borrow_amount = self.get_max_borrow_for_bank(&liab_bank_pk)?;
+
+        let liab_bank_ref = banks
             .get(&liab_bank_pk)
             .ok_or(ProcessorError::Error("Failed to get bank"))?;
 
@@ -901,9 +3398,7 @@ impl EvaLiquidator {
             .read()
             .map_err(|_| ProcessorError::Error("Failed to get bank"))?;
 
-        let asset_bank_ref = self
-            .state_engine
-            .banks
+        let asset_bank_ref = banks
             .get(&asset_bank_pk)
             .ok_or(ProcessorError::Error("Failed to get bank"))?;
 
@@ -917,131 +3412,639 @@ impl EvaLiquidator {
         );
 
         // Max USD amount the liquidator can cover
-        let mut liquidator_capacity = liab_bank.calc_value(
+        let mut liquidator_capacity = I80F48::ZERO;
+
+        let LiquidationSizing {
             max_liab_coverage_amount,
-            BalanceSide::Liabilities,
-            RequirementType::Initial,
+            asset_amount_capacity: liquidation_asset_amount_capacity,
+            asset_amount: mut slippage_adjusted_asset_amount,
+        } = size_liquidation(
+            max_borrow_amount,
+            max_asset_liquidation_amount,
+            self.config.liability_slippage_buffer,
+            self.config.asset_slippage_buffer,
+            |max_liab_coverage_amount| {
+                liquidator_capacity = liab_bank.calc_value(
+                    max_liab_coverage_amount,
+                    BalanceSide::Liabilities,
+                    RequirementType::Initial,
+                )?;
+
+                if let Some(max_liquidation_value) = self.config.max_liquidation_value {
+                    liquidator_capacity =
+                        min(liquidator_capacity, I80F48::from_num(max_liquidation_value));
+                }
+
+                if let Some(max_value) = max_value {
+                    liquidator_capacity = min(liquidator_capacity, max_value);
+                }
+
+                debug!("Liquidator capacity: ${}", liquidator_capacity);
+
+                asset_bank.calc_amount(
+                    liquidator_capacity,
+                    BalanceSide::Assets,
+                    RequirementType::Initial,
+                )
+            },
         )?;
 
-        if let Some(max_liquidation_value) = self.config.max_liquidation_value {
-            liquidator_capacity = min(liquidator_capacity, I80F48::from_num(max_liquidation_value));
-        }
+        if self.config.check_liability_coverage {
+            let liab_token_balance = self
+                .get_token_balance_for_bank(&liab_bank_pk)?
+                .unwrap_or_default();
+
+            slippage_adjusted_asset_amount = Self::cap_asset_amount_to_liab_coverage(
+                &asset_bank,
+                &liab_bank,
+                slippage_adjusted_asset_amount,
+                max_liab_coverage_amount,
+                liab_token_balance,
+            )?;
 
-        debug!("Liquidator capacity: ${}", liquidator_capacity);
+            if slippage_adjusted_asset_amount.is_zero() {
+                warn!(
+                    "Liquidator can't cover any {} liability, skipping liquidation",
+                    liab_bank.bank.mint
+                );
+                return Ok(None);
+            }
+        }
 
-        let liquidation_asset_amount_capacity = asset_bank.calc_amount(
-            liquidator_capacity,
+        let liquidated_value = asset_bank.calc_value(
+            slippage_adjusted_asset_amount,
             BalanceSide::Assets,
             RequirementType::Initial,
         )?;
 
-        let asset_amount_to_liquidate = min(
-            max_asset_liquidation_amount,
-            liquidation_asset_amount_capacity,
-        );
-
-        let slippage_adjusted_asset_amount = asset_amount_to_liquidate * I80F48!(0.98);
+        if liquidated_value < self.config.min_liquidation_value {
+            info!(
+                "Skipping liquidation: ${} of {} is below the minimum liquidation value ${} (max liquidatable: {}, liquidator capacity: ${})",
+                liquidated_value,
+                asset_bank.bank.mint,
+                self.config.min_liquidation_value,
+                max_asset_liquidation_amount,
+                liquidator_capacity
+            );
+            return Ok(None);
+        }
 
         info!(
             "Liquidating {} of {} for {}",
             slippage_adjusted_asset_amount, asset_bank.bank.mint, liab_bank.bank.mint
         );
 
+        let audit_banks = pre_liquidation_state.map(
+            |((assets_value, liabs_value), asset_balance_value, liab_balance_value)| {
+                (
+                    (assets_value, liabs_value),
+                    LiquidationAuditBank::new(
+                        &asset_bank,
+                        BalanceSide::Assets,
+                        asset_balance_value,
+                    ),
+                    LiquidationAuditBank::new(
+                        &liab_bank,
+                        BalanceSide::Liabilities,
+                        liab_balance_value,
+                    ),
+                )
+            },
+        );
+
+        let asset_mint = asset_bank.bank.mint;
+
         drop(liab_bank);
         drop(liab_bank_ref);
         drop(asset_bank);
         drop(asset_bank_ref);
 
-        self.liquidator_account.liquidate(
-            liquidate_account,
-            asset_bank_pk,
-            liab_bank_pk,
-            slippage_adjusted_asset_amount.to_num(),
-            self.config.get_tx_config(),
-        )?;
+        let expected_profit = liquidated_value * I80F48::from_num(LIQUIDATOR_FEE);
 
-        Ok(())
-    }
+        let mut tx_config = self.config.get_tx_config();
+        tx_config.compute_unit_price_micro_lamports = self.get_liquidation_compute_unit_price(
+            expected_profit,
+            &[account_view.address, asset_bank_pk, liab_bank_pk],
+        )?;
 
-    fn process_account(
-        &self,
-        account: &Arc<RwLock<MarginfiAccountWrapper>>,
-    ) -> Result<(), ProcessorError> {
-        let account = account
-            .read()
-            .map_err(|_| ProcessorError::FailedToReadAccount)?;
+        let priority_fee_value = self.get_priority_fee_value(
+            tx_config.compute_unit_limit,
+            tx_config.compute_unit_price_micro_lamports,
+        )?;
 
-        if !account.has_liabs() {
-            return Ok(());
+        if let (Some(max_fee_fraction), Some(priority_fee_value)) = (
+            self.config.max_priority_fee_fraction_of_profit,
+            priority_fee_value,
+        ) {
+            if priority_fee_value > expected_profit * I80F48::from_num(max_fee_fraction) {
+                info!(
+                    "Deferring liquidation: priority fee ${} exceeds {} of the expected profit ${}",
+                    priority_fee_value, max_fee_fraction, expected_profit
+                );
+                return Ok(None);
+            }
         }
 
-        let (assets, liabs) = account.calc_health(RequirementType::Maintenance);
+        // Seized collateral other than the swap mint is sold, losing up to its slippage
+        let slippage_cost = if asset_mint == self.config.swap_mint {
+            I80F48::ZERO
+        } else {
+            liquidated_value * I80F48::from_num(self.config.get_slippage_bps(&asset_mint))
+                / I80F48::from_num(10_000)
+        };
 
-        if liabs > assets {
+        let net_profit = expected_profit - priority_fee_value.unwrap_or_default() - slippage_cost;
+
+        if net_profit < self.config.min_profit {
             info!(
-                "Account {} can be liquidated health: {}, {} < {}",
-                account.address,
-                assets - liabs,
-                assets,
-                liabs
+                "Skipping liquidation: profit ${} net of the priority fee and ${} of swap slippage is below the minimum profit ${}",
+                net_profit, slippage_cost, self.config.min_profit
             );
+            return Ok(None);
         }
 
-        Ok(())
-    }
-
-    pub fn get_free_collateral(&self) -> Result<I80F48, ProcessorError> {
-        let account = self.get_liquidator_account()?;
-        let (assets, liabs) = account.calc_health(RequirementType::Initial);
+        if self.config.recheck_before_submit {
+            let account = liquidate_account
+                .read()
+                .map_err(|_| ProcessorError::FailedToReadAccount)?;
 
-        if assets > liabs {
-            Ok(assets - liabs)
-        } else {
-            Ok(I80F48!(0))
+            if !is_still_liquidatable(&account) {
+                return Ok(None);
+            }
         }
-    }
 
-    pub fn get_max_withdraw_for_bank(
-        &self,
-        bank_pk: &Pubkey,
-    ) -> Result<(I80F48, bool), ProcessorError> {
-        let free_collateral = self.get_free_collateral()?;
-        let balance = self
-            .get_liquidator_account()?
-            .get_balance_for_bank(bank_pk)?;
+        let liquidatee_address = liquidate_account
+            .read()
+            .map_err(|_| ProcessorError::FailedToReadAccount)?
+            .address;
 
-        debug!("Free collateral: {}", free_collateral);
+        let compute_unit_price_micro_lamports = tx_config.compute_unit_price_micro_lamports;
+        let submitted_asset_amount: u64 = slippage_adjusted_asset_amount.to_num();
 
-        Ok(match balance {
-            Some((balance, BalanceSide::Assets)) => {
-                let value = self.get_value(
-                    balance,
-                    &bank_pk,
-                    RequirementType::Initial,
-                    BalanceSide::Assets,
-                )?;
-                let max_withdraw = value.min(free_collateral);
+        let verify_balance_delta = self.should_verify_balance_deltas();
 
-                trace!("Balance {}", balance);
+        let pre_liquidation_asset_balance = if verify_balance_delta {
+            self.state_engine
+                .load_liquidator_account(self.config.liquidator_account)?;
+            self.get_liquidator_net_balance(&asset_bank_pk)?
+        } else {
+            I80F48::ZERO
+        };
 
-                trace!(
-                    "Max withdraw for bank {}: {} (balance_value: {} free_collateral: {})",
-                    bank_pk,
-                    max_withdraw,
-                    value,
-                    free_collateral
-                );
+        let account_version = liquidate_account
+            .read()
+            .map_err(|_| ProcessorError::FailedToReadAccount)?
+            .version;
 
-                (
-                    self.get_amount(max_withdraw, bank_pk, Some(PriceBias::Low))?,
-                    value <= free_collateral,
-                )
-            }
-            _ => (I80F48!(0), false),
-        })
+        let liquidation_result = self.liquidator_account.liquidate(
+            liquidate_account,
+            asset_bank_pk,
+            liab_bank_pk,
+            submitted_asset_amount,
+            tx_config,
+        );
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis())
+            .unwrap_or_default();
+
+        // A failed send may still land, the cooldown covers both outcomes
+        if !self.config.dry_run {
+            self.recently_liquidated
+                .insert(liquidatee_address, (Instant::now(), account_version));
+        }
+
+        let signature = match liquidation_result {
+            Ok(signature) => {
+                if !self.config.dry_run {
+                    self.publish_liquidation_event(LiquidationEvent::Sent {
+                        account: liquidatee_address,
+                        asset_bank: asset_bank_pk,
+                        liab_bank: liab_bank_pk,
+                        asset_amount: submitted_asset_amount,
+                        estimated_profit: expected_profit,
+                        signature,
+                        timestamp,
+                    });
+                }
+
+                signature
+            }
+            Err(e) => {
+                if let MarginfiAccountError::ConfirmationTimeout(signature) = &e {
+                    warn!(
+                        "Liquidation {} of account {} not confirmed in time, reloading the liquidator account",
+                        signature, liquidatee_address
+                    );
+
+                    if let Err(e) = self
+                        .state_engine
+                        .load_liquidator_account(self.config.liquidator_account)
+                    {
+                        error!("Failed to reload the liquidator account: {:?}", e);
+                    }
+                }
+
+                self.publish_liquidation_event(LiquidationEvent::Failed {
+                    account: liquidatee_address,
+                    asset_bank: asset_bank_pk,
+                    liab_bank: liab_bank_pk,
+                    asset_amount: submitted_asset_amount,
+                    estimated_profit: expected_profit,
+                    error: e.to_string(),
+                    timestamp,
+                });
+
+                return Err(e.into());
+            }
+        };
+
+        if self.config.dry_run {
+            info!(
+                "Dry run, would have liquidated account {} with transaction {}",
+                liquidatee_address, signature
+            );
+        } else {
+            info!(
+                "Liquidated account {}: {}",
+                liquidatee_address,
+                self.config.explorer_cluster.tx_url(&signature)
+            );
+        }
+
+        if verify_balance_delta {
+            self.state_engine
+                .load_liquidator_account(self.config.liquidator_account)?;
+
+            self.verify_balance_delta(
+                &asset_bank_pk,
+                pre_liquidation_asset_balance,
+                self.get_liquidator_net_balance(&asset_bank_pk)?,
+                I80F48::from_num(submitted_asset_amount),
+            )?;
+        }
+
+        if let Some(((assets_value, liabs_value), asset_bank, liab_bank)) = audit_banks {
+            let audit = LiquidationAudit {
+                liquidatee: liquidatee_address.to_string(),
+                signature: signature.to_string(),
+                assets_value_maint: assets_value.to_num(),
+                liabs_value_maint: liabs_value.to_num(),
+                asset_bank,
+                liab_bank,
+                max_liquidatable_asset_amount: max_asset_liquidation_amount.to_num(),
+                max_liab_coverage_amount: max_liab_coverage_amount.to_num(),
+                liquidator_capacity_value: liquidator_capacity.to_num(),
+                liquidator_capacity_asset_amount: liquidation_asset_amount_capacity.to_num(),
+                asset_slippage_buffer: self.config.asset_slippage_buffer.to_num(),
+                liability_slippage_buffer: self.config.liability_slippage_buffer.to_num(),
+                submitted_asset_amount,
+                liquidated_value: liquidated_value.to_num(),
+                expected_profit: expected_profit.to_num(),
+                compute_unit_price_micro_lamports,
+            };
+
+            match serde_json::to_string(&audit) {
+                Ok(audit) => info!("Liquidation audit: {}", audit),
+                Err(e) => warn!("Failed to serialize liquidation audit: {:?}", e),
+            }
+        }
+
+        Ok(Some(liquidated_value))
+    }
+
+    fn should_verify_balance_deltas(&self) -> bool {
+        self.config.verify_balance_deltas && !self.config.dry_run
+    }
+
+    /// Liquidator's balance in a bank, negative for a liability
+    fn get_liquidator_net_balance(&self, bank_pk: &Pubkey) -> Result<I80F48, ProcessorError> {
+        Ok(
+            match self
+                .get_liquidator_account()?
+                .get_balance_for_bank(bank_pk)?
+            {
+                Some((amount, BalanceSide::Assets)) => amount,
+                Some((amount, BalanceSide::Liabilities)) => -amount,
+                None => I80F48::ZERO,
+            },
+        )
+    }
+
+    /// Check that a balance in `bank_pk` grew by `expected_delta`, less
+    /// `balance_delta_tolerance_bps`
+    fn verify_balance_delta(
+        &self,
+        bank_pk: &Pubkey,
+        pre_balance: I80F48,
+        post_balance: I80F48,
+        expected_delta: I80F48,
+    ) -> Result<(), ProcessorError> {
+        let delta = post_balance - pre_balance;
+        let min_delta = min_balance_delta(expected_delta, self.config.balance_delta_tolerance_bps);
+
+        if delta < min_delta {
+            error!(
+                "Balance in {} changed by {} ({} -> {}), expected at least {}",
+                bank_pk, delta, pre_balance, post_balance, min_delta
+            );
+            return Err(ProcessorError::UnexpectedBalanceDelta);
+        }
+
+        debug!(
+            "Balance in {} changed by {}, expected at least {}",
+            bank_pk, delta, min_delta
+        );
+
+        Ok(())
+    }
+
+    /// USD value of the priority fee paid for a transaction requesting `compute_units`,
+    /// or `None` if there is no SOL bank to price it with
+    fn get_priority_fee_value(
+        &self,
+        compute_units: u32,
+        compute_unit_price: Option<u64>,
+    ) -> Result<Option<I80F48>, ProcessorError> {
+        let compute_unit_price = match compute_unit_price {
+            Some(price) => price,
+            None => return Ok(Some(I80F48::ZERO)),
+        };
+
+        let sol_bank_ref = match self
+            .state_engine
+            .get_bank_for_mint(&spl_token::native_mint::id())
+        {
+            Some(bank) => bank,
+            None => {
+                warn!("No SOL bank found to price the priority fee");
+                return Ok(None);
+            }
+        };
+
+        let priority_fee_lamports = I80F48::from_num(compute_unit_price)
+            * I80F48::from_num(compute_units)
+            / I80F48::from_num(1_000_000);
+
+        let priority_fee_value = sol_bank_ref
+            .read()
+            .map_err(|_| ProcessorError::Error("Failed to get bank"))?
+            .calc_value(
+                priority_fee_lamports,
+                BalanceSide::Assets,
+                RequirementType::Equity,
+            )?;
+
+        Ok(Some(priority_fee_value))
+    }
+
+    /// Compute unit price of a liquidation expected to make `expected_profit` USD
+    ///
+    /// With `priority_fee_profit_fraction` set, the priority fee is that fraction of the
+    /// expected profit, clamped to the configured min and max compute unit prices. Otherwise,
+    /// or without a SOL bank to price the fee, it's the dynamic or static compute unit price
+    /// for `fee_accounts`.
+    fn get_liquidation_compute_unit_price(
+        &self,
+        expected_profit: I80F48,
+        fee_accounts: &[Pubkey],
+    ) -> Result<Option<u64>, ProcessorError> {
+        let profit_fraction = match self.config.priority_fee_profit_fraction {
+            Some(profit_fraction) => profit_fraction,
+            None => return Ok(self.get_compute_unit_price(fee_accounts)),
+        };
+
+        let sol_bank_ref = match self
+            .state_engine
+            .get_bank_for_mint(&spl_token::native_mint::id())
+        {
+            Some(bank) => bank,
+            None => {
+                warn!("No SOL bank found to price the priority fee");
+                return Ok(self.get_compute_unit_price(fee_accounts));
+            }
+        };
+
+        let priority_fee_lamports = sol_bank_ref
+            .read()
+            .map_err(|_| ProcessorError::Error("Failed to get bank"))?
+            .calc_amount(
+                expected_profit * I80F48::from_num(profit_fraction),
+                BalanceSide::Assets,
+                RequirementType::Equity,
+            )?;
+
+        let compute_unit_price = self
+            .config
+            .clamp_compute_unit_price(compute_unit_price_for_fee(
+                priority_fee_lamports,
+                self.config.compute_unit_limit,
+            ));
+
+        debug!(
+            "Compute unit price for an expected profit of ${}: {}",
+            expected_profit, compute_unit_price
+        );
+
+        Ok(Some(compute_unit_price))
+    }
+
+    /// Compute unit price of a transaction writing to `fee_accounts`, estimated from recent
+    /// prioritization fees when `dynamic_priority_fee_percentile` is set, otherwise, or when
+    /// the estimate fails, `compute_unit_price_micro_lamports`
+    fn get_compute_unit_price(&self, fee_accounts: &[Pubkey]) -> Option<u64> {
+        self.get_dynamic_compute_unit_price(fee_accounts)
+            .or(self.config.compute_unit_price_micro_lamports)
+    }
+
+    fn get_dynamic_compute_unit_price(&self, fee_accounts: &[Pubkey]) -> Option<u64> {
+        let percentile = self.config.dynamic_priority_fee_percentile?;
+
+        let mut fees = match self
+            .state_engine
+            .rpc_pool
+            .client()
+            .get_recent_prioritization_fees(fee_accounts)
+        {
+            Ok(fees) => fees
+                .into_iter()
+                .map(|fee| fee.prioritization_fee)
+                .collect::<Vec<_>>(),
+            Err(e) => {
+                warn!(
+                    "Failed to fetch recent prioritization fees, using the static compute unit price: {:?}",
+                    e
+                );
+                return None;
+            }
+        };
+
+        if fees.is_empty() {
+            return None;
+        }
+
+        fees.sort_unstable();
+
+        let index = (fees.len() - 1) * usize::from(percentile.min(100)) / 100;
+        let compute_unit_price = self.config.clamp_compute_unit_price(fees[index]);
+
+        debug!(
+            "Compute unit price at the {}th percentile of {} recent fees: {}",
+            percentile,
+            fees.len(),
+            compute_unit_price
+        );
+
+        Some(compute_unit_price)
+    }
+
+    /// Size an asset amount down to what the liquidator can cover on the liability side
+    ///
+    /// The liability the liquidator takes on is conservatively valued at the full value of the
+    /// seized assets. It's covered by what the liquidator can borrow, `max_liab_coverage_amount`,
+    /// on top of the liability tokens it already holds, `liab_token_balance`. Returns
+    /// `asset_amount` when fully covered, a proportionally smaller amount when partially covered
+    /// and zero when nothing can be covered.
+    fn cap_asset_amount_to_liab_coverage(
+        asset_bank: &BankWrapper,
+        liab_bank: &BankWrapper,
+        asset_amount: I80F48,
+        max_liab_coverage_amount: I80F48,
+        liab_token_balance: I80F48,
+    ) -> Result<I80F48, ProcessorError> {
+        let liab_coverage_amount =
+            max_liab_coverage_amount.max(I80F48::ZERO) + liab_token_balance.max(I80F48::ZERO);
+
+        if !liab_coverage_amount.is_positive() {
+            return Ok(I80F48::ZERO);
+        }
+
+        let required_liab_value = asset_bank.calc_value(
+            asset_amount,
+            BalanceSide::Assets,
+            RequirementType::Maintenance,
+        )?;
+
+        let required_liab_amount = liab_bank.calc_amount(
+            required_liab_value,
+            BalanceSide::Liabilities,
+            RequirementType::Initial,
+        )?;
+
+        debug!(
+            "Required liability amount: {}, liability coverage: {} ({} borrowable, {} held)",
+            required_liab_amount,
+            liab_coverage_amount,
+            max_liab_coverage_amount,
+            liab_token_balance
+        );
+
+        let capped_asset_amount = scale_asset_amount_to_coverage(
+            asset_amount,
+            required_liab_amount,
+            liab_coverage_amount,
+        );
+
+        if capped_asset_amount < asset_amount {
+            info!(
+                "Liability coverage {} is below required {}, sizing asset amount down from {} to {}",
+                liab_coverage_amount, required_liab_amount, asset_amount, capped_asset_amount
+            );
+        }
+
+        Ok(capped_asset_amount)
+    }
+
+    fn process_account(
+        &self,
+        account: &Arc<RwLock<MarginfiAccountWrapper>>,
+    ) -> Result<(), ProcessorError> {
+        let account = account
+            .read()
+            .map_err(|_| ProcessorError::FailedToReadAccount)?;
+
+        if !account.has_liabs() {
+            return Ok(());
+        }
+
+        let (assets, liabs) = account.calc_health(RequirementType::Maintenance);
+
+        if liabs > assets {
+            info!(
+                "Account {} can be liquidated health: {}, {} < {}",
+                account.address,
+                assets - liabs,
+                assets,
+                liabs
+            );
+        }
+
+        Ok(())
+    }
+
+    pub fn get_free_collateral(&self) -> Result<I80F48, ProcessorError> {
+        let account = self.get_liquidator_account()?;
+        let (assets, liabs) = account.calc_health(RequirementType::Initial);
+
+        if assets > liabs {
+            Ok(assets - liabs)
+        } else {
+            Ok(I80F48!(0))
+        }
+    }
+
+    pub fn get_max_withdraw_for_bank(
+        &self,
+        bank_pk: &Pubkey,
+    ) -> Result<(I80F48, bool), ProcessorError> {
+        let free_collateral = self.get_free_collateral()?;
+        let balance = self
+            .get_liquidator_account()?
+            .get_balance_for_bank(bank_pk)?;
+
+        debug!("Free collateral: {}", free_collateral);
+
+        Ok(match balance {
+            Some((balance, BalanceSide::Assets)) => {
+                let value = self.get_value(
+                    balance,
+                    &bank_pk,
+                    RequirementType::Initial,
+                    BalanceSide::Assets,
+                )?;
+                let max_withdraw = value.min(free_collateral);
+
+                trace!("Balance {}", balance);
+
+                trace!(
+                    "Max withdraw for bank {}: {} (balance_value: {} free_collateral: {})",
+                    bank_pk,
+                    max_withdraw,
+                    value,
+                    free_collateral
+                );
+
+                (
+                    self.get_amount(max_withdraw, bank_pk, Some(PriceBias::Low))?,
+                    value <= free_collateral,
+                )
+            }
+            _ => (I80F48!(0), false),
+        })
     }
 
     pub fn get_max_borrow_for_bank(&self, bank_pk: &Pubkey) -> Result<I80F48, ProcessorError> {
+        if let Some(max_borrow) =
+            self.get_cached_capacity(|capacity| capacity.max_borrows.get(bank_pk).copied())
+        {
+            return Ok(max_borrow);
+        }
+
+        self.compute_max_borrow_for_bank(bank_pk)
+    }
+
+    fn compute_max_borrow_for_bank(&self, bank_pk: &Pubkey) -> Result<I80F48, ProcessorError> {
         let free_collateral = self.get_free_collateral()?;
 
         let bank_ref = self
@@ -1067,9 +4070,6 @@ impl EvaLiquidator {
             bank.calc_value(asset_amount, BalanceSide::Assets, RequirementType::Initial)?,
         );
 
-        let asset_weight: I80F48 = bank.bank.config.asset_weight_init.into();
-        let liab_weight: I80F48 = bank.bank.config.liability_weight_init.into();
-
         let lower_price = bank
             .oracle_adapter
             .price_adapter
@@ -1082,104 +4082,746 @@ impl EvaLiquidator {
             .get_price_of_type(OraclePriceType::TimeWeighted, Some(PriceBias::High))
             .map_err(|_| ProcessorError::Error("Failed to get price"))?;
 
-        let token_decimals = bank.bank.mint_decimals as usize;
-
-        let max_borrow_amount = if asset_weight == I80F48::ZERO {
-            let max_additional_borrow_ui =
-                (free_collateral - untied_collateral_for_bank) / (higher_price * liab_weight);
-
-            let max_additional = max_additional_borrow_ui * EXP_10_I80F48[token_decimals];
-
-            max_additional + asset_amount
-        } else {
-            let ui_amount = untied_collateral_for_bank / (lower_price * asset_weight)
-                + (free_collateral - untied_collateral_for_bank) / (higher_price * liab_weight);
-
-            ui_amount * EXP_10_I80F48[token_decimals]
-        };
+        let max_borrow_amount = checked_max_borrow_amount(
+            &bank.bank,
+            free_collateral,
+            untied_collateral_for_bank,
+            asset_amount,
+            lower_price,
+            higher_price,
+        )
+        .ok_or(ProcessorError::MathError("max borrow"))?;
 
         debug!("Max borrow for bank {}: {}", bank_pk, max_borrow_amount);
 
         Ok(max_borrow_amount)
     }
 
+    /// Swap `amount` of the `src_bank` token for the `dst_bank` token, returning the minimum
+    /// amount of the `dst_bank` token the executed quote guaranteed
+    ///
+    /// See [`Self::swap_amount`] for how slippage is handled.
     async fn swap(
         &self,
         amount: u64,
         src_bank: &Pubkey,
         dst_bank: &Pubkey,
-    ) -> Result<(), ProcessorError> {
-        let src_mint = {
-            let bank_ref = self
-                .state_engine
-                .banks
-                .get(&src_bank)
-                .ok_or(ProcessorError::Error("Failed to get bank"))?;
+        urgency: SwapUrgency,
+    ) -> Result<u64, ProcessorError> {
+        self.swap_amount(SwapAmount::ExactIn(amount), src_bank, dst_bank, urgency)
+            .await
+    }
 
-            let bank_w = bank_ref
-                .read()
-                .map_err(|_| ProcessorError::Error("Failed to get bank"))?;
+    /// Swap at most `max_in_amount` of the `src_bank` token for exactly `out_amount` of the
+    /// `dst_bank` token, returning `out_amount`
+    ///
+    /// Fails with [`ProcessorError::ExactOutUnavailable`] without sending anything when no
+    /// route delivers `out_amount` within `max_in_amount`, slippage included.
+    async fn swap_exact_out(
+        &self,
+        out_amount: u64,
+        max_in_amount: u64,
+        src_bank: &Pubkey,
+        dst_bank: &Pubkey,
+        urgency: SwapUrgency,
+    ) -> Result<u64, ProcessorError> {
+        self.swap_amount(
+            SwapAmount::ExactOut {
+                out_amount,
+                max_in_amount,
+            },
+            src_bank,
+            dst_bank,
+            urgency,
+        )
+        .await
+    }
+
+    /// Swap the `src_bank` token for the `dst_bank` token, returning the minimum amount of the
+    /// `dst_bank` token the executed quote guaranteed
+    ///
+    /// Slippage starts at the source mint's entry in `slippage_bps_overrides`, falling back to
+    /// `slippage_bps`, or past the last failed slippage of the mint pair if it recently failed.
+    /// Swaps failing on slippage are re-quoted wider by `slippage_escalation_step`, up to
+    /// `max_slippage_bps` or the starting slippage if higher, and at most `max_swap_attempts`
+    /// times.
+    async fn swap_amount(
+        &self,
+        amount: SwapAmount,
+        src_bank: &Pubkey,
+        dst_bank: &Pubkey,
+        urgency: SwapUrgency,
+    ) -> Result<u64, ProcessorError> {
+        let src_mint = self.get_mint_for_bank(src_bank)?;
+        let dst_mint = self.get_mint_for_bank(dst_bank)?;
+
+        let max_slippage_bps = self
+            .config
+            .max_slippage_bps
+            .max(self.config.get_slippage_bps(&src_mint));
+        let mut slippage_bps = self.get_initial_slippage_bps(&src_mint, &dst_mint);
+        let mut attempt = 1;
+
+        let start = Instant::now();
+
+        let result = loop {
+            match self
+                .try_swap(amount, src_bank, dst_bank, slippage_bps, urgency)
+                .await
+            {
+                Err(ProcessorError::SlippageExceeded) => {
+                    self.recent_slippage_failures
+                        .insert((src_mint, dst_mint), (slippage_bps, Instant::now()));
+
+                    if slippage_bps >= max_slippage_bps {
+                        error!(
+                            "Swap from {} to {} exceeded the maximum slippage of {} bps, giving up",
+                            src_mint, dst_mint, max_slippage_bps
+                        );
+                        break Err(ProcessorError::SlippageExceeded);
+                    }
+
+                    if attempt >= self.config.max_swap_attempts {
+                        error!(
+                            "Swap from {} to {} exceeded slippage after {} attempts, giving up at {} bps",
+                            src_mint, dst_mint, attempt, slippage_bps
+                        );
+                        break Err(ProcessorError::SlippageExceeded);
+                    }
 
-            bank_w.bank.mint
+                    attempt += 1;
+                    slippage_bps = escalate_slippage_bps(
+                        slippage_bps,
+                        self.config.slippage_escalation_step,
+                        max_slippage_bps,
+                    );
+
+                    warn!(
+                        "Swap from {} to {} exceeded slippage, retrying with {} bps (attempt {}/{})",
+                        src_mint, dst_mint, slippage_bps, attempt, self.config.max_swap_attempts
+                    );
+                }
+                result => break result,
+            }
         };
 
-        let dst_mint = {
-            let bank_ref = self
-                .state_engine
-                .banks
-                .get(&dst_bank)
-                .ok_or(ProcessorError::Error("Failed to get bank"))?;
+        let src_mint_label = src_mint.to_string();
+
+        metrics()
+            .swap_duration_seconds
+            .with_label_values(&[&src_mint_label])
+            .observe(start.elapsed().as_secs_f64());
+        metrics()
+            .swaps
+            .with_label_values(&[
+                &src_mint_label,
+                &dst_mint.to_string(),
+                if result.is_ok() {
+                    "succeeded"
+                } else {
+                    "failed"
+                },
+            ])
+            .inc();
 
-            let bank_w = bank_ref
-                .read()
-                .map_err(|_| ProcessorError::Error("Failed to get bank"))?;
+        result
+    }
 
-            bank_w.bank.mint
+    /// Sell `amount` of the `src_bank` token for the swap mint, or for the first of
+    /// `fallback_swap_mints` that works when that fails
+    ///
+    /// Fails with the error of the swap mint sale when all fallbacks fail too.
+    async fn sell_to_swap_mint(
+        &self,
+        amount: u64,
+        src_bank: &Pubkey,
+    ) -> Result<(), ProcessorError> {
+        let error = match self.sell_to_primary_swap_mint(amount, src_bank).await {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
         };
 
-        info!("Swapping {} from {} to {}", amount, src_mint, dst_mint);
+        for mint in self.config.fallback_swap_mints.iter() {
+            let fallback_bank = match self.state_engine.get_bank_for_mint(mint) {
+                Some(bank) => {
+                    bank.read()
+                        .map_err(|_| ProcessorError::Error("Failed to get bank"))?
+                        .address
+                }
+                None => continue,
+            };
 
-        let jup_swap_client = JupiterSwapApiClient::new(self.config.jup_swap_api_url.clone());
+            if fallback_bank == *src_bank || fallback_bank == self.swap_mint_bank_pk {
+                continue;
+            }
 
-        debug!("Requesting quote for swap");
-        let quote_response = jup_swap_client
-            .quote(&QuoteRequest {
-                input_mint: src_mint,
-                output_mint: dst_mint,
-                amount,
-                slippage_bps: self.config.slippage_bps,
-                ..Default::default()
-            })
-            .await
-            .map_err(|e| {
+            warn!(
+                "Selling {} of {} to the swap mint failed ({:?}), trying fallback mint {}",
+                amount, src_bank, error, mint
+            );
+
+            match self
+                .swap(amount, src_bank, &fallback_bank, SwapUrgency::Rebalance)
+                .await
+            {
+                Ok(_) => {
+                    info!(
+                        "Sold {} of {} for fallback mint {}, it's sold to the swap mint on a later rebalance",
+                        amount, src_bank, mint
+                    );
+                    return Ok(());
+                }
+                Err(e) => warn!("Selling to fallback mint {} failed: {:?}", mint, e),
+            }
+        }
+
+        Err(error)
+    }
+
+    /// Sell `amount` of the `src_bank` token for the swap mint, through an intermediate mint
+    /// when the direct route is poor, see `intermediate_swap_mints`
+    async fn sell_to_primary_swap_mint(
+        &self,
+        amount: u64,
+        src_bank: &Pubkey,
+    ) -> Result<(), ProcessorError> {
+        let intermediate_bank = match self.get_intermediate_swap_bank(amount, src_bank).await {
+            Ok(intermediate_bank) => intermediate_bank,
+            Err(e) => {
+                warn!("Failed to evaluate intermediate swap routes: {:?}", e);
+                None
+            }
+        };
+
+        let intermediate_bank = match intermediate_bank {
+            Some(intermediate_bank) => intermediate_bank,
+            None => {
+                self.swap(
+                    amount,
+                    src_bank,
+                    &self.swap_mint_bank_pk,
+                    SwapUrgency::Rebalance,
+                )
+                .await?;
+
+                return Ok(());
+            }
+        };
+
+        // Only what the first hop yields is sold, any balance of the intermediate token held
+        // before is left alone
+        self.state_engine
+            .refresh_token_account(&intermediate_bank)
+            .await?;
+
+        let balance_before = self
+            .get_token_balance_for_bank(&intermediate_bank)?
+            .unwrap_or_default();
+
+        self.swap(amount, src_bank, &intermediate_bank, SwapUrgency::Rebalance)
+            .await?;
+
+        self.state_engine
+            .refresh_token_account(&intermediate_bank)
+            .await?;
+
+        let balance_after = self
+            .get_token_balance_for_bank(&intermediate_bank)?
+            .unwrap_or_default();
+
+        let intermediate_amount = first_hop_received_amount(balance_before, balance_after);
+
+        if intermediate_amount.is_zero() {
+            warn!(
+                "No {} received from the first hop, nothing left to swap",
+                intermediate_bank
+            );
+            return Ok(());
+        }
+
+        self.swap(
+            intermediate_amount.to_num(),
+            &intermediate_bank,
+            &self.swap_mint_bank_pk,
+            SwapUrgency::Rebalance,
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    /// Bank of the intermediate mint to route a sell of `amount` of the `src_bank` token
+    /// through, `None` to swap directly to the swap mint
+    async fn get_intermediate_swap_bank(
+        &self,
+        amount: u64,
+        src_bank: &Pubkey,
+    ) -> Result<Option<Pubkey>, ProcessorError> {
+        if self.config.intermediate_swap_mints.is_empty() {
+            return Ok(None);
+        }
+
+        let direct_out_amount = self
+            .get_quote_out_amount(amount, src_bank, &self.swap_mint_bank_pk)
+            .await?;
+
+        let in_value = self.get_value(
+            I80F48::from_num(amount),
+            src_bank,
+            RequirementType::Equity,
+            BalanceSide::Assets,
+        )?;
+        let direct_out_value = self.get_value(
+            I80F48::from_num(direct_out_amount),
+            &self.swap_mint_bank_pk,
+            RequirementType::Equity,
+            BalanceSide::Assets,
+        )?;
+
+        let price_impact_bps = match price_impact_bps(in_value, direct_out_value) {
+            Some(price_impact_bps)
+                if price_impact_bps
+                    > I80F48::from_num(self.config.max_direct_swap_price_impact_bps) =>
+            {
+                price_impact_bps
+            }
+            _ => return Ok(None),
+        };
+
+        debug!(
+            "Direct swap of {} from {} has a price impact of {} bps, quoting intermediate routes",
+            amount, src_bank, price_impact_bps
+        );
+
+        let mut routes = vec![];
+
+        for mint in self.config.intermediate_swap_mints.iter() {
+            let intermediate_bank = match self.state_engine.get_bank_for_mint(mint) {
+                Some(bank) => {
+                    bank.read()
+                        .map_err(|_| ProcessorError::Error("Failed to get bank"))?
+                        .address
+                }
+                None => {
+                    debug!("No bank found for intermediate swap mint {}", mint);
+                    continue;
+                }
+            };
+
+            if intermediate_bank == *src_bank || intermediate_bank == self.swap_mint_bank_pk {
+                continue;
+            }
+
+            let route_out_amount = async {
+                let intermediate_amount = self
+                    .get_quote_out_amount(amount, src_bank, &intermediate_bank)
+                    .await?;
+                self.get_quote_out_amount(
+                    intermediate_amount,
+                    &intermediate_bank,
+                    &self.swap_mint_bank_pk,
+                )
+                .await
+            }
+            .await;
+
+            match route_out_amount {
+                Ok(out_amount) => {
+                    debug!("Route through {} yields {}", mint, out_amount);
+                    routes.push((intermediate_bank, out_amount));
+                }
+                Err(e) => warn!("Failed to quote route through {}: {:?}", mint, e),
+            }
+        }
+
+        Ok(best_intermediate_route(routes, direct_out_amount).map(
+            |(intermediate_bank, out_amount)| {
+                info!(
+                    "Routing swap of {} from {} through {}: {} instead of {} directly",
+                    amount, src_bank, intermediate_bank, out_amount, direct_out_amount
+                );
+                intermediate_bank
+            },
+        ))
+    }
+
+    /// Amount of the `dst_bank` token Jupiter quotes for `amount` of the `src_bank` token
+    async fn get_quote_out_amount(
+        &self,
+        amount: u64,
+        src_bank: &Pubkey,
+        dst_bank: &Pubkey,
+    ) -> Result<u64, ProcessorError> {
+        let src_mint = self.get_mint_for_bank(src_bank)?;
+
+        let jup_swap_client = JupiterSwapApiClient::new(self.config.jup_swap_api_url.clone());
+
+        let quote_response = self
+            .record_jupiter_call(
+                jup_swap_client
+                    .quote(&QuoteRequest {
+                        input_mint: src_mint,
+                        output_mint: self.get_mint_for_bank(dst_bank)?,
+                        amount,
+                        slippage_bps: self.config.get_slippage_bps(&src_mint),
+                        ..Default::default()
+                    })
+                    .await,
+            )
+            .map_err(|e| {
+                error!("Failed to get quote: {:?}", e);
+                ProcessorError::Error("Failed to get quote")
+            })?;
+
+        Ok(quote_response.out_amount)
+    }
+
+    /// Track the outcome of a Jupiter API call for `jupiter_failure_threshold`
+    fn record_jupiter_call<T, E>(&self, result: Result<T, E>) -> Result<T, E> {
+        let threshold = match self.config.jupiter_failure_threshold {
+            Some(threshold) => threshold,
+            None => return result,
+        };
+
+        match self
+            .jupiter_circuit_breaker
+            .record(result.is_ok(), threshold)
+        {
+            Some(false) => {
+                info!("Jupiter API recovered, resuming liquidations that require a swap")
+            }
+            Some(true) => error!(
+                "Jupiter API failed {} consecutive calls, pausing liquidations that require a swap",
+                threshold
+            ),
+            None => {}
+        }
+
+        result
+    }
+
+    /// Whether the liquidator can repay a `liab_bank_pk` liability without a swap, from a
+    /// deposit or token account balance of the liability mint
+    fn holds_liability_mint(&self, liab_bank_pk: &Pubkey) -> Result<bool, ProcessorError> {
+        if *liab_bank_pk == self.swap_mint_bank_pk
+            || self.get_liquidator_net_balance(liab_bank_pk)?.is_positive()
+        {
+            return Ok(true);
+        }
+
+        Ok(self
+            .get_token_balance_for_bank(liab_bank_pk)?
+            .map_or(false, |balance| balance.is_positive()))
+    }
+
+    /// Whether a liquidation transaction was sent for the account less than
+    /// `liquidation_cooldown_secs` ago, and no update of the account arrived since
+    fn is_in_liquidation_cooldown(&self, account: &MarginfiAccountWrapper) -> bool {
+        let (liquidated_at, version) = match self.recently_liquidated.get(&account.address) {
+            Some(entry) => *entry,
+            None => return false,
+        };
+
+        if account.version != version
+            || liquidated_at.elapsed() >= Duration::from_secs(self.config.liquidation_cooldown_secs)
+        {
+            self.recently_liquidated.remove(&account.address);
+            return false;
+        }
+
+        true
+    }
+
+    /// Whether the bank is in `excluded_banks` or its mint in `excluded_mints`
+    fn is_bank_excluded(&self, bank_pk: &Pubkey) -> bool {
+        self.config.excluded_banks.contains(bank_pk)
+            || (!self.config.excluded_mints.is_empty()
+                && self
+                    .state_engine
+                    .get_bank_mint(bank_pk)
+                    .map_or(false, |(mint, _)| {
+                        self.config.excluded_mints.contains(&mint)
+                    }))
+    }
+
+    fn get_mint_for_bank(&self, bank_pk: &Pubkey) -> Result<Pubkey, ProcessorError> {
+        self.state_engine
+            .get_bank_mint(bank_pk)
+            .map(|(mint, _)| mint)
+            .ok_or(ProcessorError::Error("Failed to get bank"))
+    }
+
+    /// Amount of the `src_bank` token needed to buy `dst_amount` of the `dst_bank` token at
+    /// Jupiter's execution price
+    ///
+    /// The price is derived from a quote for `src_amount_estimate`, the oracle based estimate,
+    /// so it includes the price impact of a swap of that size.
+    async fn get_jupiter_sizing_amount(
+        &self,
+        src_bank: &Pubkey,
+        dst_bank: &Pubkey,
+        src_amount_estimate: I80F48,
+        dst_amount: I80F48,
+    ) -> Result<I80F48, ProcessorError> {
+        let src_mint = self.get_mint_for_bank(src_bank)?;
+        let dst_mint = self.get_mint_for_bank(dst_bank)?;
+
+        let jup_swap_client = JupiterSwapApiClient::new(self.config.jup_swap_api_url.clone());
+
+        let quote_response = self
+            .record_jupiter_call(
+                jup_swap_client
+                    .quote(&QuoteRequest {
+                        input_mint: src_mint,
+                        output_mint: dst_mint,
+                        amount: src_amount_estimate.to_num(),
+                        slippage_bps: self.config.get_slippage_bps(&src_mint),
+                        ..Default::default()
+                    })
+                    .await,
+            )
+            .map_err(|e| {
                 error!("Failed to get quote: {:?}", e);
                 ProcessorError::Error("Failed to get quote")
             })?;
 
+        let sizing_amount = quoted_input_amount(
+            dst_amount,
+            quote_response.in_amount,
+            quote_response.out_amount,
+        )
+        .ok_or(ProcessorError::Error("Jupiter quoted an empty swap"))?;
+
+        debug!(
+            "Jupiter sizing for {} of {}: {} of {} (oracle estimate: {})",
+            dst_amount, dst_mint, sizing_amount, src_mint, src_amount_estimate
+        );
+
+        Ok(sizing_amount)
+    }
+
+    /// Slippage to start a swap with, pre-widened past the last failed slippage when the
+    /// mint pair recently failed a swap on slippage
+    fn get_initial_slippage_bps(&self, src_mint: &Pubkey, dst_mint: &Pubkey) -> u16 {
+        let slippage_bps = self.config.get_slippage_bps(src_mint);
+        let max_slippage_bps = self.config.max_slippage_bps.max(slippage_bps);
+
+        self.recent_slippage_failures
+            .get(&(*src_mint, *dst_mint))
+            .filter(|failure| failure.1.elapsed() < SLIPPAGE_FAILURE_TTL)
+            .map(|failure| {
+                escalate_slippage_bps(
+                    failure.0,
+                    self.config.slippage_escalation_step,
+                    max_slippage_bps,
+                )
+                .max(slippage_bps)
+            })
+            .unwrap_or(slippage_bps)
+    }
+
+    async fn try_swap(
+        &self,
+        amount: SwapAmount,
+        src_bank: &Pubkey,
+        dst_bank: &Pubkey,
+        slippage_bps: u16,
+        urgency: SwapUrgency,
+    ) -> Result<u64, ProcessorError> {
+        let src_mint = self.get_mint_for_bank(src_bank)?;
+        let dst_mint = self.get_mint_for_bank(dst_bank)?;
+
+        let (quote_amount, swap_mode) = match amount {
+            SwapAmount::ExactIn(amount) => {
+                info!(
+                    "Swapping {} from {} to {} with {} bps slippage",
+                    amount, src_mint, dst_mint, slippage_bps
+                );
+
+                (amount, SwapMode::ExactIn)
+            }
+            SwapAmount::ExactOut {
+                out_amount,
+                max_in_amount,
+            } => {
+                info!(
+                    "Swapping at most {} from {} for {} of {} with {} bps slippage",
+                    max_in_amount, src_mint, out_amount, dst_mint, slippage_bps
+                );
+
+                (out_amount, SwapMode::ExactOut)
+            }
+        };
+
+        let jup_swap_client = JupiterSwapApiClient::new(self.config.jup_swap_api_url.clone());
+
+        debug!("Requesting quote for swap");
+        let quote_response = self.record_jupiter_call(
+            jup_swap_client
+                .quote(&QuoteRequest {
+                    input_mint: src_mint,
+                    output_mint: dst_mint,
+                    amount: quote_amount,
+                    swap_mode: Some(swap_mode),
+                    slippage_bps,
+                    ..Default::default()
+                })
+                .await,
+        );
+
+        let quote_response = match (quote_response, amount) {
+            (Ok(quote_response), _) => quote_response,
+            (Err(e), SwapAmount::ExactOut { .. }) => {
+                warn!("Failed to get ExactOut quote: {:?}", e);
+                return Err(ProcessorError::ExactOutUnavailable);
+            }
+            (Err(e), SwapAmount::ExactIn(_)) => {
+                error!("Failed to get quote: {:?}", e);
+                return Err(ProcessorError::Error("Failed to get quote"));
+            }
+        };
+
         debug!("Received quote for swap: {:?}", quote_response);
 
+        match amount {
+            SwapAmount::ExactIn(amount) => {
+                match compare_quoted_in_amount(
+                    amount,
+                    quote_response.in_amount,
+                    self.config.swap_in_amount_tolerance_bps,
+                ) {
+                    std::cmp::Ordering::Greater => {
+                        error!(
+                            "Quote from {} to {} consumes {}, over the requested {}, aborting",
+                            src_mint, dst_mint, quote_response.in_amount, amount
+                        );
+                        return Err(ProcessorError::Error(
+                            "Quote input amount exceeds the requested amount",
+                        ));
+                    }
+                    std::cmp::Ordering::Less => warn!(
+                        "Quote from {} to {} only consumes {} of the requested {}, leaving {} for the next rebalance",
+                        src_mint,
+                        dst_mint,
+                        quote_response.in_amount,
+                        amount,
+                        amount - quote_response.in_amount
+                    ),
+                    std::cmp::Ordering::Equal => {}
+                }
+            }
+            SwapAmount::ExactOut { max_in_amount, .. } => {
+                // For ExactOut quotes the threshold is the maximum input, slippage included
+                if quote_response.other_amount_threshold > max_in_amount {
+                    warn!(
+                        "ExactOut quote from {} to {} may consume up to {}, over the available {}",
+                        src_mint, dst_mint, quote_response.other_amount_threshold, max_in_amount
+                    );
+                    return Err(ProcessorError::ExactOutUnavailable);
+                }
+            }
+        }
+
+        let in_value = self.get_value(
+            I80F48::from_num(quote_response.in_amount),
+            src_bank,
+            RequirementType::Equity,
+            BalanceSide::Assets,
+        )?;
+        let out_value = self.get_value(
+            I80F48::from_num(quote_response.out_amount),
+            dst_bank,
+            RequirementType::Equity,
+            BalanceSide::Assets,
+        )?;
+
+        info!(
+            "Quote from {} to {}: ${} in for ${} out",
+            src_mint, dst_mint, in_value, out_value
+        );
+
+        let max_value_loss = in_value * I80F48::from_num(self.config.max_swap_value_loss_bps)
+            / I80F48::from_num(10_000);
+
+        if in_value - out_value > max_value_loss {
+            error!(
+                "Quote from {} to {} loses ${} of ${}, over max_swap_value_loss_bps {}, aborting",
+                src_mint,
+                dst_mint,
+                in_value - out_value,
+                in_value,
+                self.config.max_swap_value_loss_bps
+            );
+            return Err(ProcessorError::Error(
+                "Swap quote value loss exceeds max_swap_value_loss_bps",
+            ));
+        }
+
+        if let Some(max_slippage_usd) = self.config.max_slippage_usd {
+            let threshold_bank = match amount {
+                SwapAmount::ExactIn(_) => dst_bank,
+                SwapAmount::ExactOut { .. } => src_bank,
+            };
+            let threshold_value = self.get_value(
+                I80F48::from_num(quote_response.other_amount_threshold),
+                threshold_bank,
+                RequirementType::Equity,
+                BalanceSide::Assets,
+            )?;
+
+            let max_slippage_cost =
+                swap_max_slippage_cost(amount, in_value, out_value, threshold_value);
+
+            if max_slippage_cost > I80F48::from_num(max_slippage_usd) {
+                error!(
+                    "Swap of ${} from {} to {} can lose up to ${}, over max_slippage_usd ${}, aborting",
+                    in_value, src_mint, dst_mint, max_slippage_cost, max_slippage_usd
+                );
+                return Err(ProcessorError::Error(
+                    "Swap slippage cost exceeds max_slippage_usd",
+                ));
+            }
+        }
+
+        let in_amount = quote_response.in_amount;
+        let min_out_amount = match amount {
+            SwapAmount::ExactIn(_) => quote_response.other_amount_threshold,
+            SwapAmount::ExactOut { out_amount, .. } => out_amount,
+        };
+
+        let amm_keys = quote_response
+            .route_plan
+            .iter()
+            .map(|step| step.swap_info.amm_key)
+            .collect::<Vec<_>>();
+        let compute_unit_price = self
+            .config
+            .get_swap_compute_unit_price(urgency)
+            .or_else(|| self.get_compute_unit_price(&amm_keys));
+
         debug!("Swapping tokens");
-        let swap = jup_swap_client
-            .swap(&SwapRequest {
-                user_public_key: self.signer_keypair.pubkey(),
-                quote_response,
-                config: TransactionConfig {
-                    wrap_and_unwrap_sol: false,
-                    compute_unit_price_micro_lamports: self
-                        .config
-                        .compute_unit_price_micro_lamports
-                        .map(|v| ComputeUnitPriceMicroLamports::MicroLamports(v)),
-                    ..Default::default()
-                },
-            })
-            .await
+        let swap = self
+            .record_jupiter_call(
+                jup_swap_client
+                    .swap(&SwapRequest {
+                        user_public_key: self.signer_keypair.pubkey(),
+                        quote_response,
+                        config: TransactionConfig {
+                            wrap_and_unwrap_sol: false,
+                            compute_unit_price_micro_lamports: compute_unit_price
+                                .map(|v| ComputeUnitPriceMicroLamports::MicroLamports(v)),
+                            ..Default::default()
+                        },
+                    })
+                    .await,
+            )
             .map_err(|e| {
                 error!("Failed to swap: {:?}", e);
                 ProcessorError::Error("Failed to swap")
             })?;
 
         debug!("Deserializing swap transaction");
-        let mut tx =
+        let tx =
             bincode::deserialize::<VersionedTransaction>(&swap.swap_transaction).map_err(|_| {
                 error!("Failed to deserialize swap transaction");
                 ProcessorError::Error("Failed to deserialize swap transaction")
@@ -1187,61 +4829,1519 @@ impl EvaLiquidator {
 
         let recent_blockhash = self
             .state_engine
-            .rpc_client
+            .rpc_pool
             .get_latest_blockhash()
             .map_err(|e| {
                 error!("Failed to get latest blockhash: {:?}", e);
                 ProcessorError::Error("Failed to get latest blockhash")
             })?;
 
-        tx.message.set_recent_blockhash(recent_blockhash);
-
         debug!("Signing swap transaction");
-        let tx = VersionedTransaction::try_new(tx.message, &[self.signer_keypair.as_ref()])
-            .map_err(|e| {
-                error!("Failed to sign swap transaction: {:?}", e);
-                ProcessorError::Error("Failed to sign swap transaction")
-            })?;
-
-        debug!("Sending swap transaction");
-        aggressive_send_tx(
-            self.state_engine.rpc_client.clone(),
-            &tx,
-            SenderCfg::DEFAULT,
+        let tx = resign_swap_message(
+            tx.message,
+            recent_blockhash,
+            self.config.swap_compute_unit_limit,
+            &self.signer_keypair,
         )
         .map_err(|e| {
+            error!("Failed to sign swap transaction: {:?}", e);
+            ProcessorError::Error("Failed to sign swap transaction")
+        })?;
+
+        if self.config.dry_run {
+            info!(
+                "Dry run, not sending swap transaction {} of {} {} for at least {} {}",
+                tx.signatures[0], in_amount, src_mint, min_out_amount, dst_mint
+            );
+            return Ok(min_out_amount);
+        }
+
+        debug!("Sending swap transaction");
+        let send_result = if self.config.send_swaps_on_blocking_pool {
+            aggressive_send_tx_async(
+                self.state_engine.rpc_pool.clone(),
+                tx,
+                self.config.get_sender_cfg(),
+            )
+            .await
+        } else {
+            aggressive_send_tx(
+                self.state_engine.rpc_pool.clone(),
+                &tx,
+                self.config.get_sender_cfg(),
+            )
+        };
+
+        send_result.map_err(|e| {
+            if is_slippage_error(&e) {
+                return ProcessorError::SlippageExceeded;
+            }
+
             error!("Failed to send swap transaction: {:?}", e);
-            ProcessorError::Error("Failed to send swap transaction")
+            send_error(e, "Failed to send swap transaction")
         })?;
 
         debug!("Swap completed successfully");
 
-        Ok(())
+        Ok(min_out_amount)
     }
 }
 
-fn get_liquidator_seed(signer: Pubkey, mint: Pubkey, seed: &[u8]) -> [u8; 32] {
-    let mut hasher = Sha256::new();
-    hasher.update(signer.as_ref());
-    hasher.update(mint.as_ref());
-    hasher.update(seed);
-    hasher.finalize().try_into().unwrap()
+/// Price impact in bps of a swap of `in_value` USD yielding `out_value` USD, `None` for an empty
+/// swap
+fn price_impact_bps(in_value: I80F48, out_value: I80F48) -> Option<I80F48> {
+    if in_value.is_zero() {
+        return None;
+    }
+
+    Some((I80F48::ONE - out_value / in_value) * I80F48::from_num(10_000))
 }
 
-fn get_keypair_for_token_account(
-    signer: Pubkey,
-    mint: Pubkey,
-    seed: &[u8],
-) -> Result<Keypair, Box<dyn Error>> {
-    let keypair_seed = get_liquidator_seed(signer, mint, seed);
-    Keypair::from_seed(&keypair_seed)
+/// Intermediate bank and output of the quoted two-hop route yielding the most, `None` when none
+/// beats the `direct_out_amount` of the direct route
+fn best_intermediate_route(
+    routes: impl IntoIterator<Item = (Pubkey, u64)>,
+    direct_out_amount: u64,
+) -> Option<(Pubkey, u64)> {
+    routes
+        .into_iter()
+        .fold(None, |best: Option<(Pubkey, u64)>, route| match best {
+            Some(best) if best.1 >= route.1 => Some(best),
+            _ => Some(route),
+        })
+        .filter(|(_, out_amount)| *out_amount > direct_out_amount)
 }
 
-fn get_address_for_token_account(
-    signer: Pubkey,
-    mint: Pubkey,
-    seed: &[u8],
-) -> Result<Pubkey, Box<dyn Error>> {
-    let keypair = get_keypair_for_token_account(signer, mint, seed)?;
-    Ok(keypair.pubkey())
+/// Amount of the intermediate token the first hop of a two-hop swap yielded, from the balance
+/// before and after it
+fn first_hop_received_amount(balance_before: I80F48, balance_after: I80F48) -> I80F48 {
+    (balance_after - balance_before).max(I80F48::ZERO)
+}
+
+/// `asset_amount` scaled down in proportion to the share of `required_liab_amount` that
+/// `liab_coverage_amount` covers, unchanged when fully covered
+fn scale_asset_amount_to_coverage(
+    asset_amount: I80F48,
+    required_liab_amount: I80F48,
+    liab_coverage_amount: I80F48,
+) -> I80F48 {
+    if required_liab_amount <= liab_coverage_amount {
+        return asset_amount;
+    }
+
+    asset_amount * liab_coverage_amount / required_liab_amount
+}
+
+/// Sizing of a liquidation, in native amounts
+struct LiquidationSizing {
+    /// Liability amount the liquidator can cover
+    max_liab_coverage_amount: I80F48,
+    /// Asset amount the liquidator can cover the liability of
+    asset_amount_capacity: I80F48,
+    /// Asset amount to seize
+    asset_amount: I80F48,
+}
+
+/// Size a liquidation of at most `max_asset_liquidation_amount`, covered by borrowing up to
+/// `max_borrow_amount`, `asset_amount_capacity` converting the liability amount the liquidator
+/// can cover into the asset amount it covers
+///
+/// `liability_slippage_buffer` shrinks the covered liability amount and `asset_slippage_buffer`
+/// the seized asset amount.
+fn size_liquidation<E>(
+    max_borrow_amount: I80F48,
+    max_asset_liquidation_amount: I80F48,
+    liability_slippage_buffer: I80F48,
+    asset_slippage_buffer: I80F48,
+    asset_amount_capacity: impl FnOnce(I80F48) -> Result<I80F48, E>,
+) -> Result<LiquidationSizing, E> {
+    let max_liab_coverage_amount = max_borrow_amount * liability_slippage_buffer;
+    let asset_amount_capacity = asset_amount_capacity(max_liab_coverage_amount)?;
+
+    // Seize slightly less than the limit so that prices moving before the transaction lands
+    // don't push the amount past what the program allows
+    let asset_amount =
+        min(max_asset_liquidation_amount, asset_amount_capacity) * asset_slippage_buffer;
+
+    Ok(LiquidationSizing {
+        max_liab_coverage_amount,
+        asset_amount_capacity,
+        asset_amount,
+    })
+}
+
+/// Point a swap `message` at `recent_blockhash`, replacing its compute unit limit with
+/// `compute_unit_limit` when set, and sign it again with `signer`
+///
+/// Jupiter returns v0 messages referencing address lookup tables. The message is only ever
+/// modified in place, never converted, so its lookups survive the re-signing.
+fn resign_swap_message(
+    mut message: VersionedMessage,
+    recent_blockhash: Hash,
+    compute_unit_limit: Option<u32>,
+    signer: &Keypair,
+) -> Result<VersionedTransaction, SignerError> {
+    message.set_recent_blockhash(recent_blockhash);
+
+    if let Some(limit) = compute_unit_limit {
+        if !set_message_compute_unit_limit(&mut message, limit) {
+            warn!("Swap transaction has no compute unit limit to replace");
+        }
+    }
+
+    VersionedTransaction::try_new(message, &[signer])
+}
+
+/// Multi-thread tokio runtime with `worker_threads` workers, one per CPU core when unset
+fn build_multi_thread_runtime(
+    worker_threads: Option<usize>,
+    thread_name: &str,
+) -> std::io::Result<tokio::runtime::Runtime> {
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+
+    if let Some(worker_threads) = worker_threads {
+        builder.worker_threads(worker_threads);
+    }
+
+    builder.thread_name(thread_name).enable_all().build()
+}
+
+/// Compute unit price in micro-lamports paying `fee_lamports` of priority fee for a transaction
+/// requesting `compute_unit_limit` compute units, saturating at `u64::MAX`
+fn compute_unit_price_for_fee(fee_lamports: I80F48, compute_unit_limit: u32) -> u64 {
+    fee_lamports
+        .checked_mul(I80F48::from_num(1_000_000))
+        .and_then(|fee| fee.checked_div(I80F48::from_num(compute_unit_limit)))
+        .and_then(|price| price.checked_to_num::<u64>())
+        .unwrap_or(u64::MAX)
+}
+
+/// Smallest balance change accepted for an operation expected to change it by `expected_delta`,
+/// short of it by at most `tolerance_bps`
+fn min_balance_delta(expected_delta: I80F48, tolerance_bps: u16) -> I80F48 {
+    expected_delta * (I80F48::ONE - I80F48::from_num(tolerance_bps) / I80F48!(10000))
+}
+
+/// How a quote's input amount compares to the `requested` amount, `Equal` when within
+/// `tolerance_bps` of it
+fn compare_quoted_in_amount(requested: u64, quoted: u64, tolerance_bps: u16) -> std::cmp::Ordering {
+    let tolerance = requested as u128 * tolerance_bps as u128 / 10_000;
+
+    if quoted as u128 > requested as u128 + tolerance {
+        std::cmp::Ordering::Greater
+    } else if quoted as u128 + tolerance < requested as u128 {
+        std::cmp::Ordering::Less
+    } else {
+        std::cmp::Ordering::Equal
+    }
+}
+
+/// Whether the free collateral returned by `get_free_collateral`, only called when a minimum is
+/// set, reaches `min_free_collateral`, true when it can't be computed
+fn has_min_free_collateral<E: std::fmt::Debug>(
+    min_free_collateral: Option<f64>,
+    get_free_collateral: impl FnOnce() -> Result<I80F48, E>,
+) -> bool {
+    let min_free_collateral = match min_free_collateral {
+        Some(min_free_collateral) => I80F48::from_num(min_free_collateral),
+        None => return true,
+    };
+
+    match get_free_collateral() {
+        Ok(free_collateral) if free_collateral < min_free_collateral => {
+            info!(
+                "Free collateral ${} below ${}, skipping liquidation scan",
+                free_collateral, min_free_collateral
+            );
+            false
+        }
+        Ok(_) => true,
+        Err(e) => {
+            warn!("Failed to get free collateral, scanning anyway: {:?}", e);
+            true
+        }
+    }
+}
+
+/// Value of the balance in `bank_pk` among the per bank balance `values`, zero when the account
+/// has no balance in it
+fn balance_value_in_bank(values: Vec<(I80F48, Pubkey)>, bank_pk: &Pubkey) -> I80F48 {
+    values
+        .into_iter()
+        .find(|(_, pk)| pk == bank_pk)
+        .map(|(value, _)| value)
+        .unwrap_or_default()
+}
+
+/// Accounts that entered and left the liquidation candidate set between two scans
+fn candidate_transitions(
+    previous_candidates: &HashSet<Pubkey>,
+    current_candidates: &HashSet<Pubkey>,
+) -> (Vec<Pubkey>, Vec<Pubkey>) {
+    let newly_liquidatable = current_candidates
+        .difference(previous_candidates)
+        .copied()
+        .collect();
+
+    let recovered = previous_candidates
+        .difference(current_candidates)
+        .copied()
+        .collect();
+
+    (newly_liquidatable, recovered)
+}
+
+/// Likely reason an account left the liquidation candidate set, `account` being its latest
+/// state or `None` when it's no longer tracked
+fn recovery_reason(account: Option<&MarginfiAccountWrapper>) -> &'static str {
+    match account {
+        None => "account no longer tracked",
+        Some(account) if !account.has_liabs() => "liabilities repaid or externally liquidated",
+        Some(account) => {
+            let (assets, liabs) = account.calc_health(RequirementType::Maintenance);
+
+            if assets >= liabs {
+                "health recovered, prices moved or collateral was added"
+            } else {
+                "liquidation no longer profitable enough"
+            }
+        }
+    }
+}
+
+/// `filter_map` of `items` in parallel, each batch of `batch_size` items processed sequentially
+/// on one thread, results keep the order of `items`
+fn par_filter_map_in_batches<T, R, F>(items: &[T], batch_size: usize, f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> Option<R> + Sync + Send,
+{
+    items
+        .par_chunks(batch_size.max(1))
+        .flat_map_iter(|chunk| chunk.iter().filter_map(&f))
+        .collect()
+}
+
+/// Outcome of a candidate's liquidation attempt within a scan cycle
+enum AttemptOutcome {
+    /// A liquidation worth this value in USD was sent
+    Liquidated(I80F48),
+    /// The candidate was skipped or its liquidation failed
+    NotLiquidated,
+    /// No further candidate must be attempted this cycle
+    Halt,
+}
+
+/// Attempt `candidates` in order, returning whether any was liquidated
+///
+/// `attempt` gets the value left under `max_cycle_value_usd`, if set, and whether a candidate
+/// was already liquidated this cycle. Candidates are tried until one liquidation is sent, only a
+/// cycle cap opts into liquidating several accounts per cycle, until the cap is reached.
+fn run_liquidation_cycle<C>(
+    candidates: impl IntoIterator<Item = C>,
+    max_cycle_value_usd: Option<f64>,
+    mut attempt: impl FnMut(C, Option<I80F48>, bool) -> AttemptOutcome,
+) -> bool {
+    let mut cycle_liquidated_value = I80F48::ZERO;
+    let mut liquidated_any = false;
+
+    for candidate in candidates {
+        let remaining_cycle_value = match max_cycle_value_usd {
+            Some(max_cycle_value) => {
+                let remaining =
+                    remaining_cycle_liquidation_value(max_cycle_value, cycle_liquidated_value);
+
+                if !remaining.is_positive() {
+                    info!(
+                        "Cycle liquidation cap of ${} reached (${} liquidated), halting further liquidations",
+                        max_cycle_value, cycle_liquidated_value
+                    );
+                    break;
+                }
+
+                Some(remaining)
+            }
+            None => None,
+        };
+
+        match attempt(candidate, remaining_cycle_value, liquidated_any) {
+            AttemptOutcome::Liquidated(liquidated_value) => {
+                cycle_liquidated_value += liquidated_value;
+                liquidated_any = true;
+
+                if max_cycle_value_usd.is_none() {
+                    break;
+                }
+            }
+            AttemptOutcome::NotLiquidated => {}
+            AttemptOutcome::Halt => break,
+        }
+    }
+
+    liquidated_any
+}
+
+/// Value in USD that can still be liquidated in a cycle capped at `max_cycle_value_usd`, zero or
+/// less once the cap is reached
+fn remaining_cycle_liquidation_value(
+    max_cycle_value_usd: f64,
+    cycle_liquidated_value: I80F48,
+) -> I80F48 {
+    I80F48::from_num(max_cycle_value_usd) - cycle_liquidated_value
+}
+
+/// Amount of `bank`'s token, in native units, the liquidator can withdraw and borrow with
+/// `free_collateral`, `untied_collateral` of which is tied up by its `asset_amount` deposit
+///
+/// `None` on overflow or when a price or weight is zero.
+fn checked_max_borrow_amount(
+    bank: &Bank,
+    free_collateral: I80F48,
+    untied_collateral: I80F48,
+    asset_amount: I80F48,
+    lower_price: I80F48,
+    higher_price: I80F48,
+) -> Option<I80F48> {
+    let asset_weight: I80F48 = bank.config.asset_weight_init.into();
+    let liab_weight: I80F48 = bank.config.liability_weight_init.into();
+    let decimal_scale = *EXP_10_I80F48.get(bank.mint_decimals as usize)?;
+
+    // Free collateral beyond what the bank's deposit ties up, which has to be borrowed
+    let untied_borrow_ui = checked_div_by_weighted_price(
+        free_collateral.checked_sub(untied_collateral)?,
+        higher_price,
+        liab_weight,
+    )?;
+
+    if asset_weight == I80F48::ZERO {
+        untied_borrow_ui
+            .checked_mul(decimal_scale)?
+            .checked_add(asset_amount)
+    } else {
+        checked_div_by_weighted_price(untied_collateral, lower_price, asset_weight)?
+            .checked_add(untied_borrow_ui)?
+            .checked_mul(decimal_scale)
+    }
+}
+
+/// `value / (price * weight)`, `None` on overflow or when the price or weight is zero
+fn checked_div_by_weighted_price(value: I80F48, price: I80F48, weight: I80F48) -> Option<I80F48> {
+    price
+        .checked_mul(weight)
+        .and_then(|weighted_price| value.checked_div(weighted_price))
+}
+
+/// The `watched` accounts loaded in `accounts`, or every account when nothing is watched
+fn select_scan_accounts(
+    accounts: &DashMap<Pubkey, Arc<RwLock<MarginfiAccountWrapper>>>,
+    watched: Option<&[Pubkey]>,
+) -> Vec<Arc<RwLock<MarginfiAccountWrapper>>> {
+    match watched {
+        Some(watched) => watched
+            .iter()
+            .filter_map(|address| {
+                let account = accounts.get(address);
+
+                if account.is_none() {
+                    trace!("Watched account {} isn't loaded", address);
+                }
+
+                account.map(|account| account.value().clone())
+            })
+            .collect(),
+        None => accounts
+            .iter()
+            .map(|account| account.value().clone())
+            .collect(),
+    }
+}
+
+/// Whether `account` is still below its maintenance requirement on the latest streamed state,
+/// false when its health can't be computed
+fn is_still_liquidatable(account: &MarginfiAccountWrapper) -> bool {
+    is_health_liquidatable(
+        &account.address,
+        account.try_calc_health(RequirementType::Maintenance),
+    )
+}
+
+/// Whether an account with the maintenance `health`, its weighted assets and liabilities, is
+/// liquidatable, false when its health can't be computed
+fn is_health_liquidatable<E: std::fmt::Debug>(
+    address: &Pubkey,
+    health: Result<(I80F48, I80F48), E>,
+) -> bool {
+    match health {
+        Ok((assets, liabs)) if assets >= liabs => {
+            info!(
+                "Account {} is no longer liquidatable (assets: ${}, liabs: ${}), skipping liquidation",
+                address, assets, liabs
+            );
+            false
+        }
+        Ok(_) => true,
+        Err(e) => {
+            warn!(
+                "Failed to recheck the health of account {}, skipping liquidation: {:?}",
+                address, e
+            );
+            false
+        }
+    }
+}
+
+/// Worst case USD cost of a swap quoted at `in_value` for `out_value`, the maximum that goes in
+/// minus the minimum that comes out, covering both price impact and slippage
+///
+/// `threshold_value` is the value of the quote's slippage threshold, the minimum output of an
+/// exact in swap or the maximum input of an exact out one.
+fn swap_max_slippage_cost(
+    amount: SwapAmount,
+    in_value: I80F48,
+    out_value: I80F48,
+    threshold_value: I80F48,
+) -> I80F48 {
+    match amount {
+        SwapAmount::ExactIn(_) => in_value - threshold_value,
+        SwapAmount::ExactOut { .. } => threshold_value - out_value,
+    }
+}
+
+/// Input needed for `out_amount` at the execution price of a quote of `quoted_in_amount` for
+/// `quoted_out_amount`, `None` for an empty quote
+fn quoted_input_amount(
+    out_amount: I80F48,
+    quoted_in_amount: u64,
+    quoted_out_amount: u64,
+) -> Option<I80F48> {
+    if quoted_out_amount == 0 {
+        return None;
+    }
+
+    Some(out_amount * I80F48::from_num(quoted_in_amount) / I80F48::from_num(quoted_out_amount))
+}
+
+/// Slippage to retry a swap that failed on `slippage_bps` with, widened by `step` up to
+/// `max_slippage_bps`
+fn escalate_slippage_bps(slippage_bps: u16, step: u16, max_slippage_bps: u16) -> u16 {
+    slippage_bps.saturating_add(step).min(max_slippage_bps)
+}
+
+fn is_slippage_error(err: &SendTxError) -> bool {
+    matches!(
+        err.transaction_error(),
+        Some(TransactionError::InstructionError(
+            _,
+            InstructionError::Custom(JUPITER_SLIPPAGE_TOLERANCE_EXCEEDED)
+        ))
+    )
+}
+
+fn get_liquidator_seed(signer: Pubkey, mint: Pubkey, seed: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(signer.as_ref());
+    hasher.update(mint.as_ref());
+    hasher.update(seed);
+    hasher.finalize().try_into().unwrap()
+}
+
+fn get_keypair_for_token_account(
+    signer: Pubkey,
+    mint: Pubkey,
+    seed: &[u8],
+) -> Result<Keypair, Box<dyn Error>> {
+    let keypair_seed = get_liquidator_seed(signer, mint, seed);
+    Keypair::from_seed(&keypair_seed)
+}
+
+fn get_address_for_token_account(
+    signer: Pubkey,
+    mint: Pubkey,
+    seed: &[u8],
+) -> Result<Pubkey, Box<dyn Error>> {
+    let keypair = get_keypair_for_token_account(signer, mint, seed)?;
+    Ok(keypair.pubkey())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cycle_cap_stops_once_the_threshold_is_reached() {
+        // Candidates liquidate their value, `None` ones are skipped
+        let candidates = [Some(40), None, Some(50), Some(30), Some(20)];
+
+        let run_cycle = |max_cycle_value_usd: Option<f64>| {
+            let mut attempts = vec![];
+
+            let liquidated_any = run_liquidation_cycle(
+                candidates,
+                max_cycle_value_usd,
+                |candidate, remaining_cycle_value, _| {
+                    attempts.push(remaining_cycle_value);
+
+                    match candidate {
+                        Some(value) => AttemptOutcome::Liquidated(I80F48::from_num(value)),
+                        None => AttemptOutcome::NotLiquidated,
+                    }
+                },
+            );
+
+            (liquidated_any, attempts)
+        };
+
+        // $120 is liquidated by the fourth candidate, the last one is never attempted
+        let (liquidated_any, attempts) = run_cycle(Some(100.0));
+        assert!(liquidated_any);
+        assert_eq!(
+            attempts,
+            [100, 60, 60, 10].map(|remaining| Some(I80F48::from_num(remaining)))
+        );
+
+        // Without a cap, the cycle stops after the first liquidation
+        let (liquidated_any, attempts) = run_cycle(None);
+        assert!(liquidated_any);
+        assert_eq!(attempts, vec![None]);
+
+        let mut attempted = 0;
+        let liquidated_any = run_liquidation_cycle([None, Some(10), Some(10)], None, |_, _, _| {
+            attempted += 1;
+            AttemptOutcome::Halt
+        });
+        assert!(!liquidated_any);
+        assert_eq!(attempted, 1);
+    }
+
+    #[test]
+    fn cycle_cap_remaining_value_shrinks_with_liquidations() {
+        assert_eq!(
+            remaining_cycle_liquidation_value(100.0, I80F48::from_num(25)),
+            I80F48::from_num(75)
+        );
+        assert!(!remaining_cycle_liquidation_value(100.0, I80F48::from_num(100)).is_positive());
+    }
+
+    /// Spawn `tasks` tasks that each wait up to a second for all of them to be running at
+    /// once, returns whether they all met
+    fn tasks_run_concurrently(runtime: &tokio::runtime::Runtime, tasks: usize) -> bool {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let running = Arc::new(AtomicUsize::new(0));
+
+        runtime.block_on(async {
+            let handles = (0..tasks)
+                .map(|_| {
+                    let running = running.clone();
+                    tokio::spawn(async move {
+                        running.fetch_add(1, Ordering::SeqCst);
+
+                        let deadline = Instant::now() + Duration::from_secs(1);
+                        while running.load(Ordering::SeqCst) < tasks && Instant::now() < deadline {
+                            std::thread::yield_now();
+                        }
+
+                        running.load(Ordering::SeqCst) >= tasks
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            let mut all_met = true;
+            for handle in handles {
+                all_met &= handle.await.unwrap();
+            }
+
+            all_met
+        })
+    }
+
+    #[test]
+    fn runtime_has_the_configured_worker_count() {
+        let runtime = build_multi_thread_runtime(Some(3), "evaLiquidatorTest").unwrap();
+
+        assert!(tasks_run_concurrently(&runtime, 3));
+        assert!(!tasks_run_concurrently(&runtime, 4));
+    }
+
+    #[test]
+    fn two_hop_route_is_constructed_when_the_direct_route_is_poor() {
+        let usdc_bank = Pubkey::new_unique();
+        let sol_bank = Pubkey::new_unique();
+
+        // 100 USD in, 90 USD out directly
+        let impact = price_impact_bps(I80F48::from_num(100), I80F48::from_num(90)).unwrap();
+        assert_eq!(impact, I80F48::from_num(1_000));
+        assert!(impact > I80F48::from_num(500));
+
+        assert_eq!(
+            best_intermediate_route([(usdc_bank, 95), (sol_bank, 98)], 90),
+            Some((sol_bank, 98))
+        );
+    }
+
+    #[test]
+    fn two_hop_route_is_skipped_when_it_does_not_beat_the_direct_route() {
+        assert_eq!(price_impact_bps(I80F48::ZERO, I80F48::ZERO), None);
+        assert_eq!(
+            best_intermediate_route([(Pubkey::new_unique(), 90)], 90),
+            None
+        );
+        assert_eq!(
+            best_intermediate_route(Vec::<(Pubkey, u64)>::new(), 90),
+            None
+        );
+    }
+
+    #[test]
+    fn second_hop_only_sells_what_the_first_hop_received() {
+        assert_eq!(
+            first_hop_received_amount(I80F48::from_num(500), I80F48::from_num(1_250)),
+            I80F48::from_num(750)
+        );
+        assert_eq!(
+            first_hop_received_amount(I80F48::from_num(500), I80F48::from_num(400)),
+            I80F48::ZERO
+        );
+    }
+
+    #[test]
+    fn liab_coverage_scales_the_asset_amount_down() {
+        let asset_amount = I80F48::from_num(1_000);
+
+        assert_eq!(
+            scale_asset_amount_to_coverage(
+                asset_amount,
+                I80F48::from_num(500),
+                I80F48::from_num(800)
+            ),
+            asset_amount
+        );
+        assert_eq!(
+            scale_asset_amount_to_coverage(
+                asset_amount,
+                I80F48::from_num(500),
+                I80F48::from_num(125)
+            ),
+            I80F48::from_num(250)
+        );
+        // Held liability tokens cover what can't be borrowed
+        assert_eq!(
+            scale_asset_amount_to_coverage(
+                asset_amount,
+                I80F48::from_num(500),
+                I80F48::from_num(300) + I80F48::from_num(200)
+            ),
+            asset_amount
+        );
+    }
+
+    #[test]
+    fn resigned_swap_keeps_its_address_lookup_tables() {
+        use solana_sdk::{
+            address_lookup_table_account::AddressLookupTableAccount,
+            compute_budget::ComputeBudgetInstruction,
+            instruction::{AccountMeta, Instruction},
+            message::v0,
+        };
+
+        let signer = Keypair::new();
+        let lookup_table = AddressLookupTableAccount {
+            key: Pubkey::new_unique(),
+            addresses: vec![Pubkey::new_unique(), Pubkey::new_unique()],
+        };
+
+        let swap_ix = Instruction::new_with_bytes(
+            Pubkey::new_unique(),
+            &[1, 2, 3],
+            vec![
+                AccountMeta::new(signer.pubkey(), true),
+                AccountMeta::new(lookup_table.addresses[0], false),
+                AccountMeta::new_readonly(lookup_table.addresses[1], false),
+            ],
+        );
+
+        let message = v0::Message::try_compile(
+            &signer.pubkey(),
+            &[
+                ComputeBudgetInstruction::set_compute_unit_limit(1_400_000),
+                swap_ix,
+            ],
+            &[lookup_table],
+            Hash::new_unique(),
+        )
+        .unwrap();
+        let message = VersionedMessage::V0(message);
+        let lookups = message.address_table_lookups().unwrap().to_vec();
+        assert!(!lookups.is_empty());
+
+        let recent_blockhash = Hash::new_unique();
+        let tx = resign_swap_message(message, recent_blockhash, Some(600_000), &signer).unwrap();
+
+        assert_eq!(tx.message.address_table_lookups().unwrap(), &lookups[..]);
+        assert_eq!(*tx.message.recent_blockhash(), recent_blockhash);
+        assert!(tx.verify_with_results().into_iter().all(|valid| valid));
+        assert!(tx
+            .message
+            .instructions()
+            .iter()
+            .any(|ix| ix.data == ComputeBudgetInstruction::set_compute_unit_limit(600_000).data));
+    }
+
+    /// Liquidator config with the defaults, overridden by the TOML `overrides`
+    fn test_config(overrides: &str) -> EvaLiquidatorCfg {
+        toml::from_str(&format!(
+            "keypair_path = \"keypair.json\"\nliquidator_account = \"{}\"\n{}",
+            Pubkey::new_unique(),
+            overrides
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn default_config_allows_liquidations() {
+        assert!(test_config("").diagnose_noop_config().is_empty());
+    }
+
+    #[test]
+    fn noop_configs_are_diagnosed() {
+        let warnings = test_config("max_liquidation_value = 2.0").diagnose_noop_config();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("min_profit $0.1 exceeds the maximum profit"));
+
+        let warnings = test_config("max_liquidation_value = 0.0").diagnose_noop_config();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("max_liquidation_value is 0"));
+
+        let warnings = test_config("preferred_mints = []").diagnose_noop_config();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("preferred_mints is empty"));
+
+        let warnings = test_config("max_liquidation_candidates = 0").diagnose_noop_config();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("max_liquidation_candidates is 0"));
+
+        let warnings = test_config("dry_run = true").diagnose_noop_config();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("dry_run is enabled"));
+    }
+
+    #[test]
+    fn min_liquidation_value_above_the_max_is_diagnosed() {
+        assert_eq!(test_config("").min_liquidation_value, 1.0);
+
+        let warnings = test_config("max_liquidation_value = 50.0\nmin_liquidation_value = 100.0")
+            .diagnose_noop_config();
+        assert_eq!(warnings.len(), 1);
+        assert!(
+            warnings[0].starts_with("min_liquidation_value $100 exceeds max_liquidation_value $50")
+        );
+
+        assert!(
+            test_config("max_liquidation_value = 50.0\nmin_liquidation_value = 5.0")
+                .diagnose_noop_config()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn slippage_escalates_by_step_up_to_the_max() {
+        let mut slippage_bps = 50;
+        let mut attempts = vec![slippage_bps];
+
+        while slippage_bps < 300 {
+            slippage_bps = escalate_slippage_bps(slippage_bps, 100, 300);
+            attempts.push(slippage_bps);
+        }
+
+        assert_eq!(attempts, vec![50, 150, 250, 300]);
+        assert_eq!(
+            escalate_slippage_bps(u16::MAX - 10, 100, u16::MAX),
+            u16::MAX
+        );
+    }
+
+    #[test]
+    fn only_jupiter_slippage_failures_are_slippage_errors() {
+        let instruction_error = |code| {
+            SendTxError::SimulationFailed(TransactionError::InstructionError(
+                2,
+                InstructionError::Custom(code),
+            ))
+        };
+
+        assert!(is_slippage_error(&instruction_error(
+            JUPITER_SLIPPAGE_TOLERANCE_EXCEEDED
+        )));
+        assert!(!is_slippage_error(&instruction_error(1)));
+        assert!(!is_slippage_error(&SendTxError::SimulationFailed(
+            TransactionError::BlockhashNotFound
+        )));
+    }
+
+    /// Account with active balances in `banks`, none of which is loaded
+    fn test_account(banks: &[Pubkey]) -> MarginfiAccountWrapper {
+        let mut account: marginfi::state::marginfi_account::MarginfiAccount =
+            bytemuck::Zeroable::zeroed();
+
+        for (balance, bank_pk) in account.lending_account.balances.iter_mut().zip(banks) {
+            balance.active = true;
+            balance.bank_pk = *bank_pk;
+        }
+
+        MarginfiAccountWrapper::new(Pubkey::new_unique(), account, Arc::new(DashMap::new()))
+    }
+
+    #[test]
+    fn recheck_skips_recovered_and_unpriceable_accounts() {
+        // No liabilities left
+        assert!(!is_still_liquidatable(&test_account(&[])));
+        // Health can't be computed without the account's bank
+        assert!(!is_still_liquidatable(&test_account(&[
+            Pubkey::new_unique()
+        ])));
+
+        let address = Pubkey::new_unique();
+        let health = |assets: u64, liabs: u64| {
+            Ok::<_, ()>((I80F48::from_num(assets), I80F48::from_num(liabs)))
+        };
+
+        // Prices moved since the scan, the account's assets cover its liabilities again
+        assert!(!is_health_liquidatable(&address, health(120, 100)));
+        assert!(!is_health_liquidatable(&address, health(100, 100)));
+        // Still underwater
+        assert!(is_health_liquidatable(&address, health(95, 100)));
+        assert!(!is_health_liquidatable(
+            &address,
+            Err::<(I80F48, I80F48), _>(())
+        ));
+    }
+
+    #[test]
+    fn swap_slippage_cost_is_measured_against_the_threshold() {
+        // $100 in, $99 quoted out, at least $97 out after slippage
+        assert_eq!(
+            swap_max_slippage_cost(
+                SwapAmount::ExactIn(100),
+                I80F48::from_num(100),
+                I80F48::from_num(99),
+                I80F48::from_num(97)
+            ),
+            I80F48::from_num(3)
+        );
+        // $50 out, $51 quoted in, at most $52.5 in after slippage
+        assert_eq!(
+            swap_max_slippage_cost(
+                SwapAmount::ExactOut {
+                    out_amount: 50,
+                    max_in_amount: 60
+                },
+                I80F48::from_num(51),
+                I80F48::from_num(50),
+                I80F48::from_num(52.5)
+            ),
+            I80F48::from_num(2.5)
+        );
+    }
+
+    #[test]
+    fn triggers_during_a_run_coalesce_into_one_follow_up() {
+        use std::sync::atomic::AtomicUsize;
+
+        let trigger = CoalescingTrigger::default();
+        let runs = AtomicUsize::new(0);
+        let release = tokio::sync::Notify::new();
+
+        let first = trigger.run(|| async {
+            if runs.fetch_add(1, Ordering::SeqCst) == 0 {
+                release.notified().await;
+            }
+            Ok::<_, ()>(())
+        });
+
+        let others = async {
+            tokio::task::yield_now().await;
+
+            // Queued behind the first run instead of running
+            for _ in 0..2 {
+                assert_eq!(trigger.run(|| async { Err(()) }).await, Ok(()));
+            }
+
+            release.notify_one();
+        };
+
+        let runtime = build_multi_thread_runtime(Some(1), "evaLiquidatorTest").unwrap();
+        let (result, ()) = runtime.block_on(async { futures::join!(first, others) });
+
+        assert_eq!(result, Ok(()));
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn jupiter_sizing_follows_the_quoted_execution_price() {
+        // 1_000 in for 500 out, so 200 out takes 400 in
+        assert_eq!(
+            quoted_input_amount(I80F48::from_num(200), 1_000, 500),
+            Some(I80F48::from_num(400))
+        );
+        assert_eq!(quoted_input_amount(I80F48::from_num(200), 1_000, 0), None);
+    }
+
+    #[test]
+    fn jupiter_sizing_diverges_from_oracle_sizing_by_the_quote() {
+        use marginfi::state::marginfi_account::{calc_amount, calc_value};
+
+        // 5 SOL of liability, bought with USDC
+        let liab_amount = I80F48::from_num(5_000_000_000u64);
+        let (liab_price, liab_decimals) = (I80F48::from_num(20), 9);
+        let (swap_price, swap_decimals) = (I80F48::ONE, 6);
+
+        // Oracle sizing, the liability value at the liability price converted at the swap price
+        let liab_value = calc_value(liab_amount, liab_price, liab_decimals, None).unwrap();
+        let oracle_amount = calc_amount(liab_value, swap_price, swap_decimals).unwrap();
+        assert_eq!(oracle_amount, I80F48::from_num(100_000_000));
+
+        // Jupiter sells 4 SOL for the oracle estimate of 100 USDC, SOL trades 25% above its
+        // oracle price
+        let jupiter_amount =
+            quoted_input_amount(liab_amount, oracle_amount.to_num(), 4_000_000_000).unwrap();
+
+        assert_eq!(jupiter_amount, I80F48::from_num(125_000_000));
+        assert_eq!(
+            jupiter_amount,
+            oracle_amount * I80F48::from_num(5) / I80F48::from_num(4)
+        );
+
+        // A quote at the oracle price sizes the same as the oracle
+        assert_eq!(
+            quoted_input_amount(liab_amount, oracle_amount.to_num(), 5_000_000_000),
+            Some(oracle_amount)
+        );
+    }
+
+    #[test]
+    fn explorer_links_point_at_the_configured_cluster() {
+        let signature = Signature::default();
+
+        assert_eq!(
+            test_config("").explorer_cluster.tx_url(&signature),
+            format!("https://explorer.solana.com/tx/{}", signature)
+        );
+        assert_eq!(
+            test_config("explorer_cluster = \"devnet\"")
+                .explorer_cluster
+                .tx_url(&signature),
+            format!(
+                "https://explorer.solana.com/tx/{}?cluster=devnet",
+                signature
+            )
+        );
+    }
+
+    #[test]
+    fn batched_health_computation_keeps_every_result_in_order() {
+        let items = (0..1000).collect::<Vec<u32>>();
+        let expected = items
+            .iter()
+            .filter(|item| *item % 3 == 0)
+            .map(|item| item * 2)
+            .collect::<Vec<_>>();
+
+        for batch_size in [0, 1, 7, 256, 5000] {
+            let results = par_filter_map_in_batches(&items, batch_size, |item| {
+                (item % 3 == 0).then_some(item * 2)
+            });
+
+            assert_eq!(results, expected, "batch size {}", batch_size);
+        }
+    }
+
+    #[test]
+    fn slippage_buffers_are_configured_per_side() {
+        let config = test_config("");
+        assert_eq!(config.asset_slippage_buffer, I80F48!(0.98));
+        assert_eq!(config.liability_slippage_buffer, I80F48::ONE);
+
+        let config = test_config("asset_slippage_buffer = 0.75\nliability_slippage_buffer = 0.5");
+
+        // Each unit of liability covers two units of the asset, the liquidatable amount binds
+        let sizing = |asset_slippage_buffer: I80F48, liability_slippage_buffer: I80F48| {
+            size_liquidation(
+                I80F48::from_num(100),
+                I80F48::from_num(100),
+                liability_slippage_buffer,
+                asset_slippage_buffer,
+                |liab_amount| Ok::<_, ()>(liab_amount * 2),
+            )
+            .unwrap()
+        };
+
+        let sized = sizing(
+            config.asset_slippage_buffer,
+            config.liability_slippage_buffer,
+        );
+        assert_eq!(sized.max_liab_coverage_amount, I80F48::from_num(50));
+        assert_eq!(sized.asset_amount_capacity, I80F48::from_num(100));
+        assert_eq!(sized.asset_amount, I80F48::from_num(75));
+
+        let sized = sizing(I80F48::from_num(0.5), config.liability_slippage_buffer);
+        assert_eq!(sized.max_liab_coverage_amount, I80F48::from_num(50));
+        assert_eq!(sized.asset_amount, I80F48::from_num(50));
+
+        let sized = sizing(config.asset_slippage_buffer, I80F48::from_num(0.75));
+        assert_eq!(sized.max_liab_coverage_amount, I80F48::from_num(75));
+        assert_eq!(sized.asset_amount_capacity, I80F48::from_num(150));
+        assert_eq!(sized.asset_amount, I80F48::from_num(75));
+    }
+
+    #[test]
+    fn candidate_transitions_are_reported_with_a_reason() {
+        let stayed = Pubkey::new_unique();
+        let left = Pubkey::new_unique();
+        let entered = Pubkey::new_unique();
+
+        let (newly_liquidatable, recovered) = candidate_transitions(
+            &HashSet::from([stayed, left]),
+            &HashSet::from([stayed, entered]),
+        );
+        assert_eq!(newly_liquidatable, vec![entered]);
+        assert_eq!(recovered, vec![left]);
+
+        assert_eq!(recovery_reason(None), "account no longer tracked");
+        assert_eq!(
+            recovery_reason(Some(&test_account(&[Pubkey::new_unique()]))),
+            "liabilities repaid or externally liquidated"
+        );
+    }
+
+    #[test]
+    fn profit_based_priority_fee_is_clamped_to_the_configured_range() {
+        // 10_000 lamports over 200_000 compute units
+        assert_eq!(
+            compute_unit_price_for_fee(I80F48::from_num(10_000), 200_000),
+            50_000
+        );
+
+        let config = test_config(
+            "min_compute_unit_price_micro_lamports = 1000\nmax_compute_unit_price_micro_lamports = 20000",
+        );
+        assert_eq!(config.clamp_compute_unit_price(50_000), 20_000);
+        assert_eq!(config.clamp_compute_unit_price(10), 1_000);
+        assert_eq!(
+            config.clamp_compute_unit_price(compute_unit_price_for_fee(I80F48::MAX, 1)),
+            20_000
+        );
+    }
+
+    #[test]
+    fn liquidation_audit_records_the_chosen_balances() {
+        let asset_bank = Pubkey::new_unique();
+        let liab_bank = Pubkey::new_unique();
+
+        let values = vec![
+            (I80F48::from_num(10), Pubkey::new_unique()),
+            (I80F48::from_num(25), asset_bank),
+        ];
+        assert_eq!(
+            balance_value_in_bank(values.clone(), &asset_bank),
+            I80F48::from_num(25)
+        );
+        assert_eq!(balance_value_in_bank(values, &liab_bank), I80F48::ZERO);
+
+        let audit_bank = |bank: Pubkey, balance_value_maint: f64| LiquidationAuditBank {
+            bank: bank.to_string(),
+            mint: Pubkey::new_unique().to_string(),
+            balance_value_maint,
+            real_time_price: Some(1.5),
+            time_weighted_price: None,
+        };
+
+        let audit = LiquidationAudit {
+            liquidatee: Pubkey::new_unique().to_string(),
+            signature: Signature::default().to_string(),
+            assets_value_maint: 100.0,
+            liabs_value_maint: 120.0,
+            asset_bank: audit_bank(asset_bank, 25.0),
+            liab_bank: audit_bank(liab_bank, 120.0),
+            max_liquidatable_asset_amount: 20.0,
+            max_liab_coverage_amount: 1000.0,
+            liquidator_capacity_value: 500.0,
+            liquidator_capacity_asset_amount: 333.0,
+            asset_slippage_buffer: 0.98,
+            liability_slippage_buffer: 1.0,
+            submitted_asset_amount: 19_600_000,
+            liquidated_value: 19.6,
+            expected_profit: 0.49,
+            compute_unit_price_micro_lamports: None,
+        };
+
+        let record: serde_json::Value =
+            serde_json::from_str(&serde_json::to_string(&audit).unwrap()).unwrap();
+        assert_eq!(record["asset_bank"]["bank"], asset_bank.to_string());
+        assert_eq!(record["asset_bank"]["balance_value_maint"], 25.0);
+        assert_eq!(
+            record["liab_bank"]["time_weighted_price"],
+            serde_json::Value::Null
+        );
+        assert_eq!(record["submitted_asset_amount"], 19_600_000);
+    }
+
+    #[test]
+    fn rebalance_orders_resolve_to_their_steps() {
+        use RebalanceStep::*;
+
+        assert_eq!(test_config("").rebalance_order, RebalanceOrder::SellFirst);
+
+        for near_health_limit in [false, true] {
+            assert_eq!(
+                RebalanceOrder::SellFirst.steps(near_health_limit)[..3],
+                [WrapExcessSol, SellNonPreferredDeposits, RepayLiabilities]
+            );
+            assert_eq!(
+                RebalanceOrder::RepayFirst.steps(near_health_limit)[..3],
+                [WrapExcessSol, RepayLiabilities, SellNonPreferredDeposits]
+            );
+        }
+
+        let interleaved = RebalanceOrder::Interleaved.steps(false);
+        assert_eq!(interleaved[2], DepositPreferredTokens);
+        assert_eq!(interleaved.last(), Some(&DepositPreferredTokens));
+
+        assert_eq!(
+            RebalanceOrder::Adaptive.steps(false),
+            RebalanceOrder::SellFirst.steps(false)
+        );
+        assert_eq!(
+            RebalanceOrder::Adaptive.steps(true),
+            RebalanceOrder::RepayFirst.steps(true)
+        );
+    }
+
+    #[test]
+    fn token_account_dust_threshold_can_be_overridden_per_mint() {
+        let usd_mint = Pubkey::new_unique();
+        let native_mint = Pubkey::new_unique();
+        let other_mint = Pubkey::new_unique();
+
+        let config = test_config(&format!(
+            "[token_account_dust_thresholds]\n\"{}\" = {{ usd = 0.5 }}\n\"{}\" = {{ native = 1000 }}",
+            usd_mint, native_mint
+        ));
+
+        let amount = |amount: u64| I80F48::from_num(amount);
+        let value = |value: f64| I80F48::from_num(value);
+
+        assert!(config.is_token_account_dust(&usd_mint, amount(1_000_000), value(0.25)));
+        assert!(!config.is_token_account_dust(&usd_mint, amount(1), value(0.75)));
+
+        assert!(config.is_token_account_dust(&native_mint, amount(999), value(100.0)));
+        assert!(!config.is_token_account_dust(&native_mint, amount(1000), value(0.0)));
+
+        // Other mints keep the default $0.01 threshold
+        assert!(config.is_token_account_dust(&other_mint, amount(1_000_000), value(0.005)));
+        assert!(!config.is_token_account_dust(&other_mint, amount(1), value(0.25)));
+    }
+
+    #[test]
+    fn deposits_are_deferred_only_after_a_failed_sell() {
+        use RebalanceStep::*;
+
+        assert_eq!(
+            DepositPreferredTokens.failed_dependency(&[RepayLiabilities, SellNonPreferredDeposits]),
+            Some(SellNonPreferredDeposits)
+        );
+        assert_eq!(
+            DepositPreferredTokens.failed_dependency(&[HandleTokensInTokenAccounts]),
+            Some(HandleTokensInTokenAccounts)
+        );
+        assert_eq!(
+            DepositPreferredTokens.failed_dependency(&[RepayLiabilities, WrapExcessSol]),
+            None
+        );
+
+        // Steps without dependencies run after any failure
+        for step in [
+            WrapExcessSol,
+            SellNonPreferredDeposits,
+            RepayLiabilities,
+            HandleTokensInTokenAccounts,
+        ] {
+            assert_eq!(
+                step.failed_dependency(&[SellNonPreferredDeposits, HandleTokensInTokenAccounts]),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn scans_are_skipped_below_the_min_free_collateral() {
+        let free_collateral = |value: u32| move || Ok::<_, ProcessorError>(I80F48::from_num(value));
+
+        assert!(has_min_free_collateral(
+            None,
+            || -> Result<I80F48, ProcessorError> {
+                panic!("free collateral computed without a minimum")
+            }
+        ));
+        assert!(!has_min_free_collateral(Some(10.0), free_collateral(5)));
+        assert!(has_min_free_collateral(Some(10.0), free_collateral(10)));
+        assert!(has_min_free_collateral(Some(10.0), || {
+            Err(ProcessorError::Error("Failed to get free collateral"))
+        }));
+    }
+
+    #[test]
+    fn quoted_input_amount_is_checked_against_the_request() {
+        use std::cmp::Ordering;
+
+        // 10 bps of 1_000_000 is 1_000
+        assert_eq!(
+            compare_quoted_in_amount(1_000_000, 1_001_000, 10),
+            Ordering::Equal
+        );
+        assert_eq!(
+            compare_quoted_in_amount(1_000_000, 1_001_001, 10),
+            Ordering::Greater
+        );
+        assert_eq!(
+            compare_quoted_in_amount(1_000_000, 999_000, 10),
+            Ordering::Equal
+        );
+        assert_eq!(
+            compare_quoted_in_amount(1_000_000, 998_999, 10),
+            Ordering::Less
+        );
+        assert_eq!(
+            compare_quoted_in_amount(u64::MAX, u64::MAX, 0),
+            Ordering::Equal
+        );
+    }
+
+    #[test]
+    fn cached_capacity_expires_after_the_refresh_interval() {
+        let capacity = |age: Duration| LiquidatorCapacity {
+            free_collateral: I80F48::from_num(100),
+            max_borrows: HashMap::new(),
+            computed_at: Instant::now() - age,
+        };
+
+        assert!(capacity(Duration::ZERO).is_fresh(Duration::from_secs(10)));
+        assert!(!capacity(Duration::from_secs(11)).is_fresh(Duration::from_secs(10)));
+        assert!(!capacity(Duration::ZERO).is_fresh(Duration::ZERO));
+    }
+
+    #[test]
+    fn balance_delta_may_fall_short_by_the_tolerance() {
+        let expected_delta = I80F48::from_num(1_000_000);
+
+        assert_eq!(
+            min_balance_delta(expected_delta, 2500),
+            I80F48::from_num(750_000)
+        );
+        assert_eq!(min_balance_delta(expected_delta, 0), expected_delta);
+
+        // The default 1% tolerance, up to fixed point rounding
+        let min_delta = min_balance_delta(expected_delta, 100);
+        assert!((min_delta - I80F48::from_num(990_000)).abs() < I80F48::ONE);
+    }
+
+    #[test]
+    fn dust_checks_value_balances_with_the_configured_bias() {
+        assert!(test_config("")
+            .dust_check_price_bias
+            .to_price_bias()
+            .is_none());
+        assert!(matches!(
+            test_config("dust_check_price_bias = \"conservative\"")
+                .dust_check_price_bias
+                .to_price_bias(),
+            Some(PriceBias::Low)
+        ));
+    }
+
+    #[test]
+    fn expected_oracle_update_interval_can_be_overridden_per_oracle() {
+        let oracle = Pubkey::new_unique();
+        let other_oracle = Pubkey::new_unique();
+
+        assert_eq!(
+            test_config("").get_expected_oracle_update_interval(&oracle),
+            None
+        );
+
+        let config = test_config(&format!(
+            "expected_oracle_update_interval_ms = 2000\n[expected_oracle_update_intervals_ms]\n\"{}\" = 60000",
+            oracle
+        ));
+        assert_eq!(
+            config.get_expected_oracle_update_interval(&oracle),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            config.get_expected_oracle_update_interval(&other_oracle),
+            Some(Duration::from_secs(2))
+        );
+    }
+
+    #[test]
+    fn jupiter_circuit_breaker_opens_after_consecutive_failures() {
+        let breaker = CircuitBreaker::default();
+
+        assert_eq!(breaker.record(false, 3), None);
+        assert_eq!(breaker.record(false, 3), None);
+        // A success in between resets the count
+        assert_eq!(breaker.record(true, 3), None);
+        assert_eq!(breaker.record(false, 3), None);
+        assert_eq!(breaker.record(false, 3), None);
+        assert!(!breaker.is_open());
+
+        assert_eq!(breaker.record(false, 3), Some(true));
+        assert!(breaker.is_open());
+        // Only reported once
+        assert_eq!(breaker.record(false, 3), None);
+
+        assert_eq!(breaker.record(true, 3), Some(false));
+        assert!(!breaker.is_open());
+    }
+
+    #[test]
+    fn out_of_range_slippage_buffers_are_diagnosed() {
+        assert!(test_config("asset_slippage_buffer = 1.0")
+            .diagnose_noop_config()
+            .is_empty());
+
+        let warnings = test_config("asset_slippage_buffer = 1.5").diagnose_noop_config();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("asset_slippage_buffer is 1.5"));
+
+        let warnings = test_config("asset_slippage_buffer = 0.0\nliability_slippage_buffer = -1.0")
+            .diagnose_noop_config();
+        assert_eq!(warnings.len(), 2);
+        assert!(warnings[0].starts_with("asset_slippage_buffer is 0"));
+        assert!(warnings[1].starts_with("liability_slippage_buffer is -1"));
+    }
+
+    #[test]
+    fn asset_slippage_buffer_scales_the_submitted_amount() {
+        let max_liquidatable_asset_amount = I80F48::from_num(1_000_000);
+
+        let submitted_amount = |overrides: &str| {
+            let config = test_config(overrides);
+
+            // The liquidator can cover far more than the liquidatable amount
+            size_liquidation(
+                I80F48::from_num(1_000_000_000),
+                max_liquidatable_asset_amount,
+                config.liability_slippage_buffer,
+                config.asset_slippage_buffer,
+                Ok::<_, ()>,
+            )
+            .unwrap()
+            .asset_amount
+        };
+
+        assert_eq!(
+            submitted_amount("asset_slippage_buffer = 1.0"),
+            max_liquidatable_asset_amount
+        );
+        assert_eq!(
+            submitted_amount("asset_slippage_buffer = 0.75"),
+            I80F48::from_num(750_000)
+        );
+        assert_eq!(
+            submitted_amount(""),
+            max_liquidatable_asset_amount * I80F48!(0.98)
+        );
+    }
+
+    #[test]
+    fn max_borrow_fails_on_zero_prices_and_weights() {
+        let mut bank: Bank = bytemuck::Zeroable::zeroed();
+        bank.mint_decimals = 6;
+        bank.config.asset_weight_init = I80F48::from_num(0.5).into();
+        bank.config.liability_weight_init = I80F48::from_num(1.25).into();
+
+        let max_borrow = |bank: &Bank, lower_price: f64, higher_price: f64| {
+            checked_max_borrow_amount(
+                bank,
+                I80F48::from_num(150),
+                I80F48::from_num(50),
+                I80F48::from_num(50_000_000),
+                I80F48::from_num(lower_price),
+                I80F48::from_num(higher_price),
+            )
+        };
+
+        // $50 tied up by the deposit withdraws 50 / (2 * 0.5) = 50 tokens, the remaining $100
+        // borrows 100 / (2 * 1.25) = 40 tokens
+        assert_eq!(
+            max_borrow(&bank, 2.0, 2.0),
+            Some(I80F48::from_num(90_000_000))
+        );
+
+        assert_eq!(max_borrow(&bank, 0.0, 2.0), None);
+        assert_eq!(max_borrow(&bank, 2.0, 0.0), None);
+
+        let mut zero_liab_weight = bank;
+        zero_liab_weight.config.liability_weight_init = I80F48::ZERO.into();
+        assert_eq!(max_borrow(&zero_liab_weight, 2.0, 2.0), None);
+
+        // Without an asset weight the deposit is withdrawn in full on top of the borrow
+        let mut zero_asset_weight = bank;
+        zero_asset_weight.config.asset_weight_init = I80F48::ZERO.into();
+        assert_eq!(
+            max_borrow(&zero_asset_weight, 0.0, 2.0),
+            Some(I80F48::from_num(90_000_000))
+        );
+
+        assert_eq!(
+            checked_max_borrow_amount(
+                &bank,
+                I80F48::MAX,
+                I80F48::ZERO,
+                I80F48::ZERO,
+                I80F48::ONE,
+                I80F48::from_num(0.001),
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn watch_only_accounts_restrict_the_scan() {
+        let watched = Pubkey::new_unique();
+        let unloaded = Pubkey::new_unique();
+        let other = Pubkey::new_unique();
+
+        let accounts = DashMap::new();
+        for address in [watched, other] {
+            let account = MarginfiAccountWrapper::new(
+                address,
+                bytemuck::Zeroable::zeroed(),
+                Arc::new(DashMap::new()),
+            );
+            accounts.insert(address, Arc::new(RwLock::new(account)));
+        }
+
+        let addresses = |scanned: Vec<Arc<RwLock<MarginfiAccountWrapper>>>| {
+            scanned
+                .iter()
+                .map(|account| account.read().unwrap().address)
+                .collect::<HashSet<_>>()
+        };
+
+        assert_eq!(
+            addresses(select_scan_accounts(&accounts, None)),
+            HashSet::from([watched, other])
+        );
+        assert_eq!(
+            addresses(select_scan_accounts(&accounts, Some(&[watched, unloaded]))),
+            HashSet::from([watched])
+        );
+        assert!(select_scan_accounts(&accounts, Some(&[])).is_empty());
+
+        assert_eq!(test_config("").watch_only_accounts, None);
+        let config = test_config(&format!("watch_only_accounts = [\"{}\"]", watched));
+        assert_eq!(config.watch_only_accounts, Some(vec![watched]));
+    }
 }
@@ -0,0 +1,211 @@
+//! RPC clients over several endpoints, failing over to the next endpoint when one fails with a
+//! transient error
+//!
+//! Endpoints are tried from the healthiest, by error rate over their recent requests, to the
+//! least healthy, ties keeping the configured order so the primary endpoint is preferred.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc,
+};
+
+use log::warn;
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind},
+    rpc_client::RpcClient,
+    rpc_request::RpcError,
+};
+use solana_sdk::hash::Hash;
+
+use crate::metrics::metrics;
+
+/// Number of requests the error rate of an endpoint is computed over, older requests weigh
+/// half as much each time it is reached
+const ERROR_RATE_WINDOW: u64 = 1000;
+
+/// JSON-RPC error code of a node behind the cluster
+const NODE_UNHEALTHY_ERROR_CODE: i64 = -32005;
+
+struct RpcEndpoint {
+    /// Host of the endpoint, its full URL may carry an API key so it is never logged
+    label: String,
+    client: Arc<RpcClient>,
+    requests: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl RpcEndpoint {
+    fn new(url: &str) -> Self {
+        let label = reqwest::Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_string))
+            .unwrap_or_else(|| "unknown".to_string());
+
+        Self {
+            label,
+            client: Arc::new(RpcClient::new(url.to_string())),
+            requests: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    fn error_rate(&self) -> f64 {
+        let requests = self.requests.load(Ordering::Relaxed);
+
+        if requests == 0 {
+            return 0.0;
+        }
+
+        self.errors.load(Ordering::Relaxed) as f64 / requests as f64
+    }
+
+    fn record(&self, success: bool) {
+        let requests = self.requests.fetch_add(1, Ordering::Relaxed) + 1;
+
+        if !success {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // Concurrent records may be lost while halving, the rate stays approximately right
+        if requests >= ERROR_RATE_WINDOW {
+            self.requests.store(requests / 2, Ordering::Relaxed);
+            self.errors
+                .store(self.errors.load(Ordering::Relaxed) / 2, Ordering::Relaxed);
+        }
+
+        metrics()
+            .rpc_requests
+            .with_label_values(&[&self.label, if success { "ok" } else { "error" }])
+            .inc();
+    }
+}
+
+pub struct RpcPool {
+    endpoints: Vec<RpcEndpoint>,
+}
+
+impl RpcPool {
+    /// Pool over `urls`, the first one being the primary endpoint
+    pub fn new(urls: &[String]) -> anyhow::Result<Self> {
+        if urls.is_empty() {
+            anyhow::bail!("At least one RPC URL is required");
+        }
+
+        Ok(Self {
+            endpoints: urls.iter().map(|url| RpcEndpoint::new(url)).collect(),
+        })
+    }
+
+    /// Endpoints from the healthiest to the least healthy
+    fn ranked_endpoints(&self) -> Vec<&RpcEndpoint> {
+        let mut endpoints = self.endpoints.iter().collect::<Vec<_>>();
+
+        endpoints.sort_by(|a, b| a.error_rate().total_cmp(&b.error_rate()));
+
+        endpoints
+    }
+
+    /// Client of the healthiest endpoint, for calls that don't need failover
+    pub fn client(&self) -> Arc<RpcClient> {
+        self.ranked_endpoints()[0].client.clone()
+    }
+
+    /// Run `request` against the healthiest endpoint, moving on to the next one whenever it
+    /// fails with a transient error
+    ///
+    /// Other errors are returned as is, they wouldn't go away on another endpoint. When every
+    /// endpoint fails the last error is returned.
+    pub fn with_failover<T>(
+        &self,
+        request: impl Fn(&RpcClient) -> Result<T, ClientError>,
+    ) -> Result<T, ClientError> {
+        let endpoints = self.ranked_endpoints();
+        let mut last_err = None;
+
+        for (i, endpoint) in endpoints.iter().enumerate() {
+            match request(&endpoint.client) {
+                Ok(value) => {
+                    endpoint.record(true);
+                    return Ok(value);
+                }
+                Err(err) if is_transient_error(&err) => {
+                    endpoint.record(false);
+
+                    if i + 1 < endpoints.len() {
+                        warn!(
+                            "RPC request to {} failed, failing over to {}: {}",
+                            endpoint.label,
+                            endpoints[i + 1].label,
+                            err
+                        );
+                    }
+
+                    last_err = Some(err);
+                }
+                Err(err) => {
+                    endpoint.record(true);
+                    return Err(err);
+                }
+            }
+        }
+
+        Err(last_err.expect("RpcPool has at least one endpoint"))
+    }
+
+    pub fn get_latest_blockhash(&self) -> Result<Hash, ClientError> {
+        self.with_failover(|rpc| rpc.get_latest_blockhash())
+    }
+}
+
+/// Whether `err` is specific to the endpoint, timeouts, rate limits, server errors and
+/// unhealthy nodes, rather than to the request
+pub fn is_transient_error(err: &ClientError) -> bool {
+    match err.kind() {
+        ClientErrorKind::Io(_) => true,
+        ClientErrorKind::Reqwest(err) => {
+            err.is_timeout()
+                || err.is_connect()
+                || err.status().map_or(false, |status| {
+                    status.as_u16() == 429 || status.is_server_error()
+                })
+        }
+        ClientErrorKind::RpcError(RpcError::RpcResponseError { code, .. }) => {
+            *code == NODE_UNHEALTHY_ERROR_CODE
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use solana_client::rpc_request::RpcResponseErrorData;
+
+    fn rpc_response_error(code: i64) -> ClientError {
+        ClientErrorKind::RpcError(RpcError::RpcResponseError {
+            code,
+            message: "error".to_string(),
+            data: RpcResponseErrorData::Empty,
+        })
+        .into()
+    }
+
+    #[test]
+    fn only_endpoint_errors_are_transient() {
+        let io_error = std::io::Error::new(std::io::ErrorKind::ConnectionReset, "reset");
+        assert!(is_transient_error(&ClientErrorKind::Io(io_error).into()));
+        assert!(is_transient_error(&rpc_response_error(
+            NODE_UNHEALTHY_ERROR_CODE
+        )));
+
+        // Errors of the request itself fail the same way on every endpoint
+        assert!(!is_transient_error(&rpc_response_error(-32602)));
+        assert!(!is_transient_error(
+            &ClientErrorKind::RpcError(RpcError::ForUser("invalid".to_string())).into()
+        ));
+        assert!(!is_transient_error(
+            &ClientErrorKind::Custom("custom".to_string()).into()
+        ));
+    }
+}
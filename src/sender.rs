@@ -1,35 +1,100 @@
-use std::time::Duration;
-use std::{error::Error, sync::Arc};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use log::{error, info};
+use log::{debug, error, info, warn};
 use serde::Deserialize;
-use solana_client::rpc_client::{RpcClient, SerializableTransaction};
+use solana_client::client_error::ClientError;
+use solana_client::rpc_client::SerializableTransaction;
 use solana_client::rpc_config::RpcSimulateTransactionConfig;
 use solana_sdk::commitment_config::CommitmentConfig;
 
+use solana_sdk::bs58;
+use solana_sdk::instruction::Instruction;
+use solana_sdk::pubkey::Pubkey;
 use solana_sdk::signature::Signature;
+use solana_sdk::system_instruction;
+use solana_sdk::transaction::{Transaction, TransactionError};
 
-#[derive(Debug, Deserialize)]
+use crate::{rpc_pool::RpcPool, utils::from_pubkey_string};
+
+#[derive(thiserror::Error, Debug)]
+pub enum SendTxError {
+    #[error("Transaction simulation failed: {0}")]
+    SimulationFailed(TransactionError),
+    #[error("RpcError: {0}")]
+    RpcError(#[from] ClientError),
+    #[error("Send task failed: {0}")]
+    TaskFailed(#[from] tokio::task::JoinError),
+    #[error("Block engine request failed: {0}")]
+    BlockEngineRequestFailed(#[from] reqwest::Error),
+    #[error("Bundle rejected by the block engine: {0}")]
+    BundleRejected(String),
+    #[error("Transaction {0} failed: {1}")]
+    TransactionFailed(Signature, TransactionError),
+    #[error("Transaction {0} not confirmed in time")]
+    ConfirmationTimeout(Signature),
+}
+
+impl SendTxError {
+    /// The transaction error behind the failure, if the transaction itself failed
+    pub fn transaction_error(&self) -> Option<TransactionError> {
+        match self {
+            SendTxError::SimulationFailed(err) => Some(err.clone()),
+            SendTxError::RpcError(err) => err.get_transaction_error(),
+            SendTxError::TransactionFailed(_, err) => Some(err.clone()),
+            SendTxError::TaskFailed(_)
+            | SendTxError::BlockEngineRequestFailed(_)
+            | SendTxError::BundleRejected(_)
+            | SendTxError::ConfirmationTimeout(_) => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
 pub struct SenderCfg {
+    /// Number of times the transaction is sent before waiting for its confirmation
     #[serde(default = "SenderCfg::default_spam_times")]
-    spam_times: u64,
+    pub spam_times: u64,
+    /// Interval between two sends of the transaction
+    #[serde(default = "SenderCfg::default_resend_interval")]
+    pub resend_interval: Duration,
+    /// Send without simulating the transaction first
     #[serde(default = "SenderCfg::default_skip_preflight")]
-    skip_preflight: bool,
+    pub skip_preflight: bool,
     #[serde(default = "SenderCfg::default_timeout")]
-    timeout: Duration,
+    pub timeout: Duration,
+    /// Wait for the transaction to be confirmed before returning, otherwise return as soon as
+    /// it is sent
+    #[serde(default = "SenderCfg::default_wait_for_confirmation")]
+    pub wait_for_confirmation: bool,
+    /// Maximum time waited for the confirmation of a sent transaction
+    #[serde(default = "SenderCfg::default_confirmation_timeout")]
+    pub confirmation_timeout: Duration,
+    /// Interval between two polls of the transaction's signature status while waiting for its
+    /// confirmation
+    #[serde(default = "SenderCfg::default_confirmation_poll_interval")]
+    pub confirmation_poll_interval: Duration,
 }
 
 impl SenderCfg {
     pub const DEFAULT: SenderCfg = SenderCfg {
         spam_times: 12,
+        resend_interval: Duration::ZERO,
         skip_preflight: false,
         timeout: Duration::from_secs(45),
+        wait_for_confirmation: true,
+        confirmation_timeout: Duration::from_secs(45),
+        confirmation_poll_interval: Duration::from_millis(500),
     };
 
     pub const fn default_spam_times() -> u64 {
         Self::DEFAULT.spam_times
     }
 
+    pub const fn default_resend_interval() -> Duration {
+        Self::DEFAULT.resend_interval
+    }
+
     pub const fn default_skip_preflight() -> bool {
         Self::DEFAULT.skip_preflight
     }
@@ -37,42 +102,347 @@ impl SenderCfg {
     const fn default_timeout() -> Duration {
         Self::DEFAULT.timeout
     }
+
+    pub const fn default_wait_for_confirmation() -> bool {
+        Self::DEFAULT.wait_for_confirmation
+    }
+
+    pub const fn default_confirmation_timeout() -> Duration {
+        Self::DEFAULT.confirmation_timeout
+    }
+
+    const fn default_confirmation_poll_interval() -> Duration {
+        Self::DEFAULT.confirmation_poll_interval
+    }
 }
 
-pub fn aggressive_send_tx(
-    rpc: Arc<RpcClient>,
-    transaction: &impl SerializableTransaction,
-    cfg: SenderCfg,
-) -> Result<Signature, Box<dyn Error>> {
-    let signature = *transaction.get_signature();
+/// Jito block engine settings, liquidations are submitted as bundles tipping `tip_account`
+/// when these are set
+#[derive(Debug, Clone, Deserialize)]
+pub struct JitoCfg {
+    /// Example: "https://mainnet.block-engine.jito.wtf"
+    pub block_engine_url: String,
+    /// Lamports transferred to `tip_account` by the bundle
+    pub tip_lamports: u64,
+    /// One of the tip accounts returned by the block engine's `getTipAccounts`
+    ///
+    /// Example: "96gYZGLnJYVFmbjzopPSU6QiEV5fGqZNyN9nmNhvrZU5"
+    #[serde(deserialize_with = "from_pubkey_string")]
+    pub tip_account: Pubkey,
+}
 
-    info!("Sending transaction: {}", signature.to_string());
+impl JitoCfg {
+    /// Instruction paying the bundle's tip from `payer`, it has to be part of one of the
+    /// bundle's transactions
+    pub fn make_tip_ix(&self, payer: &Pubkey) -> Instruction {
+        system_instruction::transfer(payer, &self.tip_account, self.tip_lamports)
+    }
 
-    if !cfg.skip_preflight {
-        let res = rpc.simulate_transaction_with_config(
+    fn bundles_url(&self) -> String {
+        format!(
+            "{}/api/v1/bundles",
+            self.block_engine_url.trim_end_matches('/')
+        )
+    }
+}
+
+/// JSON-RPC `sendBundle` request for the block engine, transactions are base58 encoded and
+/// executed in order
+pub fn make_bundle_payload(transactions: &[Transaction]) -> Result<serde_json::Value, SendTxError> {
+    let encoded = transactions
+        .iter()
+        .map(|tx| {
+            bincode::serialize(tx)
+                .map(|bytes| bs58::encode(bytes).into_string())
+                .map_err(|e| SendTxError::BundleRejected(format!("Failed to serialize: {}", e)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(serde_json::json!({
+        "jsonrpc": "2.0",
+        "id": 1,
+        "method": "sendBundle",
+        "params": [encoded],
+    }))
+}
+
+fn simulate_tx(
+    rpc: &RpcPool,
+    transaction: &impl SerializableTransaction,
+) -> Result<(), SendTxError> {
+    let signature = transaction.get_signature();
+
+    let res = rpc.with_failover(|rpc| {
+        rpc.simulate_transaction_with_config(
             transaction,
             RpcSimulateTransactionConfig {
                 commitment: Some(CommitmentConfig::processed()),
                 ..Default::default()
             },
-        )?;
+        )
+    })?;
+
+    if let Some(err) = res.value.err.clone() {
+        error!("Simulation of transaction {} failed: {:?}", signature, err);
+        debug!("Simulation logs: {:#?}", res.value.logs);
+        return Err(SendTxError::SimulationFailed(err));
+    }
+
+    Ok(())
+}
+
+/// Poll the status of `signature` until it is confirmed, fails, or `cfg.confirmation_timeout`
+/// elapses
+///
+/// Failed status polls are retried until the timeout, a transaction that failed on-chain is
+/// returned as [`SendTxError::TransactionFailed`].
+pub fn confirm_tx(
+    rpc: &RpcPool,
+    signature: &Signature,
+    cfg: &SenderCfg,
+) -> Result<(), SendTxError> {
+    let start = Instant::now();
+
+    while start.elapsed() < cfg.confirmation_timeout {
+        match rpc.with_failover(|rpc| rpc.get_signature_statuses(&[*signature])) {
+            Ok(statuses) => {
+                if let Some(Some(status)) = statuses.value.first() {
+                    if let Some(err) = &status.err {
+                        error!("Transaction {} failed: {:?}", signature, err);
+                        return Err(SendTxError::TransactionFailed(*signature, err.clone()));
+                    }
 
-        if res.value.err.is_some() {
-            error!("Failed to simulate transaction: {:#?}", res.value);
-            return Err("Transaction simulation failed".into());
+                    if status.satisfies_commitment(CommitmentConfig::confirmed()) {
+                        return Ok(());
+                    }
+                }
+            }
+            Err(e) => warn!("Failed to get status of transaction {}: {:?}", signature, e),
         }
+
+        std::thread::sleep(cfg.confirmation_poll_interval);
+    }
+
+    warn!(
+        "Transaction {} not confirmed within {:?}",
+        signature, cfg.confirmation_timeout
+    );
+
+    Err(SendTxError::ConfirmationTimeout(*signature))
+}
+
+/// Simulate, send and, unless disabled, confirm `transaction`, each RPC call failing over
+/// across the pool's endpoints
+pub fn aggressive_send_tx(
+    rpc: Arc<RpcPool>,
+    transaction: &impl SerializableTransaction,
+    cfg: SenderCfg,
+) -> Result<Signature, SendTxError> {
+    let signature = *transaction.get_signature();
+
+    info!("Sending transaction: {}", signature.to_string());
+
+    if !cfg.skip_preflight {
+        simulate_tx(&rpc, transaction)?;
     }
 
-    (0..cfg.spam_times).try_for_each(|_| {
-        rpc.send_transaction(transaction)?;
-        Ok::<_, Box<dyn Error>>(())
+    (0..cfg.spam_times).try_for_each(|i| {
+        if i > 0 && !cfg.resend_interval.is_zero() {
+            std::thread::sleep(cfg.resend_interval);
+        }
+        rpc.with_failover(|rpc| rpc.send_transaction(transaction))?;
+        Ok::<_, SendTxError>(())
     })?;
 
-    let blockhash = transaction.get_recent_blockhash();
+    if !cfg.wait_for_confirmation {
+        info!("Sent transaction: {}", signature.to_string());
+        return Ok(signature);
+    }
 
-    rpc.confirm_transaction_with_spinner(&signature, blockhash, CommitmentConfig::confirmed())?;
+    confirm_tx(&rpc, &signature, &cfg)?;
 
     info!("Confirmed transaction: {}", signature.to_string());
 
     Ok(signature)
 }
+
+/// Submit `transactions` as a single Jito bundle and, unless disabled, wait for the
+/// confirmation of the last one, whose signature is returned
+///
+/// The tip is expected to already be part of one of the transactions, see
+/// [`JitoCfg::make_tip_ix`]. Bundles land atomically, so confirming the last transaction
+/// confirms the whole bundle.
+pub fn send_bundle(
+    rpc: Arc<RpcPool>,
+    transactions: &[Transaction],
+    jito_cfg: &JitoCfg,
+    cfg: SenderCfg,
+) -> Result<Signature, SendTxError> {
+    let last_tx = transactions
+        .last()
+        .ok_or_else(|| SendTxError::BundleRejected("Empty bundle".to_string()))?;
+    let signature = *last_tx.get_signature();
+
+    if !cfg.skip_preflight {
+        transactions
+            .iter()
+            .try_for_each(|tx| simulate_tx(&rpc, tx))?;
+    }
+
+    let payload = make_bundle_payload(transactions)?;
+
+    info!(
+        "Sending bundle of {} transactions ending with {}",
+        transactions.len(),
+        signature
+    );
+
+    let response = post_bundle(jito_cfg.bundles_url(), payload, cfg.timeout)?;
+
+    if let Some(err) = response.get("error") {
+        return Err(SendTxError::BundleRejected(err.to_string()));
+    }
+
+    debug!("Bundle id: {:?}", response.get("result"));
+
+    if !cfg.wait_for_confirmation {
+        info!("Sent bundle transaction: {}", signature);
+        return Ok(signature);
+    }
+
+    confirm_tx(&rpc, &signature, &cfg)?;
+
+    info!("Confirmed bundle transaction: {}", signature);
+
+    Ok(signature)
+}
+
+/// POST a `sendBundle` payload to the block engine from a dedicated thread
+///
+/// The blocking reqwest client panics when used or dropped within a tokio runtime context,
+/// which the liquidation path runs in, so it never runs on the calling thread.
+fn post_bundle(
+    url: String,
+    payload: serde_json::Value,
+    timeout: Duration,
+) -> Result<serde_json::Value, SendTxError> {
+    std::thread::spawn(move || {
+        reqwest::blocking::Client::new()
+            .post(url)
+            .timeout(timeout)
+            .json(&payload)
+            .send()?
+            .json()
+    })
+    .join()
+    .map_err(|_| SendTxError::BundleRejected("Block engine request panicked".to_string()))?
+    .map_err(SendTxError::from)
+}
+
+/// [`aggressive_send_tx`] run on the runtime's blocking pool, so that the calling task's worker
+/// thread isn't held for the whole send and confirmation loop
+pub async fn aggressive_send_tx_async<T>(
+    rpc: Arc<RpcPool>,
+    transaction: T,
+    cfg: SenderCfg,
+) -> Result<Signature, SendTxError>
+where
+    T: SerializableTransaction + Send + 'static,
+{
+    run_on_blocking_pool(move || aggressive_send_tx(rpc, &transaction, cfg)).await
+}
+
+/// Run the blocking `send` on the runtime's blocking pool, a panicking send fails with
+/// [`SendTxError::TaskFailed`]
+async fn run_on_blocking_pool<R: Send + 'static>(
+    send: impl FnOnce() -> Result<R, SendTxError> + Send + 'static,
+) -> Result<R, SendTxError> {
+    tokio::task::spawn_blocking(send).await?
+}
+
+#[cfg(test)]
+mod tests {
+    use solana_sdk::{signature::Keypair, signer::Signer};
+
+    use super::*;
+
+    fn make_transfer_tx(payer: &Keypair, lamports: u64) -> Transaction {
+        Transaction::new_with_payer(
+            &[system_instruction::transfer(
+                &payer.pubkey(),
+                &Pubkey::new_unique(),
+                lamports,
+            )],
+            Some(&payer.pubkey()),
+        )
+    }
+
+    #[test]
+    fn bundle_payload_encodes_transactions_in_order() {
+        let payer = Keypair::new();
+        let transactions = vec![make_transfer_tx(&payer, 1), make_transfer_tx(&payer, 2)];
+
+        let payload = make_bundle_payload(&transactions).unwrap();
+
+        assert_eq!(payload["jsonrpc"], "2.0");
+        assert_eq!(payload["method"], "sendBundle");
+
+        let params = payload["params"].as_array().unwrap();
+        assert_eq!(params.len(), 1);
+
+        let encoded = params[0].as_array().unwrap();
+        assert_eq!(encoded.len(), transactions.len());
+
+        for (encoded_tx, tx) in encoded.iter().zip(transactions.iter()) {
+            let bytes = bs58::decode(encoded_tx.as_str().unwrap())
+                .into_vec()
+                .unwrap();
+            let decoded: Transaction = bincode::deserialize(&bytes).unwrap();
+
+            assert_eq!(&decoded, tx);
+        }
+    }
+
+    #[test]
+    fn bundle_tip_ix_transfers_to_the_tip_account() {
+        let jito_cfg = JitoCfg {
+            block_engine_url: "https://mainnet.block-engine.jito.wtf/".to_string(),
+            tip_lamports: 10_000,
+            tip_account: Pubkey::new_unique(),
+        };
+        let payer = Pubkey::new_unique();
+
+        assert_eq!(
+            jito_cfg.make_tip_ix(&payer),
+            system_instruction::transfer(&payer, &jito_cfg.tip_account, 10_000)
+        );
+        assert_eq!(
+            jito_cfg.bundles_url(),
+            "https://mainnet.block-engine.jito.wtf/api/v1/bundles"
+        );
+    }
+
+    #[test]
+    fn blocking_sends_run_off_the_calling_worker() {
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("worker")
+            .build()
+            .unwrap();
+
+        let sending_thread = runtime
+            .block_on(runtime.spawn(run_on_blocking_pool(|| Ok(std::thread::current().id()))))
+            .unwrap()
+            .unwrap();
+        let worker_thread = runtime
+            .block_on(runtime.spawn(async { std::thread::current().id() }))
+            .unwrap();
+        assert_ne!(sending_thread, worker_thread);
+
+        let result = runtime.block_on(run_on_blocking_pool::<Signature>(|| {
+            panic!("send panicked")
+        }));
+        assert!(matches!(result, Err(SendTxError::TaskFailed(_))));
+        assert!(result.unwrap_err().transaction_error().is_none());
+    }
+}
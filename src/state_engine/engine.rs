@@ -1,5 +1,6 @@
 use crossbeam::channel::Receiver;
 use crossbeam::channel::Sender;
+use crossbeam::channel::TrySendError;
 use fixed::types::I80F48;
 use log::info;
 use log::trace;
@@ -16,8 +17,13 @@ use solana_account_decoder::UiDataSliceConfig;
 use solana_sdk::bs58;
 use solana_sdk::commitment_config::CommitmentConfig;
 use solana_sdk::pubkey;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::sync::RwLock;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use anchor_client::anchor_lang::Discriminator;
 use anchor_client::Program;
@@ -30,24 +36,152 @@ use solana_client::{
     rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
     rpc_filter::{Memcmp, MemcmpEncodedBytes, RpcFilterType},
 };
-use solana_program::{account_info::IntoAccountInfo, program_pack::Pack, pubkey::Pubkey};
+use solana_program::{account_info::IntoAccountInfo, pubkey::Pubkey};
 use solana_sdk::{account::Account, signature::Keypair};
 
+use crate::rpc_pool::RpcPool;
 use crate::state_engine::geyser::GeyserService;
 use crate::token_account_manager::TokenAccountManager;
 use crate::utils::{
-    accessor, batch_get_multiple_accounts, from_option_vec_pubkey_string, from_pubkey_string,
+    accessor, batch_get_multiple_accounts, batch_get_multiple_accounts_with_failures,
+    from_option_vec_pubkey_string, from_pubkey_string, from_pubkey_string_map, get_oracle_keys,
     BatchLoadingConfig,
 };
 
 use super::geyser::GeyserServiceConfig;
 use super::marginfi_account::MarginfiAccountWrapper;
+use super::snapshot::StateSnapshot;
 
+const BANK_MINT_OFFSET: usize = 8;
 const BANK_GROUP_PK_OFFSET: usize = 32 + 1 + 8;
 
+/// Addresses of the `count` least relevant accounts given their maintenance liability ratio,
+/// accounts whose health can't be computed first since they can't be liquidated either
+fn least_relevant_accounts(
+    mut accounts: Vec<(Option<I80F48>, Pubkey)>,
+    count: usize,
+) -> Vec<Pubkey> {
+    // `None` orders before any ratio
+    accounts.sort_by(|a, b| a.0.cmp(&b.0));
+
+    accounts
+        .into_iter()
+        .take(count)
+        .map(|(_, address)| address)
+        .collect()
+}
+
+/// Whether a price adapter last refreshed at `last_refreshed_at` must wait before being refreshed
+/// again at `now`, never with no `min_refresh_interval`
+fn is_debounced(
+    min_refresh_interval: Option<Duration>,
+    last_refreshed_at: Option<Instant>,
+    now: Instant,
+) -> bool {
+    min_refresh_interval
+        .zip(last_refreshed_at)
+        .map_or(false, |(interval, last_refreshed_at)| {
+            now.saturating_duration_since(last_refreshed_at) < interval
+        })
+}
+
+/// Maximum age in seconds of the price of a bank with `bank_oracle_max_age`, which is unset when
+/// zero, falling back to `default_max_age_secs`
+fn oracle_max_age_secs(bank_oracle_max_age: u16, default_max_age_secs: Option<u64>) -> Option<u64> {
+    match bank_oracle_max_age {
+        0 => default_max_age_secs,
+        bank_oracle_max_age => Some(bank_oracle_max_age as u64),
+    }
+}
+
+/// Share of the deposits of `bank` that is currently borrowed
+fn calc_bank_utilization(bank: &Bank) -> anyhow::Result<I80F48> {
+    let total_assets = bank.get_asset_amount(bank.total_asset_shares.into())?;
+    let total_liabilities = bank.get_liability_amount(bank.total_liability_shares.into())?;
+
+    if total_assets.is_zero() {
+        return Ok(I80F48::ZERO);
+    }
+
+    total_liabilities
+        .checked_div(total_assets)
+        .ok_or_else(|| anyhow::anyhow!("Failed to compute utilization"))
+}
+
+/// `amount` in native units of a mint with `mint_decimals` decimals, in whole tokens
+fn native_to_ui_amount(amount: u64, mint_decimals: u8) -> Option<I80F48> {
+    EXP_10_I80F48
+        .get(mint_decimals as usize)
+        .and_then(|decimal_scale| I80F48::from_num(amount).checked_div(*decimal_scale))
+}
+
+/// Balance of a fetched token account, `None` when the account doesn't exist yet
+fn existing_token_account_balance(token_account: Option<&Account>) -> Option<u64> {
+    token_account.map(|token_account| accessor::amount(&token_account.data))
+}
+
+/// Take the updates buffered in `pending_updates`, updates buffered concurrently are either
+/// taken or left for the next drain, never lost
+fn drain_pending_updates(pending_updates: &DashMap<Pubkey, Account>) -> Vec<(Pubkey, Account)> {
+    let addresses = pending_updates
+        .iter()
+        .map(|entry| *entry.key())
+        .collect::<Vec<_>>();
+
+    addresses
+        .into_iter()
+        .filter_map(|address| pending_updates.remove(&address))
+        .collect()
+}
+
+/// Store `account` at `address`, updating a tracked account in place so that holders of its
+/// wrapper see the new state, and bumping its version to invalidate values computed from it
+fn upsert_marginfi_account(
+    marginfi_accounts: &DashMap<Pubkey, Arc<RwLock<MarginfiAccountWrapper>>>,
+    address: Pubkey,
+    account: &MarginfiAccount,
+    banks: &Arc<DashMap<Pubkey, Arc<RwLock<BankWrapper>>>>,
+) {
+    marginfi_accounts
+        .entry(address)
+        .and_modify(|marginfi_account_ref| {
+            let mut marginfi_account_guard = marginfi_account_ref.write().unwrap();
+            marginfi_account_guard.account = *account;
+            marginfi_account_guard.version += 1;
+        })
+        .or_insert_with(|| {
+            Arc::new(RwLock::new(MarginfiAccountWrapper::new(
+                address,
+                *account,
+                banks.clone(),
+            )))
+        });
+}
+
+/// Check that account data starts with the anchor discriminator of `T`, so that an account of
+/// another type is never cast into `T`
+fn check_discriminator<T: Discriminator>(address: &Pubkey, data: &[u8]) -> anyhow::Result<()> {
+    if data.len() < 8 || data[..8] != T::DISCRIMINATOR {
+        return Err(anyhow::anyhow!(
+            "Account {} has an invalid discriminator",
+            address
+        ));
+    }
+
+    Ok(())
+}
+
+#[derive(Clone)]
 pub struct OracleWrapper {
     pub address: Pubkey,
     pub price_adapter: OraclePriceFeedAdapter,
+    /// When the price was loaded from the RPC
+    pub loaded_at: Instant,
+    /// When the price was last updated from the stream, `None` until the first update
+    pub last_streamed_at: Option<Instant>,
+    /// When a price within `oracle_max_age_secs` was last applied, `None` while the oracle
+    /// only ever reported stale prices
+    pub last_good_price_at: Option<Instant>,
 }
 
 impl OracleWrapper {
@@ -55,25 +189,92 @@ impl OracleWrapper {
         Self {
             address,
             price_adapter,
+            loaded_at: Instant::now(),
+            last_streamed_at: None,
+            last_good_price_at: None,
         }
     }
+
+    /// Whether the price can be relied on: it was updated from the stream at least once, or it
+    /// was loaded less than `initial_grace` ago and the stream didn't catch up yet
+    ///
+    /// Prices loaded at startup have an unknown age, the grace gives the stream time to
+    /// refresh them without rejecting them all right after startup, while prices the stream
+    /// never refreshes are eventually refused.
+    pub fn is_warm(&self, initial_grace: Duration) -> bool {
+        self.last_streamed_at.is_some() || self.loaded_at.elapsed() <= initial_grace
+    }
+
+    /// Time since the price was last updated, from the stream or the initial load
+    pub fn update_age(&self) -> Duration {
+        self.last_streamed_at.unwrap_or(self.loaded_at).elapsed()
+    }
+
+    /// Whether the last good price is older than `max_age`, or there never was one
+    pub fn is_stale(&self, max_age: Duration) -> bool {
+        self.last_good_price_at.map_or(true, |last_good_price_at| {
+            last_good_price_at.elapsed() > max_age
+        })
+    }
 }
 
+#[derive(Clone)]
 pub struct BankWrapper {
     pub address: Pubkey,
     pub bank: Bank,
     pub oracle_adapter: OracleWrapper,
+    pub oracle_price_type_override: Option<OraclePriceType>,
+    /// Bumped on every bank or oracle update, used to invalidate values computed from the bank
+    pub version: u64,
 }
 
 impl BankWrapper {
-    pub fn new(address: Pubkey, bank: Bank, oracle_adapter_wrapper: OracleWrapper) -> Self {
+    pub fn new(
+        address: Pubkey,
+        bank: Bank,
+        oracle_adapter_wrapper: OracleWrapper,
+        oracle_price_type_override: Option<OraclePriceType>,
+    ) -> Self {
         Self {
             address,
             bank,
             oracle_adapter: oracle_adapter_wrapper,
+            oracle_price_type_override,
+            version: 0,
         }
     }
 
+    /// Maximum age of the bank's oracle price, the bank's own `oracle_max_age` when set, the
+    /// `default_max_age_secs` otherwise
+    pub fn get_oracle_max_age(&self, default_max_age_secs: Option<u64>) -> Option<Duration> {
+        oracle_max_age_secs(self.bank.config.oracle_max_age, default_max_age_secs)
+            .map(Duration::from_secs)
+    }
+
+    /// Oracle price type used for weighted values, the per-bank override if one is configured,
+    /// otherwise the one matching the requirement type
+    pub fn get_oracle_price_type(&self, requirement_type: RequirementType) -> OraclePriceType {
+        self.oracle_price_type_override
+            .unwrap_or_else(|| requirement_type.get_oracle_price_type())
+    }
+
+    /// Whether the oracle yields a positive price of every type and bias values are computed
+    /// with, pricing the bank panics otherwise
+    pub fn is_priceable(&self) -> bool {
+        [OraclePriceType::RealTime, OraclePriceType::TimeWeighted]
+            .into_iter()
+            .flat_map(|oracle_type| {
+                [None, Some(PriceBias::Low), Some(PriceBias::High)]
+                    .map(move |price_bias| (oracle_type, price_bias))
+            })
+            .all(|(oracle_type, price_bias)| {
+                self.oracle_adapter
+                    .price_adapter
+                    .get_price_of_type(oracle_type, price_bias)
+                    .map_or(false, |price| price.is_positive())
+            })
+    }
+
     fn get_pricing_params(
         &self,
         side: BalanceSide,
@@ -138,6 +339,45 @@ impl BankWrapper {
         Ok(calc_value(amount, price, self.bank.mint_decimals, None)?)
     }
 
+    /// Value of `amount` at the real-time price with `price_bias`, unweighted
+    pub fn calc_biased_value(
+        &self,
+        amount: I80F48,
+        price_bias: Option<PriceBias>,
+    ) -> anyhow::Result<I80F48> {
+        let price = self
+            .oracle_adapter
+            .price_adapter
+            .get_price_of_type(OraclePriceType::RealTime, price_bias)?;
+
+        Ok(calc_value(amount, price, self.bank.mint_decimals, None)?)
+    }
+
+    /// Share of the bank's deposits that is currently borrowed
+    pub fn calc_utilization(&self) -> anyhow::Result<I80F48> {
+        calc_bank_utilization(&self.bank)
+    }
+
+    /// Current lending and borrowing APRs, as `(lending_apr, borrowing_apr)`
+    ///
+    /// Rates follow the bank's on-chain interest rate curve at the current utilization: the base
+    /// rate grows linearly up to the optimal utilization rate, then linearly up to the max
+    /// interest rate at full utilization. The borrowing rate includes the group and insurance
+    /// fees, and the lending rate is what remains for depositors after them. Rates are simple
+    /// annual rates, compounding is not accounted for.
+    pub fn calc_interest_rates(&self) -> anyhow::Result<(I80F48, I80F48)> {
+        let utilization = self.calc_utilization()?;
+
+        let (lending_apr, borrowing_apr, _, _) = self
+            .bank
+            .config
+            .interest_rate_config
+            .calc_interest_rate(utilization)
+            .ok_or_else(|| anyhow::anyhow!("Failed to compute interest rates"))?;
+
+        Ok((lending_apr, borrowing_apr))
+    }
+
     pub fn calc_weighted_value(
         &self,
         amount: I80F48,
@@ -145,6 +385,7 @@ impl BankWrapper {
         requirement_type: RequirementType,
     ) -> anyhow::Result<I80F48> {
         let (weight, price_bias, oracle_type) = self.get_pricing_params(side, requirement_type);
+        let oracle_type = self.oracle_price_type_override.unwrap_or(oracle_type);
 
         let price = self
             .oracle_adapter
@@ -171,21 +412,26 @@ pub struct TokenAccountWrapper {
 
 impl TokenAccountWrapper {
     pub fn get_value(&self) -> Result<I80F48, Box<dyn std::error::Error>> {
-        let ui_amount = {
-            let amount = I80F48::from_num(self.balance);
-            let decimal_scale = EXP_10_I80F48[self.mint_decimals as usize];
+        self.get_value_with_bias(None)
+    }
 
-            amount
-                .checked_div(decimal_scale)
-                .ok_or("Failed to divide")?
-        };
+    /// Value of the balance at the real-time price with `price_bias`
+    pub fn get_value_with_bias(
+        &self,
+        price_bias: Option<PriceBias>,
+    ) -> Result<I80F48, Box<dyn std::error::Error>> {
+        let ui_amount =
+            native_to_ui_amount(self.balance, self.mint_decimals).ok_or("Failed to divide")?;
         let price = self
             .bank
             .read()
             .unwrap()
             .oracle_adapter
             .price_adapter
-            .get_price_of_type(marginfi::state::price::OraclePriceType::RealTime, None)?;
+            .get_price_of_type(
+                marginfi::state::price::OraclePriceType::RealTime,
+                price_bias,
+            )?;
 
         trace!(
             "Token account {} (mint: {}) balance: {} @ ${:.5} - ${:.5}",
@@ -207,6 +453,15 @@ impl TokenAccountWrapper {
 #[derive(Debug, serde::Deserialize, serde::Serialize, Clone)]
 pub struct StateEngineConfig {
     pub rpc_url: String,
+    /// RPC endpoints failed over to, in order, when `rpc_url` fails with transient errors
+    ///
+    /// Blockhash fetches, batched account loads and transaction sends fail over across all
+    /// endpoints, other calls go to the endpoint with the lowest recent error rate. The
+    /// geyser stream, anchor client and program account scans stay on `rpc_url`.
+    ///
+    /// Example: ["https://api.mainnet-beta.solana.com"]
+    #[serde(default = "StateEngineConfig::default_fallback_rpc_urls")]
+    pub fallback_rpc_urls: Vec<String>,
     pub yellowstone_endpoint: String,
     pub yellowstone_x_token: Option<String>,
 
@@ -230,6 +485,153 @@ pub struct StateEngineConfig {
         default = "StateEngineConfig::default_account_whitelist"
     )]
     pub account_whitelist: Option<Vec<Pubkey>>,
+    /// Per-bank override of the oracle price type used in weighted value calculations,
+    /// keyed by bank address
+    ///
+    /// Without an override the price type follows the requirement type, real-time for
+    /// maintenance and time-weighted for initial requirements.
+    #[serde(
+        deserialize_with = "from_pubkey_string_map",
+        default = "StateEngineConfig::default_oracle_price_type_overrides"
+    )]
+    pub oracle_price_type_overrides: HashMap<Pubkey, OraclePriceTypeOverride>,
+    /// Reject bank and marginfi account updates whose data doesn't start with the expected
+    /// account discriminator, instead of casting them as is
+    ///
+    /// Default: true
+    #[serde(default = "StateEngineConfig::default_validate_account_discriminators")]
+    pub validate_account_discriminators: bool,
+    /// Number of marginfi accounts fetched per RPC call when refreshing all accounts
+    ///
+    /// Default: 100
+    #[serde(default = "StateEngineConfig::default_marginfi_account_refresh_batch_size")]
+    pub marginfi_account_refresh_batch_size: usize,
+    /// Keep loading marginfi accounts when some RPC calls fail after their retries, retrying
+    /// only the failed accounts once instead of failing the whole load
+    ///
+    /// Default: true
+    #[serde(default = "StateEngineConfig::default_allow_partial_account_loading")]
+    pub allow_partial_account_loading: bool,
+    /// Maximum number of marginfi accounts tracked, the least relevant accounts (no
+    /// liabilities, then healthiest) are evicted beyond it
+    ///
+    /// Eviction trades memory for possibly missed candidates: an evicted account is only
+    /// readmitted when one of its own updates shows it approaching liquidation, so an evicted
+    /// account pushed towards liquidation by price moves alone goes unnoticed.
+    ///
+    /// The limit is enforced after the initial load and after each periodic refresh, accounts
+    /// first seen through the stream in between can exceed it until the next refresh.
+    pub max_tracked_accounts: Option<usize>,
+    /// Maintenance liability to asset ratio from which an evicted account is readmitted, the
+    /// liquidation threshold being 1
+    ///
+    /// Default: 0.9
+    #[serde(default = "StateEngineConfig::default_readmit_liability_ratio")]
+    pub readmit_liability_ratio: f64,
+    /// How oracle price adapters are refreshed on oracle account updates, see
+    /// [`OracleRefreshStrategy`]
+    ///
+    /// Default: full
+    #[serde(default = "StateEngineConfig::default_oracle_refresh_strategy")]
+    pub oracle_refresh_strategy: OracleRefreshStrategy,
+    /// Minimum interval in milliseconds between two refreshes of an oracle's price adapter
+    /// with the `debounced` strategy
+    ///
+    /// Default: 200
+    #[serde(default = "StateEngineConfig::default_oracle_refresh_min_interval_ms")]
+    pub oracle_refresh_min_interval_ms: u64,
+    /// Maximum interval in seconds between two geyser reconnect attempts, the interval grows
+    /// exponentially up to it while the stream stays down
+    ///
+    /// Default: 30
+    #[serde(default = "StateEngineConfig::default_geyser_reconnect_max_interval_secs")]
+    pub geyser_reconnect_max_interval_secs: u64,
+    /// Interval in milliseconds between two refreshes of all marginfi accounts from the RPC,
+    /// catching up on updates the stream missed, 0 disables the refresh
+    ///
+    /// Default: 10000
+    #[serde(default = "StateEngineConfig::default_refresh_interval_ms")]
+    pub refresh_interval_ms: u64,
+    /// Maximum age in seconds of an oracle price for banks without an `oracle_max_age` of their
+    /// own, unset disables the staleness check for them
+    ///
+    /// Oracle updates carrying an older price are rejected and the bank keeps its last good
+    /// price, accounts holding a bank without a good price within the age are skipped.
+    ///
+    /// Default: 60
+    #[serde(default = "StateEngineConfig::default_oracle_max_age_secs")]
+    pub oracle_max_age_secs: Option<u64>,
+    /// Window in milliseconds over which streamed marginfi account updates are coalesced, 0
+    /// applies every update as it arrives
+    ///
+    /// Within a window only the latest update of each account is kept, all of them are applied
+    /// together at the end of the window with a single scan. A wider window saves health
+    /// recomputations for rapidly changing accounts, at the cost of account state lagging the
+    /// chain by up to the window.
+    ///
+    /// Default: 0
+    #[serde(default = "StateEngineConfig::default_account_update_coalescing_window_ms")]
+    pub account_update_coalescing_window_ms: u64,
+    /// File the tracked marginfi accounts are snapshotted to, every
+    /// `state_snapshot_interval_secs` and on shutdown, unset disables snapshots
+    ///
+    /// On startup a snapshot younger than `state_snapshot_max_age_secs` warms the accounts,
+    /// only the accounts it lacks are fetched in full. The accounts restored from it catch up
+    /// with the chain through the stream and the periodic refresh, whose first pass runs right
+    /// after startup instead of after `refresh_interval_ms`. Snapshots are never restored with
+    /// the periodic refresh disabled, restored accounts would stay stale until their next
+    /// stream update.
+    ///
+    /// Only marginfi accounts are snapshotted, banks, oracles and token accounts are always
+    /// reloaded from the RPC.
+    ///
+    /// Example: "eva01-state.bin"
+    pub state_snapshot_path: Option<String>,
+    /// Interval in seconds between two state snapshots
+    ///
+    /// Default: 300
+    #[serde(default = "StateEngineConfig::default_state_snapshot_interval_secs")]
+    pub state_snapshot_interval_secs: u64,
+    /// Maximum age in seconds of a state snapshot restored on startup, older snapshots are
+    /// ignored and all accounts are loaded from the RPC
+    ///
+    /// Default: 3600
+    #[serde(default = "StateEngineConfig::default_state_snapshot_max_age_secs")]
+    pub state_snapshot_max_age_secs: u64,
+}
+
+/// Strategy for refreshing oracle price adapters on oracle account updates
+///
+/// The adapters can't be updated in place, a refresh always reconstructs the adapter from the
+/// oracle account.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OracleRefreshStrategy {
+    /// Reconstruct the adapter on every update, always up to date but expensive for
+    /// high-frequency feeds
+    Full,
+    /// Reconstruct the adapter at most once per `oracle_refresh_min_interval_ms`
+    ///
+    /// Updates within the interval are cached, the adapter is rebuilt from the latest one once
+    /// the interval elapses, so prices lag by at most the interval and the last update of a
+    /// burst is never lost.
+    Debounced,
+}
+
+#[derive(Debug, Clone, Copy, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OraclePriceTypeOverride {
+    RealTime,
+    TimeWeighted,
+}
+
+impl From<OraclePriceTypeOverride> for OraclePriceType {
+    fn from(value: OraclePriceTypeOverride) -> Self {
+        match value {
+            OraclePriceTypeOverride::RealTime => OraclePriceType::RealTime,
+            OraclePriceTypeOverride::TimeWeighted => OraclePriceType::TimeWeighted,
+        }
+    }
 }
 
 impl StateEngineConfig {
@@ -237,9 +639,21 @@ impl StateEngineConfig {
         GeyserServiceConfig {
             endpoint: self.yellowstone_endpoint.clone(),
             x_token: self.yellowstone_x_token.clone(),
+            reconnect_max_interval: Duration::from_secs(self.geyser_reconnect_max_interval_secs),
         }
     }
 
+    pub fn default_fallback_rpc_urls() -> Vec<String> {
+        vec![]
+    }
+
+    /// `rpc_url` followed by the fallback endpoints
+    pub fn get_rpc_urls(&self) -> Vec<String> {
+        std::iter::once(self.rpc_url.clone())
+            .chain(self.fallback_rpc_urls.iter().cloned())
+            .collect()
+    }
+
     pub fn default_marginfi_program_id() -> Pubkey {
         marginfi::id()
     }
@@ -255,6 +669,83 @@ impl StateEngineConfig {
     pub fn default_account_whitelist() -> Option<Vec<Pubkey>> {
         None
     }
+
+    pub fn default_oracle_price_type_overrides() -> HashMap<Pubkey, OraclePriceTypeOverride> {
+        HashMap::new()
+    }
+
+    pub fn default_validate_account_discriminators() -> bool {
+        true
+    }
+
+    pub fn default_marginfi_account_refresh_batch_size() -> usize {
+        100
+    }
+
+    pub fn default_allow_partial_account_loading() -> bool {
+        true
+    }
+
+    pub fn default_readmit_liability_ratio() -> f64 {
+        0.9
+    }
+
+    pub fn default_oracle_refresh_strategy() -> OracleRefreshStrategy {
+        OracleRefreshStrategy::Full
+    }
+
+    pub fn default_oracle_refresh_min_interval_ms() -> u64 {
+        200
+    }
+
+    pub fn default_geyser_reconnect_max_interval_secs() -> u64 {
+        30
+    }
+
+    pub fn default_refresh_interval_ms() -> u64 {
+        10_000
+    }
+
+    pub fn default_oracle_max_age_secs() -> Option<u64> {
+        Some(60)
+    }
+
+    pub fn default_account_update_coalescing_window_ms() -> u64 {
+        0
+    }
+
+    pub fn default_state_snapshot_interval_secs() -> u64 {
+        300
+    }
+
+    pub fn default_state_snapshot_max_age_secs() -> u64 {
+        3600
+    }
+
+    /// Loading config of the periodic refresh of all marginfi accounts
+    pub fn get_marginfi_account_refresh_loading_config(&self) -> BatchLoadingConfig {
+        BatchLoadingConfig {
+            max_batch_size: self.marginfi_account_refresh_batch_size,
+            ..BatchLoadingConfig::DEFAULT
+        }
+    }
+
+    /// Minimum interval between two refreshes of an oracle's price adapter, `None` to refresh on
+    /// every update
+    pub fn get_oracle_refresh_min_interval(&self) -> Option<Duration> {
+        match self.oracle_refresh_strategy {
+            OracleRefreshStrategy::Full => None,
+            OracleRefreshStrategy::Debounced => {
+                Some(Duration::from_millis(self.oracle_refresh_min_interval_ms))
+            }
+        }
+    }
+
+    pub fn get_oracle_price_type_override(&self, bank_address: &Pubkey) -> Option<OraclePriceType> {
+        self.oracle_price_type_overrides
+            .get(bank_address)
+            .map(|price_type| (*price_type).into())
+    }
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -263,11 +754,15 @@ pub enum StateEngineError {
     RpcError,
     #[error("Bank not found")]
     NotFound,
+    #[error("No bank for mint {0} in the marginfi group")]
+    NoBankForMint(Pubkey),
+    #[error("Bank {0} of mint {1} failed to load: {2}")]
+    BankLoadFailed(Pubkey, Pubkey, String),
 }
 
 pub struct StateEngineService {
     nb_rpc_client: Arc<solana_client::nonblocking::rpc_client::RpcClient>,
-    pub rpc_client: Arc<solana_client::rpc_client::RpcClient>,
+    pub rpc_pool: Arc<RpcPool>,
     anchor_client: anchor_client::Client<Arc<Keypair>>,
     pub marginfi_accounts: Arc<DashMap<Pubkey, Arc<RwLock<MarginfiAccountWrapper>>>>,
     pub banks: Arc<DashMap<Pubkey, Arc<RwLock<BankWrapper>>>>,
@@ -278,8 +773,27 @@ pub struct StateEngineService {
     accounts_to_track: Arc<RwLock<Vec<Pubkey>>>,
     oracle_to_bank_map: DashMap<Pubkey, Vec<Arc<RwLock<BankWrapper>>>>,
     pub mint_to_bank_map: DashMap<Pubkey, Vec<Arc<RwLock<BankWrapper>>>>,
+    /// Mint and mint decimals of every loaded bank, read on hot paths without taking the
+    /// bank's lock
+    bank_mints: DashMap<Pubkey, (Pubkey, u8)>,
     tracked_oracle_accounts: DashSet<Pubkey>,
+    /// Latest data of every tracked oracle account, price adapters of banks with several
+    /// oracles are rebuilt from all of them when any one is updated
+    oracle_accounts: DashMap<Pubkey, Account>,
+    /// Oracles with an update skipped by the debounce, their banks are refreshed from
+    /// `oracle_accounts` once the interval elapses
+    debounced_oracles: DashSet<Pubkey>,
+    /// Latest streamed update of each marginfi account within the current coalescing window
+    pending_account_updates: DashMap<Pubkey, Account>,
     tracked_token_accounts: DashSet<Pubkey>,
+    evicted_accounts: DashSet<Pubkey>,
+    refresh_in_flight: AtomicBool,
+    /// Whether marginfi accounts were restored from a state snapshot, the periodic refresh then
+    /// reconciles them with the chain right away
+    restored_from_snapshot: AtomicBool,
+    /// Banks without an oracle, these are never loaded so accounts holding them are
+    /// never considered for liquidation
+    unoracled_banks: DashSet<Pubkey>,
     update_tx: Sender<()>,
 }
 
@@ -293,13 +807,11 @@ impl StateEngineService {
         let nb_rpc_client = Arc::new(solana_client::nonblocking::rpc_client::RpcClient::new(
             config.rpc_url.clone(),
         ));
-        let rpc_client = Arc::new(solana_client::rpc_client::RpcClient::new(
-            config.rpc_url.clone(),
-        ));
+        let rpc_pool = Arc::new(RpcPool::new(&config.get_rpc_urls())?);
 
         let (update_tx, update_rx) = crossbeam::channel::bounded(1000);
 
-        let token_account_manager = TokenAccountManager::new(rpc_client.clone())?;
+        let token_account_manager = TokenAccountManager::new(rpc_pool.clone())?;
 
         let state_engine_service = Arc::new(Self {
             marginfi_accounts: Arc::new(DashMap::new()),
@@ -309,12 +821,20 @@ impl StateEngineService {
             anchor_client,
             config: config.clone(),
             nb_rpc_client,
-            rpc_client,
+            rpc_pool,
             accounts_to_track: Arc::new(RwLock::new(Vec::new())),
             oracle_to_bank_map: DashMap::new(),
             mint_to_bank_map: DashMap::new(),
+            bank_mints: DashMap::new(),
             tracked_oracle_accounts: DashSet::new(),
+            oracle_accounts: DashMap::new(),
+            debounced_oracles: DashSet::new(),
+            pending_account_updates: DashMap::new(),
             tracked_token_accounts: DashSet::new(),
+            evicted_accounts: DashSet::new(),
+            refresh_in_flight: AtomicBool::new(false),
+            restored_from_snapshot: AtomicBool::new(false),
+            unoracled_banks: DashSet::new(),
             update_tx,
             token_account_manager,
         });
@@ -326,11 +846,113 @@ impl StateEngineService {
         self.banks.get(bank_pk).map(|bank| bank.value().clone())
     }
 
+    /// Mint and mint decimals of a loaded bank, from the cache kept up to date on bank loads and
+    /// updates, falling back to the bank itself
+    pub fn get_bank_mint(&self, bank_pk: &Pubkey) -> Option<(Pubkey, u8)> {
+        if let Some(mint) = self.bank_mints.get(bank_pk) {
+            return Some(*mint);
+        }
+
+        let mint = self.get_bank(bank_pk).and_then(|bank| {
+            bank.read()
+                .ok()
+                .map(|bank| (bank.bank.mint, bank.bank.mint_decimals))
+        })?;
+
+        self.bank_mints.insert(*bank_pk, mint);
+
+        Some(mint)
+    }
+
+    /// Copies of the given banks, along with their oracle prices, that later bank and oracle
+    /// updates don't affect
+    ///
+    /// Each bank is copied whole under its lock, so a copy never mixes the bank of one update
+    /// with the price of another. Banks are copied one after the other though, an update
+    /// landing while the snapshot is taken can still be reflected in some banks and not in
+    /// others. Banks that aren't loaded are left out.
+    pub fn snapshot_banks(
+        &self,
+        bank_pks: impl IntoIterator<Item = Pubkey>,
+    ) -> Arc<DashMap<Pubkey, Arc<RwLock<BankWrapper>>>> {
+        let snapshot = DashMap::new();
+
+        for bank_pk in bank_pks {
+            if let Some(bank) = self.get_bank(&bank_pk) {
+                let bank = bank.read().unwrap().clone();
+                snapshot.insert(bank_pk, Arc::new(RwLock::new(bank)));
+            }
+        }
+
+        Arc::new(snapshot)
+    }
+
+    /// Bank of `mint`, looked up in the mint index, the first one loaded when several banks
+    /// share the mint
+    ///
     /// TODO: Enable a liquidator to specify a preferred bank
     pub fn get_bank_for_mint(&self, mint: &Pubkey) -> Option<Arc<RwLock<BankWrapper>>> {
         self.mint_to_bank_map
             .get(mint)
-            .map(|banks| banks.value().first().unwrap().clone())
+            .and_then(|banks| banks.value().first().cloned())
+    }
+
+    /// All banks of `mint`, in load order, usually at most one
+    pub fn get_banks_for_mint(&self, mint: &Pubkey) -> Vec<Arc<RwLock<BankWrapper>>> {
+        self.mint_to_bank_map
+            .get(mint)
+            .map(|banks| banks.value().clone())
+            .unwrap_or_default()
+    }
+
+    /// Load the bank of `mint` directly from the RPC, for a bank missing from the initial load
+    ///
+    /// Tells apart a mint without a bank in the group, usually a wrong mint or group in the
+    /// config, from a bank that exists but can't be loaded, usually because its oracle is
+    /// unset or its oracle account can't be parsed.
+    pub fn load_bank_for_mint(
+        &self,
+        mint: &Pubkey,
+    ) -> Result<Arc<RwLock<BankWrapper>>, StateEngineError> {
+        let banks = self
+            .rpc_pool
+            .client()
+            .get_program_accounts_with_config(
+                &self.config.marginfi_program_id,
+                RpcProgramAccountsConfig {
+                    filters: Some(vec![
+                        RpcFilterType::Memcmp(Memcmp::new_base58_encoded(
+                            BANK_GROUP_PK_OFFSET,
+                            self.config.marginfi_group_address.as_ref(),
+                        )),
+                        RpcFilterType::Memcmp(Memcmp::new_base58_encoded(
+                            BANK_MINT_OFFSET,
+                            mint.as_ref(),
+                        )),
+                    ]),
+                    account_config: RpcAccountInfoConfig {
+                        encoding: Some(UiAccountEncoding::Base64),
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            )
+            .map_err(|e| {
+                error!("Failed to load banks of mint {}: {:?}", mint, e);
+                StateEngineError::RpcError
+            })?;
+
+        let (bank_address, bank_account) = banks
+            .into_iter()
+            .next()
+            .ok_or(StateEngineError::NoBankForMint(*mint))?;
+
+        self.update_bank(&bank_address, bank_account)
+            .map_err(|e| StateEngineError::BankLoadFailed(bank_address, *mint, e.to_string()))?;
+
+        self.get_bank_for_mint(mint).ok_or_else(|| {
+            StateEngineError::BankLoadFailed(bank_address, *mint, "the bank has no oracle".into())
+        })
     }
 
     pub async fn load_initial_state(&self, liquidator_account: Pubkey) -> anyhow::Result<()> {
@@ -354,14 +976,17 @@ impl StateEngineService {
     }
 
     pub async fn refresh_token_account(&self, bank_pk: &Pubkey) -> anyhow::Result<()> {
-        let mint = self.get_bank(bank_pk).unwrap().read().unwrap().bank.mint;
+        let (mint, _) = self
+            .get_bank_mint(bank_pk)
+            .ok_or_else(|| anyhow::anyhow!("Bank {} not found", bank_pk))?;
         let token_account_addresses = self
             .token_account_manager
             .get_address_for_mint(mint)
             .ok_or_else(|| anyhow::anyhow!("No token account found for mint {}", mint))?;
 
         let account = self
-            .rpc_client
+            .rpc_pool
+            .client()
             .get_account_with_commitment(&token_account_addresses, CommitmentConfig::confirmed())
             .map_err(|e| anyhow::anyhow!("Failed to get account: {:?}", e))?
             .value
@@ -400,30 +1025,49 @@ impl StateEngineService {
 
         debug!("Found {} banks", banks.len());
 
-        let oracle_keys = banks
+        let banks = banks
+            .into_iter()
+            .filter(|(bank_address, bank)| {
+                if get_oracle_keys(bank).is_empty() {
+                    warn!(
+                        "Bank {} has no oracle, accounts holding it can't be evaluated",
+                        bank_address
+                    );
+                    self.unoracled_banks.insert(*bank_address);
+                    return false;
+                }
+
+                true
+            })
+            .collect::<Vec<_>>();
+
+        let mut oracle_keys = banks
             .iter()
-            .map(|(_, bank)| bank.config.oracle_keys[0])
+            .flat_map(|(_, bank)| get_oracle_keys(bank))
             .collect::<Vec<_>>();
+        oracle_keys.sort();
+        oracle_keys.dedup();
 
-        let mut oracle_accounts = batch_get_multiple_accounts(
-            self.rpc_client.clone(),
+        let oracle_accounts = batch_get_multiple_accounts(
+            self.rpc_pool.clone(),
             &oracle_keys,
             BatchLoadingConfig::DEFAULT,
         )?;
 
         debug!("Found {} oracle accounts", oracle_accounts.len());
 
-        let mut oracles_with_addresses = oracle_keys
-            .iter()
-            .zip(oracle_accounts.iter_mut())
-            .collect::<Vec<_>>();
+        for (oracle_address, oracle_account) in oracle_keys.iter().zip(oracle_accounts) {
+            match oracle_account {
+                Some(oracle_account) => {
+                    self.oracle_accounts.insert(*oracle_address, oracle_account);
+                }
+                None => warn!("Oracle account {} not found", oracle_address),
+            }
+        }
 
-        for ((bank_address, bank), (oracle_address, maybe_oracle_account)) in
-            banks.iter().zip(oracles_with_addresses.iter_mut())
-        {
-            let oracle_ai =
-                (*oracle_address, maybe_oracle_account.as_mut().unwrap()).into_account_info();
-            let oracle_ai_c = oracle_ai.clone();
+        for (bank_address, bank) in banks.iter() {
+            let bank_oracle_keys = get_oracle_keys(bank);
+            let oracle_wrapper = self.load_oracle_wrapper(bank, &bank_oracle_keys)?;
 
             let bank_ref = self
                 .banks
@@ -440,30 +1084,27 @@ impl StateEngineService {
                     Arc::new(RwLock::new(BankWrapper::new(
                         *bank_address,
                         bank.clone(),
-                        OracleWrapper::new(
-                            **oracle_address,
-                            OraclePriceFeedAdapter::try_from_bank_config_with_max_age(
-                                &bank.config,
-                                &[oracle_ai_c],
-                                0,
-                                u64::MAX,
-                            )
-                            .unwrap(),
-                        ),
+                        oracle_wrapper,
+                        self.config.get_oracle_price_type_override(bank_address),
                     )))
                 });
 
-            self.oracle_to_bank_map
-                .entry(**oracle_address)
-                .and_modify(|vec| vec.push(bank_ref.clone()))
-                .or_insert_with(|| vec![bank_ref.clone()]);
+            for oracle_address in bank_oracle_keys.iter() {
+                self.oracle_to_bank_map
+                    .entry(*oracle_address)
+                    .and_modify(|vec| vec.push(bank_ref.clone()))
+                    .or_insert_with(|| vec![bank_ref.clone()]);
+
+                self.tracked_oracle_accounts.insert(*oracle_address);
+            }
 
             self.mint_to_bank_map
                 .entry(bank.mint)
                 .and_modify(|vec| vec.push(bank_ref.clone()))
                 .or_insert_with(|| vec![bank_ref.clone()]);
 
-            self.tracked_oracle_accounts.insert(**oracle_address);
+            self.bank_mints
+                .insert(*bank_address, (bank.mint, bank.mint_decimals));
         }
 
         debug!("Done loading oracles and banks");
@@ -471,8 +1112,82 @@ impl StateEngineService {
         Ok(())
     }
 
+    /// Oracle of a newly loaded bank
+    ///
+    /// A stale price is still loaded so the bank can be tracked, but it isn't recorded as a good
+    /// price until a fresh one is streamed.
+    fn load_oracle_wrapper(
+        &self,
+        bank: &Bank,
+        oracle_keys: &[Pubkey],
+    ) -> anyhow::Result<OracleWrapper> {
+        let max_age_secs =
+            oracle_max_age_secs(bank.config.oracle_max_age, self.config.oracle_max_age_secs);
+
+        match self.build_price_adapter(bank, oracle_keys, max_age_secs) {
+            Ok(price_adapter) => {
+                let mut oracle_wrapper = OracleWrapper::new(oracle_keys[0], price_adapter);
+                oracle_wrapper.last_good_price_at = Some(Instant::now());
+                Ok(oracle_wrapper)
+            }
+            Err(e) if max_age_secs.is_some() => {
+                warn!(
+                    "Oracle {} loaded without a good price: {:?}",
+                    oracle_keys[0], e
+                );
+                Ok(OracleWrapper::new(
+                    oracle_keys[0],
+                    self.build_price_adapter(bank, oracle_keys, None)?,
+                ))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Build the price adapter of `bank` from the latest data of all of its `oracle_keys`,
+    /// rejecting prices older than `max_age_secs`
+    fn build_price_adapter(
+        &self,
+        bank: &Bank,
+        oracle_keys: &[Pubkey],
+        max_age_secs: Option<u64>,
+    ) -> anyhow::Result<OraclePriceFeedAdapter> {
+        let (current_timestamp, max_age) = match max_age_secs {
+            Some(max_age_secs) => (
+                SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64,
+                max_age_secs,
+            ),
+            None => (0, u64::MAX),
+        };
+
+        let mut oracle_accounts = oracle_keys
+            .iter()
+            .map(|oracle_address| {
+                self.oracle_accounts
+                    .get(oracle_address)
+                    .map(|oracle_account| (*oracle_address, oracle_account.clone()))
+                    .ok_or_else(|| anyhow::anyhow!("Oracle account {} not loaded", oracle_address))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        let oracle_ais = oracle_accounts
+            .iter_mut()
+            .map(|(oracle_address, oracle_account)| {
+                (&*oracle_address, oracle_account).into_account_info()
+            })
+            .collect::<Vec<_>>();
+
+        Ok(OraclePriceFeedAdapter::try_from_bank_config_with_max_age(
+            &bank.config,
+            &oracle_ais,
+            current_timestamp,
+            max_age,
+        )?)
+    }
+
     pub fn load_sol_accounts(&self) -> anyhow::Result<()> {
-        self.rpc_client
+        self.rpc_pool
+            .client()
             .get_account(&self.config.signer_pubkey)
             .map(|account| {
                 self.sol_accounts.insert(self.config.signer_pubkey, account);
@@ -481,24 +1196,36 @@ impl StateEngineService {
         Ok(())
     }
 
+    /// Load the liquidator's own account from the RPC, at confirmed commitment so the effects
+    /// of just confirmed liquidations are reflected
     pub fn load_liquidator_account(&self, liquidator_account: Pubkey) -> anyhow::Result<()> {
-        let account = self.rpc_client.get_account(&liquidator_account)?;
-
-        let marginfi_account = bytemuck::from_bytes::<MarginfiAccount>(&account.data[8..]);
+        let account = self
+            .rpc_pool
+            .client()
+            .get_account_with_commitment(&liquidator_account, CommitmentConfig::confirmed())?
+            .value
+            .ok_or_else(|| {
+                anyhow::anyhow!("Liquidator account {} not found", liquidator_account)
+            })?;
 
-        self.marginfi_accounts
-            .entry(liquidator_account)
-            .and_modify(|marginfi_account_ref| {
-                let mut marginfi_account_guard = marginfi_account_ref.write().unwrap();
-                marginfi_account_guard.account = marginfi_account.clone();
-            })
-            .or_insert_with(|| {
-                Arc::new(RwLock::new(MarginfiAccountWrapper::new(
+        if self.config.validate_account_discriminators {
+            check_discriminator::<MarginfiAccount>(&liquidator_account, &account.data)?;
+        }
+        let marginfi_account = bytemuck::try_from_bytes::<MarginfiAccount>(&account.data[8..])
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "Failed to cast marginfi account {}: {:?}",
                     liquidator_account,
-                    marginfi_account.clone(),
-                    self.banks.clone(),
-                )))
-            });
+                    e
+                )
+            })?;
+
+        upsert_marginfi_account(
+            &self.marginfi_accounts,
+            liquidator_account,
+            marginfi_account,
+            &self.banks,
+        );
 
         Ok(())
     }
@@ -506,26 +1233,14 @@ impl StateEngineService {
     pub fn update_oracle(
         &self,
         oracle_address: &Pubkey,
-        mut oracle_account: Account,
+        oracle_account: Account,
     ) -> anyhow::Result<()> {
         if let Some(banks_to_update) = self.oracle_to_bank_map.get(oracle_address) {
-            let oracle_ai = (oracle_address, &mut oracle_account).into_account_info();
-
-            debug!("Updating oracle {}", oracle_ai.key);
-
-            for bank_to_update in banks_to_update.iter() {
-                if let Ok(mut bank_to_update) = bank_to_update.try_write() {
-                    bank_to_update.oracle_adapter.price_adapter =
-                        OraclePriceFeedAdapter::try_from_bank_config_with_max_age(
-                            &bank_to_update.bank.config,
-                            &[oracle_ai.clone()],
-                            0,
-                            u64::MAX,
-                        )?;
-                } else {
-                    warn!("Failed to acquire write lock on bank, oracle update skipped");
-                }
-            }
+            debug!("Updating oracle {}", oracle_address);
+
+            self.oracle_accounts.insert(*oracle_address, oracle_account);
+
+            self.refresh_oracle_banks(oracle_address, banks_to_update.value());
         } else {
             warn!("Received update for unknown oracle {}", oracle_address);
         }
@@ -535,17 +1250,133 @@ impl StateEngineService {
         Ok(())
     }
 
+    /// Rebuild the price adapters of the `banks` of an oracle from the cached oracle accounts,
+    /// returns whether any bank was refreshed
+    ///
+    /// Banks refreshed less than the debounce interval ago are skipped and the oracle is marked
+    /// for a trailing refresh.
+    fn refresh_oracle_banks(
+        &self,
+        oracle_address: &Pubkey,
+        banks: &[Arc<RwLock<BankWrapper>>],
+    ) -> bool {
+        let min_refresh_interval = self.config.get_oracle_refresh_min_interval();
+        let mut refreshed = false;
+
+        for bank_to_update in banks.iter() {
+            if let Ok(mut bank_to_update) = bank_to_update.try_write() {
+                if is_debounced(
+                    min_refresh_interval,
+                    bank_to_update.oracle_adapter.last_streamed_at,
+                    Instant::now(),
+                ) {
+                    trace!(
+                        "Oracle {} refreshed less than {:?} ago, refresh deferred",
+                        oracle_address,
+                        min_refresh_interval
+                    );
+                    self.debounced_oracles.insert(*oracle_address);
+                    continue;
+                }
+
+                let price_adapter = match self.build_price_adapter(
+                    &bank_to_update.bank,
+                    &get_oracle_keys(&bank_to_update.bank),
+                    oracle_max_age_secs(
+                        bank_to_update.bank.config.oracle_max_age,
+                        self.config.oracle_max_age_secs,
+                    ),
+                ) {
+                    Ok(price_adapter) => price_adapter,
+                    Err(e) => {
+                        warn!(
+                            "Rejected oracle {} update for bank {}, keeping the last good price: {:?}",
+                            oracle_address, bank_to_update.address, e
+                        );
+                        continue;
+                    }
+                };
+
+                let now = Instant::now();
+
+                bank_to_update.oracle_adapter.price_adapter = price_adapter;
+                bank_to_update.oracle_adapter.last_streamed_at = Some(now);
+                bank_to_update.oracle_adapter.last_good_price_at = Some(now);
+                bank_to_update.version += 1;
+                refreshed = true;
+            } else {
+                warn!("Failed to acquire write lock on bank, oracle refresh deferred");
+                self.debounced_oracles.insert(*oracle_address);
+            }
+        }
+
+        refreshed
+    }
+
     pub fn update_bank(&self, bank_address: &Pubkey, bank: Account) -> anyhow::Result<bool> {
         debug!("Updating bank {}", bank_address);
-        let bank = bytemuck::from_bytes::<Bank>(&bank.data.as_slice()[8..]);
+        if self.config.validate_account_discriminators {
+            check_discriminator::<Bank>(bank_address, &bank.data)?;
+        }
+        let bank = bytemuck::try_from_bytes::<Bank>(&bank.data.as_slice()[8..])
+            .map_err(|e| anyhow::anyhow!("Failed to cast bank {}: {:?}", bank_address, e))?;
+
+        if bank.group != self.config.marginfi_group_address {
+            trace!(
+                "Ignoring bank {} of group {}, outside of the liquidator's group",
+                bank_address,
+                bank.group
+            );
+            return Ok(false);
+        }
+
+        let oracle_keys = get_oracle_keys(bank);
+
+        if oracle_keys.is_empty() {
+            if self.unoracled_banks.insert(*bank_address) {
+                warn!(
+                    "Bank {} has no oracle, accounts holding it can't be evaluated",
+                    bank_address
+                );
+            }
+            return Ok(false);
+        }
+
+        let new_bank = !self.banks.contains_key(bank_address);
+
+        // Oracle of a new bank, built from its oracle accounts not loaded yet
+        let mut oracle_wrapper = None;
+
+        if new_bank {
+            let missing_oracle_keys = oracle_keys
+                .iter()
+                .filter(|oracle_address| !self.oracle_accounts.contains_key(oracle_address))
+                .copied()
+                .collect::<Vec<_>>();
+
+            let missing_oracle_accounts = self
+                .rpc_pool
+                .client()
+                .get_multiple_accounts(&missing_oracle_keys)?;
+
+            for (oracle_address, oracle_account) in
+                missing_oracle_keys.iter().zip(missing_oracle_accounts)
+            {
+                let oracle_account = oracle_account.ok_or_else(|| {
+                    anyhow::anyhow!("Oracle account {} not found", oracle_address)
+                })?;
+                self.oracle_accounts.insert(*oracle_address, oracle_account);
+            }
 
-        let new_bank = self.banks.contains_key(bank_address);
+            oracle_wrapper = Some(self.load_oracle_wrapper(bank, &oracle_keys)?);
+        }
 
         self.banks
             .entry(*bank_address)
             .and_modify(|bank_entry| {
                 if let Ok(mut bank_entry) = bank_entry.try_write() {
                     bank_entry.bank = bank.clone();
+                    bank_entry.version += 1;
                 } else {
                     warn!("Failed to acquire write lock on bank, bank update skipped");
                 }
@@ -553,27 +1384,22 @@ impl StateEngineService {
             .or_insert_with(|| {
                 debug!("Received update for a new bank {}", bank_address);
 
-                let oracle_address = bank.config.oracle_keys[0];
-                let mut oracle_account = self.rpc_client.get_account(&oracle_address).unwrap();
-                let oracle_account_ai = (&oracle_address, &mut oracle_account).into_account_info();
-
-                self.tracked_oracle_accounts.insert(oracle_address);
-
                 let bank_entry = Arc::new(RwLock::new(BankWrapper::new(
                     *bank_address,
                     bank.clone(),
-                    OracleWrapper::new(
-                        oracle_address,
-                        OraclePriceFeedAdapter::try_from_bank_config_with_max_age(
-                            &bank.config,
-                            &[oracle_account_ai],
-                            i64::MAX,
-                            u64::MAX,
-                        )
-                        .unwrap(),
-                    ),
+                    oracle_wrapper.take().expect("Oracle of a new bank"),
+                    self.config.get_oracle_price_type_override(bank_address),
                 )));
 
+                for oracle_address in oracle_keys.iter() {
+                    self.tracked_oracle_accounts.insert(*oracle_address);
+
+                    self.oracle_to_bank_map
+                        .entry(*oracle_address)
+                        .and_modify(|vec| vec.push(bank_entry.clone()))
+                        .or_insert_with(|| vec![bank_entry.clone()]);
+                }
+
                 self.mint_to_bank_map
                     .entry(bank.mint)
                     .and_modify(|vec| vec.push(bank_entry.clone()))
@@ -582,6 +1408,9 @@ impl StateEngineService {
                 bank_entry
             });
 
+        self.bank_mints
+            .insert(*bank_address, (bank.mint, bank.mint_decimals));
+
         debug!("Done updating bank {}", bank_address);
 
         Ok(new_bank)
@@ -608,7 +1437,7 @@ impl StateEngineService {
             .get_mints_and_token_account_addresses();
 
         let accounts = batch_get_multiple_accounts(
-            self.rpc_client.clone(),
+            self.rpc_pool.clone(),
             &token_account_addresses,
             BatchLoadingConfig::DEFAULT,
         )?;
@@ -624,10 +1453,19 @@ impl StateEngineService {
         for ((token_account_address, mint), maybe_token_account) in
             token_accounts_with_addresses_and_mints.iter()
         {
-            let balance = maybe_token_account
-                .as_ref()
-                .map(|a| accessor::amount(&a.data))
-                .unwrap_or(0);
+            // Still track the address so the wrapper is created once the ATA shows up on-chain
+            self.tracked_token_accounts.insert(**token_account_address);
+
+            let balance = match existing_token_account_balance(maybe_token_account.as_ref()) {
+                Some(balance) => balance,
+                None => {
+                    debug!(
+                        "Token account {} for mint {} does not exist yet, skipping",
+                        token_account_address, mint
+                    );
+                    continue;
+                }
+            };
 
             let token_accounts = self.token_accounts.clone();
 
@@ -639,14 +1477,7 @@ impl StateEngineService {
                     token_account_guard.balance = balance;
                 })
                 .or_insert_with(|| {
-                    let bank = self
-                        .mint_to_bank_map
-                        .get(mint)
-                        .unwrap()
-                        .value()
-                        .first()
-                        .unwrap()
-                        .clone();
+                    let bank = self.get_bank_for_mint(mint).unwrap();
 
                     let mint_decimals = bank.read().unwrap().bank.mint_decimals;
 
@@ -660,8 +1491,6 @@ impl StateEngineService {
 
                     Arc::new(RwLock::new(taw))
                 });
-
-            self.tracked_token_accounts.insert(**token_account_address);
         }
 
         Ok(())
@@ -684,20 +1513,12 @@ impl StateEngineService {
                 token_account_guard.balance = balance;
             })
             .or_insert_with(|| {
-                let mint_account = self.rpc_client.get_account(&mint).unwrap();
-                let decimals = spl_token::state::Mint::unpack(&mint_account.data)
-                    .map_err(|e| anyhow::anyhow!("Failed to unpack mint: {:?}", e))
-                    .unwrap()
-                    .decimals;
-
-                let bank = self
-                    .mint_to_bank_map
-                    .get(&mint)
-                    .unwrap()
-                    .value()
-                    .first()
-                    .unwrap()
-                    .clone();
+                // Token-2022 mints with extensions are longer than classic ones, only the
+                // decimals of the shared base layout are read
+                let mint_account = self.rpc_pool.client().get_account(&mint).unwrap();
+                let decimals = accessor::mint_decimals(&mint_account.data);
+
+                let bank = self.get_bank_for_mint(&mint).unwrap();
 
                 Arc::new(RwLock::new(TokenAccountWrapper {
                     address: *token_account_address,
@@ -728,10 +1549,27 @@ impl StateEngineService {
         self.config.marginfi_program_id
     }
 
+    pub fn get_marginfi_group_address(&self) -> Pubkey {
+        self.config.marginfi_group_address
+    }
+
+    /// Maximum age in seconds of oracle prices of banks without an `oracle_max_age` of their own
+    pub fn get_default_oracle_max_age_secs(&self) -> Option<u64> {
+        self.config.oracle_max_age_secs
+    }
+
     pub fn is_tracked_oracle(&self, address: &Pubkey) -> bool {
         self.tracked_oracle_accounts.contains(address)
     }
 
+    /// Track token accounts created after the initial load, such as the liquidator's newly
+    /// created token accounts
+    pub fn track_token_accounts(&self, addresses: impl IntoIterator<Item = Pubkey>) {
+        for address in addresses {
+            self.tracked_token_accounts.insert(address);
+        }
+    }
+
     pub fn is_tracked_token_account(&self, address: &Pubkey) -> bool {
         self.tracked_token_accounts.contains(address)
     }
@@ -798,73 +1636,975 @@ impl StateEngineService {
         info!("Loading marginfi accounts");
         let start = std::time::Instant::now();
 
-        let marginfi_account_pubkeys = self.load_marginfi_account_addresses().await?;
+        let mut marginfi_account_pubkeys = self.load_marginfi_account_addresses().await?;
 
         debug!("Found {} marginfi accounts", marginfi_account_pubkeys.len());
 
-        let mut marginfi_accounts = batch_get_multiple_accounts(
-            self.rpc_client.clone(),
+        if let Some(snapshot) = self.load_state_snapshot() {
+            marginfi_account_pubkeys =
+                self.restore_state_snapshot(snapshot, marginfi_account_pubkeys)?;
+        }
+
+        self.fetch_marginfi_accounts(marginfi_account_pubkeys)?;
+
+        self.enforce_tracked_accounts_limit();
+
+        debug!("Done loading marginfi accounts, tool {:?}", start.elapsed());
+
+        Ok(())
+    }
+
+    /// State snapshot to restore on startup, if one is configured, readable, of the
+    /// liquidator's group and recent enough, and if the periodic refresh is enabled to
+    /// reconcile the restored accounts
+    fn load_state_snapshot(&self) -> Option<StateSnapshot> {
+        let path = self.config.state_snapshot_path.as_ref()?;
+
+        if self.config.refresh_interval_ms == 0 {
+            warn!(
+                "Ignoring state snapshot {}, restored accounts can't be reconciled with refresh_interval_ms = 0",
+                path
+            );
+            return None;
+        }
+
+        let snapshot = match StateSnapshot::load(path) {
+            Ok(snapshot) => snapshot,
+            Err(e) => {
+                warn!("Failed to load state snapshot {}: {:?}", path, e);
+                return None;
+            }
+        };
+
+        if snapshot.group != self.config.marginfi_group_address {
+            warn!(
+                "Ignoring state snapshot {} of group {}",
+                path, snapshot.group
+            );
+            return None;
+        }
+
+        let max_age = Duration::from_secs(self.config.state_snapshot_max_age_secs);
+
+        if snapshot.age() > max_age {
+            info!(
+                "Ignoring state snapshot {} from slot {}, {:?} old",
+                path,
+                snapshot.slot,
+                snapshot.age()
+            );
+            return None;
+        }
+
+        Some(snapshot)
+    }
+
+    /// Apply the snapshotted accounts among `marginfi_account_pubkeys`, the accounts currently
+    /// on-chain, returning the ones missing from the snapshot
+    ///
+    /// Snapshotted accounts no longer on-chain were closed since, they are dropped.
+    fn restore_state_snapshot(
+        &self,
+        snapshot: StateSnapshot,
+        marginfi_account_pubkeys: Vec<Pubkey>,
+    ) -> anyhow::Result<Vec<Pubkey>> {
+        let on_chain = marginfi_account_pubkeys.iter().collect::<HashSet<_>>();
+        let mut restored = HashSet::new();
+
+        for (address, data) in snapshot.marginfi_accounts.iter() {
+            if !on_chain.contains(address) {
+                continue;
+            }
+
+            let account = Account {
+                data: data.clone(),
+                owner: self.config.marginfi_program_id,
+                ..Default::default()
+            };
+
+            match self.update_marginfi_account(address, &account) {
+                Ok(()) => {
+                    restored.insert(*address);
+                }
+                Err(e) => warn!("Failed to restore marginfi account {}: {:?}", address, e),
+            }
+        }
+
+        info!(
+            "Restored {} marginfi accounts from the state snapshot of slot {}, {:?} old",
+            restored.len(),
+            snapshot.slot,
+            snapshot.age()
+        );
+
+        if !restored.is_empty() {
+            self.restored_from_snapshot.store(true, Ordering::Release);
+        }
+
+        Ok(marginfi_account_pubkeys
+            .into_iter()
+            .filter(|address| !restored.contains(address))
+            .collect())
+    }
+
+    /// Fetch the given marginfi accounts and apply them
+    fn fetch_marginfi_accounts(&self, marginfi_account_pubkeys: Vec<Pubkey>) -> anyhow::Result<()> {
+        if marginfi_account_pubkeys.is_empty() {
+            return Ok(());
+        }
+
+        let batch_loading_config = || BatchLoadingConfig {
+            allow_partial_failures: self.config.allow_partial_account_loading,
+            ..BatchLoadingConfig::DEFAULT
+        };
+
+        let (marginfi_accounts, failed_addresses) = batch_get_multiple_accounts_with_failures(
+            self.rpc_pool.clone(),
             &marginfi_account_pubkeys,
-            BatchLoadingConfig::DEFAULT,
+            batch_loading_config(),
         )?;
 
         debug!("Fetched {} marginfi accounts", marginfi_accounts.len());
 
-        for (address, account) in marginfi_account_pubkeys
+        let mut loaded_accounts = marginfi_account_pubkeys
+            .into_iter()
+            .zip(marginfi_accounts)
+            .collect::<Vec<_>>();
+
+        if !failed_addresses.is_empty() {
+            warn!(
+                "Failed to fetch {} marginfi accounts, retrying them",
+                failed_addresses.len()
+            );
+
+            let (retried_accounts, still_failed_addresses) =
+                batch_get_multiple_accounts_with_failures(
+                    self.rpc_pool.clone(),
+                    &failed_addresses,
+                    batch_loading_config(),
+                )?;
+
+            if !still_failed_addresses.is_empty() {
+                warn!(
+                    "Failed to fetch {} marginfi accounts after retrying, they will be loaded from the stream",
+                    still_failed_addresses.len()
+                );
+            }
+
+            loaded_accounts.extend(failed_addresses.into_iter().zip(retried_accounts));
+        }
+
+        for (address, account) in loaded_accounts {
+            match account {
+                Some(account) => self.update_marginfi_account(&address, &account)?,
+                None => debug!("Marginfi account {} not loaded, skipping", address),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the tracked marginfi accounts to `state_snapshot_path`, if set
+    pub fn save_state_snapshot(&self) -> anyhow::Result<()> {
+        let path = match &self.config.state_snapshot_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let start = Instant::now();
+
+        let slot = self.rpc_pool.client().get_slot()?;
+
+        let marginfi_accounts = self
+            .marginfi_accounts
             .iter()
-            .zip(marginfi_accounts.iter_mut())
-        {
-            let account = account.as_ref().unwrap();
-            self.update_marginfi_account(address, account)?;
+            .filter_map(|account| {
+                let account = account.read().ok()?;
+
+                let mut data = MarginfiAccount::DISCRIMINATOR.to_vec();
+                data.extend_from_slice(bytemuck::bytes_of(&account.account));
+
+                Some((account.address, data))
+            })
+            .collect::<Vec<_>>();
+
+        let snapshot =
+            StateSnapshot::new(self.config.marginfi_group_address, slot, marginfi_accounts);
+
+        snapshot.save(path)?;
+
+        debug!(
+            "Saved {} marginfi accounts of slot {} to {} in {:?}",
+            snapshot.marginfi_accounts.len(),
+            slot,
+            path,
+            start.elapsed()
+        );
+
+        Ok(())
+    }
+
+    /// Snapshot the state every `state_snapshot_interval_secs`
+    async fn run_periodic_snapshot(self: Arc<Self>, shutdown: Arc<AtomicBool>) {
+        let interval = Duration::from_secs(self.config.state_snapshot_interval_secs);
+
+        loop {
+            tokio::time::sleep(interval).await;
+
+            if shutdown.load(Ordering::Relaxed) {
+                return;
+            }
+
+            let state_engine = self.clone();
+            match tokio::task::spawn_blocking(move || state_engine.save_state_snapshot()).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Failed to save state snapshot: {:?}", e),
+                Err(e) => error!("State snapshot task failed: {:?}", e),
+            }
         }
+    }
 
-        debug!("Done loading marginfi accounts, tool {:?}", start.elapsed());
+    /// Refresh all marginfi accounts every `refresh_interval_ms`, the interval starting once
+    /// the previous refresh is done
+    ///
+    /// The first refresh runs right away when accounts were restored from a state snapshot.
+    async fn run_periodic_refresh(self: Arc<Self>, shutdown: Arc<AtomicBool>) {
+        let interval = Duration::from_millis(self.config.refresh_interval_ms);
+        let mut reconcile_restored = self.restored_from_snapshot.load(Ordering::Acquire);
+
+        loop {
+            if !std::mem::take(&mut reconcile_restored) {
+                tokio::time::sleep(interval).await;
+            }
+
+            if shutdown.load(Ordering::Relaxed) {
+                return;
+            }
+
+            let state_engine = self.clone();
+            match tokio::task::spawn_blocking(move || state_engine.update_all_marginfi_accounts())
+                .await
+            {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => error!("Failed to refresh marginfi accounts: {:?}", e),
+                Err(e) => error!("Marginfi account refresh task failed: {:?}", e),
+            }
+        }
+    }
+
+    /// Re-fetch all tracked marginfi accounts from the RPC and apply their current data
+    ///
+    /// Accounts are fetched in `getMultipleAccounts` calls of
+    /// `marginfi_account_refresh_batch_size` accounts each.
+    pub fn update_all_marginfi_accounts(&self) -> anyhow::Result<()> {
+        if self.refresh_in_flight.swap(true, Ordering::AcqRel) {
+            debug!("Marginfi account refresh already in flight, skipping");
+            return Ok(());
+        }
+
+        let result = self.refresh_all_marginfi_accounts();
+
+        self.refresh_in_flight.store(false, Ordering::Release);
+
+        result
+    }
+
+    fn refresh_all_marginfi_accounts(&self) -> anyhow::Result<()> {
+        let start = std::time::Instant::now();
+
+        let marginfi_account_pubkeys = self
+            .marginfi_accounts
+            .iter()
+            .map(|account| *account.key())
+            .collect::<Vec<_>>();
+
+        let marginfi_accounts = batch_get_multiple_accounts(
+            self.rpc_pool.clone(),
+            &marginfi_account_pubkeys,
+            self.config.get_marginfi_account_refresh_loading_config(),
+        )?;
+
+        let mut updated_accounts = 0;
+
+        for (address, account) in marginfi_account_pubkeys.iter().zip(marginfi_accounts) {
+            match account {
+                Some(account) if self.is_marginfi_account_stale(address, &account) => {
+                    self.update_marginfi_account(address, &account)?;
+                    updated_accounts += 1;
+                }
+                Some(_) => {}
+                None => {
+                    if self.remove_closed_account(address) {
+                        info!("Marginfi account {} was closed, removed it", address);
+                    }
+                }
+            }
+        }
+
+        if updated_accounts > 0 {
+            info!(
+                "Refresh found {} marginfi accounts out of sync with the chain",
+                updated_accounts
+            );
+        }
+
+        // Accounts first seen through the stream since the last refresh count towards the limit
+        self.enforce_tracked_accounts_limit();
+
+        debug!(
+            "Refreshed {} marginfi accounts in {:?}",
+            marginfi_account_pubkeys.len(),
+            start.elapsed()
+        );
 
         Ok(())
     }
 
+    /// Whether the fetched account data differs from the cached marginfi account
+    fn is_marginfi_account_stale(&self, address: &Pubkey, account: &Account) -> bool {
+        let cached_account = match self.marginfi_accounts.get(address) {
+            Some(cached_account) => cached_account,
+            None => return true,
+        };
+
+        let cached_account = match cached_account.read() {
+            Ok(cached_account) => cached_account,
+            Err(_) => return true,
+        };
+
+        account.data.get(8..) != Some(bytemuck::bytes_of(&cached_account.account))
+    }
+
     pub fn update_marginfi_account(
         &self,
         marginfi_account_address: &Pubkey,
         account: &Account,
     ) -> anyhow::Result<()> {
-        let marginfi_account = bytemuck::from_bytes::<MarginfiAccount>(&account.data[8..]);
+        if self.config.validate_account_discriminators {
+            check_discriminator::<MarginfiAccount>(marginfi_account_address, &account.data)?;
+        }
+        let marginfi_account = bytemuck::try_from_bytes::<MarginfiAccount>(&account.data[8..])
+            .map_err(|e| {
+                anyhow::anyhow!(
+                    "Failed to cast marginfi account {}: {:?}",
+                    marginfi_account_address,
+                    e
+                )
+            })?;
         let marginfi_accounts = self.marginfi_accounts.clone();
 
+        if self.evicted_accounts.contains(marginfi_account_address) {
+            let readmission_candidate = MarginfiAccountWrapper::new(
+                *marginfi_account_address,
+                *marginfi_account,
+                self.banks.clone(),
+            );
+
+            // Accounts whose health can't be computed, e.g. holding an unloaded bank, can't be
+            // liquidated either and stay evicted
+            match readmission_candidate.try_maintenance_liability_ratio() {
+                Ok(ratio) if ratio >= I80F48::from_num(self.config.readmit_liability_ratio) => {}
+                Ok(_) => {
+                    trace!(
+                        "Dropping update for evicted account {}",
+                        marginfi_account_address
+                    );
+                    return Ok(());
+                }
+                Err(e) => {
+                    trace!(
+                        "Dropping update for evicted account {} whose health can't be computed: {:?}",
+                        marginfi_account_address,
+                        e
+                    );
+                    return Ok(());
+                }
+            }
+
+            info!(
+                "Readmitting evicted account {} approaching liquidation",
+                marginfi_account_address
+            );
+            self.evicted_accounts.remove(marginfi_account_address);
+        }
+
         debug!("Updating marginfi account {}", marginfi_account_address);
 
-        marginfi_accounts
-            .entry(*marginfi_account_address)
-            .and_modify(|marginfi_account_ref| {
-                let mut marginfi_account_guard = marginfi_account_ref.write().unwrap();
-                marginfi_account_guard.account = marginfi_account.clone();
+        upsert_marginfi_account(
+            &marginfi_accounts,
+            *marginfi_account_address,
+            marginfi_account,
+            &self.banks,
+        );
+
+        Ok(())
+    }
+
+    /// Evict the least relevant accounts until at most `max_tracked_accounts` are tracked
+    ///
+    /// Accounts without liabilities go first, then the ones furthest from the liquidation
+    /// threshold. The signer's own accounts are never evicted. Evicted accounts are readmitted
+    /// once one of their updates shows them approaching liquidation, other updates are dropped.
+    fn enforce_tracked_accounts_limit(&self) {
+        let max_tracked_accounts = match self.config.max_tracked_accounts {
+            Some(max_tracked_accounts) => max_tracked_accounts,
+            None => return,
+        };
+
+        let excess = self
+            .marginfi_accounts
+            .len()
+            .saturating_sub(max_tracked_accounts);
+
+        if excess == 0 {
+            return;
+        }
+
+        let accounts_by_relevance = self
+            .marginfi_accounts
+            .iter()
+            .filter_map(|entry| {
+                let account = entry.value().read().ok()?;
+
+                if account.account.authority == self.config.signer_pubkey {
+                    return None;
+                }
+
+                Some((account.try_maintenance_liability_ratio().ok(), *entry.key()))
             })
-            .or_insert_with(|| {
-                Arc::new(RwLock::new(MarginfiAccountWrapper::new(
-                    *marginfi_account_address,
-                    marginfi_account.clone(),
-                    self.banks.clone(),
-                )))
+            .collect::<Vec<_>>();
+
+        for address in least_relevant_accounts(accounts_by_relevance, excess) {
+            self.marginfi_accounts.remove(&address);
+            self.evicted_accounts.insert(address);
+        }
+
+        info!(
+            "Evicted {} accounts to stay within {} tracked accounts",
+            excess, max_tracked_accounts
+        );
+    }
+
+    /// Drop the state of an account closed on-chain, returning whether anything was tracked
+    /// for it
+    ///
+    /// Marginfi accounts and banks are removed. Token accounts stay tracked with a zero
+    /// balance, as the liquidator reopens them when needed.
+    pub fn remove_closed_account(&self, address: &Pubkey) -> bool {
+        let mut removed = self.marginfi_accounts.remove(address).is_some();
+
+        self.pending_account_updates.remove(address);
+        self.evicted_accounts.remove(address);
+
+        if self.banks.remove(address).is_some() {
+            self.bank_mints.remove(address);
+
+            let is_other_bank = |bank: &Arc<RwLock<BankWrapper>>| {
+                bank.read().map_or(true, |bank| bank.address != *address)
+            };
+
+            self.mint_to_bank_map.retain(|_, banks| {
+                banks.retain(is_other_bank);
+                !banks.is_empty()
+            });
+            self.oracle_to_bank_map.retain(|_, banks| {
+                banks.retain(is_other_bank);
+                !banks.is_empty()
             });
 
-        Ok(())
+            removed = true;
+        }
+
+        for token_account in self.token_accounts.iter() {
+            if let Ok(mut token_account) = token_account.write() {
+                if token_account.address == *address {
+                    token_account.balance = 0;
+                    removed = true;
+                }
+            }
+        }
+
+        removed
+    }
+
+    pub fn coalesces_account_updates(&self) -> bool {
+        self.config.account_update_coalescing_window_ms > 0
+    }
+
+    /// Buffer a marginfi account update until the end of the coalescing window, replacing the
+    /// update of the same account buffered before it
+    pub fn buffer_marginfi_account_update(&self, address: Pubkey, account: Account) {
+        if self
+            .pending_account_updates
+            .insert(address, account)
+            .is_some()
+        {
+            trace!("Coalesced update of marginfi account {}", address);
+        }
+    }
+
+    /// Apply the buffered marginfi account updates at the end of every coalescing window,
+    /// signaling a single scan for all of them
+    async fn run_account_update_flush(self: Arc<Self>, shutdown: Arc<AtomicBool>) {
+        let window = Duration::from_millis(self.config.account_update_coalescing_window_ms);
+
+        loop {
+            tokio::time::sleep(window).await;
+
+            if shutdown.load(Ordering::Relaxed) {
+                return;
+            }
+
+            let mut applied_updates = 0;
+
+            for (address, account) in drain_pending_updates(&self.pending_account_updates) {
+                match self.update_marginfi_account(&address, &account) {
+                    Ok(()) => applied_updates += 1,
+                    Err(e) => warn!("Failed to apply update of {}: {:?}", address, e),
+                }
+            }
+
+            if applied_updates > 0 {
+                trace!("Applied {} coalesced account updates", applied_updates);
+                self.trigger_update_signal();
+            }
+        }
+    }
+
+    /// Refresh the banks of oracles whose updates were deferred by the debounce once their
+    /// interval elapsed, so the last update of a burst is applied without waiting for the next
+    async fn run_debounced_oracle_refresh(
+        self: Arc<Self>,
+        min_refresh_interval: Duration,
+        shutdown: Arc<AtomicBool>,
+    ) {
+        loop {
+            tokio::time::sleep(min_refresh_interval).await;
+
+            if shutdown.load(Ordering::Relaxed) {
+                return;
+            }
+
+            let oracle_addresses = self
+                .debounced_oracles
+                .iter()
+                .map(|entry| *entry.key())
+                .collect::<Vec<_>>();
+
+            let mut refreshed_oracles = 0;
+
+            for oracle_address in oracle_addresses {
+                self.debounced_oracles.remove(&oracle_address);
+
+                let banks = match self.oracle_to_bank_map.get(&oracle_address) {
+                    Some(banks) => banks.value().clone(),
+                    None => continue,
+                };
+
+                if self.refresh_oracle_banks(&oracle_address, &banks) {
+                    refreshed_oracles += 1;
+                }
+            }
+
+            if refreshed_oracles > 0 {
+                trace!("Refreshed {} debounced oracles", refreshed_oracles);
+                self.trigger_update_signal();
+            }
+        }
     }
 
     pub fn trigger_update_signal(&self) {
         match self.update_tx.try_send(()) {
-            Ok(_) => debug!("Sent update signal"),
+            Ok(_) => trace!("Sent update signal"),
+            // Signals are drained all at once, pending ones already wake the processor up
+            Err(TrySendError::Full(_)) => trace!("Update signal channel full"),
             Err(e) => error!("Failed to send update signal: {}", e),
         }
     }
 
-    pub async fn start(self: &Arc<Self>) -> anyhow::Result<()> {
-        let geyser_handle =
-            GeyserService::connect(self.config.get_geyser_service_config(), self.clone()).await?;
+    /// Run the geyser subscription and the background update tasks until `shutdown` is set
+    pub async fn start(self: &Arc<Self>, shutdown: Arc<AtomicBool>) -> anyhow::Result<()> {
+        let mut update_tasks = Vec::new();
+
+        if self.config.refresh_interval_ms > 0 {
+            let state_engine = self.clone();
+            let shutdown = shutdown.clone();
+            update_tasks.push(tokio::spawn(async move {
+                state_engine.run_periodic_refresh(shutdown).await
+            }));
+        }
+
+        if self.coalesces_account_updates() {
+            let state_engine = self.clone();
+            let shutdown = shutdown.clone();
+            update_tasks.push(tokio::spawn(async move {
+                state_engine.run_account_update_flush(shutdown).await
+            }));
+        }
+
+        if let Some(min_refresh_interval) = self.config.get_oracle_refresh_min_interval() {
+            let state_engine = self.clone();
+            let shutdown = shutdown.clone();
+            update_tasks.push(tokio::spawn(async move {
+                state_engine
+                    .run_debounced_oracle_refresh(min_refresh_interval, shutdown)
+                    .await
+            }));
+        }
+
+        if self.config.state_snapshot_path.is_some() && self.config.state_snapshot_interval_secs > 0
+        {
+            let state_engine = self.clone();
+            let shutdown = shutdown.clone();
+            update_tasks.push(tokio::spawn(async move {
+                state_engine.run_periodic_snapshot(shutdown).await
+            }));
+        }
+
+        let geyser_handle = GeyserService::connect(
+            self.config.get_geyser_service_config(),
+            self.clone(),
+            shutdown,
+        )
+        .await?;
 
         info!("StateEngineService connected to geyser");
 
-        geyser_handle.await??;
+        let result = geyser_handle.await;
+
+        update_tasks.iter().for_each(|task| task.abort());
+
+        if self.config.state_snapshot_path.is_some() {
+            let state_engine = self.clone();
+            match tokio::task::spawn_blocking(move || state_engine.save_state_snapshot()).await {
+                Ok(Ok(())) => info!("Saved state snapshot"),
+                Ok(Err(e)) => error!("Failed to save state snapshot: {:?}", e),
+                Err(e) => error!("State snapshot task failed: {:?}", e),
+            }
+        }
+
+        info!("StateEngineService stopped");
+
+        result??;
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn discriminator_check_rejects_other_account_types() {
+        let address = Pubkey::new_unique();
+
+        let mut bank_data = vec![0u8; 64];
+        bank_data[..8].copy_from_slice(&Bank::DISCRIMINATOR);
+
+        let mut marginfi_account_data = vec![0u8; 64];
+        marginfi_account_data[..8].copy_from_slice(&MarginfiAccount::DISCRIMINATOR);
+
+        assert!(check_discriminator::<Bank>(&address, &bank_data).is_ok());
+        assert!(check_discriminator::<Bank>(&address, &marginfi_account_data).is_err());
+        assert!(check_discriminator::<MarginfiAccount>(&address, &bank_data).is_err());
+        assert!(check_discriminator::<Bank>(&address, &[0u8; 64]).is_err());
+        assert!(check_discriminator::<Bank>(&address, &Bank::DISCRIMINATOR[..4]).is_err());
+    }
+
+    #[test]
+    fn eviction_picks_unpriceable_then_healthiest_accounts() {
+        let unpriceable = Pubkey::new_unique();
+        let no_liabilities = Pubkey::new_unique();
+        let healthy = Pubkey::new_unique();
+        let near_liquidation = Pubkey::new_unique();
+
+        let accounts = vec![
+            (Some(I80F48::from_num(0.95)), near_liquidation),
+            (Some(I80F48::from_num(0.3)), healthy),
+            (None, unpriceable),
+            (Some(I80F48::ZERO), no_liabilities),
+        ];
+
+        assert_eq!(
+            least_relevant_accounts(accounts.clone(), 3),
+            vec![unpriceable, no_liabilities, healthy]
+        );
+        assert!(least_relevant_accounts(accounts.clone(), 0).is_empty());
+        assert_eq!(least_relevant_accounts(accounts, 10).len(), 4);
+    }
+
+    #[test]
+    fn debounce_defers_refreshes_within_the_interval() {
+        let interval = Duration::from_millis(200);
+        let refreshed_at = Instant::now();
+
+        assert!(!is_debounced(Some(interval), None, refreshed_at));
+        assert!(is_debounced(
+            Some(interval),
+            Some(refreshed_at),
+            refreshed_at + Duration::from_millis(50)
+        ));
+        // The trailing refresh runs once the interval elapsed
+        assert!(!is_debounced(
+            Some(interval),
+            Some(refreshed_at),
+            refreshed_at + interval
+        ));
+        assert!(!is_debounced(None, Some(refreshed_at), refreshed_at));
+    }
+
+    #[test]
+    fn bank_oracle_max_age_overrides_the_default() {
+        assert_eq!(oracle_max_age_secs(30, Some(60)), Some(30));
+        assert_eq!(oracle_max_age_secs(300, None), Some(300));
+        assert_eq!(oracle_max_age_secs(0, Some(60)), Some(60));
+        assert_eq!(oracle_max_age_secs(0, None), None);
+    }
+
+    #[test]
+    fn oracle_price_type_overrides_are_keyed_by_bank_address() {
+        #[derive(serde::Deserialize)]
+        struct Overrides {
+            #[serde(deserialize_with = "from_pubkey_string_map")]
+            oracle_price_type_overrides: HashMap<Pubkey, OraclePriceTypeOverride>,
+        }
+
+        let bank = Pubkey::new_unique();
+        let overrides: Overrides = toml::from_str(&format!(
+            "[oracle_price_type_overrides]\n\"{}\" = \"time_weighted\"\n",
+            bank
+        ))
+        .unwrap();
+
+        assert!(matches!(
+            overrides
+                .oracle_price_type_overrides
+                .get(&bank)
+                .copied()
+                .map(OraclePriceType::from),
+            Some(OraclePriceType::TimeWeighted)
+        ));
+        assert!(overrides
+            .oracle_price_type_overrides
+            .get(&Pubkey::new_unique())
+            .is_none());
+    }
+
+    /// SPL token account of `mint` holding `amount`
+    fn token_account_fixture(mint: &Pubkey, amount: u64) -> Account {
+        let mut data = vec![0u8; 165];
+        data[..32].copy_from_slice(mint.as_ref());
+        data[32..64].copy_from_slice(Pubkey::new_unique().as_ref());
+        data[64..72].copy_from_slice(&amount.to_le_bytes());
+
+        Account {
+            lamports: 2_039_280,
+            data,
+            owner: spl_token::ID,
+            executable: false,
+            rent_epoch: 0,
+        }
+    }
+
+    #[test]
+    fn missing_token_accounts_have_no_balance() {
+        let mint = Pubkey::new_unique();
+        let token_account = token_account_fixture(&mint, 42);
+
+        assert_eq!(existing_token_account_balance(None), None);
+        assert_eq!(
+            existing_token_account_balance(Some(&token_account)),
+            Some(42)
+        );
+        // Streamed once created, the wrapper is keyed by the account's mint
+        assert_eq!(accessor::mint(&token_account.data), mint);
+    }
+
+    #[test]
+    fn utilization_is_the_borrowed_share_of_deposits() {
+        let mut bank: Bank = bytemuck::Zeroable::zeroed();
+        bank.asset_share_value = I80F48::from_num(2).into();
+        bank.liability_share_value = I80F48::ONE.into();
+
+        assert_eq!(calc_bank_utilization(&bank).unwrap(), I80F48::ZERO);
+
+        // 2000 deposited, 500 borrowed
+        bank.total_asset_shares = I80F48::from_num(1_000).into();
+        bank.total_liability_shares = I80F48::from_num(500).into();
+
+        assert_eq!(
+            calc_bank_utilization(&bank).unwrap(),
+            I80F48::from_num(0.25)
+        );
+    }
+
+    /// State engine config with the defaults, overridden by the TOML `overrides`
+    fn test_config(overrides: &str) -> StateEngineConfig {
+        toml::from_str(&format!(
+            "rpc_url = \"http://localhost:8899\"\nyellowstone_endpoint = \"http://localhost:10000\"\nsigner_pubkey = \"{}\"\n{}",
+            Pubkey::new_unique(),
+            overrides
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn marginfi_account_refresh_is_batched_by_the_configured_size() {
+        let loading_config = test_config("").get_marginfi_account_refresh_loading_config();
+        assert_eq!(loading_config.max_batch_size, 100);
+        assert!(!loading_config.allow_partial_failures);
+
+        let loading_config = test_config("marginfi_account_refresh_batch_size = 25")
+            .get_marginfi_account_refresh_loading_config();
+        assert_eq!(loading_config.max_batch_size, 25);
+        assert_eq!(
+            loading_config.max_concurrent_calls,
+            BatchLoadingConfig::DEFAULT.max_concurrent_calls
+        );
+    }
+
+    #[test]
+    fn refreshed_marginfi_account_is_updated_in_place() {
+        let marginfi_accounts = DashMap::new();
+        let banks = Arc::new(DashMap::new());
+        let address = Pubkey::new_unique();
+
+        let mut account: MarginfiAccount = bytemuck::Zeroable::zeroed();
+        upsert_marginfi_account(&marginfi_accounts, address, &account, &banks);
+
+        let tracked = marginfi_accounts.get(&address).unwrap().clone();
+        assert_eq!(tracked.read().unwrap().version, 0);
+
+        account.authority = Pubkey::new_unique();
+        upsert_marginfi_account(&marginfi_accounts, address, &account, &banks);
+
+        let tracked = tracked.read().unwrap();
+        assert_eq!(tracked.account.authority, account.authority);
+        assert_eq!(tracked.version, 1);
+        assert_eq!(marginfi_accounts.len(), 1);
+    }
+
+    #[test]
+    fn token_balances_are_scaled_by_the_mint_decimals() {
+        // Decimals are taken from the bank, which copies them from the mint
+        let mut bank: Bank = bytemuck::Zeroable::zeroed();
+        bank.mint_decimals = 6;
+
+        assert_eq!(
+            native_to_ui_amount(1_500_000, bank.mint_decimals),
+            Some(I80F48::from_num(1.5))
+        );
+        assert_eq!(
+            native_to_ui_amount(1_500_000, 0),
+            Some(I80F48::from_num(1_500_000))
+        );
+        assert_eq!(native_to_ui_amount(1, u8::MAX), None);
+    }
+
+    #[test]
+    fn buffered_account_updates_keep_the_latest_per_account() {
+        let pending_updates = DashMap::new();
+        let address = Pubkey::new_unique();
+        let other_address = Pubkey::new_unique();
+
+        pending_updates.insert(address, Account::new(1, 0, &Pubkey::default()));
+        pending_updates.insert(other_address, Account::new(2, 0, &Pubkey::default()));
+        pending_updates.insert(address, Account::new(3, 0, &Pubkey::default()));
+
+        let mut updates = drain_pending_updates(&pending_updates)
+            .into_iter()
+            .map(|(address, account)| (address, account.lamports))
+            .collect::<Vec<_>>();
+        updates.sort();
+
+        let mut expected = vec![(address, 3), (other_address, 2)];
+        expected.sort();
+
+        assert_eq!(updates, expected);
+        assert!(pending_updates.is_empty());
+        assert!(drain_pending_updates(&pending_updates).is_empty());
+    }
+
+    #[test]
+    fn bank_filters_match_the_bank_layout() {
+        let mut bank: Bank = bytemuck::Zeroable::zeroed();
+        bank.mint = Pubkey::new_unique();
+        bank.group = Pubkey::new_unique();
+
+        let data = [&Bank::DISCRIMINATOR[..], bytemuck::bytes_of(&bank)].concat();
+
+        assert_eq!(
+            &data[BANK_MINT_OFFSET..BANK_MINT_OFFSET + 32],
+            bank.mint.as_ref()
+        );
+        assert_eq!(
+            &data[BANK_GROUP_PK_OFFSET..BANK_GROUP_PK_OFFSET + 32],
+            bank.group.as_ref()
+        );
+    }
+
+    #[test]
+    fn closed_marginfi_accounts_are_dropped() {
+        let (state_engine, _) = StateEngineService::new(test_config("")).unwrap();
+
+        let closed = Pubkey::new_unique();
+        let open = Pubkey::new_unique();
+
+        for address in [closed, open] {
+            let account = MarginfiAccountWrapper::new(
+                address,
+                bytemuck::Zeroable::zeroed(),
+                state_engine.banks.clone(),
+            );
+            state_engine
+                .marginfi_accounts
+                .insert(address, Arc::new(RwLock::new(account)));
+        }
+        state_engine
+            .pending_account_updates
+            .insert(closed, Account::default());
+        state_engine.evicted_accounts.insert(closed);
+
+        assert!(state_engine.remove_closed_account(&closed));
+
+        assert!(!state_engine.marginfi_accounts.contains_key(&closed));
+        assert!(state_engine.marginfi_accounts.contains_key(&open));
+        assert!(!state_engine.pending_account_updates.contains_key(&closed));
+        assert!(!state_engine.evicted_accounts.contains(&closed));
+
+        // Nothing is tracked anymore for it, nor for an account never seen
+        assert!(!state_engine.remove_closed_account(&closed));
+        assert!(!state_engine.remove_closed_account(&Pubkey::new_unique()));
+    }
+
+    #[test]
+    fn snapshots_are_only_restored_with_the_periodic_refresh() {
+        let path = std::env::temp_dir().join(format!(
+            "eva_state_snapshot_{}_{}.bin",
+            std::process::id(),
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos()
+        ));
+        let path = path.to_string_lossy().to_string();
+
+        let config = test_config(&format!("state_snapshot_path = \"{}\"", path));
+        StateSnapshot::new(config.marginfi_group_address, 1, vec![])
+            .save(&path)
+            .unwrap();
+
+        let (state_engine, _) = StateEngineService::new(config).unwrap();
+        assert!(state_engine.load_state_snapshot().is_some());
+
+        let (state_engine, _) = StateEngineService::new(test_config(&format!(
+            "state_snapshot_path = \"{}\"\nrefresh_interval_ms = 0",
+            path
+        )))
+        .unwrap();
+        assert!(state_engine.load_state_snapshot().is_none());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
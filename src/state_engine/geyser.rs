@@ -1,8 +1,9 @@
 use std::mem::size_of;
-use std::time::Instant;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 use std::{collections::HashMap, sync::Arc};
 
-use backoff::{retry, ExponentialBackoff};
+use backoff::{backoff::Backoff, ExponentialBackoff};
 use futures::channel::mpsc::SendError;
 use futures::SinkExt;
 use futures::StreamExt;
@@ -19,6 +20,7 @@ use tonic::service::Interceptor;
 use yellowstone_grpc_client::{GeyserGrpcClient, GeyserGrpcClientError};
 use yellowstone_grpc_proto::prelude::*;
 
+use crate::metrics::metrics;
 use crate::utils::account_update_to_account;
 
 use super::engine::StateEngineService;
@@ -35,6 +37,9 @@ pub enum GeyserServiceError {
     SendError(#[from] SendError),
 }
 
+/// Time a geyser connection has to stay up for its reconnect backoff to be reset
+const STABLE_CONNECTION_DURATION: Duration = Duration::from_secs(60);
+
 const BANK_SIZE: usize = size_of::<Bank>() + 8;
 const MARGIN_ACCOUNT_SIZE: usize = size_of::<MarginfiAccount>() + 8;
 
@@ -93,40 +98,85 @@ impl GeyserRequestUpdate {
 pub struct GeyserServiceConfig {
     pub endpoint: String,
     pub x_token: Option<String>,
+    pub reconnect_max_interval: Duration,
 }
 
 pub struct GeyserService {}
 
 impl GeyserService {
+    /// Connect to geyser and keep the subscription running, reconnecting with exponential
+    /// backoff whenever the stream drops
+    ///
+    /// Each reconnect subscribes to the accounts tracked at that time, so banks and oracles
+    /// added while disconnected are covered. Once `shutdown` is set, the stream is closed on
+    /// the next message and the task returns instead of reconnecting.
     pub async fn connect(
         config: GeyserServiceConfig,
         state_engine: Arc<StateEngineService>,
+        shutdown: Arc<AtomicBool>,
     ) -> Result<JoinHandle<Result<(), GeyserServiceError>>, GeyserServiceError> {
-        let handle = retry(
-            ExponentialBackoff::default(),
-            move || -> Result<JoinHandle<Result<(), GeyserServiceError>>, backoff::Error<GeyserServiceError>> {
-                let endpoint = config.endpoint.clone();
-                let x_token = config.x_token.clone();
+        let handle = tokio::spawn(async move {
+            let mut reconnect_backoff = ExponentialBackoff {
+                max_interval: config.reconnect_max_interval,
+                max_elapsed_time: None,
+                ..Default::default()
+            };
+            let mut disconnected_at: Option<Instant> = None;
 
-                let geyser_client =
-                    yellowstone_grpc_client::GeyserGrpcClient::connect(endpoint, x_token, None)
-                        .map_err(|e| backoff::Error::transient(e.into()))?;
+            loop {
+                if let Some(disconnected_at) = disconnected_at {
+                    let delay = reconnect_backoff
+                        .next_backoff()
+                        .unwrap_or(config.reconnect_max_interval);
+
+                    warn!(
+                        "Reconnecting to geyser in {:?}, disconnected for {:?}",
+                        delay,
+                        disconnected_at.elapsed()
+                    );
+
+                    tokio::time::sleep(delay).await;
+                }
 
-                info!("Connected to geyser");
+                let connected_at = Instant::now();
+
+                let result = match yellowstone_grpc_client::GeyserGrpcClient::connect(
+                    config.endpoint.clone(),
+                    config.x_token.clone(),
+                    None,
+                ) {
+                    Ok(geyser_client) => {
+                        info!("Connected to geyser");
+                        Self::subscribe_and_run(
+                            geyser_client,
+                            state_engine.clone(),
+                            shutdown.clone(),
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e.into()),
+                };
 
-                let state_engine = state_engine.clone();
+                if shutdown.load(Ordering::Relaxed) {
+                    info!("Geyser service shut down");
+                    return Ok(());
+                }
 
-                let handle = tokio::spawn(async move {
-                    Self::subscribe_and_run(geyser_client, state_engine).await?;
-                    error!("Geyser service ended");
+                match result {
+                    Ok(()) => warn!("Geyser stream ended"),
+                    Err(e) => error!("Geyser stream failed: {:?}", e),
+                }
 
-                    Ok(())
-                });
+                // Only a connection that held up counts as recovered, a flapping one keeps
+                // backing off
+                if connected_at.elapsed() >= STABLE_CONNECTION_DURATION {
+                    reconnect_backoff.reset();
+                    disconnected_at = None;
+                }
 
-                Ok::<_, backoff::Error<GeyserServiceError>>(handle)
-            },
-        )
-        .map_err(|_| GeyserServiceError::GenericError)?;
+                disconnected_at.get_or_insert_with(Instant::now);
+            }
+        });
 
         Ok(handle)
     }
@@ -134,6 +184,7 @@ impl GeyserService {
     async fn subscribe_and_run(
         mut geyser_client: GeyserGrpcClient<impl Interceptor + 'static>,
         state_engine: Arc<StateEngineService>,
+        shutdown: Arc<AtomicBool>,
     ) -> Result<(), GeyserServiceError> {
         debug!("Subscribing to geyser");
         let sub_req = Self::build_geyser_subscribe_request(&state_engine);
@@ -144,7 +195,16 @@ impl GeyserService {
             GeyserServiceError::GenericError
         })?;
 
-        let _state_engine_clone = state_engine.clone();
+        // Pings and re-subscriptions are both sent through this channel, forwarded to geyser
+        let (request_tx, request_rx) = futures::channel::mpsc::unbounded::<SubscribeRequest>();
+
+        let forward_handle = tokio::task::spawn(async move {
+            if let Err(e) = request_rx.map(Ok).forward(subscribe_tx).await {
+                error!("Error sending message to geyser: {:?}", e);
+            }
+        });
+
+        let ping_tx = request_tx.clone();
 
         let handle = tokio::task::spawn(async move {
             let mut ping_id = 1;
@@ -154,14 +214,12 @@ impl GeyserService {
 
                 debug!("Sending ping to geyser server");
 
-                if let Err(e) = subscribe_tx
-                    .send(SubscribeRequest {
-                        ping: Some(SubscribeRequestPing { id: ping_id }),
-                        ..Default::default()
-                    })
-                    .await
-                {
+                if let Err(e) = ping_tx.unbounded_send(SubscribeRequest {
+                    ping: Some(SubscribeRequestPing { id: ping_id }),
+                    ..Default::default()
+                }) {
                     error!("Error sending message to geyser: {:?}", e);
+                    break;
                 }
 
                 ping_id += 1;
@@ -169,6 +227,10 @@ impl GeyserService {
         });
 
         while let Some(msg) = subscribe_rx.next().await {
+            if shutdown.load(Ordering::Relaxed) {
+                break;
+            }
+
             let start = Instant::now();
             // if last_heartbeat.elapsed() > std::time::Duration::from_secs(5) {
             //     debug!("Sending heartbeat to geyser");
@@ -179,27 +241,42 @@ impl GeyserService {
             //     last_heartbeat = Instant::now();
             // }
 
-            let _update = match msg {
-                Ok(msg) => Self::process_message(&state_engine, msg)?,
+            let update = match msg {
+                // A single malformed or wrong-typed account must not end the subscription, its
+                // update is dropped and the stream goes on
+                Ok(msg) => match Self::process_message(&state_engine, msg) {
+                    Ok(update) => update,
+                    Err(e) => {
+                        error!("Failed to process geyser update, skipping it: {:?}", e);
+                        false
+                    }
+                },
                 Err(e) => {
                     error!("Error receiving message from geyser: {:?}", e);
                     false
                 }
             };
 
-            // if update {
-            //     let sub_req = Self::build_geyser_subscribe_request(&state_engine);
-            //     if let Err(e) = subscribe_tx.send(sub_req.clone()).await {
-            //         error!("Error sending message to geyser: {:?}", e);
-            //     }
-            // }
+            // A new bank brings a new oracle to track, the subscription is replaced with one
+            // covering all accounts currently tracked
+            if update {
+                let sub_req = Self::build_geyser_subscribe_request(&state_engine);
+                if let Err(e) = request_tx.unbounded_send(sub_req) {
+                    error!("Error sending message to geyser: {:?}", e);
+                }
+            }
 
             trace!("Processed message in {:?}", start.elapsed());
         }
 
-        let _ = handle.await;
+        handle.abort();
+        forward_handle.abort();
 
-        error!("Geyser subscription ended");
+        if shutdown.load(Ordering::Relaxed) {
+            info!("Geyser subscription closed");
+        } else {
+            error!("Geyser subscription ended");
+        }
 
         Ok(())
     }
@@ -221,31 +298,58 @@ impl GeyserService {
                     }
 
                     let mut processed = false;
+                    let mut buffered = false;
                     if let Some(account) = &account.account {
+                        // Closed accounts are drained of their lamports and handed back to the
+                        // system program, they no longer match any owner
+                        if account.lamports == 0 {
+                            if let Ok(address) = Pubkey::try_from(account.pubkey.clone()) {
+                                if state_engine.remove_closed_account(&address) {
+                                    info!("Account {} was closed, removed it", address);
+                                }
+                            }
+
+                            return Ok(false);
+                        }
+
                         if let Ok(account_owner_pk) = Pubkey::try_from(account.owner.clone()) {
                             if account_owner_pk == state_engine.get_marginfi_program_id() {
                                 let maybe_update =
                                     Self::process_marginfi_account_update(state_engine, &account)?;
+                                metrics()
+                                    .geyser_updates
+                                    .with_label_values(&["marginfi"])
+                                    .inc();
                                 if let Some(_update) = maybe_update {
                                     geyser_update_request = true;
                                 }
                                 processed = true;
+                                // Buffered updates signal the processor once applied, at the
+                                // end of the coalescing window
+                                buffered = state_engine.coalesces_account_updates()
+                                    && account.data.len() == MARGIN_ACCOUNT_SIZE;
                             }
                         }
 
                         if let Ok(address) = Pubkey::try_from(account.pubkey.clone()) {
                             if state_engine.is_tracked_oracle(&address) {
                                 Self::process_oracle_account_update(state_engine, &account)?;
+                                metrics()
+                                    .geyser_updates
+                                    .with_label_values(&["oracle"])
+                                    .inc();
                                 processed = true;
                             }
 
                             if state_engine.is_tracked_token_account(&address) {
                                 Self::process_token_account_update(state_engine, &account)?;
+                                metrics().geyser_updates.with_label_values(&["token"]).inc();
                                 processed = true;
                             }
 
                             if state_engine.is_tracked_sol_account(&address) {
-                                // Self::process_token_account_update(state_engine, &account)?;
+                                Self::process_sol_account_update(state_engine, &account)?;
+                                metrics().geyser_updates.with_label_values(&["sol"]).inc();
                                 processed = true;
                             }
                         }
@@ -255,8 +359,8 @@ impl GeyserService {
                             "None of the updates were processed for account {:?}",
                             account
                         );
-                    } else {
-                        // state_engine.trigger_update_signal();
+                    } else if !buffered {
+                        state_engine.trigger_update_signal();
                     }
                 }
                 subscribe_update::UpdateOneof::Ping(_) => return Ok(false),
@@ -300,7 +404,11 @@ impl GeyserService {
             }
             MARGIN_ACCOUNT_SIZE => {
                 debug!("Processing marginfi account update");
-                state_engine.update_marginfi_account(&account_address, &account)?;
+                if state_engine.coalesces_account_updates() {
+                    state_engine.buffer_marginfi_account_update(account_address, account);
+                } else {
+                    state_engine.update_marginfi_account(&account_address, &account)?;
+                }
             }
             _ => {
                 warn!(
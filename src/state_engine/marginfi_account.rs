@@ -1,6 +1,7 @@
 use std::{
     cmp::min,
-    sync::{Arc, RwLock},
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
 };
 
 use dashmap::DashMap;
@@ -23,10 +24,36 @@ pub enum MarginfiAccountWrapperError {
     Error(&'static str),
 }
 
+/// Versions of the account data and of the banks of its active balances that a cached value
+/// was computed from, the cached value is stale as soon as any of them changes
+#[derive(Clone, PartialEq, Eq)]
+struct HealthCacheKey {
+    account_version: u64,
+    bank_versions: Vec<(Pubkey, u64)>,
+}
+
+/// Liquidation of an account on its best pair of banks, what the scan ranks candidates by
+#[derive(Debug, Clone, Copy)]
+pub struct LiquidationOpportunity {
+    pub account: Pubkey,
+    pub asset_bank: Pubkey,
+    pub liab_bank: Pubkey,
+    /// Maximum amount of the asset that can be seized, in native units
+    pub max_liquidatable_asset_amount: I80F48,
+    /// USD value of `max_liquidatable_asset_amount` at maintenance prices
+    pub max_liquidatable_value: I80F48,
+    /// Liquidator fee earned on `max_liquidatable_value` in USD, before priority fees and
+    /// swap slippage
+    pub estimated_profit: I80F48,
+}
+
 pub struct MarginfiAccountWrapper {
     pub address: Pubkey,
     pub account: MarginfiAccount,
     pub banks: Arc<DashMap<Pubkey, Arc<RwLock<BankWrapper>>>>,
+    /// Bumped on every account update, used to invalidate values computed from the account
+    pub version: u64,
+    max_liquidatable_cache: Mutex<Option<(HealthCacheKey, Option<LiquidationOpportunity>)>>,
 }
 
 impl MarginfiAccountWrapper {
@@ -39,9 +66,40 @@ impl MarginfiAccountWrapper {
             address,
             account,
             banks,
+            version: 0,
+            max_liquidatable_cache: Mutex::new(None),
         }
     }
 
+    /// Copy of the account evaluated against `banks` instead of the live banks, e.g. a
+    /// snapshot from [`super::engine::StateEngineService::snapshot_banks`]
+    pub fn with_banks(&self, banks: Arc<DashMap<Pubkey, Arc<RwLock<BankWrapper>>>>) -> Self {
+        let cached = self
+            .max_liquidatable_cache
+            .lock()
+            .ok()
+            .and_then(|cache| cache.clone());
+
+        Self {
+            address: self.address,
+            account: self.account,
+            banks,
+            version: self.version,
+            max_liquidatable_cache: Mutex::new(cached),
+        }
+    }
+
+    /// Banks of the account's active balances
+    pub fn get_active_banks(&self) -> Vec<Pubkey> {
+        self.account
+            .lending_account
+            .balances
+            .iter()
+            .filter(|b| b.active)
+            .map(|b| b.bank_pk)
+            .collect()
+    }
+
     pub fn has_liabs(&self) -> bool {
         self.account
             .lending_account
@@ -191,6 +249,15 @@ impl MarginfiAccountWrapper {
         Ok(deposits)
     }
 
+    /// Unweighted value in USD of all deposits of the account
+    pub fn get_total_deposits_value(&self) -> anyhow::Result<I80F48> {
+        Ok(self
+            .get_deposits_values(RequirementType::Equity)?
+            .into_iter()
+            .map(|(value, _)| value)
+            .sum())
+    }
+
     pub fn get_balance_for_bank(
         &self,
         bank_pk: &Pubkey,
@@ -267,22 +334,155 @@ impl MarginfiAccountWrapper {
     }
 
     pub fn calc_health(&self, requirement_type: RequirementType) -> (I80F48, I80F48) {
+        self.try_calc_health(requirement_type).unwrap()
+    }
+
+    /// Weighted assets and liabilities of the account, failing when one of its banks isn't
+    /// loaded or can't be priced
+    pub fn try_calc_health(
+        &self,
+        requirement_type: RequirementType,
+    ) -> anyhow::Result<(I80F48, I80F48)> {
         let baws =
-            BankAccountWithPriceFeedEva::load(&self.account.lending_account, self.banks.clone())
-                .unwrap();
+            BankAccountWithPriceFeedEva::load(&self.account.lending_account, self.banks.clone())?;
 
-        baws.iter().fold(
+        baws.iter().try_fold(
             (I80F48::ZERO, I80F48::ZERO),
             |(total_assets, total_liabs), baw| {
-                let (assets, liabs) = baw
-                    .calc_weighted_assets_and_liabilities_values(requirement_type)
-                    .unwrap();
+                let (assets, liabs) =
+                    baw.calc_weighted_assets_and_liabilities_values(requirement_type)?;
 
-                (total_assets + assets, total_liabs + liabs)
+                Ok((total_assets + assets, total_liabs + liabs))
             },
         )
     }
 
+    /// Whether an oracle of the banks of the account's active balances went silent, i.e. wasn't
+    /// updated within its `expected_update_interval`
+    pub fn has_silent_oracles(
+        &self,
+        expected_update_interval: impl Fn(&Pubkey) -> Option<Duration>,
+    ) -> bool {
+        self.account
+            .lending_account
+            .balances
+            .iter()
+            .filter(|b| b.active)
+            .any(|b| {
+                self.banks
+                    .get(&b.bank_pk)
+                    .and_then(|bank| {
+                        bank.read().ok().map(|bank| {
+                            expected_update_interval(&bank.oracle_adapter.address)
+                                .map_or(false, |interval| {
+                                    bank.oracle_adapter.update_age() > interval
+                                })
+                        })
+                    })
+                    .unwrap_or(false)
+            })
+    }
+
+    /// Whether any bank of the account's active balances has no good oracle price within its
+    /// maximum age, see [`BankWrapper::get_oracle_max_age`]
+    pub fn has_stale_oracles(&self, default_max_age_secs: Option<u64>) -> bool {
+        self.account
+            .lending_account
+            .balances
+            .iter()
+            .filter(|b| b.active)
+            .any(|b| {
+                self.banks
+                    .get(&b.bank_pk)
+                    .and_then(|bank| {
+                        bank.read().ok().map(|bank| {
+                            bank.get_oracle_max_age(default_max_age_secs)
+                                .map_or(false, |max_age| bank.oracle_adapter.is_stale(max_age))
+                        })
+                    })
+                    .unwrap_or(true)
+            })
+    }
+
+    /// Banks of the account's active balances that don't belong to `group`
+    ///
+    /// Only banks of the liquidator's group are loaded, so a bank that isn't loaded counts as
+    /// belonging to another group.
+    pub fn get_cross_group_banks(&self, group: &Pubkey) -> Vec<Pubkey> {
+        self.account
+            .lending_account
+            .balances
+            .iter()
+            .filter(|b| b.active)
+            .filter(|b| {
+                self.banks
+                    .get(&b.bank_pk)
+                    .and_then(|bank| bank.read().ok().map(|bank| bank.bank.group != *group))
+                    .unwrap_or(true)
+            })
+            .map(|b| b.bank_pk)
+            .collect()
+    }
+
+    /// Banks of the account's active balances that aren't loaded or can't be priced, see
+    /// [`BankWrapper::is_priceable`]
+    pub fn get_unpriceable_banks(&self) -> Vec<Pubkey> {
+        self.get_active_banks()
+            .into_iter()
+            .filter(|bank_pk| {
+                self.banks
+                    .get(bank_pk)
+                    .and_then(|bank| bank.read().ok().map(|bank| !bank.is_priceable()))
+                    .unwrap_or(true)
+            })
+            .collect()
+    }
+
+    /// Whether the oracles of all banks of the account's active balances are warm, see
+    /// [`super::engine::OracleWrapper::is_warm`]
+    ///
+    /// Banks that aren't loaded, such as banks without an oracle, count as cold so accounts
+    /// holding them are never evaluated
+    pub fn has_warm_oracles(&self, initial_grace: Duration) -> bool {
+        self.account
+            .lending_account
+            .balances
+            .iter()
+            .filter(|b| b.active)
+            .all(|b| {
+                self.banks
+                    .get(&b.bank_pk)
+                    .and_then(|bank| {
+                        bank.read()
+                            .ok()
+                            .map(|bank| bank.oracle_adapter.is_warm(initial_grace))
+                    })
+                    .unwrap_or(false)
+            })
+    }
+
+    /// Ratio of maintenance weighted liabilities to assets, the account is liquidatable once
+    /// it exceeds 1
+    pub fn maintenance_liability_ratio(&self) -> I80F48 {
+        self.try_maintenance_liability_ratio().unwrap()
+    }
+
+    /// Same as [`Self::maintenance_liability_ratio`], failing when the account's health can't
+    /// be computed
+    pub fn try_maintenance_liability_ratio(&self) -> anyhow::Result<I80F48> {
+        if !self.has_liabs() {
+            return Ok(I80F48::ZERO);
+        }
+
+        let (assets, liabs) = self.try_calc_health(RequirementType::Maintenance)?;
+
+        if assets.is_zero() {
+            return Ok(I80F48::MAX);
+        }
+
+        Ok(liabs / assets)
+    }
+
     pub fn get_observation_accounts(
         &self,
         banks_to_include: &[Pubkey],
@@ -330,31 +530,103 @@ impl MarginfiAccountWrapper {
     }
 
     /// Find the banks that are candidates for liquidation
-    /// Returns the asset bank and the liability bank with the highest value
-    pub fn find_liquidaiton_bank_canididates(&self) -> anyhow::Result<(Pubkey, Pubkey)> {
+    /// Returns the asset bank and the liability bank with the highest value, leaving out the
+    /// banks `is_excluded` returns true for
+    ///
+    /// Returns `None` when the account has no viable pair, i.e. no deposit with any collateral
+    /// value (e.g. only isolated-tier deposits) or no liability
+    pub fn find_liquidaiton_bank_canididates(
+        &self,
+        is_excluded: impl Fn(&Pubkey) -> bool,
+    ) -> anyhow::Result<Option<(Pubkey, Pubkey)>> {
         let deposits = self.get_deposits_values(RequirementType::Maintenance)?;
         let liabs = self.get_liabilities_value(RequirementType::Maintenance)?;
 
-        let (asset_value, asset_bank) = deposits
-            .iter()
-            .max_by(|a, b| a.0.cmp(&b.0))
-            .ok_or_else(|| anyhow::anyhow!("No asset bank found"))?;
-
-        let (liab_value, liab_bank) = liabs
-            .iter()
-            .max_by(|a, b| a.0.cmp(&b.0))
-            .ok_or_else(|| anyhow::anyhow!("No liability bank found"))?;
+        let ((asset_value, asset_bank), (liab_value, liab_bank)) =
+            match largest_liquidation_candidates(&deposits, &liabs, is_excluded) {
+                (Some(asset), Some(liab)) => (asset, liab),
+                (None, _) => {
+                    debug!("Account {} has no collateral to liquidate", self.address);
+                    return Ok(None);
+                }
+                (_, None) => {
+                    debug!("Account {} has no liability to liquidate", self.address);
+                    return Ok(None);
+                }
+            };
 
         debug!(
             "Asset Bank: {:?}, Asset Value: {:?}, Liability Bank: {:?}, Liability Value: {:?}",
             asset_bank, asset_value, liab_bank, liab_value
         );
 
-        Ok((*asset_bank, *liab_bank))
+        Ok(Some((asset_bank, liab_bank)))
+    }
+
+    fn health_cache_key(&self) -> HealthCacheKey {
+        let bank_versions = self
+            .account
+            .lending_account
+            .balances
+            .iter()
+            .filter(|b| b.active)
+            .map(|b| {
+                let bank_version = self
+                    .banks
+                    .get(&b.bank_pk)
+                    .and_then(|bank| bank.read().ok().map(|bank| bank.version))
+                    .unwrap_or_default();
+
+                (b.bank_pk, bank_version)
+            })
+            .collect();
+
+        HealthCacheKey {
+            account_version: self.version,
+            bank_versions,
+        }
+    }
+
+    /// Same as [`Self::compute_max_liquidatable_asset_amount`], reusing the result of the last
+    /// computation as long as neither the account nor any of its banks or oracles changed since
+    ///
+    /// `is_excluded` must not change over the account's lifetime, the cache doesn't track it.
+    pub fn compute_max_liquidatable_asset_amount_cached(
+        &self,
+        is_excluded: impl Fn(&Pubkey) -> bool,
+    ) -> anyhow::Result<Option<LiquidationOpportunity>> {
+        let key = self.health_cache_key();
+
+        let mut cache = self
+            .max_liquidatable_cache
+            .lock()
+            .map_err(|_| anyhow::anyhow!("Failed to lock health cache"))?;
+
+        if let Some((cached_key, cached_value)) = cache.as_ref() {
+            if *cached_key == key {
+                trace!("Reusing cached health for account {}", self.address);
+                return Ok(*cached_value);
+            }
+        }
+
+        let value = self.compute_max_liquidatable_asset_amount(is_excluded)?;
+
+        *cache = Some((key, value));
+
+        Ok(value)
     }
 
-    pub fn compute_max_liquidatable_asset_amount(&self) -> anyhow::Result<(I80F48, I80F48)> {
-        let (asset_bank_pk, liab_bank_pk) = self.find_liquidaiton_bank_canididates()?;
+    /// Liquidation opportunity on the best pair of banks outside of those `is_excluded`
+    /// returns true for, `None` when the account is healthy or has no viable pair
+    pub fn compute_max_liquidatable_asset_amount(
+        &self,
+        is_excluded: impl Fn(&Pubkey) -> bool,
+    ) -> anyhow::Result<Option<LiquidationOpportunity>> {
+        let (asset_bank_pk, liab_bank_pk) =
+            match self.find_liquidaiton_bank_canididates(is_excluded)? {
+                Some(candidates) => candidates,
+                None => return Ok(None),
+            };
 
         self.compute_max_liquidatable_asset_amount_with_banks(
             self.banks.clone(),
@@ -363,18 +635,20 @@ impl MarginfiAccountWrapper {
         )
     }
 
+    /// Liquidation opportunity of the account on the given pair of banks, evaluated against
+    /// `banks`, `None` when the account is healthy
     pub fn compute_max_liquidatable_asset_amount_with_banks(
         &self,
         banks: Arc<DashMap<Pubkey, Arc<RwLock<BankWrapper>>>>,
         asset_bank_pk: &Pubkey,
         liab_bank_pk: &Pubkey,
-    ) -> anyhow::Result<(I80F48, I80F48)> {
+    ) -> anyhow::Result<Option<LiquidationOpportunity>> {
         let (assets, liabs) = self.calc_health(RequirementType::Maintenance);
 
         let maintenence_health = assets - liabs;
 
         if maintenence_health >= I80F48::ZERO {
-            return Ok((I80F48::ZERO, I80F48::ZERO));
+            return Ok(None);
         }
 
         let asset_bank = banks
@@ -454,6 +728,146 @@ impl MarginfiAccountWrapper {
             max_liquidatable_asset_amount, liquidator_profit
         );
 
-        Ok((max_liquidatable_asset_amount, liquidator_profit))
+        Ok(Some(LiquidationOpportunity {
+            account: self.address,
+            asset_bank: *asset_bank_pk,
+            liab_bank: *liab_bank_pk,
+            max_liquidatable_asset_amount,
+            max_liquidatable_value,
+            estimated_profit: liquidator_profit,
+        }))
+    }
+}
+
+/// Largest deposit with collateral value and largest liability of an account by value, among
+/// the banks that aren't excluded
+fn largest_liquidation_candidates(
+    deposits: &[(I80F48, Pubkey)],
+    liabs: &[(I80F48, Pubkey)],
+    is_excluded: impl Fn(&Pubkey) -> bool,
+) -> (Option<(I80F48, Pubkey)>, Option<(I80F48, Pubkey)>) {
+    let asset_candidate = deposits
+        .iter()
+        .filter(|(value, bank_pk)| value.is_positive() && !is_excluded(bank_pk))
+        .max_by(|a, b| a.0.cmp(&b.0))
+        .copied();
+
+    let liab_candidate = liabs
+        .iter()
+        .filter(|(_, bank_pk)| !is_excluded(bank_pk))
+        .max_by(|a, b| a.0.cmp(&b.0))
+        .copied();
+
+    (asset_candidate, liab_candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn liquidation_candidates_need_collateral_and_a_liability() {
+        let isolated_bank = Pubkey::new_unique();
+        let sol_bank = Pubkey::new_unique();
+        let usdc_bank = Pubkey::new_unique();
+        let excluded_bank = Pubkey::new_unique();
+
+        let liabs = [(I80F48::from_num(80), usdc_bank)];
+
+        // Isolated-tier deposits carry no collateral value
+        let (asset, liab) =
+            largest_liquidation_candidates(&[(I80F48::ZERO, isolated_bank)], &liabs, |_| false);
+        assert_eq!(asset, None);
+        assert_eq!(liab, Some((I80F48::from_num(80), usdc_bank)));
+
+        let deposits = [
+            (I80F48::from_num(50), sol_bank),
+            (I80F48::from_num(500), excluded_bank),
+        ];
+        let (asset, _) =
+            largest_liquidation_candidates(&deposits, &liabs, |bank| *bank == excluded_bank);
+        assert_eq!(asset, Some((I80F48::from_num(50), sol_bank)));
+
+        let (_, liab) = largest_liquidation_candidates(&deposits, &[], |_| false);
+        assert_eq!(liab, None);
+    }
+
+    #[test]
+    fn health_cache_is_invalidated_by_account_updates() {
+        let mut account: MarginfiAccount = bytemuck::Zeroable::zeroed();
+        account.lending_account.balances[0].active = true;
+        account.lending_account.balances[0].bank_pk = Pubkey::new_unique();
+
+        let mut wrapper =
+            MarginfiAccountWrapper::new(Pubkey::new_unique(), account, Arc::new(DashMap::new()));
+
+        let key = wrapper.health_cache_key();
+        assert!(wrapper.health_cache_key() == key);
+
+        wrapper.version += 1;
+        assert!(wrapper.health_cache_key() != key);
+
+        let key = wrapper.health_cache_key();
+
+        wrapper.account.lending_account.balances[1].active = true;
+        wrapper.account.lending_account.balances[1].bank_pk = Pubkey::new_unique();
+        assert!(wrapper.health_cache_key() != key);
+    }
+
+    #[test]
+    fn accounts_holding_unloaded_banks_are_never_warm() {
+        let mut account: MarginfiAccount = bytemuck::Zeroable::zeroed();
+        let wrapper =
+            MarginfiAccountWrapper::new(Pubkey::new_unique(), account, Arc::new(DashMap::new()));
+        assert!(wrapper.has_warm_oracles(Duration::MAX));
+
+        account.lending_account.balances[0].active = true;
+        account.lending_account.balances[0].bank_pk = Pubkey::new_unique();
+        let wrapper =
+            MarginfiAccountWrapper::new(Pubkey::new_unique(), account, Arc::new(DashMap::new()));
+        assert!(!wrapper.has_warm_oracles(Duration::MAX));
+    }
+
+    #[test]
+    fn unloaded_banks_count_as_outside_of_the_group() {
+        let group = Pubkey::new_unique();
+        let bank = Pubkey::new_unique();
+
+        let mut account: MarginfiAccount = bytemuck::Zeroable::zeroed();
+        account.group = group;
+        account.lending_account.balances[0].active = true;
+        account.lending_account.balances[0].bank_pk = bank;
+        // Inactive balances are ignored
+        account.lending_account.balances[1].bank_pk = Pubkey::new_unique();
+
+        let wrapper =
+            MarginfiAccountWrapper::new(Pubkey::new_unique(), account, Arc::new(DashMap::new()));
+
+        assert_eq!(wrapper.get_cross_group_banks(&group), vec![bank]);
+    }
+
+    #[test]
+    fn snapshot_view_keeps_the_account_and_swaps_the_banks() {
+        let active_bank = Pubkey::new_unique();
+
+        let mut account: MarginfiAccount = bytemuck::Zeroable::zeroed();
+        account.lending_account.balances[0].active = true;
+        account.lending_account.balances[0].bank_pk = active_bank;
+        account.lending_account.balances[1].bank_pk = Pubkey::new_unique();
+
+        let mut wrapper =
+            MarginfiAccountWrapper::new(Pubkey::new_unique(), account, Arc::new(DashMap::new()));
+        wrapper.version = 3;
+
+        assert_eq!(wrapper.get_active_banks(), vec![active_bank]);
+
+        let snapshot = Arc::new(DashMap::new());
+        let view = wrapper.with_banks(snapshot.clone());
+
+        assert_eq!(view.address, wrapper.address);
+        assert_eq!(view.version, wrapper.version);
+        assert_eq!(view.get_active_banks(), vec![active_bank]);
+        assert!(Arc::ptr_eq(&view.banks, &snapshot));
+        assert!(!Arc::ptr_eq(&view.banks, &wrapper.banks));
     }
 }
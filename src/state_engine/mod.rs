@@ -1,3 +1,4 @@
 pub mod engine;
 pub mod geyser;
 pub mod marginfi_account;
+pub mod snapshot;
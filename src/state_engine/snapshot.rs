@@ -0,0 +1,70 @@
+//! Snapshots of the tracked marginfi accounts, persisted so that a restart can warm the state
+//! engine from disk instead of fetching every account from the RPC
+
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use solana_program::pubkey::Pubkey;
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct StateSnapshot {
+    /// Group the accounts belong to, snapshots of another group are never restored
+    pub group: Pubkey,
+    /// Slot the snapshot was taken at
+    pub slot: u64,
+    /// Unix timestamp in seconds the snapshot was taken at
+    pub timestamp: u64,
+    /// Address and data, discriminator included, of each tracked marginfi account
+    pub marginfi_accounts: Vec<(Pubkey, Vec<u8>)>,
+}
+
+impl StateSnapshot {
+    pub fn new(group: Pubkey, slot: u64, marginfi_accounts: Vec<(Pubkey, Vec<u8>)>) -> Self {
+        Self {
+            group,
+            slot,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|elapsed| elapsed.as_secs())
+                .unwrap_or_default(),
+            marginfi_accounts,
+        }
+    }
+
+    /// Time since the snapshot was taken, zero for snapshots from the future
+    pub fn age(&self) -> Duration {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+
+        Duration::from_secs(now.saturating_sub(self.timestamp))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let file = File::open(path)?;
+
+        Ok(bincode::deserialize_from(BufReader::new(file))?)
+    }
+
+    /// Write the snapshot to `path` through a temporary file, so that a crash while saving
+    /// never leaves a truncated snapshot behind
+    pub fn save(&self, path: impl AsRef<Path>) -> anyhow::Result<()> {
+        let path = path.as_ref();
+        let tmp_path = path.with_extension("tmp");
+
+        {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            bincode::serialize_into(&mut writer, self)?;
+            writer.flush()?;
+        }
+
+        fs::rename(&tmp_path, path)?;
+
+        Ok(())
+    }
+}
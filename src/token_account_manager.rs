@@ -1,22 +1,23 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     sync::{Arc, RwLock},
 };
 
-use anchor_spl::associated_token;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use sha2::{Digest, Sha256};
-use solana_client::rpc_client::RpcClient;
 use solana_sdk::{
+    account::Account,
+    commitment_config::CommitmentConfig,
     pubkey::Pubkey,
     signature::Keypair,
     signer::{SeedDerivable, Signer},
 };
 
 use crate::{
+    rpc_pool::RpcPool,
     sender::{aggressive_send_tx, SenderCfg},
-    utils::{batch_get_multiple_accounts, BatchLoadingConfig},
+    utils::{accessor, batch_get_multiple_accounts, is_token_program, BatchLoadingConfig},
 };
 
 const TOKEN_ACCOUNT_SEED: &[u8] = b"liquidator_ta";
@@ -31,31 +32,64 @@ pub enum TokenAccountManagerError {
 #[derive(Clone)]
 pub struct TokenAccountManager {
     mint_to_account: Arc<RwLock<HashMap<Pubkey, Pubkey>>>,
-    rpc_client: Arc<RpcClient>,
+    /// Token program owning each mint, classic SPL Token or Token-2022
+    mint_to_token_program: Arc<RwLock<HashMap<Pubkey, Pubkey>>>,
+    rpc_pool: Arc<RpcPool>,
 }
 
 impl TokenAccountManager {
-    pub fn new(rpc_client: Arc<RpcClient>) -> Result<Self, TokenAccountManagerError> {
+    pub fn new(rpc_pool: Arc<RpcPool>) -> Result<Self, TokenAccountManagerError> {
         Ok(Self {
             mint_to_account: Arc::new(RwLock::new(HashMap::new())),
-            rpc_client,
+            mint_to_token_program: Arc::new(RwLock::new(HashMap::new())),
+            rpc_pool,
         })
     }
 
+    /// Track the token accounts of `mints`, derived for the token program owning each mint
     pub fn add_mints(
         &self,
         mints: &[Pubkey],
         signer: Pubkey,
     ) -> Result<(), TokenAccountManagerError> {
-        let mut mint_to_account = self.mint_to_account.write().unwrap();
+        let mint_accounts =
+            batch_get_multiple_accounts(self.rpc_pool.clone(), mints, BatchLoadingConfig::DEFAULT)
+                .map_err(|e| {
+                    error!("Failed to fetch mint accounts: {:?}", e);
+                    TokenAccountManagerError::SetupFailed("Failed to fetch mint accounts")
+                })?;
 
-        mints.iter().try_for_each(|mint| {
-            let address = get_address_for_token_account(signer, *mint, TOKEN_ACCOUNT_SEED)?;
+        let mut mint_to_account = self.mint_to_account.write().unwrap();
+        let mut mint_to_token_program = self.mint_to_token_program.write().unwrap();
 
-            mint_to_account.insert(*mint, address);
+        mints
+            .iter()
+            .zip(mint_accounts)
+            .try_for_each(|(mint, mint_account)| {
+                let token_program = get_token_program_of_mint(mint, mint_account.as_ref());
+
+                let address = get_address_for_token_account(
+                    signer,
+                    *mint,
+                    &token_program,
+                    TOKEN_ACCOUNT_SEED,
+                )?;
+
+                mint_to_account.insert(*mint, address);
+                mint_to_token_program.insert(*mint, token_program);
+
+                Ok::<_, TokenAccountManagerError>(())
+            })
+    }
 
-            Ok::<_, TokenAccountManagerError>(())
-        })
+    /// Token program owning `mint`, SPL Token for mints that weren't added
+    pub fn get_token_program_for_mint(&self, mint: &Pubkey) -> Pubkey {
+        self.mint_to_token_program
+            .read()
+            .unwrap()
+            .get(mint)
+            .copied()
+            .unwrap_or(spl_token::ID)
     }
 
     pub fn get_mints_and_token_account_addresses(&self) -> (Vec<Pubkey>, Vec<Pubkey>) {
@@ -75,10 +109,15 @@ impl TokenAccountManager {
         (mints, addresses)
     }
 
+    /// Create the token accounts of the tracked mints that don't exist yet, returning the
+    /// addresses of the created accounts
+    ///
+    /// Existing accounts are left alone, so calling it again once every account exists sends
+    /// nothing.
     pub fn create_token_accounts(
         &self,
         signer: Arc<Keypair>,
-    ) -> Result<(), TokenAccountManagerError> {
+    ) -> Result<HashSet<Pubkey>, TokenAccountManagerError> {
         let mints = self
             .mint_to_account
             .read()
@@ -87,7 +126,7 @@ impl TokenAccountManager {
             .copied()
             .collect::<Vec<_>>();
 
-        let rpc_client = self.rpc_client.clone();
+        let rpc_pool = self.rpc_pool.clone();
 
         let tas = mints
             .iter()
@@ -110,7 +149,7 @@ impl TokenAccountManager {
             let addresses = tas.iter().map(|(_, address)| *address).collect::<Vec<_>>();
 
             let res = batch_get_multiple_accounts(
-                rpc_client.clone(),
+                rpc_pool.clone(),
                 &addresses,
                 BatchLoadingConfig::DEFAULT,
             )
@@ -130,28 +169,34 @@ impl TokenAccountManager {
                         None
                     }
                 })
-                .map(|(_, mint)| -> Result<_, TokenAccountManagerError> {
+                .map(|(address, mint)| -> Result<_, TokenAccountManagerError> {
                     let signer_pk = signer.pubkey();
-                    let ix = spl_associated_token_account::instruction::create_associated_token_account_idempotent(&signer_pk, &signer_pk, mint, &spl_token::ID);
+                    let token_program = self.get_token_program_for_mint(mint);
+                    let ix = spl_associated_token_account::instruction::create_associated_token_account_idempotent(&signer_pk, &signer_pk, mint, &token_program);
 
-                    Ok(ix)
+                    Ok((*address, ix))
                 })
                 .collect::<Result<Vec<_>, _>>()?;
 
+            if tas_to_create.is_empty() {
+                debug!("All {} token accounts exist", tas.len());
+                return Ok(HashSet::new());
+            }
+
             info!("Creating {} token accounts", tas_to_create.len());
 
-            let recent_blockhash = rpc_client.get_latest_blockhash().map_err(|e| {
+            let recent_blockhash = rpc_pool.get_latest_blockhash().map_err(|e| {
                 error!("Failed to get recent blockhash: {:?}", e);
                 TokenAccountManagerError::SetupFailed("Failed to get recent blockhash")
             })?;
 
-            tas_to_create
+            let created = tas_to_create
                 .par_iter()
                 .chunks(MAX_INIT_TA_IXS)
-                .try_for_each(|chunk| {
-                    let rpc = rpc_client.clone();
+                .map(|chunk| {
+                    let rpc = rpc_pool.clone();
 
-                    let ixs = chunk.iter().map(|ix| (*ix).clone()).collect::<Vec<_>>();
+                    let ixs = chunk.iter().map(|(_, ix)| ix.clone()).collect::<Vec<_>>();
                     let signers = vec![signer.as_ref()];
 
                     let tx = solana_sdk::transaction::Transaction::new_signed_with_payer(
@@ -168,11 +213,87 @@ impl TokenAccountManager {
 
                     debug!("Token accounts created {:?}", sig);
 
-                    Ok::<_, TokenAccountManagerError>(())
+                    Ok::<_, TokenAccountManagerError>(
+                        chunk
+                            .iter()
+                            .map(|(address, _)| *address)
+                            .collect::<Vec<_>>(),
+                    )
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok(created.into_iter().flatten().collect())
+        }
+    }
+
+    /// Make sure the token account for `mint` exists and belongs to the signer, creating it if
+    /// it doesn't exist yet
+    pub fn ensure_token_account(
+        &self,
+        mint: Pubkey,
+        signer: Arc<Keypair>,
+    ) -> Result<Pubkey, TokenAccountManagerError> {
+        let address =
+            self.get_address_for_mint(mint)
+                .ok_or(TokenAccountManagerError::SetupFailed(
+                    "Failed to find token account address",
+                ))?;
+
+        let account = self
+            .rpc_pool
+            .client()
+            .get_account_with_commitment(&address, CommitmentConfig::confirmed())
+            .map_err(|e| {
+                error!("Failed to get token account {}: {:?}", address, e);
+                TokenAccountManagerError::SetupFailed("Failed to get token account")
+            })?
+            .value;
+
+        let token_program = self.get_token_program_for_mint(&mint);
+
+        match account {
+            Some(account) => {
+                if !is_token_account_of(&account, &token_program, &signer.pubkey()) {
+                    error!(
+                        "Token account {} for mint {} is not a token account of the signer",
+                        address, mint
+                    );
+                    return Err(TokenAccountManagerError::SetupFailed(
+                        "Token account not owned by the signer",
+                    ));
+                }
+
+                debug!("Token account {} for mint {} exists", address, mint);
+            }
+            None => {
+                info!("Creating token account {} for mint {}", address, mint);
+
+                let signer_pk = signer.pubkey();
+                let ix = spl_associated_token_account::instruction::create_associated_token_account_idempotent(&signer_pk, &signer_pk, &mint, &token_program);
+
+                let recent_blockhash = self.rpc_pool.get_latest_blockhash().map_err(|e| {
+                    error!("Failed to get recent blockhash: {:?}", e);
+                    TokenAccountManagerError::SetupFailed("Failed to get recent blockhash")
                 })?;
+
+                let tx = solana_sdk::transaction::Transaction::new_signed_with_payer(
+                    &[ix],
+                    Some(&signer_pk),
+                    &[signer.as_ref()],
+                    recent_blockhash,
+                );
+
+                let sig = aggressive_send_tx(self.rpc_pool.clone(), &tx, SenderCfg::DEFAULT)
+                    .map_err(|e| {
+                        error!("Failed to send transaction: {:?}", e);
+                        TokenAccountManagerError::SetupFailed("Failed to send transaction")
+                    })?;
+
+                debug!("Token account created {:?}", sig);
+            }
         }
 
-        Ok(())
+        Ok(address)
     }
 
     pub fn get_address_for_mint(&self, mint: Pubkey) -> Option<Pubkey> {
@@ -200,12 +321,145 @@ fn get_keypair_for_token_account(
         .map_err(|_| TokenAccountManagerError::SetupFailed("Keypair::from_seed failed"))?)
 }
 
+/// Token program owning the `mint_account` of `mint`, SPL Token when it's missing or not owned
+/// by a token program
+fn get_token_program_of_mint(mint: &Pubkey, mint_account: Option<&Account>) -> Pubkey {
+    match mint_account {
+        Some(mint_account) if is_token_program(&mint_account.owner) => mint_account.owner,
+        Some(mint_account) => {
+            warn!(
+                "Mint {} is owned by {}, not a token program, assuming SPL Token",
+                mint, mint_account.owner
+            );
+            spl_token::ID
+        }
+        None => {
+            warn!("Mint {} not found, assuming SPL Token", mint);
+            spl_token::ID
+        }
+    }
+}
+
+/// Whether `account` is a token account of `token_program` with `authority` as its owner
+fn is_token_account_of(account: &Account, token_program: &Pubkey, authority: &Pubkey) -> bool {
+    account.owner == *token_program && accessor::authority(&account.data) == *authority
+}
+
 fn get_address_for_token_account(
     signer: Pubkey,
     mint: Pubkey,
+    token_program: &Pubkey,
     _seed: &[u8],
 ) -> Result<Pubkey, TokenAccountManagerError> {
-    Ok(associated_token::get_associated_token_address(
-        &signer, &mint,
-    ))
+    Ok(
+        spl_associated_token_account::get_associated_token_address_with_program_id(
+            &signer,
+            &mint,
+            token_program,
+        ),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::TOKEN_2022_PROGRAM_ID;
+
+    /// Token-2022 mint with 6 decimals and a metadata pointer extension after the base layout
+    fn token_2022_mint_fixture() -> Account {
+        let mut data = vec![0u8; 82];
+        data[44] = 6;
+        data[45] = 1;
+        // Padding up to the account type, the account type and a metadata pointer extension
+        data.resize(165, 0);
+        data.push(1);
+        data.extend_from_slice(&18u16.to_le_bytes());
+        data.extend_from_slice(&64u16.to_le_bytes());
+        data.extend_from_slice(&[0u8; 64]);
+
+        Account {
+            lamports: 1_000_000,
+            data,
+            owner: TOKEN_2022_PROGRAM_ID,
+            executable: false,
+            rent_epoch: 0,
+        }
+    }
+
+    #[test]
+    fn token_2022_mint_resolves_to_the_token_2022_program() {
+        let mint = Pubkey::new_unique();
+        let mint_account = token_2022_mint_fixture();
+
+        assert_eq!(
+            get_token_program_of_mint(&mint, Some(&mint_account)),
+            TOKEN_2022_PROGRAM_ID
+        );
+        assert_eq!(accessor::mint_decimals(&mint_account.data), 6);
+
+        let other_owner = Account {
+            owner: Pubkey::new_unique(),
+            ..mint_account
+        };
+        assert_eq!(
+            get_token_program_of_mint(&mint, Some(&other_owner)),
+            spl_token::ID
+        );
+        assert_eq!(get_token_program_of_mint(&mint, None), spl_token::ID);
+    }
+
+    #[test]
+    fn token_2022_token_account_is_derived_for_the_token_2022_program() {
+        let signer = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+
+        let address =
+            get_address_for_token_account(signer, mint, &TOKEN_2022_PROGRAM_ID, TOKEN_ACCOUNT_SEED)
+                .unwrap();
+
+        let (expected, _) = Pubkey::find_program_address(
+            &[
+                signer.as_ref(),
+                TOKEN_2022_PROGRAM_ID.as_ref(),
+                mint.as_ref(),
+            ],
+            &spl_associated_token_account::id(),
+        );
+
+        assert_eq!(address, expected);
+        assert_ne!(
+            address,
+            get_address_for_token_account(signer, mint, &spl_token::ID, TOKEN_ACCOUNT_SEED)
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn existing_swap_token_account_must_belong_to_the_signer() {
+        let signer = Pubkey::new_unique();
+
+        let mut data = vec![0u8; 165];
+        data[..32].copy_from_slice(Pubkey::new_unique().as_ref());
+        data[32..64].copy_from_slice(signer.as_ref());
+
+        let account = Account {
+            lamports: 1_000_000,
+            data,
+            owner: spl_token::ID,
+            executable: false,
+            rent_epoch: 0,
+        };
+
+        assert!(is_token_account_of(&account, &spl_token::ID, &signer));
+        assert!(!is_token_account_of(
+            &account,
+            &spl_token::ID,
+            &Pubkey::new_unique()
+        ));
+        assert!(!is_token_account_of(
+            &account,
+            &TOKEN_2022_PROGRAM_ID,
+            &signer
+        ));
+    }
 }
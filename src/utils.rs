@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     str::FromStr,
     sync::{atomic::AtomicUsize, Arc, RwLock},
 };
@@ -21,20 +22,25 @@ use serde::{Deserialize, Deserializer};
 use solana_account_decoder::UiAccountEncoding;
 use solana_client::rpc_config::RpcAccountInfoConfig;
 use solana_program::pubkey::Pubkey;
-use solana_sdk::account::Account;
+use solana_sdk::{
+    account::Account, compute_budget, compute_budget::ComputeBudgetInstruction,
+    message::VersionedMessage, pubkey,
+};
 use yellowstone_grpc_proto::geyser::SubscribeUpdateAccountInfo;
 
-use crate::state_engine::engine::BankWrapper;
+use crate::{rpc_pool::RpcPool, state_engine::engine::BankWrapper};
 
 pub struct BatchLoadingConfig {
     pub max_batch_size: usize,
     pub max_concurrent_calls: usize,
+    pub allow_partial_failures: bool,
 }
 
 impl BatchLoadingConfig {
     pub const DEFAULT: Self = Self {
         max_batch_size: 100,
         max_concurrent_calls: 64,
+        allow_partial_failures: false,
     };
 }
 
@@ -42,6 +48,8 @@ impl BatchLoadingConfig {
 ///
 /// - `max_batch_size`: The maximum number of accounts to load in a single RPC call.
 /// - `max_concurrent_calls`: The maximum number of concurrent RPC calls.
+/// - `allow_partial_failures`: Tolerate chunks failing after their retries, see
+///   [`batch_get_multiple_accounts_with_failures`].
 ///
 /// This function will perform multiple RPC calls concurrently, up to `max_concurrent_calls`.
 /// If the number of pending RPC calls exceeds `max_concurrent_calls`, the function will
@@ -49,18 +57,47 @@ impl BatchLoadingConfig {
 /// Additionally, logs progress information including the number of accounts being fetched,
 /// the size of each chunk, and the current progress using trace and debug logs.
 pub fn batch_get_multiple_accounts(
-    rpc_client: Arc<solana_client::rpc_client::RpcClient>,
+    rpc_pool: Arc<RpcPool>,
+    addresses: &[Pubkey],
+    config: BatchLoadingConfig,
+) -> anyhow::Result<Vec<Option<Account>>> {
+    let (accounts, failed_addresses) =
+        batch_get_multiple_accounts_with_failures(rpc_pool, addresses, config)?;
+
+    if !failed_addresses.is_empty() {
+        log::warn!(
+            "Failed to fetch {} / {} accounts",
+            failed_addresses.len(),
+            addresses.len()
+        );
+    }
+
+    Ok(accounts)
+}
+
+/// Same as [`batch_get_multiple_accounts`], also returning the addresses that failed to load.
+///
+/// Without `allow_partial_failures`, the first chunk failing after its retries fails the whole
+/// call. With it, the accounts of a failed chunk are returned as `None`, just like accounts that
+/// don't exist, and their addresses are returned as failed so callers can tell them apart and
+/// retry just those. Returned accounts are always in the order of `addresses`.
+///
+/// Each call fails over across the pool's endpoints before being retried.
+pub fn batch_get_multiple_accounts_with_failures(
+    rpc_pool: Arc<RpcPool>,
     addresses: &[Pubkey],
     BatchLoadingConfig {
         max_batch_size,
         max_concurrent_calls,
+        allow_partial_failures,
     }: BatchLoadingConfig,
-) -> anyhow::Result<Vec<Option<Account>>> {
+) -> anyhow::Result<(Vec<Option<Account>>, Vec<Pubkey>)> {
     let batched_addresses = addresses.chunks(max_batch_size * max_concurrent_calls);
     let total_addresses = addresses.len();
     let total_batches = batched_addresses.len();
 
     let mut accounts = Vec::new();
+    let mut failed_addresses = Vec::new();
     let fetched_accounts = Arc::new(AtomicUsize::new(0));
 
     for (batch_index, batch) in batched_addresses.enumerate() {
@@ -73,30 +110,37 @@ pub fn batch_get_multiple_accounts(
             batch_size
         );
 
-        let mut batched_accounts = batch
+        let chunk_results = batch
             .par_chunks(max_batch_size)
-            .map(|chunk| -> anyhow::Result<Vec<_>> {
-                let rpc_client = rpc_client.clone();
+            .map(|chunk| -> (&[Pubkey], anyhow::Result<Vec<_>>) {
+                let rpc_pool = rpc_pool.clone();
+                let chunk_addresses = chunk;
                 let chunk = chunk.to_vec();
                 let chunk_size = chunk.len();
 
                 log::trace!(" - Fetching chunk of size {}", chunk_size);
 
                 let chunk_res = backoff::retry(ExponentialBackoff::default(), move || {
-                    let rpc_client = rpc_client.clone();
+                    let rpc_pool = rpc_pool.clone();
                     let chunk = chunk.clone();
 
-                    rpc_client
-                        .get_multiple_accounts_with_config(
-                            &chunk,
-                            RpcAccountInfoConfig {
-                                encoding: Some(UiAccountEncoding::Base64Zstd),
-                                ..Default::default()
-                            },
-                        )
+                    rpc_pool
+                        .with_failover(|rpc_client| {
+                            rpc_client.get_multiple_accounts_with_config(
+                                &chunk,
+                                RpcAccountInfoConfig {
+                                    encoding: Some(UiAccountEncoding::Base64Zstd),
+                                    ..Default::default()
+                                },
+                            )
+                        })
                         .map_err(backoff::Error::transient)
-                })?
-                .value;
+                });
+
+                let chunk_res = match chunk_res {
+                    Ok(chunk_res) => chunk_res.value,
+                    Err(e) => return (chunk_addresses, Err(e.into())),
+                };
 
                 let fetched_chunk_size = chunk_res.len();
 
@@ -110,15 +154,16 @@ pub fn batch_get_multiple_accounts(
                     total_addresses
                 );
 
-                Ok(chunk_res)
+                (chunk_addresses, Ok(chunk_res))
             })
-            .collect::<Result<Vec<_>>>()?
-            .iter()
-            .flatten()
-            .cloned()
             .collect::<Vec<_>>();
 
-        accounts.append(&mut batched_accounts);
+        collect_chunk_results(
+            chunk_results,
+            allow_partial_failures,
+            &mut accounts,
+            &mut failed_addresses,
+        )?;
     }
 
     log::debug!(
@@ -126,14 +171,56 @@ pub fn batch_get_multiple_accounts(
         fetched_accounts.load(std::sync::atomic::Ordering::Relaxed)
     );
 
-    Ok(accounts)
+    Ok((accounts, failed_addresses))
+}
+
+/// Append the accounts of fetched chunks to `accounts` in order, failed chunks count as missing
+/// accounts and their addresses are appended to `failed_addresses`, unless
+/// `allow_partial_failures` is off and the first failure is returned
+fn collect_chunk_results(
+    chunk_results: Vec<(&[Pubkey], Result<Vec<Option<Account>>>)>,
+    allow_partial_failures: bool,
+    accounts: &mut Vec<Option<Account>>,
+    failed_addresses: &mut Vec<Pubkey>,
+) -> Result<()> {
+    for (chunk_addresses, chunk_res) in chunk_results {
+        match chunk_res {
+            Ok(mut chunk_accounts) => accounts.append(&mut chunk_accounts),
+            Err(e) if allow_partial_failures => {
+                log::warn!(
+                    " - Failed to fetch chunk of {} accounts: {:?}",
+                    chunk_addresses.len(),
+                    e
+                );
+                accounts.extend(chunk_addresses.iter().map(|_| None));
+                failed_addresses.extend_from_slice(chunk_addresses);
+            }
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(())
+}
+
+/// Token-2022 program, owning mints and token accounts of assets listed with token extensions
+pub const TOKEN_2022_PROGRAM_ID: Pubkey = pubkey!("TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb");
+
+/// Whether `program_id` is the classic SPL Token program or Token-2022
+pub fn is_token_program(program_id: &Pubkey) -> bool {
+    *program_id == spl_token::ID || *program_id == TOKEN_2022_PROGRAM_ID
 }
 
 // Field parsers to save compute. All account validation is assumed to be done
 // outside of these methods.
+//
+// Token-2022 mints and token accounts start with the same base layout as classic SPL Token
+// ones, extensions are only appended after it, so the offsets hold for both programs.
 pub mod accessor {
     use super::*;
 
+    /// Offset of the decimals in a mint: mint authority option (36), supply (8)
+    const MINT_DECIMALS_OFFSET: usize = 44;
+
     pub fn amount(bytes: &[u8]) -> u64 {
         let mut amount_bytes = [0u8; 8];
         amount_bytes.copy_from_slice(&bytes[64..72]);
@@ -151,6 +238,11 @@ pub mod accessor {
         owner_bytes.copy_from_slice(&bytes[32..64]);
         Pubkey::new_from_array(owner_bytes)
     }
+
+    /// Decimals of a mint account
+    pub fn mint_decimals(bytes: &[u8]) -> u8 {
+        bytes[MINT_DECIMALS_OFFSET]
+    }
 }
 
 pub fn account_update_to_account(account_update: &SubscribeUpdateAccountInfo) -> Result<Account> {
@@ -221,6 +313,19 @@ where
         .collect()
 }
 
+pub(crate) fn from_pubkey_string_map<'de, D, V>(
+    deserializer: D,
+) -> Result<HashMap<Pubkey, V>, D::Error>
+where
+    D: Deserializer<'de>,
+    V: Deserialize<'de>,
+{
+    let s: HashMap<String, V> = Deserialize::deserialize(deserializer)?;
+    s.into_iter()
+        .map(|(k, v)| Ok((Pubkey::from_str(&k).map_err(serde::de::Error::custom)?, v)))
+        .collect()
+}
+
 pub struct BankAccountWithPriceFeedEva<'a> {
     bank: Arc<RwLock<BankWrapper>>,
     balance: &'a Balance,
@@ -313,14 +418,17 @@ impl<'a> BankAccountWithPriceFeedEva<'a> {
         match bank.config.risk_tier {
             RiskTier::Collateral => {
                 let price_feed = &self.bank.read().unwrap().oracle_adapter.price_adapter;
+                let oracle_price_type = self
+                    .bank
+                    .read()
+                    .unwrap()
+                    .get_oracle_price_type(requirement_type);
                 let mut asset_weight = bank
                     .config
                     .get_weight(requirement_type, BalanceSide::Assets);
 
-                let lower_price = price_feed.get_price_of_type(
-                    requirement_type.get_oracle_price_type(),
-                    Some(PriceBias::Low),
-                )?;
+                let lower_price =
+                    price_feed.get_price_of_type(oracle_price_type, Some(PriceBias::Low))?;
 
                 if matches!(requirement_type, RequirementType::Initial) {
                     if let Some(discount) =
@@ -350,14 +458,17 @@ impl<'a> BankAccountWithPriceFeedEva<'a> {
         bank: &Bank,
     ) -> MarginfiResult<I80F48> {
         let price_feed = &self.bank.read().unwrap().oracle_adapter.price_adapter;
+        let oracle_price_type = self
+            .bank
+            .read()
+            .unwrap()
+            .get_oracle_price_type(requirement_type);
         let liability_weight = bank
             .config
             .get_weight(requirement_type, BalanceSide::Liabilities);
 
-        let higher_price = price_feed.get_price_of_type(
-            requirement_type.get_oracle_price_type(),
-            Some(PriceBias::High),
-        )?;
+        let higher_price =
+            price_feed.get_price_of_type(oracle_price_type, Some(PriceBias::High))?;
 
         calc_value(
             bank.get_liability_amount(self.balance.liability_shares.into())?,
@@ -389,6 +500,28 @@ pub fn find_bank_vault_authority_pda(
     Pubkey::find_program_address(bank_authority_seed!(vault_type, bank_pk), program_id)
 }
 
+/// Oracle keys configured on `bank`, skipping the unused default entries of `oracle_keys`
+pub fn get_oracle_keys(bank: &Bank) -> Vec<Pubkey> {
+    bank.config
+        .oracle_keys
+        .iter()
+        .filter(|oracle_key| **oracle_key != Pubkey::default())
+        .copied()
+        .collect()
+}
+
+/// Convert a fixed point token amount into the native amount an instruction can move, and the
+/// fractional remainder left behind.
+///
+/// Amounts are rounded down, rounding up would request more than the account holds. Negative
+/// amounts convert to zero.
+pub fn fixed_to_native_amount(amount: I80F48) -> (u64, I80F48) {
+    let amount = amount.max(I80F48::ZERO);
+    let whole = amount.floor();
+
+    (whole.checked_to_num().unwrap_or(u64::MAX), amount - whole)
+}
+
 pub fn calc_weighted_assets(
     bank_rw_lock: Arc<RwLock<BankWrapper>>,
     amount: I80F48,
@@ -407,8 +540,10 @@ pub fn calc_weighted_assets(
         Some(PriceBias::Low)
     };
 
-    let lower_price =
-        price_feed.get_price_of_type(requirement_type.get_oracle_price_type(), price_bias)?;
+    let lower_price = price_feed.get_price_of_type(
+        bank_wrapper_ref.get_oracle_price_type(requirement_type),
+        price_bias,
+    )?;
 
     if matches!(requirement_type, RequirementType::Initial) {
         if let Some(discount) = bank_wrapper_ref
@@ -448,8 +583,10 @@ pub fn calc_weighted_liabs(
         Some(PriceBias::High)
     };
 
-    let higher_price =
-        price_feed.get_price_of_type(requirement_type.get_oracle_price_type(), price_bias)?;
+    let higher_price = price_feed.get_price_of_type(
+        bank_wrapper_ref.get_oracle_price_type(requirement_type),
+        price_bias,
+    )?;
 
     Ok(calc_value(
         amount,
@@ -458,3 +595,87 @@ pub fn calc_weighted_liabs(
         Some(liability_weight),
     )?)
 }
+
+/// Replace the compute unit limit a message requests, e.g. the one Jupiter adds to its swap
+/// transactions
+///
+/// Returns whether the message had a compute unit limit instruction to replace, the message
+/// has to be signed again afterwards. Program ids are always static keys, so only those are
+/// searched, address lookup tables of v0 messages are left untouched.
+pub fn set_message_compute_unit_limit(message: &mut VersionedMessage, limit: u32) -> bool {
+    let limit_data = ComputeBudgetInstruction::set_compute_unit_limit(limit).data;
+
+    let (account_keys, instructions) = match message {
+        VersionedMessage::Legacy(message) => (&message.account_keys, &mut message.instructions),
+        VersionedMessage::V0(message) => (&message.account_keys, &mut message.instructions),
+    };
+
+    match instructions.iter_mut().find(|ix| {
+        account_keys.get(usize::from(ix.program_id_index)) == Some(&compute_budget::id())
+            && ix.data.first() == limit_data.first()
+    }) {
+        Some(ix) => {
+            ix.data = limit_data;
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_chunks_are_reported_when_partial_failures_are_allowed() {
+        let addresses = (0..4).map(|_| Pubkey::new_unique()).collect::<Vec<_>>();
+        let account = Account::new(1, 0, &Pubkey::new_unique());
+
+        let chunk_results = || {
+            vec![
+                (&addresses[..2], Ok(vec![Some(account.clone()), None])),
+                (&addresses[2..], Err(anyhow!("RPC unavailable"))),
+            ]
+        };
+
+        let mut accounts = vec![];
+        let mut failed_addresses = vec![];
+        collect_chunk_results(chunk_results(), true, &mut accounts, &mut failed_addresses).unwrap();
+
+        assert_eq!(accounts, vec![Some(account), None, None, None]);
+        assert_eq!(failed_addresses, addresses[2..].to_vec());
+
+        assert!(collect_chunk_results(chunk_results(), false, &mut vec![], &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn banks_without_an_oracle_have_no_oracle_keys() {
+        let mut bank: Bank = bytemuck::Zeroable::zeroed();
+        assert!(get_oracle_keys(&bank).is_empty());
+
+        let oracle = Pubkey::new_unique();
+        bank.config.oracle_keys[0] = oracle;
+        assert_eq!(get_oracle_keys(&bank), vec![oracle]);
+    }
+
+    #[test]
+    fn fixed_amounts_round_down_to_native_units() {
+        assert_eq!(
+            fixed_to_native_amount(I80F48::from_num(1234.75)),
+            (1234, I80F48::from_num(0.75))
+        );
+        assert_eq!(
+            fixed_to_native_amount(I80F48::from_num(0.5)),
+            (0, I80F48::from_num(0.5))
+        );
+        assert_eq!(
+            fixed_to_native_amount(I80F48::from_num(42)),
+            (42, I80F48::ZERO)
+        );
+        assert_eq!(
+            fixed_to_native_amount(I80F48::from_num(-3.5)),
+            (0, I80F48::ZERO)
+        );
+        assert_eq!(fixed_to_native_amount(I80F48::MAX).0, u64::MAX);
+    }
+}